@@ -0,0 +1,77 @@
+//! Per-block `aes_encrypt`/`aes_decrypt` against the batched `aes_encrypt_blocks`/
+//! `aes_decrypt_blocks`, plus CBC decryption and CTR, which go through the batch path.
+//!
+//! Run with `cargo bench --bench batch`. The speedup comes from two places: the key is
+//! expanded once instead of per block, and the cipher keeps several blocks in flight.
+use std::time::{Duration, Instant};
+
+use aes_modes::{
+	aes_decrypt, aes_decrypt_blocks, aes_encrypt, aes_encrypt_blocks, cbc_decrypt, cbc_encrypt,
+	ctr_decrypt, ctr_encrypt, SecretKey, BLOCK_SIZE,
+};
+
+const MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+const ROUNDS: u32 = 5;
+
+fn main() {
+	let key = [42u8; BLOCK_SIZE];
+	let blocks: Vec<[u8; BLOCK_SIZE]> =
+		(0..MESSAGE_SIZE / BLOCK_SIZE).map(|i| [i as u8; BLOCK_SIZE]).collect();
+	let cbc_cipher_text = cbc_encrypt(blocks.concat(), &SecretKey::new(key));
+	let ctr_cipher_text = ctr_encrypt(blocks.concat(), &SecretKey::new(key));
+
+	println!("{} MiB message, best of {} rounds", MESSAGE_SIZE / (1024 * 1024), ROUNDS);
+	println!("{:<12} {:>14} {:>14}", "", "per block", "batched");
+
+	let encrypt_single = measure(|| blocks.iter().map(|&block| aes_encrypt(block, &key)).collect());
+	let encrypt_batch = measure(|| {
+		let mut blocks = blocks.clone();
+		aes_encrypt_blocks(&mut blocks, &key);
+		blocks
+	});
+	println!(
+		"{:<12} {:>14} {:>14}",
+		"encrypt",
+		throughput(encrypt_single),
+		throughput(encrypt_batch)
+	);
+
+	let decrypt_single = measure(|| blocks.iter().map(|&block| aes_decrypt(block, &key)).collect());
+	let decrypt_batch = measure(|| {
+		let mut blocks = blocks.clone();
+		aes_decrypt_blocks(&mut blocks, &key);
+		blocks
+	});
+	println!(
+		"{:<12} {:>14} {:>14}",
+		"decrypt",
+		throughput(decrypt_single),
+		throughput(decrypt_batch)
+	);
+
+	let cbc = measure(|| vec![cbc_decrypt(cbc_cipher_text.clone(), &SecretKey::new(key))]);
+	println!("{:<12} {:>14} {:>14}", "CBC decrypt", "-", throughput(cbc));
+
+	let plain_text = blocks.concat();
+	let ctr_encrypt = measure(|| ctr_encrypt(plain_text.clone(), &SecretKey::new(key)));
+	println!("{:<12} {:>14} {:>14}", "CTR encrypt", "-", throughput(ctr_encrypt));
+	let ctr_decrypt = measure(|| ctr_decrypt(ctr_cipher_text.clone(), &SecretKey::new(key)));
+	println!("{:<12} {:>14} {:>14}", "CTR decrypt", "-", throughput(ctr_decrypt));
+}
+
+/// Best wall-clock time over a few rounds.
+fn measure<T>(mut f: impl FnMut() -> Vec<T>) -> Duration {
+	(0..ROUNDS)
+		.map(|_| {
+			let start = Instant::now();
+			std::hint::black_box(f());
+			start.elapsed()
+		})
+		.min()
+		.unwrap()
+}
+
+fn throughput(elapsed: Duration) -> String {
+	let mib_per_second = MESSAGE_SIZE as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64();
+	format!("{:.1} MiB/s", mib_per_second)
+}
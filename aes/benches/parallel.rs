@@ -0,0 +1,62 @@
+//! Throughput of the parallel modes for 1 up to N threads.
+//!
+//! Run with `cargo bench --bench parallel`. Thread count 1 is the serial code path.
+use std::time::{Duration, Instant};
+
+use aes_modes::{
+	cbc_encrypt,
+	parallel::{
+		available_threads, cbc_decrypt_parallel, ctr_decrypt_parallel, ctr_encrypt_parallel,
+		ecb_encrypt_parallel,
+	},
+	SecretKey, BLOCK_SIZE,
+};
+
+const MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+const ROUNDS: u32 = 5;
+
+fn main() {
+	let key = SecretKey::new([42u8; BLOCK_SIZE]);
+	let plain_text: Vec<u8> = (0..MESSAGE_SIZE).map(|i| i as u8).collect();
+	let ctr_cipher_text = ctr_encrypt_parallel(plain_text.clone(), &key, available_threads());
+	let cbc_cipher_text = cbc_encrypt(plain_text.clone(), &key);
+
+	println!("{} MiB message, best of {} rounds", MESSAGE_SIZE / (1024 * 1024), ROUNDS);
+	println!(
+		"{:<8} {:>14} {:>14} {:>14} {:>14}",
+		"threads", "ECB enc", "CTR enc", "CTR dec", "CBC dec"
+	);
+
+	for threads in 1..=available_threads() {
+		let ecb = measure(|| ecb_encrypt_parallel(plain_text.clone(), &key, threads));
+		let ctr_encrypt = measure(|| ctr_encrypt_parallel(plain_text.clone(), &key, threads));
+		let ctr_decrypt = measure(|| ctr_decrypt_parallel(ctr_cipher_text.clone(), &key, threads));
+		let cbc_decrypt = measure(|| cbc_decrypt_parallel(cbc_cipher_text.clone(), &key, threads));
+
+		println!(
+			"{:<8} {:>14} {:>14} {:>14} {:>14}",
+			threads,
+			throughput(ecb),
+			throughput(ctr_encrypt),
+			throughput(ctr_decrypt),
+			throughput(cbc_decrypt)
+		);
+	}
+}
+
+/// Best wall-clock time over a few rounds.
+fn measure(mut f: impl FnMut() -> Vec<u8>) -> Duration {
+	(0..ROUNDS)
+		.map(|_| {
+			let start = Instant::now();
+			std::hint::black_box(f());
+			start.elapsed()
+		})
+		.min()
+		.unwrap()
+}
+
+fn throughput(elapsed: Duration) -> String {
+	let mib_per_second = MESSAGE_SIZE as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64();
+	format!("{:.1} MiB/s", mib_per_second)
+}
@@ -0,0 +1,324 @@
+//! AES and GHASH on the x86 AES-NI and PCLMULQDQ instructions.
+//!
+//! Modern x86 CPUs do a whole AES round in one instruction (`aesenc`), and multiply
+//! polynomials over GF(2) in another (`pclmulqdq`), which is the expensive part of GHASH.
+//! Both run in constant time, and both are pipelined: a new `aesenc` can start every cycle
+//! while the previous ones take several to finish, so we keep eight blocks in flight.
+//!
+//! Not every x86 CPU has them, so nothing here can be constructed unless the CPU says it
+//! supports both. `aes_encrypt`, the batch helpers (which CBC decryption and GCM's counter
+//! mode run on) and GHASH check once per call and fall back to the portable code when the
+//! instructions are missing. The module only exists on x86_64.
+use std::arch::x86_64::*;
+
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::BLOCK_SIZE;
+
+/// AES-128 has 10 rounds, so 11 round keys.
+const ROUND_KEYS: usize = 11;
+
+/// How many blocks are kept in flight at once.
+const PARALLEL_BLOCKS: usize = 8;
+
+/// Whether this CPU has AES-NI and PCLMULQDQ.
+pub fn is_available() -> bool {
+	#[cfg(test)]
+	if tests::PORTABLE_ONLY.with(std::cell::Cell::get) {
+		return false;
+	}
+
+	is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+}
+
+/// An AES-128 key expanded for AES-NI.
+pub struct Aes128 {
+	encrypt_keys: [__m128i; ROUND_KEYS],
+	// `aesdec` expects the middle round keys run through InvMixColumns ("equivalent inverse
+	// cipher", FIPS-197 section 5.3.5).
+	decrypt_keys: [__m128i; ROUND_KEYS],
+}
+
+impl Aes128 {
+	/// Returns `None` if the CPU has no AES-NI.
+	pub fn new(key: &[u8; BLOCK_SIZE]) -> Option<Self> {
+		if !is_available() {
+			return None;
+		}
+		// SAFETY: we just checked that the CPU supports the instructions.
+		Some(unsafe { Self::expand(key) })
+	}
+
+	/// Encrypts the blocks in place.
+	pub fn encrypt_blocks(&self, blocks: &mut [[u8; BLOCK_SIZE]]) {
+		for chunk in blocks.chunks_mut(PARALLEL_BLOCKS) {
+			// SAFETY: `self` only exists if the CPU supports AES-NI.
+			unsafe { self.encrypt_chunk(chunk) };
+		}
+	}
+
+	/// Decrypts the blocks in place.
+	pub fn decrypt_blocks(&self, blocks: &mut [[u8; BLOCK_SIZE]]) {
+		for chunk in blocks.chunks_mut(PARALLEL_BLOCKS) {
+			// SAFETY: `self` only exists if the CPU supports AES-NI.
+			unsafe { self.decrypt_chunk(chunk) };
+		}
+	}
+
+	/// The AES-128 key expansion. `aeskeygenassist` does the RotWord, SubWord and round
+	/// constant part; the shifts and XORs fold in the previous round key.
+	#[target_feature(enable = "aes")]
+	unsafe fn expand(key: &[u8; BLOCK_SIZE]) -> Self {
+		let mut encrypt_keys = [_mm_setzero_si128(); ROUND_KEYS];
+		encrypt_keys[0] = _mm_loadu_si128(key.as_ptr().cast());
+
+		// The round constant has to be a compile-time constant for `aeskeygenassist`.
+		macro_rules! expand_round {
+			($round:expr, $round_constant:expr) => {{
+				let previous = encrypt_keys[$round - 1];
+				let assist =
+					_mm_shuffle_epi32(_mm_aeskeygenassist_si128(previous, $round_constant), 0xff);
+				let mut round_key = previous;
+				round_key = _mm_xor_si128(round_key, _mm_slli_si128(round_key, 4));
+				round_key = _mm_xor_si128(round_key, _mm_slli_si128(round_key, 4));
+				round_key = _mm_xor_si128(round_key, _mm_slli_si128(round_key, 4));
+				encrypt_keys[$round] = _mm_xor_si128(round_key, assist);
+			}};
+		}
+		expand_round!(1, 0x01);
+		expand_round!(2, 0x02);
+		expand_round!(3, 0x04);
+		expand_round!(4, 0x08);
+		expand_round!(5, 0x10);
+		expand_round!(6, 0x20);
+		expand_round!(7, 0x40);
+		expand_round!(8, 0x80);
+		expand_round!(9, 0x1b);
+		expand_round!(10, 0x36);
+
+		let mut decrypt_keys = [_mm_setzero_si128(); ROUND_KEYS];
+		decrypt_keys[0] = encrypt_keys[ROUND_KEYS - 1];
+		for round in 1..ROUND_KEYS - 1 {
+			decrypt_keys[round] = _mm_aesimc_si128(encrypt_keys[ROUND_KEYS - 1 - round]);
+		}
+		decrypt_keys[ROUND_KEYS - 1] = encrypt_keys[0];
+
+		Self { encrypt_keys, decrypt_keys }
+	}
+
+	/// Encrypts up to eight blocks. Each round is applied to all of them before moving on,
+	/// so consecutive `aesenc`s don't wait for each other.
+	#[target_feature(enable = "aes")]
+	unsafe fn encrypt_chunk(&self, chunk: &mut [[u8; BLOCK_SIZE]]) {
+		let keys = &self.encrypt_keys;
+		let mut state = [_mm_setzero_si128(); PARALLEL_BLOCKS];
+		let state = &mut state[..chunk.len()];
+
+		for (s, block) in state.iter_mut().zip(chunk.iter()) {
+			*s = _mm_xor_si128(_mm_loadu_si128(block.as_ptr().cast()), keys[0]);
+		}
+		for key in &keys[1..ROUND_KEYS - 1] {
+			state.iter_mut().for_each(|s| *s = _mm_aesenc_si128(*s, *key));
+		}
+		for (s, block) in state.iter_mut().zip(chunk.iter_mut()) {
+			*s = _mm_aesenclast_si128(*s, keys[ROUND_KEYS - 1]);
+			_mm_storeu_si128(block.as_mut_ptr().cast(), *s);
+		}
+	}
+
+	#[target_feature(enable = "aes")]
+	unsafe fn decrypt_chunk(&self, chunk: &mut [[u8; BLOCK_SIZE]]) {
+		let keys = &self.decrypt_keys;
+		let mut state = [_mm_setzero_si128(); PARALLEL_BLOCKS];
+		let state = &mut state[..chunk.len()];
+
+		for (s, block) in state.iter_mut().zip(chunk.iter()) {
+			*s = _mm_xor_si128(_mm_loadu_si128(block.as_ptr().cast()), keys[0]);
+		}
+		for key in &keys[1..ROUND_KEYS - 1] {
+			state.iter_mut().for_each(|s| *s = _mm_aesdec_si128(*s, *key));
+		}
+		for (s, block) in state.iter_mut().zip(chunk.iter_mut()) {
+			*s = _mm_aesdeclast_si128(*s, keys[ROUND_KEYS - 1]);
+			_mm_storeu_si128(block.as_mut_ptr().cast(), *s);
+		}
+	}
+}
+
+impl Zeroize for Aes128 {
+	fn zeroize(&mut self) {
+		self.encrypt_keys.zeroize();
+		self.decrypt_keys.zeroize();
+	}
+}
+
+impl Drop for Aes128 {
+	fn drop(&mut self) {
+		self.zeroize();
+	}
+}
+
+impl ZeroizeOnDrop for Aes128 {}
+
+/// Multiplication in GF(2^128) in GCM's bit order, like the portable `gf_mul` in `ghash`.
+/// Returns `None` if the CPU has no PCLMULQDQ.
+pub fn gf_mul(x: u128, y: u128) -> Option<u128> {
+	if !is_available() {
+		return None;
+	}
+	// SAFETY: we just checked that the CPU supports the instruction.
+	Some(unsafe { clmul_gf_mul(x, y) })
+}
+
+/// Carry-less multiplication of the two 128-bit values into 256 bits, then reduction.
+///
+/// GCM stores polynomials bit-reflected: the top bit of the `u128` is the coefficient of
+/// `x^0`. Multiplying two reflected values gives the reflected product shifted right by
+/// one place, hence the shift by one before reducing.
+#[target_feature(enable = "pclmulqdq")]
+unsafe fn clmul_gf_mul(x: u128, y: u128) -> u128 {
+	let (x1, x0) = ((x >> 64) as u64, x as u64);
+	let (y1, y0) = ((y >> 64) as u64, y as u64);
+
+	let low = clmul(x0, y0);
+	let high = clmul(x1, y1);
+	let middle = clmul(x0, y1) ^ clmul(x1, y0);
+
+	let low = low ^ (middle << 64);
+	let high = high ^ (middle >> 64);
+
+	// Undo the reflection offset.
+	let high = (high << 1) | (low >> 127);
+	let low = low << 1;
+
+	// Now `high` holds the coefficients of x^0 to x^127 and `low` those of x^128 to x^255.
+	// Fold `low` back down with x^128 = x^7 + x^2 + x + 1. In reflected order multiplying
+	// by x is a right shift, and the bits that fall off the end need folding once more.
+	let overflow = (low << 127) ^ (low << 126) ^ (low << 121);
+	let folded = low ^ overflow;
+	high ^ folded ^ (folded >> 1) ^ (folded >> 2) ^ (folded >> 7)
+}
+
+#[target_feature(enable = "pclmulqdq")]
+unsafe fn clmul(a: u64, b: u64) -> u128 {
+	let product =
+		_mm_clmulepi64_si128(_mm_set_epi64x(0, a as i64), _mm_set_epi64x(0, b as i64), 0x00);
+	let mut bytes = [0u8; 16];
+	_mm_storeu_si128(bytes.as_mut_ptr().cast(), product);
+	u128::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use std::cell::Cell;
+
+	use rand::{rngs::StdRng, Rng, SeedableRng};
+
+	use super::*;
+	use crate::{
+		aes_decrypt, aes_encrypt, aes_encrypt_blocks, cbc_decrypt, gcm, ghash,
+		incremental::{CbcEncryptor, Encryptor},
+		reference, SecretKey,
+	};
+
+	thread_local! {
+		/// Makes `is_available` return false on this thread, to test the fallback.
+		pub(crate) static PORTABLE_ONLY: Cell<bool> = const { Cell::new(false) };
+	}
+
+	/// Runs `f` with AES-NI turned off on this thread.
+	fn portable<T>(f: impl FnOnce() -> T) -> T {
+		PORTABLE_ONLY.with(|flag| flag.set(true));
+		let result = f();
+		PORTABLE_ONLY.with(|flag| flag.set(false));
+		result
+	}
+
+	#[test]
+	fn test_fips_197_appendix_c() {
+		let Some(aes) = Aes128::new(&std::array::from_fn(|i| i as u8)) else {
+			return;
+		};
+		let plain_text = [
+			0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+			0xee, 0xff,
+		];
+		let cipher_text = [
+			0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+			0xc5, 0x5a,
+		];
+
+		let mut blocks = [plain_text];
+		aes.encrypt_blocks(&mut blocks);
+		assert_eq!(blocks, [cipher_text]);
+		aes.decrypt_blocks(&mut blocks);
+		assert_eq!(blocks, [plain_text]);
+	}
+
+	#[test]
+	fn test_forcing_the_portable_path() {
+		assert!(portable(|| Aes128::new(&[0u8; BLOCK_SIZE]).is_none()));
+		assert!(portable(|| gf_mul(1, 1).is_none()));
+	}
+
+	#[test]
+	fn test_blocks_match_reference() {
+		let mut rng = StdRng::seed_from_u64(36);
+		for count in 0..3 * PARALLEL_BLOCKS {
+			let key: [u8; BLOCK_SIZE] = rng.gen();
+			let Some(aes) = Aes128::new(&key) else {
+				return;
+			};
+			let reference = reference::Aes::new_128(&key);
+			let blocks: Vec<[u8; BLOCK_SIZE]> = (0..count).map(|_| rng.gen()).collect();
+
+			let mut encrypted = blocks.clone();
+			aes.encrypt_blocks(&mut encrypted);
+			let expected: Vec<_> =
+				blocks.iter().map(|&block| reference.encrypt_block(block)).collect();
+			assert_eq!(encrypted, expected);
+
+			aes.decrypt_blocks(&mut encrypted);
+			assert_eq!(encrypted, blocks);
+		}
+	}
+
+	#[test]
+	fn test_gf_mul_matches_portable() {
+		let mut rng = StdRng::seed_from_u64(36);
+		for _ in 0..1000 {
+			let (x, y): (u128, u128) = (rng.gen(), rng.gen());
+			let Some(product) = gf_mul(x, y) else {
+				return;
+			};
+			assert_eq!(product, ghash::portable_gf_mul(x, y));
+		}
+		// 1 is the top bit in GCM's order.
+		assert_eq!(gf_mul(1 << 127, 0x1234), Some(0x1234));
+	}
+
+	#[test]
+	fn test_both_paths_agree() {
+		let mut rng = StdRng::seed_from_u64(360);
+		let key: [u8; BLOCK_SIZE] = rng.gen();
+		let nonce: [u8; gcm::NONCE_SIZE] = rng.gen();
+		let blocks: Vec<[u8; BLOCK_SIZE]> = (0..37).map(|_| rng.gen()).collect();
+		let message: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+		let secret_key = SecretKey::new(key);
+
+		let run = || {
+			let mut batch = blocks.clone();
+			aes_encrypt_blocks(&mut batch, &key);
+			let single = aes_encrypt(blocks[0], &key);
+			let decrypted = aes_decrypt(single, &key);
+			let gcm = gcm::gcm_encrypt(&message, b"header", &nonce, &secret_key).unwrap();
+			let mut encryptor = CbcEncryptor::with_iv(&secret_key, [7u8; BLOCK_SIZE]);
+			let mut cbc = encryptor.update(&message);
+			cbc.extend(encryptor.finalize());
+			let decrypted_cbc = cbc_decrypt(cbc.clone(), &secret_key);
+			(batch, single, decrypted, gcm, cbc, decrypted_cbc)
+		};
+
+		assert_eq!(run(), portable(run));
+	}
+}
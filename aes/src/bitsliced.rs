@@ -0,0 +1,409 @@
+//! Constant-time AES, bitsliced.
+//!
+//! A table-based AES (like the one in `reference`) looks up the S-box with secret bytes as
+//! the index. Which cache lines those lookups touch depends on the key, and an attacker who
+//! can time the cipher, or just share a cache with it, can recover the key from that.
+//!
+//! Bitslicing avoids lookups altogether. Following Käsper and Schwabe, eight blocks are
+//! encrypted together and stored as eight 128-bit "planes": plane `b` holds bit `b` of
+//! every one of the 128 bytes. Every AES step then becomes plain XOR, AND and shifting of
+//! whole planes, the same instructions for every key and every input:
+//!
+//! - SubBytes computes the S-box from its definition: the inverse in GF(2^8) as `x^254`,
+//!   built from bitsliced multiplications, and then the affine transformation. Käsper and
+//!   Schwabe use a smaller tower-field circuit; this one is slower but much easier to check.
+//! - ShiftRows and the column rotations inside MixColumns move whole bytes, which are
+//!   8-bit lanes of the planes.
+//! - MixColumns multiplies by `x` (`xtime`), which on planes is just renaming them plus a
+//!   few XORs for the reduction.
+//!
+//! The key expansion uses the same bitsliced S-box, so the key never indexes a table
+//! either. Build with `--features bitsliced-aes` to run every AES mode in the crate (ECB,
+//! CBC, CTR, GCM, the CTR DRBG, ...) on this backend. ChaCha20 doesn't use AES and is not
+//! affected. Rust makes no formal promise about the machine code it emits, so constant time
+//! is by construction rather than guaranteed.
+//!
+//! Käsper & Schwabe, "Faster and Timing-Attack Resistant AES-GCM", CHES 2009:
+//! https://eprint.iacr.org/2009/129
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::{
+	reference::{expand_key, MAX_ROUNDS},
+	Error, BLOCK_SIZE,
+};
+
+/// How many blocks are encrypted together. Fewer blocks cost as much as a full batch.
+pub const PARALLEL_BLOCKS: usize = 8;
+
+/// Bit `b` of byte `p` of block `i` lives in plane `b`, at bit `8 * p + i`. So byte
+/// position `p` of all eight blocks is the 8-bit lane `p` of each plane.
+type Planes = [u128; 8];
+
+/// `SHIFT_ROWS[p]` is the byte position that moves to position `p`. Row `r` comes from `r`
+/// columns further right.
+const SHIFT_ROWS: [usize; BLOCK_SIZE] = byte_permutation(0, [0, 1, 2, 3]);
+const INV_SHIFT_ROWS: [usize; BLOCK_SIZE] = byte_permutation(0, [0, 3, 2, 1]);
+
+/// Rotate every column up by one, two or three rows.
+const ROTATE_1: [usize; BLOCK_SIZE] = byte_permutation(1, [0; 4]);
+const ROTATE_2: [usize; BLOCK_SIZE] = byte_permutation(2, [0; 4]);
+const ROTATE_3: [usize; BLOCK_SIZE] = byte_permutation(3, [0; 4]);
+
+/// An AES key, expanded into bitsliced round keys.
+pub struct Aes {
+	round_keys: [Planes; MAX_ROUNDS + 1],
+	rounds: usize,
+}
+
+impl Aes {
+	/// Expands a 16, 24 or 32 byte key, for AES-128, AES-192 or AES-256.
+	pub fn new(key: &[u8]) -> Result<Self, Error> {
+		match key.len() {
+			16 | 24 | 32 => Ok(Self::from_key(key)),
+			_ => Err(Error::InvalidKeyLength),
+		}
+	}
+
+	/// AES-128, the variant the rest of the crate uses.
+	pub fn new_128(key: &[u8; BLOCK_SIZE]) -> Self {
+		Self::from_key(key)
+	}
+
+	fn from_key(key: &[u8]) -> Self {
+		let (mut round_keys, rounds) = expand_key(key, sub_word);
+		// Every block gets the same round key, so it goes into all eight slots.
+		let planes = round_keys.map(|round_key| pack(&[round_key; PARALLEL_BLOCKS]));
+		round_keys.zeroize();
+		Self { round_keys: planes, rounds }
+	}
+
+	/// Encrypts the blocks in place, eight at a time.
+	pub fn encrypt_blocks(&self, blocks: &mut [[u8; BLOCK_SIZE]]) {
+		for chunk in blocks.chunks_mut(PARALLEL_BLOCKS) {
+			let mut batch = [[0u8; BLOCK_SIZE]; PARALLEL_BLOCKS];
+			batch[..chunk.len()].copy_from_slice(chunk);
+
+			let mut state = pack(&batch);
+			self.encrypt_planes(&mut state);
+			let mut output = unpack(&state);
+			chunk.copy_from_slice(&output[..chunk.len()]);
+
+			batch.zeroize();
+			state.zeroize();
+			output.zeroize();
+		}
+	}
+
+	/// Decrypts the blocks in place, eight at a time.
+	pub fn decrypt_blocks(&self, blocks: &mut [[u8; BLOCK_SIZE]]) {
+		for chunk in blocks.chunks_mut(PARALLEL_BLOCKS) {
+			let mut batch = [[0u8; BLOCK_SIZE]; PARALLEL_BLOCKS];
+			batch[..chunk.len()].copy_from_slice(chunk);
+
+			let mut state = pack(&batch);
+			self.decrypt_planes(&mut state);
+			let mut output = unpack(&state);
+			chunk.copy_from_slice(&output[..chunk.len()]);
+
+			batch.zeroize();
+			state.zeroize();
+			output.zeroize();
+		}
+	}
+
+	/// Encrypts a single block. This costs as much as eight.
+	pub fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+		let mut blocks = [block];
+		self.encrypt_blocks(&mut blocks);
+		blocks[0]
+	}
+
+	/// Decrypts a single block. This costs as much as eight.
+	pub fn decrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+		let mut blocks = [block];
+		self.decrypt_blocks(&mut blocks);
+		blocks[0]
+	}
+
+	/// The same round structure as `reference::Aes::encrypt_block`.
+	fn encrypt_planes(&self, state: &mut Planes) {
+		add_round_key(state, &self.round_keys[0]);
+
+		for round in 1..self.rounds {
+			sub_bytes(state);
+			permute_bytes(state, &SHIFT_ROWS);
+			mix_columns(state);
+			add_round_key(state, &self.round_keys[round]);
+		}
+
+		sub_bytes(state);
+		permute_bytes(state, &SHIFT_ROWS);
+		add_round_key(state, &self.round_keys[self.rounds]);
+	}
+
+	fn decrypt_planes(&self, state: &mut Planes) {
+		add_round_key(state, &self.round_keys[self.rounds]);
+
+		for round in (1..self.rounds).rev() {
+			permute_bytes(state, &INV_SHIFT_ROWS);
+			inv_sub_bytes(state);
+			add_round_key(state, &self.round_keys[round]);
+			inv_mix_columns(state);
+		}
+
+		permute_bytes(state, &INV_SHIFT_ROWS);
+		inv_sub_bytes(state);
+		add_round_key(state, &self.round_keys[0]);
+	}
+}
+
+impl Zeroize for Aes {
+	fn zeroize(&mut self) {
+		self.round_keys.zeroize();
+	}
+}
+
+impl Drop for Aes {
+	fn drop(&mut self) {
+		self.zeroize();
+	}
+}
+
+impl ZeroizeOnDrop for Aes {}
+
+/// Transposes eight blocks into planes.
+fn pack(blocks: &[[u8; BLOCK_SIZE]; PARALLEL_BLOCKS]) -> Planes {
+	let mut planes = [0u128; 8];
+	for (i, block) in blocks.iter().enumerate() {
+		for (position, &byte) in block.iter().enumerate() {
+			for (bit, plane) in planes.iter_mut().enumerate() {
+				*plane |= (((byte >> bit) & 1) as u128) << (8 * position + i);
+			}
+		}
+	}
+	planes
+}
+
+/// Opposite of pack.
+fn unpack(planes: &Planes) -> [[u8; BLOCK_SIZE]; PARALLEL_BLOCKS] {
+	let mut blocks = [[0u8; BLOCK_SIZE]; PARALLEL_BLOCKS];
+	for (i, block) in blocks.iter_mut().enumerate() {
+		for (position, byte) in block.iter_mut().enumerate() {
+			for (bit, plane) in planes.iter().enumerate() {
+				*byte |= (((plane >> (8 * position + i)) & 1) as u8) << bit;
+			}
+		}
+	}
+	blocks
+}
+
+/// The S-box on one word, for the key expansion.
+fn sub_word(word: [u8; 4]) -> [u8; 4] {
+	let mut block = [0u8; BLOCK_SIZE];
+	block[..4].copy_from_slice(&word);
+
+	let mut state = pack(&[block; PARALLEL_BLOCKS]);
+	sub_bytes(&mut state);
+	unpack(&state)[0][..4].try_into().unwrap()
+}
+
+/// The S-box: the inverse in GF(2^8), then the affine transformation
+/// `b ^ (b <<< 1) ^ (b <<< 2) ^ (b <<< 3) ^ (b <<< 4) ^ 0x63`.
+fn sub_bytes(state: &mut Planes) {
+	let b = gf_inverse(state);
+	*state = std::array::from_fn(|i| {
+		b[i] ^ b[(i + 7) % 8] ^ b[(i + 6) % 8] ^ b[(i + 5) % 8] ^ b[(i + 4) % 8] ^ constant(0x63, i)
+	});
+}
+
+/// Undoes the affine transformation, `(b <<< 1) ^ (b <<< 3) ^ (b <<< 6) ^ 0x05`, then
+/// inverts again.
+fn inv_sub_bytes(state: &mut Planes) {
+	let b = *state;
+	let affine: Planes = std::array::from_fn(|i| {
+		b[(i + 7) % 8] ^ b[(i + 5) % 8] ^ b[(i + 2) % 8] ^ constant(0x05, i)
+	});
+	*state = gf_inverse(&affine);
+}
+
+/// Bit `i` of a public constant, spread over a whole plane.
+fn constant(value: u8, i: usize) -> u128 {
+	if (value >> i) & 1 == 1 {
+		u128::MAX
+	} else {
+		0
+	}
+}
+
+/// `a^254`, which is the inverse of `a` (and 0 for 0): `a^2 * a^4 * ... * a^128`.
+fn gf_inverse(a: &Planes) -> Planes {
+	let mut power = gf_mul(a, a);
+	let mut result = power;
+	for _ in 0..6 {
+		power = gf_mul(&power, &power);
+		result = gf_mul(&result, &power);
+	}
+	result
+}
+
+/// Multiplication in GF(2^8) of all 128 byte pairs at once: schoolbook multiplication of
+/// the bit polynomials, then reduction modulo `x^8 + x^4 + x^3 + x + 1`.
+fn gf_mul(a: &Planes, b: &Planes) -> Planes {
+	let mut product = [0u128; 15];
+	for i in 0..8 {
+		for j in 0..8 {
+			product[i + j] ^= a[i] & b[j];
+		}
+	}
+
+	// x^8 = x^4 + x^3 + x + 1, so every bit above x^7 folds back down four places.
+	for k in (8..15).rev() {
+		product[k - 4] ^= product[k];
+		product[k - 5] ^= product[k];
+		product[k - 7] ^= product[k];
+		product[k - 8] ^= product[k];
+	}
+
+	product[..8].try_into().unwrap()
+}
+
+/// Multiplication by `x`: every bit moves up a plane, and the top bit is reduced by
+/// `0x1b`, i.e. into planes 0, 1, 3 and 4.
+fn xtime(a: &Planes) -> Planes {
+	[a[7], a[0] ^ a[7], a[1], a[2] ^ a[7], a[3] ^ a[7], a[4], a[5], a[6]]
+}
+
+/// `b = 2 * a0 ^ 3 * a1 ^ a2 ^ a3` for every row, written as `2 * (a0 ^ a1) ^ a1 ^ a2 ^ a3`
+/// where `a1`, `a2`, `a3` are the column rotated by one, two and three rows.
+fn mix_columns(state: &mut Planes) {
+	let (mut rotated_1, mut rotated_2, mut rotated_3) = (*state, *state, *state);
+	permute_bytes(&mut rotated_1, &ROTATE_1);
+	permute_bytes(&mut rotated_2, &ROTATE_2);
+	permute_bytes(&mut rotated_3, &ROTATE_3);
+
+	let doubled = xtime(&std::array::from_fn(|i| state[i] ^ rotated_1[i]));
+	*state = std::array::from_fn(|i| doubled[i] ^ rotated_1[i] ^ rotated_2[i] ^ rotated_3[i]);
+}
+
+/// InvMixColumns is MixColumns after multiplying every column by `4x^2 + 5`, which only
+/// needs `4 * (a0 ^ a2)` added to rows 0 and 2 (and `4 * (a1 ^ a3)` to rows 1 and 3).
+fn inv_mix_columns(state: &mut Planes) {
+	let mut rotated_2 = *state;
+	permute_bytes(&mut rotated_2, &ROTATE_2);
+
+	let quadrupled = xtime(&xtime(&std::array::from_fn(|i| state[i] ^ rotated_2[i])));
+	state.iter_mut().zip(quadrupled).for_each(|(plane, x)| *plane ^= x);
+	mix_columns(state);
+}
+
+fn add_round_key(state: &mut Planes, round_key: &Planes) {
+	state.iter_mut().zip(round_key).for_each(|(x1, &x2)| *x1 ^= x2);
+}
+
+/// Moves the byte lanes of every plane: lane `p` gets the old lane `source[p]`. The
+/// positions are fixed, so this leaks nothing.
+fn permute_bytes(state: &mut Planes, source: &[usize; BLOCK_SIZE]) {
+	for plane in state.iter_mut() {
+		let lanes = plane.to_le_bytes();
+		*plane = u128::from_le_bytes(std::array::from_fn(|p| lanes[source[p]]));
+	}
+}
+
+/// Builds a byte permutation where the byte at `(row, column)` comes from `row_offset`
+/// rows further down and `column_shifts[row]` columns further right, wrapping around.
+const fn byte_permutation(row_offset: usize, column_shifts: [usize; 4]) -> [usize; BLOCK_SIZE] {
+	let mut source = [0; BLOCK_SIZE];
+	let mut position = 0;
+	while position < BLOCK_SIZE {
+		let (row, column) = (position % 4, position / 4);
+		source[position] = (row + row_offset) % 4 + 4 * ((column + column_shifts[row]) % 4);
+		position += 1;
+	}
+	source
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::{rngs::StdRng, Rng, SeedableRng};
+
+	use super::*;
+	use crate::reference;
+	use crate::test_util::hex;
+
+	fn block(s: &str) -> [u8; BLOCK_SIZE] {
+		hex(s).try_into().unwrap()
+	}
+
+	#[test]
+	fn test_fips_197_appendix_c() {
+		let plain_text = block("00112233445566778899aabbccddeeff");
+		let vectors = [
+			("000102030405060708090a0b0c0d0e0f", "69c4e0d86a7b0430d8cdb78070b4c55a"),
+			(
+				"000102030405060708090a0b0c0d0e0f1011121314151617",
+				"dda97ca4864cdfe06eaf70a0ec0d7191",
+			),
+			(
+				"000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+				"8ea2b7ca516745bfeafc49904b496089",
+			),
+		];
+
+		for (key, cipher_text) in vectors {
+			let aes = Aes::new(&hex(key)).unwrap();
+			assert_eq!(aes.encrypt_block(plain_text), block(cipher_text));
+			assert_eq!(aes.decrypt_block(block(cipher_text)), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_s_box_matches_reference() {
+		// All 256 byte values, 128 per batch.
+		for half in 0..2 {
+			let blocks: [[u8; BLOCK_SIZE]; PARALLEL_BLOCKS] =
+				std::array::from_fn(|i| std::array::from_fn(|p| (128 * half + 16 * i + p) as u8));
+
+			let mut state = pack(&blocks);
+			sub_bytes(&mut state);
+			let mut expected = blocks;
+			expected.iter_mut().for_each(reference::sub_bytes);
+			assert_eq!(unpack(&state), expected);
+
+			inv_sub_bytes(&mut state);
+			assert_eq!(unpack(&state), blocks);
+		}
+	}
+
+	#[test]
+	fn test_matches_reference() {
+		let mut rng = StdRng::seed_from_u64(35);
+
+		for count in 0..3 * PARALLEL_BLOCKS {
+			let key: [u8; BLOCK_SIZE] = rng.gen();
+			let blocks: Vec<[u8; BLOCK_SIZE]> = (0..count).map(|_| rng.gen()).collect();
+
+			let mut encrypted = blocks.clone();
+			Aes::new_128(&key).encrypt_blocks(&mut encrypted);
+			let reference = reference::Aes::new_128(&key);
+			let expected: Vec<_> =
+				blocks.iter().map(|&block| reference.encrypt_block(block)).collect();
+			assert_eq!(encrypted, expected);
+
+			Aes::new_128(&key).decrypt_blocks(&mut encrypted);
+			assert_eq!(encrypted, blocks);
+		}
+	}
+
+	#[test]
+	fn test_invalid_key_length() {
+		assert!(matches!(Aes::new(&[0u8; 8]), Err(Error::InvalidKeyLength)));
+	}
+
+	#[test]
+	fn test_zeroize() {
+		let mut aes = Aes::new_128(&[7u8; BLOCK_SIZE]);
+		aes.zeroize();
+
+		assert_eq!(aes.round_keys, [[0u128; 8]; MAX_ROUNDS + 1]);
+	}
+}
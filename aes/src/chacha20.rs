@@ -0,0 +1,518 @@
+//! ChaCha20 and ChaCha20-Poly1305 (RFC 8439).
+//!
+//! So far every mode in the crate turns the AES block cipher into something that can
+//! encrypt long messages. ChaCha20 takes a different route: it is a stream cipher from the
+//! start. Its core is a function that scrambles a 64-byte block made of a constant, the
+//! key, a block counter and a nonce, using only 32-bit additions, rotations and XORs
+//! ("ARX"). The output is the keystream, XORed with the data just like in CTR mode.
+//!
+//! Because there are no table lookups, a plain software implementation is fast and
+//! constant-time, which makes ChaCha20 the usual choice on devices without AES hardware.
+//!
+//! `chacha20_encrypt`/`chacha20_decrypt` have the same shape as `ctr_encrypt` and
+//! `ctr_decrypt`, and are just as unauthenticated. `chacha20_poly1305_encrypt` adds a
+//! Poly1305 tag the way `gcm_encrypt` adds a GHASH one.
+//!
+//! The spec is RFC 8439: https://www.rfc-editor.org/rfc/rfc8439
+use rand::{CryptoRng, Rng};
+
+use crate::{
+	gcm::constant_time_eq,
+	incremental::{Decryptor, Encryptor},
+	poly1305::{self, Poly1305},
+	Error, SecretKey,
+};
+use zeroize::Zeroize;
+
+/// ChaCha20 only comes with 256-bit keys.
+pub const KEY_SIZE: usize = 32;
+
+/// The 96-bit nonce of RFC 8439.
+pub const NONCE_SIZE: usize = 12;
+
+pub const TAG_SIZE: usize = poly1305::TAG_SIZE;
+
+/// The size of one keystream block.
+pub const CHACHA_BLOCK_SIZE: usize = 64;
+
+/// "expand 32-byte k", the first four words of every block.
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+
+/// Encrypts with ChaCha20 under a random nonce, which becomes the first 12 bytes of the
+/// output.
+///
+/// There is no padding: the ciphertext is exactly as long as the plaintext plus the nonce.
+/// Like CTR, this does not detect tampering; see `chacha20_poly1305_encrypt` for that.
+///
+/// Fails with `Error::StreamTooLong` if the plaintext needs more keystream blocks than the
+/// 32-bit block counter can number (256 GiB).
+pub fn chacha20_encrypt(plain_text: Vec<u8>, key: &SecretKey<KEY_SIZE>) -> Result<Vec<u8>, Error> {
+	chacha20_encrypt_with_rng(plain_text, key, &mut rand::thread_rng())
+}
+
+/// Like `chacha20_encrypt`, but the nonce is drawn from `rng`.
+pub fn chacha20_encrypt_with_rng(
+	plain_text: Vec<u8>,
+	key: &SecretKey<KEY_SIZE>,
+	rng: &mut impl CryptoRng,
+) -> Result<Vec<u8>, Error> {
+	let mut encryptor = ChaCha20Encryptor::with_rng(key, rng);
+	let mut cipher_text = encryptor.try_update(&plain_text)?;
+	cipher_text.extend(encryptor.finalize());
+
+	Ok(cipher_text)
+}
+
+/// Opposite of chacha20_encrypt.
+///
+/// Fails with `Error::Truncated` if the ciphertext is shorter than the nonce.
+pub fn chacha20_decrypt(cipher_text: Vec<u8>, key: &SecretKey<KEY_SIZE>) -> Result<Vec<u8>, Error> {
+	let mut decryptor = ChaCha20Decryptor::new(key);
+	let mut plain_text = decryptor.update(&cipher_text);
+	plain_text.extend(decryptor.finalize()?);
+
+	Ok(plain_text)
+}
+
+/// Encrypts `plain_text` and authenticates it together with `associated_data`, RFC 8439
+/// section 2.8.
+///
+/// Block 0 of the keystream becomes the one-time Poly1305 key, and the data is encrypted
+/// from block 1 on. Returns the ciphertext followed by the tag, or `Error::StreamTooLong` if
+/// the plaintext is too long for the block counter.
+pub fn chacha20_poly1305_encrypt(
+	plain_text: &[u8],
+	associated_data: &[u8],
+	nonce: &[u8; NONCE_SIZE],
+	key: &SecretKey<KEY_SIZE>,
+) -> Result<Vec<u8>, Error> {
+	let key = key.expose_secret();
+	let mut cipher_text = plain_text.to_vec();
+	apply_keystream(&mut cipher_text, key, 1, nonce)?;
+
+	let tag = aead_tag(associated_data, &cipher_text, nonce, key);
+	cipher_text.extend_from_slice(&tag);
+
+	Ok(cipher_text)
+}
+
+/// Opposite of chacha20_poly1305_encrypt.
+///
+/// The tag is checked before anything is decrypted, so a forged ciphertext never produces
+/// plaintext.
+pub fn chacha20_poly1305_decrypt(
+	cipher_text: &[u8],
+	associated_data: &[u8],
+	nonce: &[u8; NONCE_SIZE],
+	key: &SecretKey<KEY_SIZE>,
+) -> Result<Vec<u8>, Error> {
+	let key = key.expose_secret();
+	if cipher_text.len() < TAG_SIZE {
+		return Err(Error::Truncated);
+	}
+	let (body, received_tag) = cipher_text.split_at(cipher_text.len() - TAG_SIZE);
+	check_counter(1, body.len() as u64)?;
+
+	let expected_tag = aead_tag(associated_data, body, nonce, key);
+	if !constant_time_eq(&expected_tag, received_tag) {
+		return Err(Error::Authentication);
+	}
+
+	let mut plain_text = body.to_vec();
+	apply_keystream(&mut plain_text, key, 1, nonce)?;
+
+	Ok(plain_text)
+}
+
+/// The ChaCha20 block function, RFC 8439 section 2.3.
+pub fn chacha20_block(
+	key: &[u8; KEY_SIZE],
+	counter: u32,
+	nonce: &[u8; NONCE_SIZE],
+) -> [u8; CHACHA_BLOCK_SIZE] {
+	let mut initial = [0u32; 16];
+	initial[..4].copy_from_slice(&CONSTANTS);
+	for (word, bytes) in initial[4..12].iter_mut().zip(key.chunks_exact(4)) {
+		*word = u32::from_le_bytes(bytes.try_into().unwrap());
+	}
+	initial[12] = counter;
+	for (word, bytes) in initial[13..].iter_mut().zip(nonce.chunks_exact(4)) {
+		*word = u32::from_le_bytes(bytes.try_into().unwrap());
+	}
+
+	// 20 rounds, alternating between the columns and the diagonals of the 4x4 state.
+	let mut state = initial;
+	for _ in 0..10 {
+		quarter_round(&mut state, 0, 4, 8, 12);
+		quarter_round(&mut state, 1, 5, 9, 13);
+		quarter_round(&mut state, 2, 6, 10, 14);
+		quarter_round(&mut state, 3, 7, 11, 15);
+		quarter_round(&mut state, 0, 5, 10, 15);
+		quarter_round(&mut state, 1, 6, 11, 12);
+		quarter_round(&mut state, 2, 7, 8, 13);
+		quarter_round(&mut state, 3, 4, 9, 14);
+	}
+
+	// Adding the input back in makes the function impossible to run backwards.
+	let mut block = [0u8; CHACHA_BLOCK_SIZE];
+	for (i, (word, initial_word)) in state.iter().zip(initial).enumerate() {
+		block[4 * i..4 * i + 4].copy_from_slice(&word.wrapping_add(initial_word).to_le_bytes());
+	}
+	block
+}
+
+/// XORs `data` with the keystream starting at block `counter`, RFC 8439 section 2.4.
+/// Encryption and decryption are the same operation.
+///
+/// Fails with `Error::StreamTooLong`, without touching the data, if the block counter would
+/// overflow before the end of the data.
+pub fn apply_keystream(
+	data: &mut [u8],
+	key: &[u8; KEY_SIZE],
+	counter: u32,
+	nonce: &[u8; NONCE_SIZE],
+) -> Result<(), Error> {
+	check_counter(counter as u64 * CHACHA_BLOCK_SIZE as u64, data.len() as u64)?;
+
+	for (i, chunk) in data.chunks_mut(CHACHA_BLOCK_SIZE).enumerate() {
+		// Can't wrap, that was checked above.
+		let mut keystream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+		chunk.iter_mut().zip(keystream.iter()).for_each(|(x1, &x2)| *x1 ^= x2);
+		keystream.zeroize();
+	}
+	Ok(())
+}
+
+/// Fails if `len` bytes of keystream, starting `position` bytes after the start of block 0,
+/// would need a block counter past `u32::MAX`.
+fn check_counter(position: u64, len: u64) -> Result<(), Error> {
+	let max_end = (u32::MAX as u64 + 1) * CHACHA_BLOCK_SIZE as u64;
+	match position.checked_add(len) {
+		Some(end) if end <= max_end => Ok(()),
+		_ => Err(Error::StreamTooLong),
+	}
+}
+
+/// Incremental form of `chacha20_encrypt`. The nonce comes out of the first `update`.
+pub struct ChaCha20Encryptor {
+	keystream: Keystream,
+	nonce_written: bool,
+}
+
+impl ChaCha20Encryptor {
+	/// Encrypts under a random nonce.
+	pub fn new(key: &SecretKey<KEY_SIZE>) -> Self {
+		Self::with_rng(key, &mut rand::thread_rng())
+	}
+
+	/// Encrypts under a nonce drawn from `rng`.
+	pub fn with_rng(key: &SecretKey<KEY_SIZE>, rng: &mut impl CryptoRng) -> Self {
+		Self::with_nonce(key, rng.gen())
+	}
+
+	/// Encrypts under the given nonce, which must never be reused with the same key.
+	pub fn with_nonce(key: &SecretKey<KEY_SIZE>, nonce: [u8; NONCE_SIZE]) -> Self {
+		Self { keystream: Keystream::new(key, nonce), nonce_written: false }
+	}
+
+	/// Like `update`, but fails with `Error::StreamTooLong` instead of panicking once the
+	/// block counter runs out. Nothing is encrypted in that case.
+	pub fn try_update(&mut self, plain_text: &[u8]) -> Result<Vec<u8>, Error> {
+		let mut data = plain_text.to_vec();
+		self.keystream.apply(&mut data)?;
+
+		let mut cipher_text = Vec::with_capacity(NONCE_SIZE + data.len());
+		if !self.nonce_written {
+			cipher_text.extend_from_slice(&self.keystream.nonce);
+			self.nonce_written = true;
+		}
+		cipher_text.extend_from_slice(&data);
+
+		Ok(cipher_text)
+	}
+}
+
+impl Encryptor for ChaCha20Encryptor {
+	/// # Panics
+	///
+	/// If the message needs more keystream than the 32-bit block counter allows. Use
+	/// `try_update` to get an error instead.
+	fn update(&mut self, plain_text: &[u8]) -> Vec<u8> {
+		self.try_update(plain_text).expect("ChaCha20 block counter overflowed")
+	}
+
+	/// A stream cipher has nothing left over, so this only writes the nonce if `update`
+	/// never ran.
+	fn finalize(mut self) -> Vec<u8> {
+		self.update(&[])
+	}
+}
+
+/// Incremental form of `chacha20_decrypt`.
+pub struct ChaCha20Decryptor {
+	key: SecretKey<KEY_SIZE>,
+	nonce: Vec<u8>,
+	// Created once the whole nonce has arrived.
+	keystream: Option<Keystream>,
+	// Set once the ciphertext ran past the end of the keystream, so that `finalize` fails.
+	too_long: bool,
+}
+
+impl ChaCha20Decryptor {
+	pub fn new(key: &SecretKey<KEY_SIZE>) -> Self {
+		let nonce = Vec::with_capacity(NONCE_SIZE);
+		Self { key: key.clone(), nonce, keystream: None, too_long: false }
+	}
+}
+
+impl Decryptor for ChaCha20Decryptor {
+	/// Decrypts the input as it comes. Ciphertext past the end of the keystream produces no
+	/// plaintext, and makes `finalize` fail.
+	fn update(&mut self, mut cipher_text: &[u8]) -> Vec<u8> {
+		if self.keystream.is_none() {
+			let missing = (NONCE_SIZE - self.nonce.len()).min(cipher_text.len());
+			self.nonce.extend_from_slice(&cipher_text[..missing]);
+			cipher_text = &cipher_text[missing..];
+
+			if self.nonce.len() < NONCE_SIZE {
+				return Vec::new();
+			}
+			let nonce = self.nonce.as_slice().try_into().unwrap();
+			self.keystream = Some(Keystream::new(&self.key, nonce));
+		}
+
+		let mut plain_text = cipher_text.to_vec();
+		if self.too_long
+			|| self.keystream.as_mut().expect("set above").apply(&mut plain_text).is_err()
+		{
+			self.too_long = true;
+			return Vec::new();
+		}
+		plain_text
+	}
+
+	/// Fails with `Truncated` if the input ended inside the nonce, and with `StreamTooLong`
+	/// if it ran past the end of the keystream.
+	fn finalize(self) -> Result<Vec<u8>, Error> {
+		if self.too_long {
+			return Err(Error::StreamTooLong);
+		}
+		match self.keystream {
+			Some(_) => Ok(Vec::new()),
+			None => Err(Error::Truncated),
+		}
+	}
+}
+
+/// The keystream from block 1 on, consumed a byte at a time across calls.
+struct Keystream {
+	key: SecretKey<KEY_SIZE>,
+	nonce: [u8; NONCE_SIZE],
+	// The next unused keystream byte, counted from the start of block 1.
+	position: u64,
+}
+
+impl Keystream {
+	fn new(key: &SecretKey<KEY_SIZE>, nonce: [u8; NONCE_SIZE]) -> Self {
+		Self { key: key.clone(), nonce, position: 0 }
+	}
+
+	/// Fails, without touching the data, if the data runs past the last keystream block.
+	fn apply(&mut self, data: &mut [u8]) -> Result<(), Error> {
+		let block_size = CHACHA_BLOCK_SIZE as u64;
+		check_counter(block_size + self.position, data.len() as u64)?;
+		let mut done = 0;
+
+		while done < data.len() {
+			let counter = (1 + self.position / block_size) as u32;
+			let skip = (self.position % block_size) as usize;
+
+			let mut keystream = chacha20_block(self.key.expose_secret(), counter, &self.nonce);
+			let take = (CHACHA_BLOCK_SIZE - skip).min(data.len() - done);
+			for (byte, key_byte) in data[done..done + take].iter_mut().zip(&keystream[skip..]) {
+				*byte ^= key_byte;
+			}
+			keystream.zeroize();
+			done += take;
+			self.position += take as u64;
+		}
+		Ok(())
+	}
+}
+
+/// `state[d] ^= (state[a] += state[b]); state[d] <<<= 16` and so on, RFC 8439 section 2.1.
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(16);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(12);
+	state[a] = state[a].wrapping_add(state[b]);
+	state[d] = (state[d] ^ state[a]).rotate_left(8);
+	state[c] = state[c].wrapping_add(state[d]);
+	state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+/// Poly1305 over `associated data | pad | ciphertext | pad | lengths`, keyed with the first
+/// 32 bytes of keystream block 0.
+fn aead_tag(
+	associated_data: &[u8],
+	cipher_text: &[u8],
+	nonce: &[u8; NONCE_SIZE],
+	key: &[u8; KEY_SIZE],
+) -> [u8; TAG_SIZE] {
+	let mut block = chacha20_block(key, 0, nonce);
+	let mut poly = Poly1305::new(block[..poly1305::KEY_SIZE].try_into().unwrap());
+	block.zeroize();
+
+	poly.update(associated_data);
+	poly.pad();
+	poly.update(cipher_text);
+	poly.pad();
+	poly.update(&(associated_data.len() as u64).to_le_bytes());
+	poly.update(&(cipher_text.len() as u64).to_le_bytes());
+
+	poly.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::hex;
+
+	const SUNSCREEN: &[u8] =
+		b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+		one tip for the future, sunscreen would be it.";
+
+	fn key() -> SecretKey<KEY_SIZE> {
+		SecretKey::new(std::array::from_fn(|i| i as u8))
+	}
+
+	#[test]
+	fn test_rfc_8439_block_function() {
+		// Section 2.3.2.
+		let nonce = hex("000000090000004a00000000").try_into().unwrap();
+		let expected = hex("10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+			d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e");
+
+		assert_eq!(chacha20_block(key().expose_secret(), 1, &nonce).to_vec(), expected);
+	}
+
+	#[test]
+	fn test_rfc_8439_encryption() {
+		// Section 2.4.2.
+		let nonce: [u8; NONCE_SIZE] = hex("000000000000004a00000000").try_into().unwrap();
+		let expected = hex("6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+			f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+			07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+			5af90bbf74a35be6b40b8eedf2785e42874d");
+
+		let mut cipher_text = SUNSCREEN.to_vec();
+		apply_keystream(&mut cipher_text, key().expose_secret(), 1, &nonce).unwrap();
+		assert_eq!(cipher_text, expected);
+
+		// The streaming encryptor starts at block 1 too, and writes the nonce first.
+		let mut encryptor = ChaCha20Encryptor::with_nonce(&key(), nonce);
+		let mut streamed = Vec::new();
+		for piece in SUNSCREEN.chunks(7) {
+			streamed.extend(encryptor.update(piece));
+		}
+		streamed.extend(encryptor.finalize());
+		assert_eq!(streamed[..NONCE_SIZE], nonce);
+		assert_eq!(streamed[NONCE_SIZE..], expected);
+	}
+
+	#[test]
+	fn test_rfc_8439_aead() {
+		// Section 2.8.2.
+		let key = SecretKey::new(std::array::from_fn(|i| 0x80 + i as u8));
+		let nonce = hex("070000004041424344454647").try_into().unwrap();
+		let associated_data = hex("50515253c0c1c2c3c4c5c6c7");
+		let expected = hex("d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+			3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+			92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+			3ff4def08e4b7a9de576d26586cec64b6116\
+			1ae10b594f09e26a7e902ecbd0600691");
+
+		let cipher_text =
+			chacha20_poly1305_encrypt(SUNSCREEN, &associated_data, &nonce, &key).unwrap();
+		assert_eq!(cipher_text, expected);
+
+		let decrypted = chacha20_poly1305_decrypt(&cipher_text, &associated_data, &nonce, &key);
+		assert_eq!(decrypted.unwrap(), SUNSCREEN);
+	}
+
+	#[test]
+	fn test_aead_rejects_tampering() {
+		let nonce = [1u8; NONCE_SIZE];
+		let mut cipher_text = chacha20_poly1305_encrypt(b"hello", b"", &nonce, &key()).unwrap();
+
+		let wrong_data = chacha20_poly1305_decrypt(&cipher_text, b"x", &nonce, &key());
+		assert!(matches!(wrong_data, Err(Error::Authentication)));
+
+		cipher_text[0] ^= 1;
+		let tampered = chacha20_poly1305_decrypt(&cipher_text, b"", &nonce, &key());
+		assert!(matches!(tampered, Err(Error::Authentication)));
+
+		let truncated = chacha20_poly1305_decrypt(&cipher_text[..3], b"", &nonce, &key());
+		assert!(matches!(truncated, Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_chacha20_round_trip() {
+		for len in [0, 1, CHACHA_BLOCK_SIZE, 1000] {
+			let plain_text: Vec<u8> = (0..len).map(|i| i as u8).collect();
+			let cipher_text = chacha20_encrypt(plain_text.clone(), &key()).unwrap();
+
+			assert_eq!(cipher_text.len(), NONCE_SIZE + len);
+			assert_eq!(chacha20_decrypt(cipher_text, &key()).unwrap(), plain_text);
+		}
+		assert!(matches!(chacha20_decrypt(vec![0; NONCE_SIZE - 1], &key()), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_decryptor_split_nonce() {
+		let cipher_text = chacha20_encrypt(SUNSCREEN.to_vec(), &key()).unwrap();
+
+		let mut decryptor = ChaCha20Decryptor::new(&key());
+		let mut plain_text = Vec::new();
+		for piece in cipher_text.chunks(5) {
+			plain_text.extend(decryptor.update(piece));
+		}
+		plain_text.extend(decryptor.finalize().unwrap());
+		assert_eq!(plain_text, SUNSCREEN);
+
+		let mut decryptor = ChaCha20Decryptor::new(&key());
+		decryptor.update(&cipher_text[..NONCE_SIZE - 1]);
+		assert!(matches!(decryptor.finalize(), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_block_counter_overflow() {
+		let nonce = [1u8; NONCE_SIZE];
+		let key = key();
+
+		// The last block fits, one byte more does not, and the data is left alone.
+		let mut data = [0u8; CHACHA_BLOCK_SIZE + 1];
+		apply_keystream(&mut data[..CHACHA_BLOCK_SIZE], key.expose_secret(), u32::MAX, &nonce)
+			.unwrap();
+		let before = data;
+		let result = apply_keystream(&mut data, key.expose_secret(), u32::MAX, &nonce);
+		assert!(matches!(result, Err(Error::StreamTooLong)));
+		assert_eq!(data, before);
+
+		// Same for the incremental types, started just before the end of the keystream.
+		let last_block = (u32::MAX as u64 - 1) * CHACHA_BLOCK_SIZE as u64;
+		let mut encryptor = ChaCha20Encryptor::with_nonce(&key, nonce);
+		encryptor.keystream.position = last_block;
+		assert_eq!(encryptor.try_update(&[0u8; 60]).unwrap().len(), NONCE_SIZE + 60);
+		assert!(matches!(encryptor.try_update(&[0u8; 5]), Err(Error::StreamTooLong)));
+		assert_eq!(encryptor.try_update(&[0u8; 4]).unwrap().len(), 4);
+
+		let mut decryptor = ChaCha20Decryptor::new(&key);
+		decryptor.update(&nonce);
+		decryptor.keystream.as_mut().unwrap().position = last_block;
+		assert_eq!(decryptor.update(&[0u8; CHACHA_BLOCK_SIZE]).len(), CHACHA_BLOCK_SIZE);
+		assert!(decryptor.update(&[0u8]).is_empty());
+		assert!(matches!(decryptor.finalize(), Err(Error::StreamTooLong)));
+	}
+}
@@ -0,0 +1,395 @@
+//! CTR_DRBG, the AES-based random bit generator from NIST SP 800-90A.
+//!
+//! The generator keeps an AES key and a 128-bit counter `V`. Output is the encryption of
+//! `V + 1`, `V + 2`, ..., a counter-mode keystream. Unlike `ctr_encrypt`, which splits its
+//! counter block into a 64-bit nonce and a 64-bit block counter, the whole of `V` counts
+//! and carries from one half into the other. After every request the key and counter are
+//! replaced by more of that keystream, so a later compromise of the state doesn't reveal
+//! earlier output (backtracking resistance).
+//!
+//! This is the AES-128 variant with the derivation function, which condenses entropy,
+//! nonce, personalization string and additional input of any length into a 256-bit seed.
+//! The generator is seeded from an `EntropySource`, by default the operating system, and:
+//!
+//! - reseeds itself from the source after `reseed_interval` requests, 2^48 at most;
+//! - with prediction resistance on, reseeds before every request, so that even someone who
+//!   learns the current state can't predict the next output;
+//! - mixes an optional personalization string into the seed, to keep instances apart.
+//!
+//! `CtrDrbg` implements `RngCore` and `CryptoRng`, so it can go anywhere the crate takes an
+//! RNG, e.g. `cbc_encrypt_with_rng` or `nonce::RngNonces`.
+use std::io;
+
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+use crate::{aes_encrypt, xor_blocks, Error, SecretKey, BLOCK_SIZE};
+
+/// The seed is a key and a counter block.
+const SEED_SIZE: usize = 2 * BLOCK_SIZE;
+
+/// Bytes of entropy drawn for every (re)seed: the security strength of AES-128.
+pub const ENTROPY_SIZE: usize = 16;
+
+/// Bytes of nonce drawn at instantiation, half the security strength.
+pub const NONCE_SIZE: usize = 8;
+
+/// The most output one request may ask for, 2^19 bits.
+pub const MAX_REQUEST_SIZE: usize = 1 << 16;
+
+/// The most requests allowed between reseeds.
+pub const MAX_RESEED_INTERVAL: u64 = 1 << 48;
+
+/// Where a DRBG gets its entropy and nonce from.
+pub trait EntropySource {
+	/// Fills `dest` with full-entropy bytes.
+	fn fill_entropy(&mut self, dest: &mut [u8]) -> Result<(), Error>;
+}
+
+/// Entropy from the operating system's random number generator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsEntropy;
+
+impl EntropySource for OsEntropy {
+	fn fill_entropy(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+		OsRng.try_fill_bytes(dest).map_err(|e| Error::Io(io::Error::from(e)))
+	}
+}
+
+/// An AES-128 CTR_DRBG with the derivation function.
+pub struct CtrDrbg<E = OsEntropy> {
+	key: SecretKey,
+	v: [u8; BLOCK_SIZE],
+	reseed_counter: u64,
+	reseed_interval: u64,
+	prediction_resistance: bool,
+	entropy: E,
+}
+
+impl CtrDrbg {
+	/// Seeds a generator from the operating system.
+	///
+	/// Fails with `Error::InvalidParameters` if `personalization` is 4 GiB or more.
+	pub fn new(personalization: &[u8]) -> Result<Self, Error> {
+		Self::with_entropy(OsEntropy, personalization)
+	}
+}
+
+impl<E: EntropySource> CtrDrbg<E> {
+	/// Seeds a generator from `entropy`, mixing in the personalization string.
+	///
+	/// Fails with `Error::InvalidParameters` if `personalization` is 4 GiB or more.
+	pub fn with_entropy(mut entropy: E, personalization: &[u8]) -> Result<Self, Error> {
+		let mut seed_material = vec![0u8; ENTROPY_SIZE + NONCE_SIZE];
+		entropy.fill_entropy(&mut seed_material[..ENTROPY_SIZE])?;
+		entropy.fill_entropy(&mut seed_material[ENTROPY_SIZE..])?;
+		seed_material.extend_from_slice(personalization);
+
+		let mut drbg = Self {
+			key: SecretKey::new([0u8; BLOCK_SIZE]),
+			v: [0u8; BLOCK_SIZE],
+			reseed_counter: 1,
+			reseed_interval: MAX_RESEED_INTERVAL,
+			prediction_resistance: false,
+			entropy,
+		};
+		let seed = derive(&seed_material);
+		seed_material.zeroize();
+		drbg.seed(seed?);
+
+		Ok(drbg)
+	}
+
+	/// With prediction resistance on, every request reseeds from the entropy source first.
+	pub fn set_prediction_resistance(&mut self, enabled: bool) {
+		self.prediction_resistance = enabled;
+	}
+
+	/// Sets how many requests may be served before the generator reseeds itself.
+	///
+	/// Panics unless `interval` is between 1 and `MAX_RESEED_INTERVAL`.
+	pub fn set_reseed_interval(&mut self, interval: u64) {
+		assert!((1..=MAX_RESEED_INTERVAL).contains(&interval), "reseed interval out of range");
+		self.reseed_interval = interval;
+	}
+
+	/// The number of the next request since the last (re)seed, counting from 1.
+	pub fn reseed_counter(&self) -> u64 {
+		self.reseed_counter
+	}
+
+	/// Mixes fresh entropy and `additional_input` into the state.
+	///
+	/// Fails with `Error::InvalidParameters` if `additional_input` is 4 GiB or more.
+	pub fn reseed(&mut self, additional_input: &[u8]) -> Result<(), Error> {
+		let mut seed_material = vec![0u8; ENTROPY_SIZE];
+		self.entropy.fill_entropy(&mut seed_material)?;
+		seed_material.extend_from_slice(additional_input);
+
+		let seed = derive(&seed_material);
+		seed_material.zeroize();
+		self.seed(seed?);
+		Ok(())
+	}
+
+	/// Fills `output` with random bytes, mixing in `additional_input` if it is not empty.
+	///
+	/// Fails with `Error::InvalidParameters` if `output` is longer than `MAX_REQUEST_SIZE` or
+	/// `additional_input` is 4 GiB or more, or with the entropy source's error if a reseed
+	/// was due and failed.
+	pub fn generate(&mut self, output: &mut [u8], additional_input: &[u8]) -> Result<(), Error> {
+		if output.len() > MAX_REQUEST_SIZE {
+			return Err(Error::InvalidParameters);
+		}
+
+		// A reseed uses up the additional input.
+		let mut additional_input = additional_input;
+		if self.prediction_resistance || self.reseed_counter > self.reseed_interval {
+			self.reseed(additional_input)?;
+			additional_input = &[];
+		}
+
+		let mut additional = [0u8; SEED_SIZE];
+		if !additional_input.is_empty() {
+			additional = derive(additional_input)?;
+			self.update(&additional);
+		}
+
+		for chunk in output.chunks_mut(BLOCK_SIZE) {
+			let mut block = self.next_block();
+			chunk.copy_from_slice(&block[..chunk.len()]);
+			block.zeroize();
+		}
+
+		self.update(&additional);
+		additional.zeroize();
+		self.reseed_counter += 1;
+		Ok(())
+	}
+
+	/// Replaces the state with one derived from `seed` and resets the reseed counter.
+	fn seed(&mut self, mut seed: [u8; SEED_SIZE]) {
+		self.update(&seed);
+		seed.zeroize();
+		self.reseed_counter = 1;
+	}
+}
+
+impl<E> CtrDrbg<E> {
+	/// Increments `V` and encrypts it.
+	fn next_block(&mut self) -> [u8; BLOCK_SIZE] {
+		self.v = u128::from_be_bytes(self.v).wrapping_add(1).to_be_bytes();
+		aes_encrypt(self.v, self.key.expose_secret())
+	}
+
+	/// CTR_DRBG_Update: the next two keystream blocks, XORed with `provided_data`, become
+	/// the new key and counter.
+	fn update(&mut self, provided_data: &[u8; SEED_SIZE]) {
+		let (key_data, v_data) = provided_data.split_at(BLOCK_SIZE);
+		let mut key = xor_blocks(self.next_block(), key_data.try_into().unwrap());
+		self.v = xor_blocks(self.next_block(), v_data.try_into().unwrap());
+		self.key.expose_secret_mut().copy_from_slice(&key);
+		key.zeroize();
+	}
+}
+
+impl<E> Drop for CtrDrbg<E> {
+	fn drop(&mut self) {
+		self.v.zeroize();
+	}
+}
+
+impl<E: EntropySource> RngCore for CtrDrbg<E> {
+	fn next_u32(&mut self) -> u32 {
+		let mut bytes = [0u8; 4];
+		self.fill_bytes(&mut bytes);
+		u32::from_le_bytes(bytes)
+	}
+
+	fn next_u64(&mut self) -> u64 {
+		let mut bytes = [0u8; 8];
+		self.fill_bytes(&mut bytes);
+		u64::from_le_bytes(bytes)
+	}
+
+	/// Panics if a reseed was due and the entropy source failed.
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		self.try_fill_bytes(dest).expect("the DRBG could not reseed")
+	}
+
+	/// Splits `dest` into requests of at most `MAX_REQUEST_SIZE` bytes.
+	fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+		for chunk in dest.chunks_mut(MAX_REQUEST_SIZE) {
+			self.generate(chunk, &[]).map_err(rand::Error::new)?;
+		}
+		Ok(())
+	}
+}
+
+impl<E: EntropySource> CryptoRng for CtrDrbg<E> {}
+
+/// Block_Cipher_df: condenses `input` into a seed with CBC-MACs (BCC) under a fixed key.
+///
+/// Fails with `Error::InvalidParameters` if `input` is too long for its 32-bit length field.
+fn derive(input: &[u8]) -> Result<[u8; SEED_SIZE], Error> {
+	// S = L || N || input || 0x80, zero-padded to whole blocks.
+	let input_len = u32::try_from(input.len()).map_err(|_| Error::InvalidParameters)?;
+	let mut s = Vec::with_capacity(input.len() + 2 * BLOCK_SIZE);
+	s.extend_from_slice(&input_len.to_be_bytes());
+	s.extend_from_slice(&(SEED_SIZE as u32).to_be_bytes());
+	s.extend_from_slice(input);
+	s.push(0x80);
+	s.resize(s.len().next_multiple_of(BLOCK_SIZE), 0);
+
+	let df_key: [u8; BLOCK_SIZE] = std::array::from_fn(|i| i as u8);
+	let mut temp = [0u8; SEED_SIZE];
+	for (i, half) in temp.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+		// BCC over IV || S, where the IV block holds the block's index.
+		let mut iv = [0u8; BLOCK_SIZE];
+		iv[..4].copy_from_slice(&(i as u32).to_be_bytes());
+		let mut chaining = aes_encrypt(iv, &df_key);
+		for block in s.chunks_exact(BLOCK_SIZE) {
+			chaining = aes_encrypt(xor_blocks(chaining, block.try_into().unwrap()), &df_key);
+		}
+		half.copy_from_slice(&chaining);
+	}
+	s.zeroize();
+
+	// Then encrypt X repeatedly under the new key K.
+	let (key, x) = temp.split_at(BLOCK_SIZE);
+	let key: [u8; BLOCK_SIZE] = key.try_into().unwrap();
+	let mut x: [u8; BLOCK_SIZE] = x.try_into().unwrap();
+	let mut seed = [0u8; SEED_SIZE];
+	for half in seed.chunks_exact_mut(BLOCK_SIZE) {
+		x = aes_encrypt(x, &key);
+		half.copy_from_slice(&x);
+	}
+	temp.zeroize();
+	x.zeroize();
+
+	Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::VecDeque;
+
+	use super::*;
+	use crate::test_util::hex;
+
+	/// Hands out the given inputs in order, like the entropy in a CAVP test case.
+	struct FixedEntropy(VecDeque<Vec<u8>>);
+
+	impl FixedEntropy {
+		fn new(inputs: &[&str]) -> Self {
+			Self(inputs.iter().map(|input| hex(input)).collect())
+		}
+	}
+
+	impl EntropySource for FixedEntropy {
+		fn fill_entropy(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+			let input = self.0.pop_front().ok_or_else(|| io::Error::other("out of entropy"))?;
+			dest.copy_from_slice(&input);
+			Ok(())
+		}
+	}
+
+	/// The CAVP procedure: two requests, of which only the second is checked.
+	fn second_output<E: EntropySource>(drbg: &mut CtrDrbg<E>, additional: [&[u8]; 2]) -> Vec<u8> {
+		let mut output = vec![0u8; 64];
+		drbg.generate(&mut output, additional[0]).unwrap();
+		drbg.generate(&mut output, additional[1]).unwrap();
+		output
+	}
+
+	#[test]
+	fn test_cavp_no_prediction_resistance() {
+		// NIST CAVP CTR_DRBG.rsp, AES-128 use df, PredictionResistance = False, COUNT = 0.
+		let entropy = FixedEntropy::new(&["890eb067acf7382eff80b0c73bc872c6", "aad471ef3ef1d203"]);
+		let mut drbg = CtrDrbg::with_entropy(entropy, b"").unwrap();
+
+		assert_eq!(
+			second_output(&mut drbg, [b"", b""]),
+			hex("a5514ed7095f64f3d0d3a5760394ab42062f373a25072a6ea6bcfd8489e94af6\
+			     cf18659fea22ed1ca0a9e33f718b115ee536b12809c31b72b08ddd8be1910fa3")
+		);
+	}
+
+	#[test]
+	fn test_prediction_resistance() {
+		// Not a CAVP vector: the expected output comes from OpenSSL 3's CTR-DRBG with
+		// AES-128-CTR and the df, an independent implementation fed the same inputs.
+		let entropy = FixedEntropy::new(&[
+			"000102030405060708090a0b0c0d0e0f",
+			"2021222324252627",
+			"101112131415161718191a1b1c1d1e1f",
+			"303132333435363738393a3b3c3d3e3f",
+		]);
+		let mut drbg = CtrDrbg::with_entropy(entropy, b"personalization").unwrap();
+		drbg.set_prediction_resistance(true);
+
+		assert_eq!(
+			second_output(&mut drbg, [b"first", b"second"]),
+			hex("80dd07b438afe13cad4f57645075f851a527fd1466a4a9e7a3ae350032ed2eb9\
+			     6c8c51e91235837a0e5b4b069208f3ba17bcd93e8b0a7c3c913361711312110b")
+		);
+		// Both requests reseeded, and there is no entropy left for a third.
+		assert!(matches!(drbg.generate(&mut [0u8; 16], b""), Err(Error::Io(_))));
+	}
+
+	#[test]
+	fn test_reseed_with_additional_input() {
+		// Not a CAVP vector either, see `test_prediction_resistance`.
+		let entropy = FixedEntropy::new(&[
+			"000102030405060708090a0b0c0d0e0f",
+			"2021222324252627",
+			"404142434445464748494a4b4c4d4e4f",
+		]);
+		let mut drbg = CtrDrbg::with_entropy(entropy, b"").unwrap();
+		drbg.reseed(b"reseed").unwrap();
+
+		assert_eq!(
+			second_output(&mut drbg, [b"first", b"second"]),
+			hex("aa277169af605c82081d7b1b4fba0588caad85f634c6decb0e4d48d4d02cc14d\
+			     9023788b54a82a8d2f37ebb2a27da57e94c917d663f2c5499c8d714b47c02a15")
+		);
+	}
+
+	#[test]
+	fn test_reseed_interval() {
+		let entropy = FixedEntropy::new(&[&"00".repeat(16), &"00".repeat(8), &"01".repeat(16)]);
+		let mut drbg = CtrDrbg::with_entropy(entropy, b"").unwrap();
+		drbg.set_reseed_interval(2);
+
+		let mut output = [0u8; 16];
+		drbg.generate(&mut output, b"").unwrap();
+		drbg.generate(&mut output, b"").unwrap();
+		assert_eq!(drbg.reseed_counter(), 3);
+
+		// The third request is over the interval, so it reseeds first.
+		drbg.generate(&mut output, b"").unwrap();
+		assert_eq!(drbg.reseed_counter(), 2);
+	}
+
+	#[test]
+	fn test_request_size_limit() {
+		let mut drbg = CtrDrbg::new(b"test").unwrap();
+
+		let mut output = vec![0u8; MAX_REQUEST_SIZE + 1];
+		assert!(matches!(drbg.generate(&mut output, b""), Err(Error::InvalidParameters)));
+
+		// The RngCore interface splits large requests up instead.
+		drbg.fill_bytes(&mut output);
+		assert_ne!(output[..BLOCK_SIZE], output[MAX_REQUEST_SIZE - BLOCK_SIZE..MAX_REQUEST_SIZE]);
+		assert_ne!(output, vec![0u8; MAX_REQUEST_SIZE + 1]);
+	}
+
+	#[test]
+	fn test_personalization_separates_instances() {
+		let entropy = || FixedEntropy::new(&[&"00".repeat(16), &"00".repeat(8)]);
+		let mut a = CtrDrbg::with_entropy(entropy(), b"a").unwrap();
+		let mut b = CtrDrbg::with_entropy(entropy(), b"b").unwrap();
+
+		assert_ne!(a.next_u64(), b.next_u64());
+	}
+}
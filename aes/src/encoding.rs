@@ -0,0 +1,336 @@
+//! Text encodings for ciphertexts, so they can be pasted into tickets, configs and emails.
+//!
+//! Hex and Base64 (standard, and URL-safe without padding) are plain conversions. Decoding
+//! skips whitespace, since text that has been through an email client or a config file
+//! tends to come back wrapped.
+//!
+//! The armored format is modelled on PEM and OpenPGP armor:
+//!
+//! ```text
+//! -----BEGIN AES MESSAGE-----
+//! Mode: gcm
+//!
+//! QUVTRgEBr2bQ9f1kV7l0cmAk...
+//! =njUN
+//! -----END AES MESSAGE-----
+//! ```
+//!
+//! The optional `Name: value` headers describe the data without being part of it, and end
+//! at a blank line. The data is Base64 in lines of 64 characters, followed by a CRC-24
+//! checksum (RFC 4880, section 6.1) of the data, so that a mangled paste is caught before
+//! decryption rather than showing up as an authentication failure. Anything before the
+//! BEGIN line or after the END line, e.g. the rest of an email, is ignored.
+use base64ct::{Base64, Base64UrlUnpadded, Encoding as _};
+
+use crate::Error;
+
+/// The label of armored ciphertexts.
+pub const ARMOR_LABEL: &str = "AES MESSAGE";
+
+const LINE_LENGTH: usize = 64;
+
+/// A way of writing binary data as text, or not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+	/// The raw bytes.
+	#[default]
+	Binary,
+	Hex,
+	Base64,
+	/// URL- and filename-safe Base64 (RFC 4648, section 5), without padding.
+	Base64Url,
+	/// An armored block labelled `ARMOR_LABEL`, without headers.
+	Armor,
+}
+
+impl Encoding {
+	/// Encodes `data`. Every encoding but `Binary` ends with a newline.
+	pub fn encode(self, data: &[u8]) -> Vec<u8> {
+		let text = match self {
+			Encoding::Binary => return data.to_vec(),
+			Encoding::Hex => hex_encode(data) + "\n",
+			Encoding::Base64 => base64_encode(data) + "\n",
+			Encoding::Base64Url => base64url_encode(data) + "\n",
+			Encoding::Armor => armor(ARMOR_LABEL, &[], data),
+		};
+		text.into_bytes()
+	}
+
+	/// Opposite of encode. Armored input may have any label and headers.
+	pub fn decode(self, data: &[u8]) -> Result<Vec<u8>, Error> {
+		if self == Encoding::Binary {
+			return Ok(data.to_vec());
+		}
+		let text = std::str::from_utf8(data).map_err(|_| Error::InvalidEncoding)?;
+		match self {
+			Encoding::Binary => unreachable!(),
+			Encoding::Hex => hex_decode(text),
+			Encoding::Base64 => base64_decode(text),
+			Encoding::Base64Url => base64url_decode(text),
+			Encoding::Armor => Ok(dearmor(text)?.data),
+		}
+	}
+}
+
+/// Lowercase hex.
+pub fn hex_encode(data: &[u8]) -> String {
+	data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Opposite of hex_encode. Accepts either case.
+pub fn hex_decode(text: &str) -> Result<Vec<u8>, Error> {
+	let digits = without_whitespace(text);
+	if !digits.len().is_multiple_of(2) {
+		return Err(Error::InvalidEncoding);
+	}
+	digits
+		.chunks(2)
+		.map(|pair| {
+			// `from_str_radix` also accepts a sign, as in "+a".
+			if !pair.iter().all(u8::is_ascii_hexdigit) {
+				return Err(Error::InvalidEncoding);
+			}
+			let pair = std::str::from_utf8(pair).map_err(|_| Error::InvalidEncoding)?;
+			u8::from_str_radix(pair, 16).map_err(|_| Error::InvalidEncoding)
+		})
+		.collect()
+}
+
+/// Standard Base64 (RFC 4648, section 4), with padding.
+pub fn base64_encode(data: &[u8]) -> String {
+	Base64::encode_string(data)
+}
+
+/// Opposite of base64_encode.
+pub fn base64_decode(text: &str) -> Result<Vec<u8>, Error> {
+	decode_with::<Base64>(text)
+}
+
+/// URL-safe Base64 (RFC 4648, section 5), without padding.
+pub fn base64url_encode(data: &[u8]) -> String {
+	Base64UrlUnpadded::encode_string(data)
+}
+
+/// Opposite of base64url_encode.
+pub fn base64url_decode(text: &str) -> Result<Vec<u8>, Error> {
+	decode_with::<Base64UrlUnpadded>(text)
+}
+
+fn decode_with<E: base64ct::Encoding>(text: &str) -> Result<Vec<u8>, Error> {
+	let text = String::from_utf8(without_whitespace(text)).map_err(|_| Error::InvalidEncoding)?;
+	E::decode_vec(&text).map_err(|_| Error::InvalidEncoding)
+}
+
+fn without_whitespace(text: &str) -> Vec<u8> {
+	text.bytes().filter(|byte| !byte.is_ascii_whitespace()).collect()
+}
+
+/// The contents of an armored block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Armored {
+	pub label: String,
+	pub headers: Vec<(String, String)>,
+	pub data: Vec<u8>,
+}
+
+impl Armored {
+	/// The value of the first header called `name`, ignoring case.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers
+			.iter()
+			.find(|(key, _)| key.eq_ignore_ascii_case(name))
+			.map(|(_, value)| &**value)
+	}
+}
+
+/// Wraps `data` in an armored block with the given label and headers.
+///
+/// Panics if a header name or value contains a line break, or a name contains `:`, since
+/// they could not be read back.
+pub fn armor(label: &str, headers: &[(&str, &str)], data: &[u8]) -> String {
+	let mut output = format!("-----BEGIN {}-----\n", label);
+	for (name, value) in headers {
+		assert!(!name.contains([':', '\n', '\r']) && !value.contains(['\n', '\r']), "bad header");
+		output.push_str(&format!("{}: {}\n", name, value));
+	}
+	if !headers.is_empty() {
+		output.push('\n');
+	}
+
+	let encoded = base64_encode(data);
+	for line in encoded.as_bytes().chunks(LINE_LENGTH) {
+		// Base64 is ASCII, so any split is on a character boundary.
+		output.push_str(std::str::from_utf8(line).unwrap());
+		output.push('\n');
+	}
+	output.push('=');
+	output.push_str(&base64_encode(&crc24(data).to_be_bytes()[1..]));
+	output.push_str(&format!("\n-----END {}-----\n", label));
+	output
+}
+
+/// Opposite of armor. Reads the first armored block in `text`.
+///
+/// Fails with `Error::InvalidEncoding` if there is no complete block, or the data doesn't
+/// match its checksum. A missing checksum is accepted.
+pub fn dearmor(text: &str) -> Result<Armored, Error> {
+	let mut lines = text.lines().map(str::trim_end);
+	let label = lines
+		.find_map(|line| line.strip_prefix("-----BEGIN ")?.strip_suffix("-----"))
+		.ok_or(Error::InvalidEncoding)?;
+	let end = format!("-----END {}-----", label);
+
+	let mut headers = Vec::new();
+	let mut body = String::new();
+	let mut checksum = None;
+	let mut in_headers = true;
+	for line in lines.by_ref() {
+		if line == end {
+			let data = base64_decode(&body)?;
+			if checksum.is_some_and(|checksum| checksum != crc24(&data)) {
+				return Err(Error::InvalidEncoding);
+			}
+			return Ok(Armored { label: label.to_string(), headers, data });
+		}
+		if checksum.is_some() {
+			// Nothing but the END line may follow the checksum.
+			return Err(Error::InvalidEncoding);
+		}
+
+		if in_headers {
+			if line.is_empty() {
+				in_headers = false;
+				continue;
+			}
+			if let Some((name, value)) = line.split_once(": ") {
+				headers.push((name.to_string(), value.to_string()));
+				continue;
+			}
+			// No headers, so this is already the data.
+			in_headers = false;
+		}
+
+		if let Some(encoded) = line.strip_prefix('=') {
+			let bytes = base64_decode(encoded)?;
+			let bytes: [u8; 3] = bytes.try_into().map_err(|_| Error::InvalidEncoding)?;
+			checksum = Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]));
+		} else {
+			body.push_str(line);
+		}
+	}
+	Err(Error::InvalidEncoding)
+}
+
+/// CRC-24 as used by OpenPGP (RFC 4880, section 6.1).
+fn crc24(data: &[u8]) -> u32 {
+	const INIT: u32 = 0xb704ce;
+	const POLY: u32 = 0x1864cfb;
+
+	let mut crc = INIT;
+	for &byte in data {
+		crc ^= (byte as u32) << 16;
+		for _ in 0..8 {
+			crc <<= 1;
+			if crc & 0x1000000 != 0 {
+				crc ^= POLY;
+			}
+		}
+	}
+	crc & 0xffffff
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_hex() {
+		assert_eq!(hex_encode(&[0x00, 0xab, 0xff]), "00abff");
+		assert_eq!(hex_decode("00AB ff\n").unwrap(), [0x00, 0xab, 0xff]);
+		assert!(matches!(hex_decode("abc"), Err(Error::InvalidEncoding)));
+		assert!(matches!(hex_decode("zz"), Err(Error::InvalidEncoding)));
+		assert!(matches!(hex_decode("+a+b"), Err(Error::InvalidEncoding)));
+	}
+
+	#[test]
+	fn test_base64_vectors() {
+		// RFC 4648, section 10.
+		for (data, encoded) in [
+			("", ""),
+			("f", "Zg=="),
+			("fo", "Zm8="),
+			("foo", "Zm9v"),
+			("foob", "Zm9vYg=="),
+			("fooba", "Zm9vYmE="),
+			("foobar", "Zm9vYmFy"),
+		] {
+			assert_eq!(base64_encode(data.as_bytes()), encoded);
+			assert_eq!(base64_decode(encoded).unwrap(), data.as_bytes());
+		}
+
+		assert_eq!(base64url_encode(&[0xfb, 0xff]), "-_8");
+		assert_eq!(base64url_decode("-_8").unwrap(), [0xfb, 0xff]);
+		assert_eq!(base64_encode(&[0xfb, 0xff]), "+/8=");
+		assert!(matches!(base64_decode("-_8"), Err(Error::InvalidEncoding)));
+		assert_eq!(base64_decode("Zm9v\r\nYmFy").unwrap(), b"foobar");
+	}
+
+	#[test]
+	fn test_crc24() {
+		// The check value of CRC-24/OPENPGP.
+		assert_eq!(crc24(b"123456789"), 0x21cf02);
+	}
+
+	#[test]
+	fn test_armor_round_trip() {
+		let data: Vec<u8> = (0..=255).collect();
+		let text = armor(ARMOR_LABEL, &[("Mode", "cbc"), ("Comment", "a: b")], &data);
+		assert!(text.starts_with("-----BEGIN AES MESSAGE-----\nMode: cbc\nComment: a: b\n\n"));
+		assert!(text.lines().all(|line| line.len() <= LINE_LENGTH || line.starts_with("-----")));
+
+		let armored = dearmor(&format!("Hi,\r\nhere it is:\n\n{}\nThanks", text)).unwrap();
+		assert_eq!(armored.label, ARMOR_LABEL);
+		assert_eq!(armored.header("mode"), Some("cbc"));
+		assert_eq!(armored.header("comment"), Some("a: b"));
+		assert_eq!(armored.data, data);
+	}
+
+	#[test]
+	fn test_armor_without_headers() {
+		for data in [&b""[..], b"x", b"hello world"] {
+			let text = armor("TEST", &[], data);
+			let armored = dearmor(&text).unwrap();
+			assert!(armored.headers.is_empty());
+			assert_eq!(armored.data, data);
+		}
+	}
+
+	#[test]
+	fn test_dearmor_rejects_damage() {
+		let text = armor(ARMOR_LABEL, &[], b"attack at dawn");
+		assert!(dearmor(&text.replace("YXR0", "YXR1")).is_err());
+		assert!(dearmor(&text.replace("-----END AES MESSAGE-----\n", "")).is_err());
+		assert!(dearmor(&text.replace("END AES", "END PGP")).is_err());
+		assert!(dearmor("no armor here").is_err());
+
+		// Without the checksum line, the data is taken as it is.
+		let unchecked: String =
+			text.lines().filter(|line| !line.starts_with('=')).collect::<Vec<_>>().join("\n");
+		assert_eq!(dearmor(&unchecked).unwrap().data, b"attack at dawn");
+	}
+
+	#[test]
+	fn test_encoding_round_trip() {
+		let data = b"\x00\x01binary\xff";
+		for encoding in [
+			Encoding::Binary,
+			Encoding::Hex,
+			Encoding::Base64,
+			Encoding::Base64Url,
+			Encoding::Armor,
+		] {
+			assert_eq!(encoding.decode(&encoding.encode(data)).unwrap(), data, "{:?}", encoding);
+		}
+		assert!(matches!(Encoding::Hex.decode(b"\xff"), Err(Error::InvalidEncoding)));
+	}
+}
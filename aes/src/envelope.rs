@@ -0,0 +1,390 @@
+//! Envelope encryption: data encrypted under its own key, which is encrypted under a master
+//! key.
+//!
+//! This is how key management services are used. The master key never leaves the service
+//! (or, here, the `KeyEncryptionProvider`), and bulk data never goes to it: every object
+//! gets a fresh random data key, the data is encrypted locally with that key, and only the
+//! 16-byte data key is sent off to be wrapped. The wrapped key is stored next to the
+//! ciphertext, so one call to the provider unwraps it again. Rotating the master key only
+//! means rewrapping data keys (`envelope_rewrap`), not re-encrypting data.
+//!
+//! Two providers come with the crate. `LocalKeyProvider` holds a master key in memory, or
+//! loads it from a file, and wraps with AES Key Wrap (RFC 3394). A `Keyring` is also a
+//! provider: it wraps under its primary key, and unwraps under whichever key the envelope
+//! names, so old envelopes keep working across rotations. A real KMS can be plugged in by
+//! implementing the trait.
+//!
+//! The layout is:
+//!
+//! ```text
+//! "AESE" | version (1) | key ID length (2) | key ID | wrapped key length (2) | wrapped key
+//!        | nonce (12) | GCM output
+//! ```
+//!
+//! with the lengths as big-endian integers. The data is encrypted with GCM, with the magic
+//! and version as associated data. The rest of the header is not authenticated by GCM, so
+//! that it can be rewritten by `envelope_rewrap`, but a wrapped key that has been tampered
+//! with fails to unwrap, and one taken from another envelope fails GCM.
+use std::{fs, path::Path};
+
+use rand::{CryptoRng, Rng};
+use zeroize::Zeroizing;
+
+use crate::{
+	aes_decrypt, aes_encrypt,
+	gcm::{self, constant_time_eq, gcm_decrypt, gcm_encrypt},
+	keyring::{KeyId, Keyring, KEY_ID_SIZE},
+	nonce::KeyFingerprint,
+	Error, SecretKey, BLOCK_SIZE,
+};
+
+const MAGIC: &[u8; 4] = b"AESE";
+const VERSION: u8 = 1;
+
+/// The initial value of RFC 3394, section 2.2.3.1.
+const KEY_WRAP_IV: [u8; 8] = [0xa6; 8];
+
+/// A data key encrypted by a `KeyEncryptionProvider`, with the ID of the master key that
+/// encrypted it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+	pub key_id: Vec<u8>,
+	pub wrapped: Vec<u8>,
+}
+
+/// Something that holds master keys and encrypts data keys under them.
+pub trait KeyEncryptionProvider {
+	/// Encrypts `data_key` under the current master key.
+	fn wrap_key(&self, data_key: &SecretKey) -> Result<WrappedKey, Error>;
+
+	/// Decrypts a data key under the master key it names. Fails with `Error::UnknownKey`
+	/// if the provider doesn't have that key, and with `Error::Authentication` if the
+	/// wrapped key has been modified.
+	fn unwrap_key(&self, wrapped: &WrappedKey) -> Result<SecretKey, Error>;
+}
+
+/// A single master key, held in memory, that wraps with AES Key Wrap.
+///
+/// The key ID is the key's `KeyFingerprint`, so envelopes wrapped under another key fail
+/// with `Error::UnknownKey`.
+#[derive(Debug, Clone)]
+pub struct LocalKeyProvider {
+	master: SecretKey,
+	key_id: KeyFingerprint,
+}
+
+impl LocalKeyProvider {
+	pub fn new(master: SecretKey) -> Self {
+		let key_id = KeyFingerprint::of(&master);
+		Self { master, key_id }
+	}
+
+	/// Reads the master key from a file holding exactly the 16 raw key bytes.
+	pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let bytes = Zeroizing::new(fs::read(path)?);
+		Ok(Self::new(SecretKey::from_slice(&bytes)?))
+	}
+}
+
+impl KeyEncryptionProvider for LocalKeyProvider {
+	fn wrap_key(&self, data_key: &SecretKey) -> Result<WrappedKey, Error> {
+		Ok(WrappedKey {
+			key_id: self.key_id.as_bytes().to_vec(),
+			wrapped: key_wrap(data_key.expose_secret(), &self.master),
+		})
+	}
+
+	fn unwrap_key(&self, wrapped: &WrappedKey) -> Result<SecretKey, Error> {
+		if wrapped.key_id != self.key_id.as_bytes() {
+			return Err(Error::UnknownKey);
+		}
+		let key = Zeroizing::new(key_unwrap(&wrapped.wrapped, &self.master)?);
+		SecretKey::from_slice(&key)
+	}
+}
+
+/// Wraps under the primary key. The key ID is the `KeyId`, as a 4-byte big-endian integer.
+impl KeyEncryptionProvider for Keyring {
+	fn wrap_key(&self, data_key: &SecretKey) -> Result<WrappedKey, Error> {
+		let id = self.primary().ok_or(Error::UnknownKey)?;
+		Ok(WrappedKey {
+			key_id: id.0.to_be_bytes().to_vec(),
+			wrapped: key_wrap(data_key.expose_secret(), self.key(id)?),
+		})
+	}
+
+	fn unwrap_key(&self, wrapped: &WrappedKey) -> Result<SecretKey, Error> {
+		let id: [u8; KEY_ID_SIZE] =
+			wrapped.key_id.as_slice().try_into().map_err(|_| Error::UnknownKey)?;
+		let key = self.key(KeyId(u32::from_be_bytes(id)))?;
+		let data_key = Zeroizing::new(key_unwrap(&wrapped.wrapped, key)?);
+		SecretKey::from_slice(&data_key)
+	}
+}
+
+/// AES Key Wrap (RFC 3394) of `key_data`, which must be a multiple of 8 bytes long and at
+/// least 16. The output is 8 bytes longer.
+pub fn key_wrap(key_data: &[u8], kek: &SecretKey) -> Vec<u8> {
+	assert!(
+		key_data.len().is_multiple_of(8) && key_data.len() >= 16,
+		"key data must be 8n bytes, n >= 2"
+	);
+	let n = key_data.len() / 8;
+	let mut a = KEY_WRAP_IV;
+	let mut r = Zeroizing::new(key_data.to_vec());
+	let mut block = Zeroizing::new([0u8; BLOCK_SIZE]);
+
+	for j in 0..6 {
+		for i in 0..n {
+			block[..8].copy_from_slice(&a);
+			block[8..].copy_from_slice(&r[i * 8..i * 8 + 8]);
+			*block = aes_encrypt(*block, kek.expose_secret());
+			let t = (n * j + i + 1) as u64;
+			a = xor_counter(&block[..8], t);
+			r[i * 8..i * 8 + 8].copy_from_slice(&block[8..]);
+		}
+	}
+
+	let mut output = Vec::with_capacity(8 + r.len());
+	output.extend_from_slice(&a);
+	output.extend_from_slice(&r);
+	output
+}
+
+/// Opposite of key_wrap. Fails with `Error::Authentication` if the integrity check fails,
+/// and with `Error::Truncated` if `wrapped` has the wrong length.
+pub fn key_unwrap(wrapped: &[u8], kek: &SecretKey) -> Result<Vec<u8>, Error> {
+	if !wrapped.len().is_multiple_of(8) || wrapped.len() < 24 {
+		return Err(Error::Truncated);
+	}
+	let n = wrapped.len() / 8 - 1;
+	let mut a: [u8; 8] = wrapped[..8].try_into().unwrap();
+	let mut r = Zeroizing::new(wrapped[8..].to_vec());
+	let mut block = Zeroizing::new([0u8; BLOCK_SIZE]);
+
+	for j in (0..6).rev() {
+		for i in (0..n).rev() {
+			let t = (n * j + i + 1) as u64;
+			block[..8].copy_from_slice(&xor_counter(&a, t));
+			block[8..].copy_from_slice(&r[i * 8..i * 8 + 8]);
+			*block = aes_decrypt(*block, kek.expose_secret());
+			a.copy_from_slice(&block[..8]);
+			r[i * 8..i * 8 + 8].copy_from_slice(&block[8..]);
+		}
+	}
+
+	if !constant_time_eq(&a, &KEY_WRAP_IV) {
+		return Err(Error::Authentication);
+	}
+	Ok(std::mem::take(&mut *r))
+}
+
+fn xor_counter(a: &[u8], t: u64) -> [u8; 8] {
+	let mut out: [u8; 8] = a.try_into().unwrap();
+	out.iter_mut().zip(t.to_be_bytes()).for_each(|(byte, t)| *byte ^= t);
+	out
+}
+
+/// Encrypts `plain_text` under a fresh data key, wrapped by `provider`.
+pub fn envelope_encrypt(
+	plain_text: &[u8],
+	provider: &impl KeyEncryptionProvider,
+) -> Result<Vec<u8>, Error> {
+	envelope_encrypt_with_rng(plain_text, provider, &mut rand::thread_rng())
+}
+
+/// `envelope_encrypt` with the data key and nonce drawn from `rng`.
+pub fn envelope_encrypt_with_rng(
+	plain_text: &[u8],
+	provider: &impl KeyEncryptionProvider,
+	rng: &mut impl CryptoRng,
+) -> Result<Vec<u8>, Error> {
+	let data_key = SecretKey::random_with_rng(rng);
+	let nonce: [u8; gcm::NONCE_SIZE] = rng.gen();
+
+	let mut output = header(&provider.wrap_key(&data_key)?)?;
+	output.extend_from_slice(&nonce);
+	output.extend(gcm_encrypt(plain_text, &aad(), &nonce, &data_key)?);
+	Ok(output)
+}
+
+/// Opposite of envelope_encrypt. The data key is unwrapped by `provider`.
+pub fn envelope_decrypt(
+	envelope: &[u8],
+	provider: &impl KeyEncryptionProvider,
+) -> Result<Vec<u8>, Error> {
+	let (wrapped, rest) = read_header(envelope)?;
+	if rest.len() < gcm::NONCE_SIZE {
+		return Err(Error::Truncated);
+	}
+	let (nonce, body) = rest.split_at(gcm::NONCE_SIZE);
+
+	let data_key = provider.unwrap_key(&wrapped)?;
+	gcm_decrypt(body, &aad(), nonce.try_into().unwrap(), &data_key)
+}
+
+/// Unwraps the data key of an envelope with `provider` and wraps it again under the
+/// provider's current master key. The encrypted data is copied unchanged.
+pub fn envelope_rewrap(
+	envelope: &[u8],
+	provider: &impl KeyEncryptionProvider,
+) -> Result<Vec<u8>, Error> {
+	let (wrapped, rest) = read_header(envelope)?;
+	let data_key = provider.unwrap_key(&wrapped)?;
+
+	let mut output = header(&provider.wrap_key(&data_key)?)?;
+	output.extend_from_slice(rest);
+	Ok(output)
+}
+
+/// The wrapped data key of an envelope, e.g. to see which master key it needs.
+pub fn envelope_wrapped_key(envelope: &[u8]) -> Result<WrappedKey, Error> {
+	Ok(read_header(envelope)?.0)
+}
+
+fn aad() -> [u8; 5] {
+	let mut aad = [VERSION; 5];
+	aad[..4].copy_from_slice(MAGIC);
+	aad
+}
+
+fn header(wrapped: &WrappedKey) -> Result<Vec<u8>, Error> {
+	let mut header = aad().to_vec();
+	for field in [&wrapped.key_id, &wrapped.wrapped] {
+		let len = u16::try_from(field.len()).map_err(|_| Error::InvalidParameters)?;
+		header.extend_from_slice(&len.to_be_bytes());
+		header.extend_from_slice(field);
+	}
+	Ok(header)
+}
+
+fn read_header(envelope: &[u8]) -> Result<(WrappedKey, &[u8]), Error> {
+	let rest = envelope.strip_prefix(MAGIC).ok_or(Error::InvalidHeader)?;
+	let rest = rest.strip_prefix(&[VERSION]).ok_or(Error::InvalidHeader)?;
+	let (key_id, rest) = read_field(rest)?;
+	let (wrapped, rest) = read_field(rest)?;
+	Ok((WrappedKey { key_id: key_id.to_vec(), wrapped: wrapped.to_vec() }, rest))
+}
+
+fn read_field(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+	let len = data.get(..2).ok_or(Error::Truncated)?;
+	let len = u16::from_be_bytes(len.try_into().unwrap()) as usize;
+	let rest = &data[2..];
+	if rest.len() < len {
+		return Err(Error::Truncated);
+	}
+	Ok(rest.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::{hex, temp_path};
+
+	const MASTER: SecretKey = SecretKey::new([7u8; BLOCK_SIZE]);
+
+	#[test]
+	fn test_key_wrap_vectors() {
+		// RFC 3394 section 4.1.
+		let kek = SecretKey::new(hex("000102030405060708090a0b0c0d0e0f").try_into().unwrap());
+		let key_data = hex("00112233445566778899aabbccddeeff");
+		let wrapped = key_wrap(&key_data, &kek);
+		assert_eq!(wrapped, hex("1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5"));
+		assert_eq!(key_unwrap(&wrapped, &kek).unwrap(), key_data);
+
+		// Checked against the `cryptography` Python package.
+		let wrapped = key_wrap(&[9u8; 16], &MASTER);
+		assert_eq!(wrapped, hex("5713e1bd22ea053c8be28f98f15cf8c6ac5b79cb3ccbe160"));
+	}
+
+	#[test]
+	fn test_key_unwrap_rejects_tampering() {
+		let mut wrapped = key_wrap(&[9u8; 16], &MASTER);
+		wrapped[10] ^= 1;
+		assert!(matches!(key_unwrap(&wrapped, &MASTER), Err(Error::Authentication)));
+		assert!(matches!(key_unwrap(&wrapped[..16], &MASTER), Err(Error::Truncated)));
+
+		let other = SecretKey::new([8u8; BLOCK_SIZE]);
+		let wrapped = key_wrap(&[9u8; 16], &MASTER);
+		assert!(matches!(key_unwrap(&wrapped, &other), Err(Error::Authentication)));
+	}
+
+	#[test]
+	fn test_round_trip() {
+		let provider = LocalKeyProvider::new(MASTER);
+		for len in [0, 1, 16, 100] {
+			let plain_text = vec![3u8; len];
+			let envelope = envelope_encrypt(&plain_text, &provider).unwrap();
+			assert_eq!(envelope_decrypt(&envelope, &provider).unwrap(), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_fresh_data_key_per_envelope() {
+		let provider = LocalKeyProvider::new(MASTER);
+		let first = envelope_wrapped_key(&envelope_encrypt(b"data", &provider).unwrap()).unwrap();
+		let second = envelope_wrapped_key(&envelope_encrypt(b"data", &provider).unwrap()).unwrap();
+		assert_eq!(first.key_id, second.key_id);
+		assert_ne!(first.wrapped, second.wrapped);
+	}
+
+	#[test]
+	fn test_wrong_master_key() {
+		let envelope = envelope_encrypt(b"data", &LocalKeyProvider::new(MASTER)).unwrap();
+		let other = LocalKeyProvider::new(SecretKey::new([8u8; BLOCK_SIZE]));
+		assert!(matches!(envelope_decrypt(&envelope, &other), Err(Error::UnknownKey)));
+	}
+
+	#[test]
+	fn test_rejects_tampering() {
+		let provider = LocalKeyProvider::new(MASTER);
+		let envelope = envelope_encrypt(b"data", &provider).unwrap();
+
+		let mut body = envelope.clone();
+		*body.last_mut().unwrap() ^= 1;
+		assert!(matches!(envelope_decrypt(&body, &provider), Err(Error::Authentication)));
+
+		// Swapping in the wrapped key of another envelope.
+		let other = envelope_encrypt(b"data", &provider).unwrap();
+		let mut swapped = header(&envelope_wrapped_key(&other).unwrap()).unwrap();
+		swapped.extend_from_slice(&envelope[swapped.len()..]);
+		assert!(matches!(envelope_decrypt(&swapped, &provider), Err(Error::Authentication)));
+
+		assert!(matches!(envelope_decrypt(b"AESX", &provider), Err(Error::InvalidHeader)));
+		assert!(matches!(envelope_decrypt(&envelope[..30], &provider), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_keyring_provider_and_rewrap() {
+		let mut keyring = Keyring::new();
+		let old = keyring.rotate();
+		let envelope = envelope_encrypt(b"data", &keyring).unwrap();
+		let wrapped = envelope_wrapped_key(&envelope).unwrap();
+		assert_eq!(wrapped.key_id, old.0.to_be_bytes());
+
+		let new = keyring.rotate();
+		assert_eq!(envelope_decrypt(&envelope, &keyring).unwrap(), b"data");
+
+		let rewrapped = envelope_rewrap(&envelope, &keyring).unwrap();
+		assert_eq!(envelope_wrapped_key(&rewrapped).unwrap().key_id, new.0.to_be_bytes());
+		// The data itself is untouched.
+		assert!(envelope.ends_with(&rewrapped[rewrapped.len() - 20..]));
+
+		keyring.retire(old).unwrap();
+		assert!(matches!(envelope_decrypt(&envelope, &keyring), Err(Error::KeyRetired)));
+		assert_eq!(envelope_decrypt(&rewrapped, &keyring).unwrap(), b"data");
+	}
+
+	#[test]
+	fn test_provider_from_file() {
+		let path = temp_path("envelope_master");
+		fs::write(&path, MASTER.expose_secret()).unwrap();
+		let provider = LocalKeyProvider::from_file(&path).unwrap();
+		let envelope = envelope_encrypt(b"data", &provider).unwrap();
+		assert_eq!(envelope_decrypt(&envelope, &LocalKeyProvider::new(MASTER)).unwrap(), b"data");
+
+		fs::write(&path, [0u8; 15]).unwrap();
+		assert!(matches!(LocalKeyProvider::from_file(&path), Err(Error::InvalidKeyLength)));
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,86 @@
+use std::{fmt, io};
+
+/// Errors returned by everything in the crate that can fail.
+///
+/// The unauthenticated modes (ECB, CBC, CTR) cannot tell a wrong key from a modified
+/// ciphertext, so they just hand back whatever bytes come out. Authenticated modes can, and
+/// when they do they refuse to release any plaintext at all.
+#[derive(Debug)]
+pub enum Error {
+	/// The authentication tag did not match. The key is wrong, or the ciphertext (or the
+	/// data bound to it) has been modified, reordered or duplicated.
+	Authentication,
+	/// The ciphertext ended before it was supposed to, e.g. the final segment of a stream
+	/// is missing.
+	Truncated,
+	/// The message or stream is longer than its counter can number, e.g. a GCM message of
+	/// more than 2^32 - 2 blocks.
+	StreamTooLong,
+	/// The caller-provided buffer has no room for the IV and padding.
+	BufferTooSmall,
+	/// AES keys are 16, 24 or 32 bytes long.
+	InvalidKeyLength,
+	/// The data does not start with a header this version of the crate understands.
+	InvalidHeader,
+	/// Key derivation parameters are out of range, e.g. zero iterations.
+	InvalidParameters,
+	/// The nonce has already been used with this key, according to a `NonceRegistry`.
+	NonceReuse,
+	/// The nonce source cannot produce any more nonces, e.g. its counter has run out.
+	NonceExhausted,
+	/// The key has reached its usage limits and can't be replaced by a new one.
+	KeyExhausted,
+	/// There is no such key in the keyring or keystore, or no primary key to encrypt with.
+	UnknownKey,
+	/// The key has been retired and its key material wiped.
+	KeyRetired,
+	/// The primary key can't be retired or removed.
+	KeyInUse,
+	/// The keystore already has a key with that name.
+	KeyExists,
+	/// The padding at the end of a CBC message is malformed, e.g. the key is wrong.
+	InvalidPadding,
+	/// Text that should be hex, Base64 or armored isn't, or its checksum doesn't match.
+	InvalidEncoding,
+	/// Reading the input or writing the output failed.
+	Io(io::Error),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Error::Authentication => write!(f, "authentication failed"),
+			Error::Truncated => write!(f, "ciphertext is truncated"),
+			Error::StreamTooLong => write!(f, "message or stream is too long for its counter"),
+			Error::BufferTooSmall => write!(f, "buffer is too small for the output"),
+			Error::InvalidKeyLength => write!(f, "key must be 16, 24 or 32 bytes"),
+			Error::InvalidHeader => write!(f, "header is malformed or has an unknown format"),
+			Error::InvalidParameters => write!(f, "key derivation parameters are out of range"),
+			Error::NonceReuse => write!(f, "nonce has already been used with this key"),
+			Error::NonceExhausted => write!(f, "nonce source has run out of nonces"),
+			Error::KeyExhausted => write!(f, "key has reached its usage limit"),
+			Error::UnknownKey => write!(f, "no such key"),
+			Error::KeyRetired => write!(f, "key has been retired"),
+			Error::KeyInUse => write!(f, "key is the primary key"),
+			Error::KeyExists => write!(f, "a key with that name already exists"),
+			Error::InvalidPadding => write!(f, "padding is invalid"),
+			Error::InvalidEncoding => write!(f, "input is not validly encoded"),
+			Error::Io(e) => write!(f, "I/O error: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Error::Io(e) => Some(e),
+			_ => None,
+		}
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Self {
+		Error::Io(e)
+	}
+}
@@ -0,0 +1,370 @@
+//! Galois/Counter Mode (GCM).
+//!
+//! None of the modes in the crate root can tell whether a ciphertext has been tampered with.
+//! GCM fixes that by combining two things we already know: counter mode for confidentiality,
+//! and a polynomial hash over the ciphertext (GHASH, see `ghash`) for integrity. The hash is
+//! keyed with `H = AES(key, 0)` and its result is masked with the encryption of the very
+//! first counter block, which gives a 16-byte authentication tag.
+//!
+//! Like CTR, GCM must never reuse a nonce under the same key. Doing so leaks the XOR of the
+//! two plaintexts _and_ lets an attacker recover `H` and forge tags.
+//!
+//! The spec is NIST SP 800-38D: https://csrc.nist.gov/pubs/sp/800/38/d/final
+use crate::{
+	aes_encrypt, aes_encrypt_blocks,
+	ghash::GHash,
+	incremental::{Decryptor, Encryptor},
+	xor_blocks, Error, SecretKey, BLOCK_SIZE,
+};
+use zeroize::Zeroize;
+
+/// GCM is defined for any nonce length, but 96 bits is the fast and recommended case.
+pub const NONCE_SIZE: usize = 12;
+
+/// The full 128-bit tag. Truncated tags are allowed by the spec but we don't offer them.
+pub const TAG_SIZE: usize = 16;
+
+/// The longest plaintext GCM can encrypt under one nonce: 2^32 - 2 blocks. The 32-bit
+/// counter starts at 2, and going past the last block would wrap around to the counter
+/// blocks of the tag and the start of the message.
+pub const MAX_MESSAGE_LEN: u64 = ((1 << 32) - 2) * BLOCK_SIZE as u64;
+
+/// Encrypts `plain_text` and authenticates it together with `associated_data`.
+///
+/// The associated data is not encrypted and not included in the output, but the tag covers
+/// it, so decryption fails unless exactly the same associated data is supplied.
+///
+/// Returns the ciphertext followed by the tag, or `Error::StreamTooLong` if the plaintext
+/// is longer than `MAX_MESSAGE_LEN`.
+pub fn gcm_encrypt(
+	plain_text: &[u8],
+	associated_data: &[u8],
+	nonce: &[u8; NONCE_SIZE],
+	key: &SecretKey,
+) -> Result<Vec<u8>, Error> {
+	let mut encryptor = GcmEncryptor::new(key, nonce, associated_data);
+	let mut cipher_text = encryptor.try_update(plain_text)?;
+	cipher_text.extend(encryptor.finalize());
+
+	Ok(cipher_text)
+}
+
+/// Opposite of gcm_encrypt.
+///
+/// The tag is checked before anything is decrypted, so a forged ciphertext never produces
+/// plaintext.
+pub fn gcm_decrypt(
+	cipher_text: &[u8],
+	associated_data: &[u8],
+	nonce: &[u8; NONCE_SIZE],
+	key: &SecretKey,
+) -> Result<Vec<u8>, Error> {
+	if cipher_text.len() < TAG_SIZE {
+		return Err(Error::Truncated);
+	}
+	let (body, received_tag) = cipher_text.split_at(cipher_text.len() - TAG_SIZE);
+	check_len(body.len())?;
+
+	let mut ghash = ghash_for(key);
+	ghash.update(associated_data);
+	ghash.pad();
+	ghash.update(body);
+	let expected_tag = tag(ghash, associated_data.len(), body.len(), nonce, key);
+
+	if !constant_time_eq(&expected_tag, received_tag) {
+		return Err(Error::Authentication);
+	}
+
+	let mut plain_text = body.to_vec();
+	apply_keystream(&mut plain_text, nonce, 2, key);
+
+	Ok(plain_text)
+}
+
+/// Incremental form of `gcm_encrypt`. The tag is returned by `finalize`.
+pub struct GcmEncryptor {
+	key: SecretKey,
+	nonce: [u8; NONCE_SIZE],
+	ghash: GHash,
+	associated_len: usize,
+	cipher_len: usize,
+	// Counter 1 is reserved for the tag, so data starts at 2.
+	counter: u32,
+	buffer: Vec<u8>,
+}
+
+impl GcmEncryptor {
+	/// The associated data has to come first because GHASH absorbs it before the ciphertext.
+	pub fn new(key: &SecretKey, nonce: &[u8; NONCE_SIZE], associated_data: &[u8]) -> Self {
+		let mut ghash = ghash_for(key);
+		ghash.update(associated_data);
+		ghash.pad();
+
+		Self {
+			key: key.clone(),
+			nonce: *nonce,
+			ghash,
+			associated_len: associated_data.len(),
+			cipher_len: 0,
+			counter: 2,
+			buffer: Vec::new(),
+		}
+	}
+
+	/// Like `update`, but fails with `Error::StreamTooLong` instead of panicking once the
+	/// message grows past `MAX_MESSAGE_LEN`. Nothing is encrypted in that case.
+	pub fn try_update(&mut self, plain_text: &[u8]) -> Result<Vec<u8>, Error> {
+		let cipher_len = self
+			.cipher_len
+			.checked_add(self.buffer.len())
+			.and_then(|len| len.checked_add(plain_text.len()))
+			.ok_or(Error::StreamTooLong)?;
+		check_len(cipher_len)?;
+
+		self.buffer.extend_from_slice(plain_text);
+		let whole = self.buffer.len() - self.buffer.len() % BLOCK_SIZE;
+		let blocks: Vec<u8> = self.buffer.drain(..whole).collect();
+
+		Ok(self.encrypt(blocks))
+	}
+
+	/// Callers have checked the total length against `MAX_MESSAGE_LEN`, so the counter
+	/// only wraps to 0 after the very last block, and is never used again.
+	fn encrypt(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+		apply_keystream(&mut data, &self.nonce, self.counter, &self.key);
+		self.ghash.update(&data);
+		self.cipher_len += data.len();
+		self.counter = self.counter.wrapping_add(data.len().div_ceil(BLOCK_SIZE) as u32);
+		data
+	}
+}
+
+impl Encryptor for GcmEncryptor {
+	/// # Panics
+	///
+	/// If the message grows past `MAX_MESSAGE_LEN`. Use `try_update` to get an error
+	/// instead.
+	fn update(&mut self, plain_text: &[u8]) -> Vec<u8> {
+		self.try_update(plain_text).expect("GCM message is longer than 2^32 - 2 blocks")
+	}
+
+	/// Encrypts the last partial block, if any, and appends the tag. There is no padding:
+	/// the last block just uses part of the keystream.
+	fn finalize(mut self) -> Vec<u8> {
+		let rest = std::mem::take(&mut self.buffer);
+		let mut cipher_text = self.encrypt(rest);
+
+		let tag = tag(self.ghash, self.associated_len, self.cipher_len, &self.nonce, &self.key);
+		cipher_text.extend_from_slice(&tag);
+
+		cipher_text
+	}
+}
+
+/// Incremental form of `gcm_decrypt`.
+///
+/// Releasing plaintext before the tag has been checked would defeat the point of GCM, so
+/// `update` only collects the ciphertext and everything comes out of `finalize`.
+pub struct GcmDecryptor {
+	key: SecretKey,
+	nonce: [u8; NONCE_SIZE],
+	associated_data: Vec<u8>,
+	cipher_text: Vec<u8>,
+}
+
+impl GcmDecryptor {
+	pub fn new(key: &SecretKey, nonce: &[u8; NONCE_SIZE], associated_data: &[u8]) -> Self {
+		Self {
+			key: key.clone(),
+			nonce: *nonce,
+			associated_data: associated_data.to_vec(),
+			cipher_text: Vec::new(),
+		}
+	}
+}
+
+impl Decryptor for GcmDecryptor {
+	fn update(&mut self, cipher_text: &[u8]) -> Vec<u8> {
+		self.cipher_text.extend_from_slice(cipher_text);
+		Vec::new()
+	}
+
+	fn finalize(self) -> Result<Vec<u8>, Error> {
+		gcm_decrypt(&self.cipher_text, &self.associated_data, &self.nonce, &self.key)
+	}
+}
+
+/// Fails if a message of `len` bytes would run the 32-bit counter out of blocks.
+fn check_len(len: usize) -> Result<(), Error> {
+	if len as u64 > MAX_MESSAGE_LEN {
+		return Err(Error::StreamTooLong);
+	}
+	Ok(())
+}
+
+/// Builds `nonce | counter` with a 32-bit big-endian counter, as GCM does.
+fn counter_block(nonce: &[u8; NONCE_SIZE], counter: u32) -> [u8; BLOCK_SIZE] {
+	let mut block = [0u8; BLOCK_SIZE];
+	block[..NONCE_SIZE].copy_from_slice(nonce);
+	block[NONCE_SIZE..].copy_from_slice(&counter.to_be_bytes());
+	block
+}
+
+/// XORs `data` with the keystream starting at block `first_counter`. The last block may be
+/// partial. The keystream blocks don't depend on each other, so they are encrypted as one
+/// batch.
+fn apply_keystream(data: &mut [u8], nonce: &[u8; NONCE_SIZE], first_counter: u32, key: &SecretKey) {
+	let mut keystream: Vec<[u8; BLOCK_SIZE]> = (0..data.len().div_ceil(BLOCK_SIZE) as u32)
+		.map(|i| counter_block(nonce, first_counter.wrapping_add(i)))
+		.collect();
+	aes_encrypt_blocks(&mut keystream, key.expose_secret());
+
+	for (chunk, keystream_block) in data.chunks_mut(BLOCK_SIZE).zip(&keystream) {
+		chunk.iter_mut().zip(keystream_block).for_each(|(x1, &x2)| *x1 ^= x2);
+	}
+	keystream.zeroize();
+}
+
+/// The GHASH state for `key`, keyed with `H = AES(key, 0)`.
+fn ghash_for(key: &SecretKey) -> GHash {
+	GHash::new(&aes_encrypt([0u8; BLOCK_SIZE], key.expose_secret()))
+}
+
+/// `GHASH(A, C)` masked with the encrypted first counter block.
+fn tag(
+	mut ghash: GHash,
+	associated_len: usize,
+	cipher_len: usize,
+	nonce: &[u8; NONCE_SIZE],
+	key: &SecretKey,
+) -> [u8; TAG_SIZE] {
+	ghash.pad();
+
+	// The final block holds both lengths in bits, so that moving bytes between the
+	// associated data and the ciphertext changes the tag.
+	let lengths = ((associated_len as u128 * 8) << 64) | (cipher_len as u128 * 8);
+	ghash.update(&lengths.to_be_bytes());
+
+	xor_blocks(ghash.finalize(), aes_encrypt(counter_block(nonce, 1), key.expose_secret()))
+}
+
+/// Compares two tags without returning early on the first differing byte.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::hex;
+
+	// Test cases 3 and 4 from the original GCM submission (McGrew & Viega), AES-128.
+	const KEY: &str = "feffe9928665731c6d6a8f9467308308";
+	const NONCE: &str = "cafebabefacedbaddecaf888";
+	const PLAIN_TEXT: &str = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+		1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255";
+	const CIPHER_TEXT: &str = "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+		21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e091473f5985";
+
+	fn key_and_nonce() -> (SecretKey, [u8; NONCE_SIZE]) {
+		(SecretKey::from_slice(&hex(KEY)).unwrap(), hex(NONCE).try_into().unwrap())
+	}
+
+	#[test]
+	fn test_gcm_empty_message_vector() {
+		let tag =
+			gcm_encrypt(&[], &[], &[0u8; NONCE_SIZE], &SecretKey::new([0u8; BLOCK_SIZE])).unwrap();
+		assert_eq!(tag, hex("58e2fccefa7e3061367f1d57a4e7455a"));
+	}
+
+	#[test]
+	fn test_gcm_encrypt_vector() {
+		let (key, nonce) = key_and_nonce();
+
+		let encrypted = gcm_encrypt(&hex(PLAIN_TEXT), &[], &nonce, &key).unwrap();
+
+		let mut expected = hex(CIPHER_TEXT);
+		expected.extend(hex("4d5c2af327cd64a62cf35abd2ba6fab4"));
+		assert_eq!(encrypted, expected);
+	}
+
+	#[test]
+	fn test_gcm_encrypt_vector_with_associated_data() {
+		let (key, nonce) = key_and_nonce();
+		let associated_data = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+
+		let encrypted =
+			gcm_encrypt(&hex(PLAIN_TEXT)[..60], &associated_data, &nonce, &key).unwrap();
+
+		let mut expected = hex(CIPHER_TEXT)[..60].to_vec();
+		expected.extend(hex("5bc94fbc3221a5db94fae95ae7121a47"));
+		assert_eq!(encrypted, expected);
+	}
+
+	#[test]
+	fn test_gcm_encrypt_decrypt() {
+		let key = SecretKey::new([7u8; BLOCK_SIZE]);
+		let nonce = [1u8; NONCE_SIZE];
+		let plain_text = b"Hello PBA Team, now with authentication!".to_vec();
+
+		let encrypted = gcm_encrypt(&plain_text, b"header", &nonce, &key).unwrap();
+		let decrypted = gcm_decrypt(&encrypted, b"header", &nonce, &key).unwrap();
+
+		assert_eq!(plain_text, decrypted);
+	}
+
+	#[test]
+	fn test_gcm_rejects_modified_cipher_text() {
+		let key = SecretKey::new([7u8; BLOCK_SIZE]);
+		let nonce = [1u8; NONCE_SIZE];
+
+		let mut encrypted = gcm_encrypt(b"attack at dawn", &[], &nonce, &key).unwrap();
+		encrypted[0] ^= 1;
+
+		assert!(matches!(gcm_decrypt(&encrypted, &[], &nonce, &key), Err(Error::Authentication)));
+	}
+
+	#[test]
+	fn test_gcm_rejects_wrong_associated_data() {
+		let key = SecretKey::new([7u8; BLOCK_SIZE]);
+		let nonce = [1u8; NONCE_SIZE];
+
+		let encrypted = gcm_encrypt(b"attack at dawn", b"to: alice", &nonce, &key).unwrap();
+
+		assert!(matches!(
+			gcm_decrypt(&encrypted, b"to: bob", &nonce, &key),
+			Err(Error::Authentication)
+		));
+	}
+
+	#[test]
+	fn test_gcm_rejects_short_cipher_text() {
+		let result = gcm_decrypt(
+			&[0u8; TAG_SIZE - 1],
+			&[],
+			&[0u8; NONCE_SIZE],
+			&SecretKey::new([0u8; BLOCK_SIZE]),
+		);
+		assert!(matches!(result, Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_gcm_message_length_limit() {
+		let (key, nonce) = key_and_nonce();
+		let max_len = MAX_MESSAGE_LEN as usize;
+
+		// Pretend all but the last two blocks have been encrypted already.
+		let mut encryptor = GcmEncryptor::new(&key, &nonce, &[]);
+		encryptor.cipher_len = max_len - 2 * BLOCK_SIZE;
+		encryptor.counter = u32::MAX - 1;
+		assert_eq!(encryptor.try_update(&[0u8; BLOCK_SIZE + 1]).unwrap().len(), BLOCK_SIZE);
+		assert!(matches!(encryptor.try_update(&[0u8; BLOCK_SIZE]), Err(Error::StreamTooLong)));
+		// The failed call didn't take anything in, so the last block still fits.
+		assert_eq!(encryptor.try_update(&[0u8; BLOCK_SIZE - 1]).unwrap().len(), BLOCK_SIZE);
+		assert_eq!(encryptor.counter, 0);
+		assert!(matches!(encryptor.try_update(&[0u8]), Err(Error::StreamTooLong)));
+
+		assert!(check_len(max_len).is_ok());
+		assert!(matches!(check_len(max_len + 1), Err(Error::StreamTooLong)));
+	}
+}
@@ -0,0 +1,235 @@
+//! The GHASH and POLYVAL universal hashes.
+//!
+//! Both treat the message as a sequence of 16-byte blocks and evaluate it as a polynomial
+//! at a secret point `H` in GF(2^128): `((X1 * H + X2) * H + X3) * H ...`. On their own
+//! they are not MACs: anyone who learns one output for a known message can work out `H`.
+//! An AEAD hides the output by XORing it with a keystream block, as GCM does.
+//!
+//! GHASH is the one inside GCM (NIST SP 800-38D). POLYVAL, from AES-GCM-SIV (RFC 8452), is
+//! the same hash with the bits in a more natural order: little-endian, with no reflection.
+//! The two are related by `POLYVAL(H, X) = rev(GHASH(mulX(rev(H)), rev(X)))`, where `rev`
+//! reverses the bytes of each block, and that is how `Polyval` is built here.
+//!
+//! The multiplications are constant-time: PCLMULQDQ where the CPU has it, otherwise a
+//! masked shift-and-add loop.
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::BLOCK_SIZE;
+
+/// GHASH keyed with `H`. In GCM, `H` is the encryption of the all-zero block.
+///
+/// Data can be fed in pieces of any size; partial blocks are buffered until they fill up
+/// or `pad` is called.
+pub struct GHash {
+	h: u128,
+	accumulator: u128,
+	buffer: Vec<u8>,
+}
+
+impl GHash {
+	pub fn new(h: &[u8; BLOCK_SIZE]) -> Self {
+		Self { h: u128::from_be_bytes(*h), accumulator: 0, buffer: Vec::new() }
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+		let whole = self.buffer.len() - self.buffer.len() % BLOCK_SIZE;
+		let blocks: Vec<u8> = self.buffer.drain(..whole).collect();
+		for block in blocks.chunks(BLOCK_SIZE) {
+			self.absorb(block.try_into().unwrap());
+		}
+	}
+
+	/// Partial blocks are padded with zeros on the right. GCM pads the associated data and
+	/// the ciphertext separately.
+	pub fn pad(&mut self) {
+		if !self.buffer.is_empty() {
+			let mut block = [0u8; BLOCK_SIZE];
+			block[..self.buffer.len()].copy_from_slice(&self.buffer);
+			self.buffer.clear();
+			self.absorb(block);
+		}
+	}
+
+	/// Pads whatever is left over and returns the hash.
+	pub fn finalize(mut self) -> [u8; BLOCK_SIZE] {
+		self.pad();
+		self.accumulator.to_be_bytes()
+	}
+
+	fn absorb(&mut self, block: [u8; BLOCK_SIZE]) {
+		self.accumulator = gf_mul(self.accumulator ^ u128::from_be_bytes(block), self.h);
+	}
+}
+
+impl Zeroize for GHash {
+	fn zeroize(&mut self) {
+		self.h.zeroize();
+		self.accumulator.zeroize();
+		self.buffer.zeroize();
+	}
+}
+
+impl Drop for GHash {
+	fn drop(&mut self) {
+		self.zeroize();
+	}
+}
+
+impl ZeroizeOnDrop for GHash {}
+
+/// POLYVAL keyed with `H`, RFC 8452 section 3.
+pub struct Polyval {
+	ghash: GHash,
+	buffer: Vec<u8>,
+}
+
+impl Polyval {
+	pub fn new(h: &[u8; BLOCK_SIZE]) -> Self {
+		let mut reversed = *h;
+		reversed.reverse();
+		Self { ghash: GHash::new(&mul_x(reversed)), buffer: Vec::new() }
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+		let whole = self.buffer.len() - self.buffer.len() % BLOCK_SIZE;
+		let blocks: Vec<u8> = self.buffer.drain(..whole).collect();
+		for block in blocks.chunks(BLOCK_SIZE) {
+			let mut reversed: [u8; BLOCK_SIZE] = block.try_into().unwrap();
+			reversed.reverse();
+			self.ghash.update(&reversed);
+		}
+	}
+
+	/// Pads a partial block with zeros.
+	pub fn pad(&mut self) {
+		if !self.buffer.is_empty() {
+			let missing = BLOCK_SIZE - self.buffer.len();
+			self.update(&[0u8; BLOCK_SIZE][..missing]);
+		}
+	}
+
+	/// Pads whatever is left over and returns the hash.
+	pub fn finalize(mut self) -> [u8; BLOCK_SIZE] {
+		self.pad();
+		self.ghash.pad();
+		let mut output = self.ghash.accumulator.to_be_bytes();
+		output.reverse();
+		output
+	}
+}
+
+impl Zeroize for Polyval {
+	fn zeroize(&mut self) {
+		self.ghash.zeroize();
+		self.buffer.zeroize();
+	}
+}
+
+impl Drop for Polyval {
+	fn drop(&mut self) {
+		self.zeroize();
+	}
+}
+
+impl ZeroizeOnDrop for Polyval {}
+
+/// Multiplication by `x` in GHASH's reflected bit order: a right shift, reducing the bit
+/// that falls off the end.
+fn mul_x(block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+	let v = u128::from_be_bytes(block);
+	let carry = v & 1;
+	((v >> 1) ^ ((0xE1 << 120) & carry.wrapping_neg())).to_be_bytes()
+}
+
+/// Multiplication in GF(2^128), on PCLMULQDQ when the CPU has it.
+pub(crate) fn gf_mul(x: u128, y: u128) -> u128 {
+	#[cfg(target_arch = "x86_64")]
+	if let Some(product) = crate::aesni::gf_mul(x, y) {
+		return product;
+	}
+
+	portable_gf_mul(x, y)
+}
+
+/// Multiplication in GF(2^128) using GCM's bit order, where the most significant bit of
+/// the block is the coefficient of x^0.
+///
+/// This is the textbook shift-and-add loop from the spec. Instead of branching on key
+/// bits we turn each bit into an all-zeros or all-ones mask, so the running time does not
+/// depend on `H`.
+pub(crate) fn portable_gf_mul(x: u128, y: u128) -> u128 {
+	const R: u128 = 0xE1 << 120;
+
+	let mut z = 0u128;
+	let mut v = y;
+	for i in 0..128 {
+		let bit = (x >> (127 - i)) & 1;
+		z ^= v & bit.wrapping_neg();
+		let carry = v & 1;
+		v = (v >> 1) ^ (R & carry.wrapping_neg());
+	}
+	z
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn block(s: &str) -> [u8; BLOCK_SIZE] {
+		let bytes: Vec<u8> = (0..s.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+			.collect();
+		bytes.try_into().unwrap()
+	}
+
+	#[test]
+	fn test_ghash_gcm_test_case_2() {
+		// McGrew & Viega test case 2: the ciphertext block followed by the lengths block.
+		let mut ghash = GHash::new(&block("66e94bd4ef8a2c3b884cfa59ca342b2e"));
+		ghash.update(&block("0388dace60b6a392f328c2b971b2fe78"));
+		ghash.update(&block("00000000000000000000000000000080"));
+
+		assert_eq!(ghash.finalize(), block("f38cbb1ad69223dcc3457ae5b6b0f885"));
+	}
+
+	#[test]
+	fn test_polyval_rfc_8452() {
+		// RFC 8452 appendix A.
+		let mut polyval = Polyval::new(&block("25629347589242761d31f826ba4b757b"));
+		polyval.update(&block("4f4f95668c83dfb6401762bb2d01a262"));
+		polyval.update(&block("d1a24ddd2721d006bbe45f20d3c9f362"));
+
+		assert_eq!(polyval.finalize(), block("f7a3b47b846119fae5b7866cf5e5b77e"));
+	}
+
+	#[test]
+	fn test_incremental_updates() {
+		let h = block("25629347589242761d31f826ba4b757b");
+		let data: Vec<u8> = (0..100).collect();
+
+		let mut whole = Polyval::new(&h);
+		whole.update(&data);
+		let mut pieces = Polyval::new(&h);
+		data.chunks(7).for_each(|piece| pieces.update(piece));
+		assert_eq!(whole.finalize(), pieces.finalize());
+
+		let mut whole = GHash::new(&h);
+		whole.update(&data);
+		let mut pieces = GHash::new(&h);
+		data.chunks(7).for_each(|piece| pieces.update(piece));
+		assert_eq!(whole.finalize(), pieces.finalize());
+	}
+
+	#[test]
+	fn test_zeroize() {
+		let mut ghash = GHash::new(&[7u8; BLOCK_SIZE]);
+		ghash.update(b"a partial block");
+		ghash.zeroize();
+
+		assert_eq!((ghash.h, ghash.accumulator), (0, 0));
+		assert!(ghash.buffer.is_empty());
+	}
+}
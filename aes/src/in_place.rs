@@ -0,0 +1,380 @@
+//! In-place versions of the modes that never touch the heap.
+//!
+//! The functions in the crate root are written to be easy to follow, not to be frugal:
+//! `group`, `un_group` and `pad` each build a fresh `Vec`, and every block gets copied a few
+//! times on its way through. On a small embedded device that may not even have an allocator,
+//! we want to encrypt a message right where it sits.
+//!
+//! So these functions work on a caller-provided `&mut [u8]`. For encryption the message
+//! starts at the front of the buffer and the buffer must have room for the IV (or nonce) and
+//! the padding; `padded_len`, `cbc_cipher_len` and `ctr_cipher_len` tell you how much. The
+//! output has exactly the same format as the `Vec` functions, so the two can be mixed.
+//!
+//! Since there is no random number generator without allocating either, the caller
+//! supplies the IV or nonce.
+use crate::{
+	aes_decrypt_blocks, aes_encrypt_blocks, apply_ctr_keystream, incremental::CTR_NONCE_SIZE,
+	Error, SecretKey, BLOCK_SIZE,
+};
+
+/// How many blocks `cbc_decrypt_in_place` decrypts at a time. Each batch is copied to the
+/// stack first, so this also bounds the stack use.
+const DECRYPT_BATCH_BLOCKS: usize = 32;
+
+/// Length of the message after `pad`.
+pub const fn padded_len(plain_len: usize) -> usize {
+	plain_len + BLOCK_SIZE - plain_len % BLOCK_SIZE
+}
+
+/// Length of the output of `cbc_encrypt` for a message of `plain_len` bytes.
+pub const fn cbc_cipher_len(plain_len: usize) -> usize {
+	BLOCK_SIZE + padded_len(plain_len)
+}
+
+/// Length of the output of `ctr_encrypt` for a message of `plain_len` bytes.
+pub const fn ctr_cipher_len(plain_len: usize) -> usize {
+	CTR_NONCE_SIZE + padded_len(plain_len)
+}
+
+/// Like `aes_encrypt`, but overwrites the block.
+pub fn aes_encrypt_in_place(block: &mut [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) {
+	aes_encrypt_blocks(std::slice::from_mut(block), key);
+}
+
+/// Like `aes_decrypt`, but overwrites the block.
+pub fn aes_decrypt_in_place(block: &mut [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) {
+	aes_decrypt_blocks(std::slice::from_mut(block), key);
+}
+
+/// Writes the padding for the `data_len` bytes at the front of `buffer` right after them.
+///
+/// Returns the padded length.
+pub fn pad_in_place(buffer: &mut [u8], data_len: usize) -> Result<usize, Error> {
+	let padded = padded_len(data_len);
+	if buffer.len() < padded {
+		return Err(Error::BufferTooSmall);
+	}
+
+	let number_pad_bytes = padded - data_len;
+	buffer[data_len..padded].fill(number_pad_bytes as u8);
+
+	Ok(padded)
+}
+
+/// Does the opposite of `pad_in_place`. Nothing needs to be moved, so this just returns the
+/// length of the data without the padding.
+///
+/// Just like `un_pad`, a last byte that can't be padding leaves the data as it is.
+pub fn un_padded_len(data: &[u8]) -> usize {
+	let Some(&pad_byte) = data.last() else {
+		return 0;
+	};
+	let pad_len = pad_byte as usize;
+
+	if pad_len <= BLOCK_SIZE && data.len() >= pad_len {
+		data.len() - pad_len
+	} else {
+		data.len()
+	}
+}
+
+/// Encrypts the first `plain_len` bytes of `buffer` in ECB mode.
+///
+/// Afterwards the buffer starts with the ciphertext, exactly like the output of
+/// `ecb_encrypt`. Returns its length.
+pub fn ecb_encrypt_in_place(
+	buffer: &mut [u8],
+	plain_len: usize,
+	key: &SecretKey,
+) -> Result<usize, Error> {
+	let cipher_len = pad_in_place(buffer, plain_len)?;
+
+	let (blocks, _) = buffer[..cipher_len].as_chunks_mut::<BLOCK_SIZE>();
+	aes_encrypt_blocks(blocks, key.expose_secret());
+
+	Ok(cipher_len)
+}
+
+/// Opposite of ecb_encrypt_in_place.
+///
+/// Afterwards the buffer starts with the plaintext. Returns its length.
+pub fn ecb_decrypt_in_place(buffer: &mut [u8], key: &SecretKey) -> Result<usize, Error> {
+	if buffer.is_empty() || !buffer.len().is_multiple_of(BLOCK_SIZE) {
+		return Err(Error::Truncated);
+	}
+
+	let (blocks, _) = buffer.as_chunks_mut::<BLOCK_SIZE>();
+	aes_decrypt_blocks(blocks, key.expose_secret());
+
+	Ok(un_padded_len(buffer))
+}
+
+/// Encrypts the first `plain_len` bytes of `buffer` in CBC mode.
+///
+/// Afterwards the buffer starts with the IV followed by the ciphertext blocks, exactly like
+/// the output of `cbc_encrypt`. Returns the length of that output.
+pub fn cbc_encrypt_in_place(
+	buffer: &mut [u8],
+	plain_len: usize,
+	iv: [u8; BLOCK_SIZE],
+	key: &SecretKey,
+) -> Result<usize, Error> {
+	let cipher_len = cbc_cipher_len(plain_len);
+	if buffer.len() < cipher_len {
+		return Err(Error::BufferTooSmall);
+	}
+
+	// Make room for the IV in front of the message.
+	buffer.copy_within(..plain_len, BLOCK_SIZE);
+	buffer[..BLOCK_SIZE].copy_from_slice(&iv);
+	pad_in_place(&mut buffer[BLOCK_SIZE..], plain_len)?;
+
+	let (iv_block, blocks) = buffer[..cipher_len].split_at_mut(BLOCK_SIZE);
+	let mut prev_block: [u8; BLOCK_SIZE] = (&*iv_block).try_into().unwrap();
+	for block in blocks.chunks_exact_mut(BLOCK_SIZE) {
+		let block: &mut [u8; BLOCK_SIZE] = block.try_into().unwrap();
+		xor_in_place(block, &prev_block);
+		aes_encrypt_in_place(block, key.expose_secret());
+		prev_block = *block;
+	}
+
+	Ok(cipher_len)
+}
+
+/// Opposite of cbc_encrypt_in_place.
+///
+/// Afterwards the buffer starts with the plaintext. Returns its length.
+pub fn cbc_decrypt_in_place(buffer: &mut [u8], key: &SecretKey) -> Result<usize, Error> {
+	if buffer.len() < 2 * BLOCK_SIZE || !buffer.len().is_multiple_of(BLOCK_SIZE) {
+		return Err(Error::Truncated);
+	}
+
+	let (iv_block, blocks) = buffer.split_at_mut(BLOCK_SIZE);
+	let mut prev_block: [u8; BLOCK_SIZE] = (&*iv_block).try_into().unwrap();
+	let (blocks, _) = blocks.as_chunks_mut::<BLOCK_SIZE>();
+
+	// Decrypting overwrites the ciphertext, which the next block still needs to XOR with.
+	// So we decrypt in batches and keep a copy of each batch on the stack.
+	for batch in blocks.chunks_mut(DECRYPT_BATCH_BLOCKS) {
+		let mut cipher_blocks = [[0u8; BLOCK_SIZE]; DECRYPT_BATCH_BLOCKS];
+		cipher_blocks[..batch.len()].copy_from_slice(batch);
+
+		aes_decrypt_blocks(batch, key.expose_secret());
+
+		for (block, cipher_block) in batch.iter_mut().zip(cipher_blocks) {
+			xor_in_place(block, &prev_block);
+			prev_block = cipher_block;
+		}
+	}
+
+	// Drop the IV by moving the plaintext to the front.
+	buffer.copy_within(BLOCK_SIZE.., 0);
+	Ok(un_padded_len(&buffer[..buffer.len() - BLOCK_SIZE]))
+}
+
+/// Encrypts the first `plain_len` bytes of `buffer` in CTR mode.
+///
+/// Afterwards the buffer starts with the nonce followed by the ciphertext, exactly like the
+/// output of `ctr_encrypt`. Returns the length of that output.
+pub fn ctr_encrypt_in_place(
+	buffer: &mut [u8],
+	plain_len: usize,
+	nonce: u64,
+	key: &SecretKey,
+) -> Result<usize, Error> {
+	let cipher_len = ctr_cipher_len(plain_len);
+	if buffer.len() < cipher_len {
+		return Err(Error::BufferTooSmall);
+	}
+
+	buffer.copy_within(..plain_len, CTR_NONCE_SIZE);
+	buffer[..CTR_NONCE_SIZE].copy_from_slice(&nonce.to_be_bytes());
+	pad_in_place(&mut buffer[CTR_NONCE_SIZE..], plain_len)?;
+
+	apply_ctr_keystream(&mut buffer[CTR_NONCE_SIZE..cipher_len], nonce, 0, key);
+
+	Ok(cipher_len)
+}
+
+/// Opposite of ctr_encrypt_in_place.
+///
+/// Afterwards the buffer starts with the plaintext. Returns its length.
+pub fn ctr_decrypt_in_place(buffer: &mut [u8], key: &SecretKey) -> Result<usize, Error> {
+	if buffer.len() <= CTR_NONCE_SIZE {
+		return Err(Error::Truncated);
+	}
+
+	let nonce = u64::from_be_bytes(buffer[..CTR_NONCE_SIZE].try_into().unwrap());
+	apply_ctr_keystream(&mut buffer[CTR_NONCE_SIZE..], nonce, 0, key);
+
+	buffer.copy_within(CTR_NONCE_SIZE.., 0);
+	Ok(un_padded_len(&buffer[..buffer.len() - CTR_NONCE_SIZE]))
+}
+
+/// Like `xor_blocks`, but overwrites `a`.
+fn xor_in_place(a: &mut [u8; BLOCK_SIZE], b: &[u8; BLOCK_SIZE]) {
+	a.iter_mut().zip(b.iter()).for_each(|(x1, &x2)| *x1 ^= x2);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		alloc::{GlobalAlloc, Layout, System},
+		cell::Cell,
+	};
+
+	use super::*;
+	use crate::{
+		aes_decrypt, aes_encrypt, cbc_decrypt, cbc_encrypt, ctr_decrypt, ctr_encrypt, ecb_encrypt,
+	};
+
+	/// Counts allocations, but only on threads that asked for it, so tests running in
+	/// parallel don't disturb each other.
+	struct CountingAllocator;
+
+	thread_local! {
+		static COUNTING: Cell<bool> = const { Cell::new(false) };
+		static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+	}
+
+	unsafe impl GlobalAlloc for CountingAllocator {
+		unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+			if COUNTING.with(|counting| counting.get()) {
+				ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+			}
+			System.alloc(layout)
+		}
+
+		unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+			System.dealloc(ptr, layout)
+		}
+	}
+
+	#[global_allocator]
+	static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+	/// Runs `f` and returns how many heap allocations it made.
+	fn count_allocations(f: impl FnOnce()) -> usize {
+		ALLOCATIONS.with(|allocations| allocations.set(0));
+		COUNTING.with(|counting| counting.set(true));
+		f();
+		COUNTING.with(|counting| counting.set(false));
+		ALLOCATIONS.with(|allocations| allocations.get())
+	}
+
+	const KEY: SecretKey = SecretKey::new([9u8; BLOCK_SIZE]);
+	const PLAIN_TEXT: &[u8] = b"Hello PBA Team, no heap was harmed in this message!";
+
+	#[test]
+	fn test_aes_in_place_matches_helpers() {
+		let mut block = [1u8; BLOCK_SIZE];
+
+		aes_encrypt_in_place(&mut block, KEY.expose_secret());
+		assert_eq!(block, aes_encrypt([1u8; BLOCK_SIZE], KEY.expose_secret()));
+
+		aes_decrypt_in_place(&mut block, KEY.expose_secret());
+		assert_eq!(
+			block,
+			aes_decrypt(aes_encrypt([1u8; BLOCK_SIZE], KEY.expose_secret()), KEY.expose_secret())
+		);
+	}
+
+	#[test]
+	fn test_pad_in_place() {
+		let mut buffer = [0u8; 2 * BLOCK_SIZE];
+		buffer[..3].copy_from_slice(&[1, 2, 3]);
+
+		assert_eq!(pad_in_place(&mut buffer, 3).unwrap(), BLOCK_SIZE);
+		assert_eq!(buffer[3..BLOCK_SIZE], [13u8; 13]);
+		assert_eq!(un_padded_len(&buffer[..BLOCK_SIZE]), 3);
+
+		assert_eq!(pad_in_place(&mut buffer, BLOCK_SIZE).unwrap(), 2 * BLOCK_SIZE);
+		assert_eq!(buffer[BLOCK_SIZE..], [16u8; BLOCK_SIZE]);
+	}
+
+	#[test]
+	fn test_ecb_in_place_matches_vec_api() {
+		let mut buffer = [0u8; padded_len(PLAIN_TEXT.len())];
+		buffer[..PLAIN_TEXT.len()].copy_from_slice(PLAIN_TEXT);
+
+		let cipher_len = ecb_encrypt_in_place(&mut buffer, PLAIN_TEXT.len(), &KEY).unwrap();
+		assert_eq!(buffer[..cipher_len], ecb_encrypt(PLAIN_TEXT.to_vec(), &KEY));
+
+		let plain_len = ecb_decrypt_in_place(&mut buffer, &KEY).unwrap();
+		assert_eq!(&buffer[..plain_len], PLAIN_TEXT);
+		assert!(matches!(ecb_decrypt_in_place(&mut buffer[1..], &KEY), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_cbc_in_place_matches_vec_api() {
+		let mut buffer = [0u8; cbc_cipher_len(PLAIN_TEXT.len())];
+		buffer[..PLAIN_TEXT.len()].copy_from_slice(PLAIN_TEXT);
+
+		let iv = [5u8; BLOCK_SIZE];
+		let cipher_len = cbc_encrypt_in_place(&mut buffer, PLAIN_TEXT.len(), iv, &KEY).unwrap();
+		assert_eq!(cbc_decrypt(buffer[..cipher_len].to_vec(), &KEY), PLAIN_TEXT);
+
+		let mut cipher_text = cbc_encrypt(PLAIN_TEXT.to_vec(), &KEY);
+		let plain_len = cbc_decrypt_in_place(&mut cipher_text, &KEY).unwrap();
+		assert_eq!(&cipher_text[..plain_len], PLAIN_TEXT);
+	}
+
+	#[test]
+	fn test_ctr_in_place_matches_vec_api() {
+		let mut buffer = [0u8; ctr_cipher_len(PLAIN_TEXT.len())];
+		buffer[..PLAIN_TEXT.len()].copy_from_slice(PLAIN_TEXT);
+
+		let cipher_len = ctr_encrypt_in_place(&mut buffer, PLAIN_TEXT.len(), 77, &KEY).unwrap();
+		assert_eq!(ctr_decrypt(buffer[..cipher_len].to_vec(), &KEY), PLAIN_TEXT);
+
+		let mut cipher_text = ctr_encrypt(PLAIN_TEXT.to_vec(), &KEY);
+		let plain_len = ctr_decrypt_in_place(&mut cipher_text, &KEY).unwrap();
+		assert_eq!(&cipher_text[..plain_len], PLAIN_TEXT);
+	}
+
+	#[test]
+	fn test_in_place_buffer_too_small() {
+		let mut buffer = [0u8; BLOCK_SIZE];
+
+		let result = cbc_encrypt_in_place(&mut buffer, 1, [0u8; BLOCK_SIZE], &KEY);
+
+		assert!(matches!(result, Err(Error::BufferTooSmall)));
+	}
+
+	#[test]
+	fn test_in_place_rejects_partial_block() {
+		let mut cipher_text = [0u8; 2 * BLOCK_SIZE + 1];
+		assert!(matches!(cbc_decrypt_in_place(&mut cipher_text, &KEY), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_in_place_modes_do_not_allocate() {
+		let mut buffer = [0u8; cbc_cipher_len(PLAIN_TEXT.len())];
+
+		let allocations = count_allocations(|| {
+			buffer[..PLAIN_TEXT.len()].copy_from_slice(PLAIN_TEXT);
+			let len = cbc_encrypt_in_place(&mut buffer, PLAIN_TEXT.len(), [5u8; 16], &KEY).unwrap();
+			let len = cbc_decrypt_in_place(&mut buffer[..len], &KEY).unwrap();
+			assert_eq!(&buffer[..len], PLAIN_TEXT);
+
+			let len = ctr_encrypt_in_place(&mut buffer, PLAIN_TEXT.len(), 77, &KEY).unwrap();
+			let len = ctr_decrypt_in_place(&mut buffer[..len], &KEY).unwrap();
+			assert_eq!(&buffer[..len], PLAIN_TEXT);
+
+			let len = ecb_encrypt_in_place(&mut buffer, len, &KEY).unwrap();
+			let len = ecb_decrypt_in_place(&mut buffer[..len], &KEY).unwrap();
+			assert_eq!(&buffer[..len], PLAIN_TEXT);
+		});
+
+		assert_eq!(allocations, 0);
+	}
+
+	#[test]
+	fn test_counting_allocator_sees_allocations() {
+		let allocations = count_allocations(|| {
+			cbc_encrypt(PLAIN_TEXT.to_vec(), &KEY);
+		});
+
+		assert!(allocations > 0);
+	}
+}
@@ -0,0 +1,600 @@
+//! Incremental versions of the modes.
+//!
+//! `cbc_encrypt` and friends need the whole message up front. The modes themselves don't:
+//! they walk the data one block at a time and only carry a little state from one block to
+//! the next (the previous ciphertext block for CBC, the counter for CTR). The types here
+//! keep that state in a struct, so data can be fed in whatever pieces it arrives in.
+//!
+//! Every mode follows the same two-step shape, similar to OpenSSL's `EVP_EncryptUpdate` /
+//! `EVP_EncryptFinal`: call `update` as often as you like, then `finalize` once. However
+//! the input is split up, the concatenated output is exactly what the one-shot function
+//! produces.
+//!
+//! Input that doesn't fill a whole block yet is buffered until the next call. Padding is
+//! only added (or removed) by `finalize`, because until then we can't know which block is
+//! the last one.
+//!
+//! Buffered plaintext is wiped when it is no longer needed, and when an encryptor is
+//! dropped half way through a message.
+use rand::{CryptoRng, Rng};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+	aes_decrypt, aes_decrypt_blocks, aes_encrypt, aes_encrypt_blocks, apply_ctr_keystream, group,
+	pad, secret::append_zeroizing, un_group, un_pad, xor_blocks, Error, SecretKey, BLOCK_SIZE,
+};
+
+/// The nonce that `ctr_encrypt` writes in front of the ciphertext is 64 bits.
+pub const CTR_NONCE_SIZE: usize = 8;
+
+/// A mode that encrypts a message piece by piece.
+pub trait Encryptor {
+	/// Encrypts as much of the input as possible and returns the ciphertext produced so far.
+	/// Anything that can't be encrypted yet is buffered for the next call.
+	fn update(&mut self, plain_text: &[u8]) -> Vec<u8>;
+
+	/// Ends the message and returns the rest of the ciphertext, including any padding or
+	/// authentication tag.
+	fn finalize(self) -> Vec<u8>;
+}
+
+/// A mode that decrypts a message piece by piece.
+pub trait Decryptor {
+	/// Decrypts as much of the input as possible and returns the plaintext produced so far.
+	fn update(&mut self, cipher_text: &[u8]) -> Vec<u8>;
+
+	/// Ends the message and returns the rest of the plaintext.
+	///
+	/// Fails if the ciphertext was cut short, or for authenticated modes, if it does not
+	/// authenticate.
+	fn finalize(self) -> Result<Vec<u8>, Error>;
+}
+
+/// Incremental form of `ecb_encrypt`.
+pub struct EcbEncryptor {
+	key: SecretKey,
+	buffer: Zeroizing<Vec<u8>>,
+}
+
+impl EcbEncryptor {
+	pub fn new(key: &SecretKey) -> Self {
+		Self { key: key.clone(), buffer: Zeroizing::default() }
+	}
+
+	fn encrypt_blocks(&self, mut blocks: Vec<[u8; BLOCK_SIZE]>) -> Vec<u8> {
+		aes_encrypt_blocks(&mut blocks, self.key.expose_secret());
+		un_group(blocks)
+	}
+}
+
+impl Encryptor for EcbEncryptor {
+	/// Encrypts as many whole blocks as are available and returns their ciphertext.
+	fn update(&mut self, plain_text: &[u8]) -> Vec<u8> {
+		self.buffer.extend_from_slice(plain_text);
+		let blocks = take_blocks(&mut self.buffer, false);
+		self.encrypt_blocks(blocks)
+	}
+
+	/// Pads whatever is left over and returns the final ciphertext block(s).
+	fn finalize(mut self) -> Vec<u8> {
+		let blocks = group(pad(std::mem::take(&mut *self.buffer)));
+		self.encrypt_blocks(blocks)
+	}
+}
+
+/// Incremental form of `ecb_decrypt`.
+pub struct EcbDecryptor {
+	key: SecretKey,
+	buffer: Vec<u8>,
+}
+
+impl EcbDecryptor {
+	pub fn new(key: &SecretKey) -> Self {
+		Self { key: key.clone(), buffer: Vec::new() }
+	}
+
+	fn decrypt_blocks(&self, mut blocks: Vec<[u8; BLOCK_SIZE]>) -> Vec<u8> {
+		aes_decrypt_blocks(&mut blocks, self.key.expose_secret());
+
+		let mut plain_text = Vec::with_capacity(blocks.len() * BLOCK_SIZE);
+		for block in &blocks {
+			plain_text.extend_from_slice(block);
+		}
+		blocks.zeroize();
+		plain_text
+	}
+}
+
+impl Decryptor for EcbDecryptor {
+	/// Decrypts as many blocks as possible and returns the plaintext produced so far.
+	///
+	/// Just like for CBC, the most recent whole block is held back because it might contain
+	/// padding.
+	fn update(&mut self, cipher_text: &[u8]) -> Vec<u8> {
+		self.buffer.extend_from_slice(cipher_text);
+		let blocks = take_blocks(&mut self.buffer, true);
+		self.decrypt_blocks(blocks)
+	}
+
+	/// Decrypts the held back block and removes the padding.
+	///
+	/// Fails if the ciphertext was empty or did not end on a block boundary.
+	fn finalize(mut self) -> Result<Vec<u8>, Error> {
+		let mut plain_text = self.update(&[]);
+
+		let Ok(block) = <[u8; BLOCK_SIZE]>::try_from(self.buffer.as_slice()) else {
+			return Err(Error::Truncated);
+		};
+
+		append_zeroizing(&mut plain_text, self.decrypt_blocks(vec![block]));
+		Ok(un_pad(plain_text))
+	}
+}
+
+/// Incremental form of `cbc_encrypt`.
+pub struct CbcEncryptor {
+	key: SecretKey,
+	prev_block: [u8; BLOCK_SIZE],
+	iv_written: bool,
+	buffer: Zeroizing<Vec<u8>>,
+}
+
+impl CbcEncryptor {
+	/// Starts a new message with a random initialization vector.
+	pub fn new(key: &SecretKey) -> Self {
+		Self::with_rng(key, &mut rand::thread_rng())
+	}
+
+	/// Starts a new message with an initialization vector drawn from `rng`.
+	pub fn with_rng(key: &SecretKey, rng: &mut impl CryptoRng) -> Self {
+		Self::with_iv(key, rng.gen())
+	}
+
+	/// Starts a new message with the given initialization vector. It must be unpredictable,
+	/// so only use this if you have a good reason not to use `new`.
+	pub fn with_iv(key: &SecretKey, iv: [u8; BLOCK_SIZE]) -> Self {
+		Self { key: key.clone(), prev_block: iv, iv_written: false, buffer: Zeroizing::default() }
+	}
+
+	fn encrypt_blocks(&mut self, mut blocks: Vec<[u8; BLOCK_SIZE]>) -> Vec<u8> {
+		let mut cipher_blocks = Vec::with_capacity(blocks.len());
+		for &block in &blocks {
+			let xored_block = xor_blocks(block, self.prev_block);
+			let encrypted_block = aes_encrypt(xored_block, self.key.expose_secret());
+			cipher_blocks.push(encrypted_block);
+			self.prev_block = encrypted_block;
+		}
+		blocks.zeroize();
+		un_group(cipher_blocks)
+	}
+}
+
+impl Encryptor for CbcEncryptor {
+	/// Encrypts as many whole blocks as are available and returns the ciphertext produced so
+	/// far. The first call also returns the IV.
+	fn update(&mut self, plain_text: &[u8]) -> Vec<u8> {
+		let mut cipher_text = Vec::new();
+		if !self.iv_written {
+			cipher_text.extend_from_slice(&self.prev_block);
+			self.iv_written = true;
+		}
+
+		self.buffer.extend_from_slice(plain_text);
+		let blocks = take_blocks(&mut self.buffer, false);
+		cipher_text.extend(self.encrypt_blocks(blocks));
+
+		cipher_text
+	}
+
+	/// Pads whatever is left over and returns the final ciphertext block(s).
+	fn finalize(mut self) -> Vec<u8> {
+		let mut cipher_text = self.update(&[]);
+
+		let blocks = group(pad(std::mem::take(&mut *self.buffer)));
+		cipher_text.extend(self.encrypt_blocks(blocks));
+
+		cipher_text
+	}
+}
+
+/// Incremental form of `cbc_decrypt`.
+pub struct CbcDecryptor {
+	key: SecretKey,
+	prev_block: Option<[u8; BLOCK_SIZE]>,
+	buffer: Vec<u8>,
+}
+
+impl CbcDecryptor {
+	pub fn new(key: &SecretKey) -> Self {
+		Self { key: key.clone(), prev_block: None, buffer: Vec::new() }
+	}
+}
+
+impl Decryptor for CbcDecryptor {
+	/// Decrypts as many blocks as possible and returns the plaintext produced so far.
+	///
+	/// The most recent whole block is held back, since it might be the last one and
+	/// contain padding.
+	fn update(&mut self, cipher_text: &[u8]) -> Vec<u8> {
+		self.buffer.extend_from_slice(cipher_text);
+
+		if self.prev_block.is_none() && self.buffer.len() >= BLOCK_SIZE {
+			let mut iv = [0u8; BLOCK_SIZE];
+			iv.copy_from_slice(&self.buffer[..BLOCK_SIZE]);
+			self.buffer.drain(..BLOCK_SIZE);
+			self.prev_block = Some(iv);
+		}
+
+		let mut plain_text = Vec::new();
+		if let Some(mut prev_block) = self.prev_block {
+			// All the ciphertext blocks are known, so they can be decrypted in one batch.
+			let cipher_blocks = take_blocks(&mut self.buffer, true);
+			let mut decrypted_blocks = cipher_blocks.clone();
+			aes_decrypt_blocks(&mut decrypted_blocks, self.key.expose_secret());
+
+			plain_text.reserve_exact(cipher_blocks.len() * BLOCK_SIZE);
+			for (&decrypted_block, cipher_block) in decrypted_blocks.iter().zip(cipher_blocks) {
+				plain_text.extend_from_slice(&xor_blocks(decrypted_block, prev_block));
+				prev_block = cipher_block;
+			}
+			decrypted_blocks.zeroize();
+			self.prev_block = Some(prev_block);
+		}
+
+		plain_text
+	}
+
+	/// Decrypts the held back block and removes the padding.
+	///
+	/// Fails if the ciphertext did not end on a block boundary, or ended before the first
+	/// block after the IV.
+	fn finalize(mut self) -> Result<Vec<u8>, Error> {
+		let mut plain_text = self.update(&[]);
+
+		let Some(prev_block) = self.prev_block else {
+			return Err(Error::Truncated);
+		};
+		let Ok(block) = <[u8; BLOCK_SIZE]>::try_from(self.buffer.as_slice()) else {
+			return Err(Error::Truncated);
+		};
+
+		let last_block = xor_blocks(aes_decrypt(block, self.key.expose_secret()), prev_block);
+		append_zeroizing(&mut plain_text, last_block.to_vec());
+		Ok(un_pad(plain_text))
+	}
+}
+
+/// Incremental form of `ctr_encrypt`.
+pub struct CtrEncryptor {
+	key: SecretKey,
+	nonce: u64,
+	counter: u64,
+	nonce_written: bool,
+	buffer: Zeroizing<Vec<u8>>,
+}
+
+impl CtrEncryptor {
+	/// Starts a new message with a random nonce.
+	pub fn new(key: &SecretKey) -> Self {
+		Self::with_rng(key, &mut rand::thread_rng())
+	}
+
+	/// Starts a new message with a nonce drawn from `rng`.
+	pub fn with_rng(key: &SecretKey, rng: &mut impl CryptoRng) -> Self {
+		Self::with_nonce(key, u64::from_be_bytes(rng.gen()))
+	}
+
+	/// Starts a new message with the given nonce. It must never be reused with the same key.
+	pub fn with_nonce(key: &SecretKey, nonce: u64) -> Self {
+		let buffer = Zeroizing::default();
+		Self { key: key.clone(), nonce, counter: 0, nonce_written: false, buffer }
+	}
+
+	fn apply_keystream(&mut self, mut data: Vec<u8>) -> Vec<u8> {
+		apply_ctr_keystream(&mut data, self.nonce, self.counter, &self.key);
+		self.counter += data.len().div_ceil(BLOCK_SIZE) as u64;
+		data
+	}
+}
+
+impl Encryptor for CtrEncryptor {
+	/// Encrypts as many whole blocks as are available and returns the ciphertext produced so
+	/// far. The first call also returns the nonce.
+	fn update(&mut self, plain_text: &[u8]) -> Vec<u8> {
+		let mut cipher_text = Vec::new();
+		if !self.nonce_written {
+			cipher_text.extend_from_slice(&self.nonce.to_be_bytes());
+			self.nonce_written = true;
+		}
+
+		self.buffer.extend_from_slice(plain_text);
+		let whole = self.buffer.len() - self.buffer.len() % BLOCK_SIZE;
+		let blocks: Vec<u8> = self.buffer.drain(..whole).collect();
+		cipher_text.extend(self.apply_keystream(blocks));
+
+		cipher_text
+	}
+
+	/// Pads whatever is left over and returns the final ciphertext block(s).
+	fn finalize(mut self) -> Vec<u8> {
+		let mut cipher_text = self.update(&[]);
+
+		let padded = pad(std::mem::take(&mut *self.buffer));
+		cipher_text.extend(self.apply_keystream(padded));
+
+		cipher_text
+	}
+}
+
+/// Incremental form of `ctr_decrypt`.
+pub struct CtrDecryptor {
+	key: SecretKey,
+	nonce: Option<u64>,
+	counter: u64,
+	buffer: Vec<u8>,
+}
+
+impl CtrDecryptor {
+	pub fn new(key: &SecretKey) -> Self {
+		Self { key: key.clone(), nonce: None, counter: 0, buffer: Vec::new() }
+	}
+}
+
+impl Decryptor for CtrDecryptor {
+	/// Decrypts as many blocks as possible and returns the plaintext produced so far.
+	///
+	/// Just like for CBC, the most recent whole block is held back because it might contain
+	/// padding.
+	fn update(&mut self, cipher_text: &[u8]) -> Vec<u8> {
+		self.buffer.extend_from_slice(cipher_text);
+
+		if self.nonce.is_none() && self.buffer.len() >= CTR_NONCE_SIZE {
+			let nonce_bytes: Vec<u8> = self.buffer.drain(..CTR_NONCE_SIZE).collect();
+			self.nonce = Some(u64::from_be_bytes(nonce_bytes.try_into().unwrap()));
+		}
+
+		let mut plain_text = Vec::new();
+		if let Some(nonce) = self.nonce {
+			let whole = whole_blocks_len(self.buffer.len(), true);
+			plain_text = self.buffer.drain(..whole).collect();
+			apply_ctr_keystream(&mut plain_text, nonce, self.counter, &self.key);
+			self.counter += (plain_text.len() / BLOCK_SIZE) as u64;
+		}
+
+		plain_text
+	}
+
+	/// Decrypts whatever is left and removes the padding.
+	///
+	/// Fails if the ciphertext ended before the first block after the nonce.
+	fn finalize(mut self) -> Result<Vec<u8>, Error> {
+		let mut plain_text = self.update(&[]);
+
+		let Some(nonce) = self.nonce else {
+			return Err(Error::Truncated);
+		};
+		if self.buffer.is_empty() {
+			return Err(Error::Truncated);
+		}
+
+		apply_ctr_keystream(&mut self.buffer, nonce, self.counter, &self.key);
+		append_zeroizing(&mut plain_text, std::mem::take(&mut self.buffer));
+		Ok(un_pad(plain_text))
+	}
+}
+
+/// Removes all whole blocks from the front of `buffer`, leaving any partial block behind.
+///
+/// With `hold_back_last`, a buffer that ends exactly on a block boundary keeps its last
+/// block too. Decryptors need that to strip the padding later on.
+fn take_blocks(buffer: &mut Vec<u8>, hold_back_last: bool) -> Vec<[u8; BLOCK_SIZE]> {
+	let whole = whole_blocks_len(buffer.len(), hold_back_last);
+	group(buffer.drain(..whole).collect())
+}
+
+/// How many bytes of a buffer of `len` bytes `take_blocks` would take.
+fn whole_blocks_len(len: usize, hold_back_last: bool) -> usize {
+	let whole = len - len % BLOCK_SIZE;
+	if hold_back_last && whole == len {
+		whole.saturating_sub(BLOCK_SIZE)
+	} else {
+		whole
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::{rngs::StdRng, SeedableRng};
+
+	use super::*;
+	use crate::{
+		cbc_decrypt, cbc_encrypt, ctr_decrypt, ctr_encrypt, ecb_encrypt,
+		gcm::{gcm_encrypt, GcmDecryptor, GcmEncryptor},
+	};
+
+	const KEY: SecretKey = SecretKey::new([4u8; BLOCK_SIZE]);
+	const PLAIN_TEXT: &[u8] = b"Hello PBA Team, we can now encrypt one piece at a time!";
+
+	/// Feeds `input` to `update` in pieces, cutting it at each of the (sorted) `cuts`.
+	fn feed(input: &[u8], cuts: &[usize], mut update: impl FnMut(&[u8]) -> Vec<u8>) -> Vec<u8> {
+		let mut output = Vec::new();
+		let mut start = 0;
+		for &cut in cuts.iter().chain([input.len()].iter()) {
+			output.extend(update(&input[start..cut]));
+			start = cut;
+		}
+		output
+	}
+
+	fn encrypt_split<E: Encryptor>(mut encryptor: E, input: &[u8], cuts: &[usize]) -> Vec<u8> {
+		let mut output = feed(input, cuts, |piece| encryptor.update(piece));
+		output.extend(encryptor.finalize());
+		output
+	}
+
+	fn decrypt_split<D: Decryptor>(mut decryptor: D, input: &[u8], cuts: &[usize]) -> Vec<u8> {
+		let mut output = feed(input, cuts, |piece| decryptor.update(piece));
+		output.extend(decryptor.finalize().unwrap());
+		output
+	}
+
+	/// Every way of cutting the input once, plus a bunch of random multi-way cuts
+	/// (including empty pieces).
+	fn splits(len: usize) -> Vec<Vec<usize>> {
+		let mut rng = StdRng::seed_from_u64(28);
+		let mut splits: Vec<Vec<usize>> = (0..=len).map(|cut| vec![cut]).collect();
+		for _ in 0..200 {
+			let pieces = rng.gen_range(2..10);
+			let mut cuts: Vec<usize> = (0..pieces).map(|_| rng.gen_range(0..=len)).collect();
+			cuts.sort();
+			splits.push(cuts);
+		}
+		splits
+	}
+
+	/// Checks that every split of the input encrypts to `expected`, and that every split of
+	/// `expected` decrypts back to the input.
+	fn assert_split_invariant<E: Encryptor, D: Decryptor>(
+		new_encryptor: impl Fn() -> E,
+		new_decryptor: impl Fn() -> D,
+		expected: &[u8],
+	) {
+		for cuts in splits(PLAIN_TEXT.len()) {
+			let encrypted = encrypt_split(new_encryptor(), PLAIN_TEXT, &cuts);
+			assert_eq!(encrypted, expected, "cuts {:?}", cuts);
+		}
+		for cuts in splits(expected.len()) {
+			let decrypted = decrypt_split(new_decryptor(), expected, &cuts);
+			assert_eq!(decrypted, PLAIN_TEXT, "cuts {:?}", cuts);
+		}
+	}
+
+	#[test]
+	fn test_ecb_any_split_matches_one_shot() {
+		assert_split_invariant(
+			|| EcbEncryptor::new(&KEY),
+			|| EcbDecryptor::new(&KEY),
+			&ecb_encrypt(PLAIN_TEXT.to_vec(), &KEY),
+		);
+	}
+
+	#[test]
+	fn test_cbc_any_split_matches_one_shot() {
+		let iv = [6u8; BLOCK_SIZE];
+		assert_split_invariant(
+			|| CbcEncryptor::with_iv(&KEY, iv),
+			|| CbcDecryptor::new(&KEY),
+			&encrypt_split(CbcEncryptor::with_iv(&KEY, iv), PLAIN_TEXT, &[]),
+		);
+	}
+
+	#[test]
+	fn test_ctr_any_split_matches_one_shot() {
+		assert_split_invariant(
+			|| CtrEncryptor::with_nonce(&KEY, 42),
+			|| CtrDecryptor::new(&KEY),
+			&encrypt_split(CtrEncryptor::with_nonce(&KEY, 42), PLAIN_TEXT, &[]),
+		);
+	}
+
+	#[test]
+	fn test_gcm_any_split_matches_one_shot() {
+		let nonce = [3u8; 12];
+		assert_split_invariant(
+			|| GcmEncryptor::new(&KEY, &nonce, b"header"),
+			|| GcmDecryptor::new(&KEY, &nonce, b"header"),
+			&gcm_encrypt(PLAIN_TEXT, b"header", &nonce, &KEY).unwrap(),
+		);
+	}
+
+	#[test]
+	fn test_cbc_encryptor_in_pieces() {
+		let mut encryptor = CbcEncryptor::new(&KEY);
+		let mut cipher_text = Vec::new();
+		for piece in PLAIN_TEXT.chunks(7) {
+			cipher_text.extend(encryptor.update(piece));
+		}
+		cipher_text.extend(encryptor.finalize());
+
+		assert_eq!(cbc_decrypt(cipher_text, &KEY), PLAIN_TEXT);
+	}
+
+	#[test]
+	fn test_cbc_decryptor_in_pieces() {
+		let cipher_text = cbc_encrypt(PLAIN_TEXT.to_vec(), &KEY);
+
+		let mut decryptor = CbcDecryptor::new(&KEY);
+		let mut plain_text = Vec::new();
+		for piece in cipher_text.chunks(5) {
+			plain_text.extend(decryptor.update(piece));
+		}
+		plain_text.extend(decryptor.finalize().unwrap());
+
+		assert_eq!(plain_text, PLAIN_TEXT);
+	}
+
+	#[test]
+	fn test_cbc_encryptor_holds_partial_blocks() {
+		let mut encryptor = CbcEncryptor::with_iv(&KEY, [1u8; BLOCK_SIZE]);
+
+		assert_eq!(encryptor.update(b"short").len(), BLOCK_SIZE);
+		assert_eq!(encryptor.update(b"").len(), 0);
+		assert_eq!(encryptor.finalize().len(), BLOCK_SIZE);
+	}
+
+	#[test]
+	fn test_cbc_decryptor_rejects_partial_block() {
+		let mut cipher_text = cbc_encrypt(PLAIN_TEXT.to_vec(), &KEY);
+		cipher_text.pop();
+
+		let mut decryptor = CbcDecryptor::new(&KEY);
+		decryptor.update(&cipher_text);
+
+		assert!(matches!(decryptor.finalize(), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_ecb_decryptor_rejects_partial_block() {
+		let mut cipher_text = ecb_encrypt(PLAIN_TEXT.to_vec(), &KEY);
+		cipher_text.pop();
+
+		let mut decryptor = EcbDecryptor::new(&KEY);
+		decryptor.update(&cipher_text);
+
+		assert!(matches!(decryptor.finalize(), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_ctr_encryptor_in_pieces() {
+		let mut encryptor = CtrEncryptor::new(&KEY);
+		let mut cipher_text = Vec::new();
+		for piece in PLAIN_TEXT.chunks(3) {
+			cipher_text.extend(encryptor.update(piece));
+		}
+		cipher_text.extend(encryptor.finalize());
+
+		assert_eq!(ctr_decrypt(cipher_text, &KEY), PLAIN_TEXT);
+	}
+
+	#[test]
+	fn test_ctr_decryptor_in_pieces() {
+		let cipher_text = ctr_encrypt(PLAIN_TEXT.to_vec(), &KEY);
+
+		let mut decryptor = CtrDecryptor::new(&KEY);
+		let mut plain_text = Vec::new();
+		for piece in cipher_text.chunks(11) {
+			plain_text.extend(decryptor.update(piece));
+		}
+		plain_text.extend(decryptor.finalize().unwrap());
+
+		assert_eq!(plain_text, PLAIN_TEXT);
+	}
+
+	#[test]
+	fn test_ctr_decryptor_rejects_missing_nonce() {
+		let mut decryptor = CtrDecryptor::new(&KEY);
+		decryptor.update(&[1, 2, 3]);
+
+		assert!(matches!(decryptor.finalize(), Err(Error::Truncated)));
+	}
+}
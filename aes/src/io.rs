@@ -0,0 +1,327 @@
+//! `std::io` adapters for the modes.
+//!
+//! These wrap the incremental encryptors and decryptors so ciphertext can be written
+//! straight into a file, socket or compressor (or read back out of one) without ever
+//! holding the whole message in memory.
+//!
+//! Note that CBC and CTR are not authenticated. A `DecryptReader` will happily return
+//! garbage for a modified ciphertext. Use the `stream` module if you need to detect that.
+use std::io::{self, Read, Write};
+
+use crate::{
+	incremental::{CbcDecryptor, CbcEncryptor, CtrDecryptor, CtrEncryptor, Decryptor, Encryptor},
+	SecretKey,
+};
+
+/// How much ciphertext `DecryptReader` pulls from the inner reader at a time.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Encrypts everything written to it and passes the ciphertext on to the inner writer.
+///
+/// The last, padded block can only be written once we know no more data is coming, so
+/// you **must** call `finish` when you are done. Dropping the writer without it loses the
+/// tail of the message.
+///
+/// Once `write` has encrypted some data, it reports it as written even if the inner writer
+/// fails, e.g. with `WouldBlock` on a non-blocking socket: the ciphertext is kept and sent
+/// ahead of anything else by the next `write`, `flush` or `finish`, which return the error
+/// if it persists. Encrypting the same data twice would corrupt the stream.
+pub struct EncryptWriter<W: Write, E: Encryptor> {
+	inner: W,
+	encryptor: E,
+	// Ciphertext the inner writer hasn't taken yet.
+	pending: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W, CbcEncryptor> {
+	/// Encrypts in CBC mode, with a random IV written first.
+	pub fn cbc(inner: W, key: &SecretKey) -> Self {
+		Self::new(inner, CbcEncryptor::new(key))
+	}
+}
+
+impl<W: Write> EncryptWriter<W, CtrEncryptor> {
+	/// Encrypts in CTR mode, with a random nonce written first.
+	pub fn ctr(inner: W, key: &SecretKey) -> Self {
+		Self::new(inner, CtrEncryptor::new(key))
+	}
+}
+
+impl<W: Write, E: Encryptor> EncryptWriter<W, E> {
+	/// Encrypts with any of the incremental modes.
+	pub fn new(inner: W, encryptor: E) -> Self {
+		Self { inner, encryptor, pending: Vec::new() }
+	}
+
+	/// Writes any pending ciphertext and the padded final block, flushes, and hands back the
+	/// inner writer.
+	pub fn finish(self) -> io::Result<W> {
+		let Self { mut inner, encryptor, mut pending } = self;
+		write_pending(&mut inner, &mut pending)?;
+		pending = encryptor.finalize();
+		write_pending(&mut inner, &mut pending)?;
+		inner.flush()?;
+		Ok(inner)
+	}
+}
+
+impl<W: Write, E: Encryptor> Write for EncryptWriter<W, E> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// Nothing has been encrypted yet, so on failure the caller can simply retry.
+		write_pending(&mut self.inner, &mut self.pending)?;
+
+		self.pending = self.encryptor.update(buf);
+		// `buf` is used up now. If the inner writer fails, the ciphertext stays pending and
+		// the error comes back from the next call.
+		let _ = write_pending(&mut self.inner, &mut self.pending);
+		Ok(buf.len())
+	}
+
+	/// Writes any pending ciphertext and flushes the inner writer. A partial block stays
+	/// buffered until more data arrives or `finish` is called.
+	fn flush(&mut self) -> io::Result<()> {
+		write_pending(&mut self.inner, &mut self.pending)?;
+		self.inner.flush()
+	}
+}
+
+/// Passes `pending` on to `inner`, keeping whatever it doesn't take.
+fn write_pending(inner: &mut impl Write, pending: &mut Vec<u8>) -> io::Result<()> {
+	while !pending.is_empty() {
+		match inner.write(pending) {
+			Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+			Ok(written) => drop(pending.drain(..written)),
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+/// Reads ciphertext from the inner reader and returns the decrypted plaintext.
+///
+/// A ciphertext that ends early shows up as an `InvalidData` error from `read`.
+pub struct DecryptReader<R: Read, D: Decryptor> {
+	inner: R,
+	// `None` once the inner reader is exhausted and the final block has been decrypted.
+	decryptor: Option<D>,
+	plain_text: Vec<u8>,
+	position: usize,
+}
+
+impl<R: Read> DecryptReader<R, CbcDecryptor> {
+	/// Decrypts the output of `cbc_encrypt` or `EncryptWriter::cbc`.
+	pub fn cbc(inner: R, key: &SecretKey) -> Self {
+		Self::new(inner, CbcDecryptor::new(key))
+	}
+}
+
+impl<R: Read> DecryptReader<R, CtrDecryptor> {
+	/// Decrypts the output of `ctr_encrypt` or `EncryptWriter::ctr`.
+	pub fn ctr(inner: R, key: &SecretKey) -> Self {
+		Self::new(inner, CtrDecryptor::new(key))
+	}
+}
+
+impl<R: Read, D: Decryptor> DecryptReader<R, D> {
+	/// Decrypts with any of the incremental modes.
+	pub fn new(inner: R, decryptor: D) -> Self {
+		Self { inner, decryptor: Some(decryptor), plain_text: Vec::new(), position: 0 }
+	}
+
+	/// Hands back the inner reader.
+	pub fn into_inner(self) -> R {
+		self.inner
+	}
+
+	/// Refills `plain_text` until it has something in it or there is nothing left to read.
+	fn fill(&mut self) -> io::Result<()> {
+		let mut chunk = [0u8; READ_CHUNK_SIZE];
+
+		while self.position == self.plain_text.len() {
+			let Some(decryptor) = &mut self.decryptor else {
+				return Ok(());
+			};
+
+			let read = match self.inner.read(&mut chunk) {
+				Ok(read) => read,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e),
+			};
+
+			self.plain_text = if read == 0 {
+				let decryptor = self.decryptor.take().expect("checked above");
+				decryptor.finalize().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+			} else {
+				decryptor.update(&chunk[..read])
+			};
+			self.position = 0;
+		}
+
+		Ok(())
+	}
+}
+
+impl<R: Read, D: Decryptor> Read for DecryptReader<R, D> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.fill()?;
+
+		let available = &self.plain_text[self.position..];
+		let len = available.len().min(buf.len());
+		buf[..len].copy_from_slice(&available[..len]);
+		self.position += len;
+
+		Ok(len)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		cbc_decrypt, cbc_encrypt, ctr_decrypt, ctr_encrypt,
+		incremental::{EcbDecryptor, EcbEncryptor},
+		test_util::sample,
+		BLOCK_SIZE,
+	};
+
+	const KEY: SecretKey = SecretKey::new([8u8; BLOCK_SIZE]);
+
+	/// Reads through a tiny buffer to exercise the partial-read paths.
+	fn read_slowly<R: Read>(mut reader: R) -> io::Result<Vec<u8>> {
+		let mut output = Vec::new();
+		let mut buf = [0u8; 5];
+		loop {
+			match reader.read(&mut buf)? {
+				0 => return Ok(output),
+				n => output.extend_from_slice(&buf[..n]),
+			}
+		}
+	}
+
+	#[test]
+	fn test_encrypt_writer_cbc() {
+		let plain_text = sample(1000);
+
+		let mut writer = EncryptWriter::cbc(Vec::new(), &KEY);
+		for piece in plain_text.chunks(37) {
+			writer.write_all(piece).unwrap();
+		}
+		let cipher_text = writer.finish().unwrap();
+
+		assert_eq!(cbc_decrypt(cipher_text, &KEY), plain_text);
+	}
+
+	#[test]
+	fn test_encrypt_writer_ctr() {
+		let plain_text = sample(1000);
+
+		let mut writer = EncryptWriter::ctr(Vec::new(), &KEY);
+		io::copy(&mut plain_text.as_slice(), &mut writer).unwrap();
+		let cipher_text = writer.finish().unwrap();
+
+		assert_eq!(ctr_decrypt(cipher_text, &KEY), plain_text);
+	}
+
+	/// Takes at most 7 bytes per call, and fails every other call with `WouldBlock` while
+	/// `blocking` is off, like a non-blocking socket with a small send buffer.
+	struct NonBlockingWriter {
+		data: Vec<u8>,
+		blocking: bool,
+		would_block: bool,
+	}
+
+	impl Write for NonBlockingWriter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.would_block = !self.would_block && !self.blocking;
+			if self.would_block {
+				return Err(io::ErrorKind::WouldBlock.into());
+			}
+			let len = buf.len().min(7);
+			self.data.extend_from_slice(&buf[..len]);
+			Ok(len)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_encrypt_writer_would_block() {
+		let plain_text = sample(1000);
+		let inner = NonBlockingWriter { data: Vec::new(), blocking: false, would_block: false };
+
+		let mut writer = EncryptWriter::ctr(inner, &KEY);
+		let mut remaining = plain_text.as_slice();
+		while !remaining.is_empty() {
+			// Retry on errors, the way an event loop would.
+			if let Ok(written) = writer.write(&remaining[..remaining.len().min(50)]) {
+				remaining = &remaining[written..];
+			}
+		}
+		while writer.flush().is_err() {}
+		writer.inner.blocking = true;
+		let cipher_text = writer.finish().unwrap().data;
+
+		assert_eq!(ctr_decrypt(cipher_text, &KEY), plain_text);
+	}
+
+	#[test]
+	fn test_decrypt_reader_cbc() {
+		for len in [0, 1, BLOCK_SIZE, READ_CHUNK_SIZE, 3 * READ_CHUNK_SIZE + 5] {
+			let plain_text = sample(len);
+			let cipher_text = cbc_encrypt(plain_text.clone(), &KEY);
+
+			let reader = DecryptReader::cbc(cipher_text.as_slice(), &KEY);
+
+			assert_eq!(read_slowly(reader).unwrap(), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_decrypt_reader_ctr() {
+		let plain_text = sample(READ_CHUNK_SIZE + 3);
+		let cipher_text = ctr_encrypt(plain_text.clone(), &KEY);
+
+		let mut reader = DecryptReader::ctr(cipher_text.as_slice(), &KEY);
+		let mut decrypted = Vec::new();
+		reader.read_to_end(&mut decrypted).unwrap();
+
+		assert_eq!(decrypted, plain_text);
+	}
+
+	#[test]
+	fn test_writer_reader_pipe() {
+		let plain_text = sample(5000);
+
+		let mut writer = EncryptWriter::cbc(Vec::new(), &KEY);
+		writer.write_all(&plain_text).unwrap();
+		let cipher_text = writer.finish().unwrap();
+
+		let reader = DecryptReader::cbc(cipher_text.as_slice(), &KEY);
+		assert_eq!(read_slowly(reader).unwrap(), plain_text);
+	}
+
+	#[test]
+	fn test_writer_reader_ecb() {
+		let plain_text = sample(100);
+
+		let mut writer = EncryptWriter::new(Vec::new(), EcbEncryptor::new(&KEY));
+		writer.write_all(&plain_text).unwrap();
+		let cipher_text = writer.finish().unwrap();
+
+		let reader = DecryptReader::new(cipher_text.as_slice(), EcbDecryptor::new(&KEY));
+		assert_eq!(read_slowly(reader).unwrap(), plain_text);
+	}
+
+	#[test]
+	fn test_decrypt_reader_truncated() {
+		let mut cipher_text = cbc_encrypt(sample(100), &KEY);
+		cipher_text.truncate(cipher_text.len() - 3);
+
+		let error = read_slowly(DecryptReader::cbc(cipher_text.as_slice(), &KEY)).unwrap_err();
+
+		assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,181 @@
+//! Deriving subkeys from a master key.
+//!
+//! The same key should never be used for two purposes, say CBC encryption and a MAC: some
+//! combinations are outright broken, and even the safe ones make the security argument
+//! harder. Rather than managing a separate random key for each purpose, derive them all
+//! from one master key with a label saying what each is for. Two constructions are
+//! provided:
+//!
+//! - HKDF-SHA256 (RFC 5869), for input keying material of any length and quality, e.g. a
+//!   Diffie-Hellman shared secret. It first extracts a uniform key, then expands it.
+//! - The SP 800-108 counter-mode KDF with AES-CMAC as the PRF, for when the master key is
+//!   already a uniformly random AES key and only AES is available.
+//!
+//! Different labels or contexts give independent keys, and so do different output lengths
+//! with the SP 800-108 KDF, since the length is part of its input.
+use hkdf::Hkdf;
+use sha2::Sha256;
+use zeroize::Zeroize;
+
+use crate::{cmac::aes_cmac, Error, SecretKey, BLOCK_SIZE};
+
+/// The size of an HKDF-SHA256 pseudorandom key, and of each block of its output.
+pub const HKDF_PRK_SIZE: usize = 32;
+
+/// HKDF-Extract: condenses `ikm` into a pseudorandom key. An empty salt means no salt.
+pub fn hkdf_sha256_extract(salt: &[u8], ikm: &[u8]) -> [u8; HKDF_PRK_SIZE] {
+	let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), ikm);
+	prk.into()
+}
+
+/// HKDF-Expand: stretches a pseudorandom key into `len` bytes bound to `info`.
+///
+/// Fails with `Error::InvalidParameters` if `len` is more than 255 * 32 bytes.
+pub fn hkdf_sha256_expand(
+	prk: &[u8; HKDF_PRK_SIZE],
+	info: &[u8],
+	len: usize,
+) -> Result<Vec<u8>, Error> {
+	let hkdf = Hkdf::<Sha256>::from_prk(prk).map_err(|_| Error::InvalidParameters)?;
+	let mut okm = vec![0u8; len];
+	hkdf.expand(info, &mut okm).map_err(|_| Error::InvalidParameters)?;
+
+	Ok(okm)
+}
+
+/// Extract followed by expand.
+pub fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+	hkdf_sha256_expand(&hkdf_sha256_extract(salt, ikm), info, len)
+}
+
+/// The SP 800-108 counter-mode KDF with AES-CMAC, producing `len` bytes.
+///
+/// Each block of output is `CMAC(key, [i] || label || 0x00 || context || [L])`, where `i`
+/// counts from 1 and `L` is `len` in bits, both as 32-bit big-endian integers.
+///
+/// Fails with `Error::InvalidParameters` if `len` in bits does not fit in 32 bits.
+pub fn kdf_counter_cmac(
+	key: &SecretKey,
+	label: &[u8],
+	context: &[u8],
+	len: usize,
+) -> Result<Vec<u8>, Error> {
+	let len_bits = len.checked_mul(8).and_then(|bits| u32::try_from(bits).ok());
+	let len_bits = len_bits.ok_or(Error::InvalidParameters)?;
+
+	let mut fixed_input = label.to_vec();
+	fixed_input.push(0);
+	fixed_input.extend_from_slice(context);
+	fixed_input.extend_from_slice(&len_bits.to_be_bytes());
+
+	Ok(counter_mode(key, &fixed_input, 4, len))
+}
+
+/// A `BLOCK_SIZE` subkey of `master` for the purpose named by `label`, e.g. `b"cbc"` or
+/// `b"mac"`, optionally bound to a `context` such as a user or file ID.
+pub fn derive_subkey(master: &SecretKey, label: &[u8], context: &[u8]) -> SecretKey {
+	let mut subkey = kdf_counter_cmac(master, label, context, BLOCK_SIZE).unwrap();
+	let key = SecretKey::from_slice(&subkey).unwrap();
+	subkey.zeroize();
+	key
+}
+
+/// Counter mode with the counter in front of the fixed input data, as `counter_len`
+/// big-endian bytes. The counter never wraps for any length `kdf_counter_cmac` accepts.
+fn counter_mode(key: &SecretKey, fixed_input: &[u8], counter_len: usize, len: usize) -> Vec<u8> {
+	let mut output = Vec::with_capacity(len.next_multiple_of(BLOCK_SIZE));
+	let mut message = vec![0u8; counter_len];
+	message.extend_from_slice(fixed_input);
+
+	for i in 1..=len.div_ceil(BLOCK_SIZE) as u32 {
+		message[..counter_len].copy_from_slice(&i.to_be_bytes()[4 - counter_len..]);
+		output.extend_from_slice(&aes_cmac(&message, key));
+	}
+	output.truncate(len);
+
+	output
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::hex;
+
+	#[test]
+	fn test_hkdf_rfc_5869_test_case_1() {
+		let ikm = [0x0b; 22];
+		let salt = hex("000102030405060708090a0b0c");
+		let info = hex("f0f1f2f3f4f5f6f7f8f9");
+
+		let prk = hkdf_sha256_extract(&salt, &ikm);
+		assert_eq!(
+			prk.to_vec(),
+			hex("077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5")
+		);
+		assert_eq!(
+			hkdf_sha256(&ikm, &salt, &info, 42).unwrap(),
+			hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+			     34007208d5b887185865")
+		);
+	}
+
+	#[test]
+	fn test_hkdf_rfc_5869_test_case_3() {
+		// No salt and no info.
+		assert_eq!(
+			hkdf_sha256(&[0x0b; 22], b"", b"", 42).unwrap(),
+			hex("8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d\
+			     9d201395faa4b61a96c8")
+		);
+	}
+
+	#[test]
+	fn test_hkdf_length_limit() {
+		let prk = [0u8; HKDF_PRK_SIZE];
+		assert_eq!(hkdf_sha256_expand(&prk, b"", 255 * 32).unwrap().len(), 255 * 32);
+		assert!(matches!(
+			hkdf_sha256_expand(&prk, b"", 255 * 32 + 1),
+			Err(Error::InvalidParameters)
+		));
+	}
+
+	#[test]
+	fn test_counter_mode_cavp() {
+		// NIST CAVP KDFCTR_gen.txt, CMAC_AES128, BEFORE_FIXED, RLEN=8_BITS, COUNT=0.
+		let key = SecretKey::from_slice(&hex("dff1e50ac0b69dc40f1051d46c2b069c")).unwrap();
+		let fixed_input = hex("c16e6e02c5a3dcc8d78b9ac1306877761310455b4e41469951d9e6c2245a064b\
+			 33fd8c3b01203a7824485bf0a64060c4648b707d2607935699316ea5");
+
+		assert_eq!(
+			counter_mode(&key, &fixed_input, 1, 16),
+			hex("8be8f0869b3c0ba97b71863d1b9f7813")
+		);
+	}
+
+	#[test]
+	fn test_kdf_counter_cmac() {
+		// Cross-checked against pyca/cryptography's KBKDFCMAC with 32-bit r and L.
+		let key = SecretKey::new(std::array::from_fn(|i| i as u8));
+		let output = kdf_counter_cmac(&key, b"encryption", b"user 42", 40).unwrap();
+
+		assert_eq!(
+			output,
+			hex("d33207d00d6b5ed2ec6bdc9e0437dfa260b6f6fe0c15e9d84c88079f5a457b46\
+			     ca3263f4aad456d5")
+		);
+	}
+
+	#[test]
+	fn test_subkeys_are_independent() {
+		let master = SecretKey::new([7u8; BLOCK_SIZE]);
+		let cbc = derive_subkey(&master, b"cbc", b"");
+		let mac = derive_subkey(&master, b"mac", b"");
+
+		assert_ne!(cbc, mac);
+		assert_ne!(cbc, derive_subkey(&master, b"cbc", b"file 1"));
+		assert_eq!(cbc, derive_subkey(&master, b"cbc", b""));
+		// The length is an input, so a shorter output is not a prefix of a longer one.
+		let long = kdf_counter_cmac(&master, b"cbc", b"", 32).unwrap();
+		assert_ne!(long[..BLOCK_SIZE], cbc.expose_secret()[..]);
+	}
+}
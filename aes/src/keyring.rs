@@ -0,0 +1,324 @@
+//! Several keys under one name, for rotating keys without losing old data.
+//!
+//! Rotating a key means encrypting new data under a new key, while data encrypted under
+//! the old ones stays readable until it has been migrated. That needs two things the bare
+//! modes don't have: a set of keys to choose from, and a way to tell which key a
+//! ciphertext was encrypted under.
+//!
+//! A `Keyring` numbers its keys with `KeyId`s, counting up from 1, and every key is in one
+//! of three states:
+//!
+//! - `Primary`: used for all new encryption. At most one key is primary.
+//! - `DecryptOnly`: a previous primary, still used to decrypt old data.
+//! - `Retired`: the key material has been wiped. Its ID is kept so that ciphertexts under
+//!   it fail with `Error::KeyRetired` rather than `Error::UnknownKey`.
+//!
+//! Ciphertexts from the keyring are the key ID, as a 4-byte big-endian integer, followed by
+//! the ciphertext of the mode, so `decrypt_cbc` and `decrypt_ctr` can pick the right key.
+//! The ID is not authenticated: changing it just makes decryption use the wrong key.
+use std::collections::BTreeMap;
+
+use crate::{
+	incremental::{CbcDecryptor, CtrDecryptor, Decryptor},
+	secret::append_zeroizing,
+	Error, SecretKey,
+};
+
+/// The size of the key ID in front of every ciphertext.
+pub const KEY_ID_SIZE: usize = 4;
+
+/// Identifies a key in a keyring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyId(pub u32);
+
+/// What a key in a keyring may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+	Primary,
+	DecryptOnly,
+	Retired,
+}
+
+#[derive(Debug)]
+struct Entry {
+	key: Option<SecretKey>,
+	state: KeyState,
+}
+
+/// A set of keys with IDs, one of which is used for encryption.
+#[derive(Debug, Default)]
+pub struct Keyring {
+	entries: BTreeMap<KeyId, Entry>,
+	/// IDs are never reused, even after the key with the highest one is removed.
+	last_id: u32,
+}
+
+impl Keyring {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds `key` as a `DecryptOnly` key and returns its ID. Use `set_primary` to start
+	/// encrypting with it.
+	pub fn insert(&mut self, key: SecretKey) -> KeyId {
+		self.last_id += 1;
+		let id = KeyId(self.last_id);
+		self.entries.insert(id, Entry { key: Some(key), state: KeyState::DecryptOnly });
+		id
+	}
+
+	/// Generates a new random key and makes it the primary one. The previous primary key
+	/// becomes `DecryptOnly`.
+	pub fn rotate(&mut self) -> KeyId {
+		let id = self.insert(SecretKey::random());
+		self.set_primary(id).expect("the key was just inserted");
+		id
+	}
+
+	/// Makes the key `id` the primary one. The previous primary key becomes `DecryptOnly`.
+	///
+	/// Fails with `Error::UnknownKey` or `Error::KeyRetired` if the key can't be used.
+	pub fn set_primary(&mut self, id: KeyId) -> Result<(), Error> {
+		self.key(id)?;
+		for entry in self.entries.values_mut() {
+			if entry.state == KeyState::Primary {
+				entry.state = KeyState::DecryptOnly;
+			}
+		}
+		self.entries.get_mut(&id).expect("checked above").state = KeyState::Primary;
+		Ok(())
+	}
+
+	/// Wipes the key `id`, so nothing encrypted under it can be decrypted any more.
+	///
+	/// The primary key can't be retired, and fails with `Error::KeyInUse`; rotate to a new
+	/// one first. Fails with `Error::UnknownKey` if there is no such key.
+	pub fn retire(&mut self, id: KeyId) -> Result<(), Error> {
+		let entry = self.entries.get_mut(&id).ok_or(Error::UnknownKey)?;
+		if entry.state == KeyState::Primary {
+			return Err(Error::KeyInUse);
+		}
+		entry.key = None;
+		entry.state = KeyState::Retired;
+		Ok(())
+	}
+
+	/// Removes the key `id` altogether, along with any record of it. Like `retire`, this
+	/// fails with `Error::KeyInUse` for the primary key.
+	pub fn remove(&mut self, id: KeyId) -> Result<(), Error> {
+		match self.entries.get(&id).map(|entry| entry.state) {
+			None => Err(Error::UnknownKey),
+			Some(KeyState::Primary) => Err(Error::KeyInUse),
+			Some(_) => {
+				self.entries.remove(&id);
+				Ok(())
+			},
+		}
+	}
+
+	/// The ID of the primary key, if there is one.
+	pub fn primary(&self) -> Option<KeyId> {
+		self.entries.iter().find(|(_, entry)| entry.state == KeyState::Primary).map(|(&id, _)| id)
+	}
+
+	pub fn state(&self, id: KeyId) -> Option<KeyState> {
+		self.entries.get(&id).map(|entry| entry.state)
+	}
+
+	/// Every key ID with its state, in order.
+	pub fn keys(&self) -> impl Iterator<Item = (KeyId, KeyState)> + '_ {
+		self.entries.iter().map(|(&id, entry)| (id, entry.state))
+	}
+
+	/// The key `id`, unless it is unknown or retired.
+	pub fn key(&self, id: KeyId) -> Result<&SecretKey, Error> {
+		let entry = self.entries.get(&id).ok_or(Error::UnknownKey)?;
+		entry.key.as_ref().ok_or(Error::KeyRetired)
+	}
+
+	/// `cbc_encrypt` under the primary key, with its ID in front.
+	///
+	/// Fails with `Error::UnknownKey` if there is no primary key.
+	pub fn encrypt_cbc(&self, plain_text: Vec<u8>) -> Result<Vec<u8>, Error> {
+		let (id, key) = self.primary_key()?;
+		Ok(with_key_id(id, crate::cbc_encrypt(plain_text, key)))
+	}
+
+	/// Decrypts the output of `encrypt_cbc` with whichever key it names.
+	pub fn decrypt_cbc(&self, cipher_text: &[u8]) -> Result<Vec<u8>, Error> {
+		let (id, body) = split_key_id(cipher_text)?;
+		decrypt_with(CbcDecryptor::new(self.key(id)?), body)
+	}
+
+	/// `ctr_encrypt` under the primary key, with its ID in front.
+	pub fn encrypt_ctr(&self, plain_text: Vec<u8>) -> Result<Vec<u8>, Error> {
+		let (id, key) = self.primary_key()?;
+		Ok(with_key_id(id, crate::ctr_encrypt(plain_text, key)))
+	}
+
+	/// Decrypts the output of `encrypt_ctr` with whichever key it names.
+	pub fn decrypt_ctr(&self, cipher_text: &[u8]) -> Result<Vec<u8>, Error> {
+		let (id, body) = split_key_id(cipher_text)?;
+		decrypt_with(CtrDecryptor::new(self.key(id)?), body)
+	}
+
+	/// Moves a CBC ciphertext over to the primary key. Ciphertexts that already use it are
+	/// returned as they are.
+	pub fn reencrypt_cbc(&self, cipher_text: &[u8]) -> Result<Vec<u8>, Error> {
+		if Some(split_key_id(cipher_text)?.0) == self.primary() {
+			return Ok(cipher_text.to_vec());
+		}
+		self.encrypt_cbc(self.decrypt_cbc(cipher_text)?)
+	}
+
+	/// Moves a CTR ciphertext over to the primary key. Ciphertexts that already use it are
+	/// returned as they are.
+	pub fn reencrypt_ctr(&self, cipher_text: &[u8]) -> Result<Vec<u8>, Error> {
+		if Some(split_key_id(cipher_text)?.0) == self.primary() {
+			return Ok(cipher_text.to_vec());
+		}
+		self.encrypt_ctr(self.decrypt_ctr(cipher_text)?)
+	}
+
+	fn primary_key(&self) -> Result<(KeyId, &SecretKey), Error> {
+		let id = self.primary().ok_or(Error::UnknownKey)?;
+		Ok((id, self.key(id)?))
+	}
+}
+
+/// The key ID of a ciphertext from a keyring, without decrypting it.
+pub fn key_id(cipher_text: &[u8]) -> Result<KeyId, Error> {
+	Ok(split_key_id(cipher_text)?.0)
+}
+
+fn with_key_id(id: KeyId, cipher_text: Vec<u8>) -> Vec<u8> {
+	let mut output = Vec::with_capacity(KEY_ID_SIZE + cipher_text.len());
+	output.extend_from_slice(&id.0.to_be_bytes());
+	output.extend(cipher_text);
+	output
+}
+
+fn split_key_id(cipher_text: &[u8]) -> Result<(KeyId, &[u8]), Error> {
+	let (id, body) = cipher_text.split_first_chunk::<KEY_ID_SIZE>().ok_or(Error::Truncated)?;
+	Ok((KeyId(u32::from_be_bytes(*id)), body))
+}
+
+/// Runs a decryptor over the whole body, failing instead of panicking on bad lengths.
+fn decrypt_with(mut decryptor: impl Decryptor, body: &[u8]) -> Result<Vec<u8>, Error> {
+	let mut plain_text = decryptor.update(body);
+	append_zeroizing(&mut plain_text, decryptor.finalize()?);
+	Ok(plain_text)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{cbc_decrypt, BLOCK_SIZE};
+
+	const PLAIN_TEXT: &[u8] = b"Hello, keyring!";
+
+	#[test]
+	fn test_ids_and_states() {
+		let mut keyring = Keyring::new();
+		assert_eq!(keyring.primary(), None);
+
+		let first = keyring.rotate();
+		let second = keyring.rotate();
+		let third = keyring.insert(SecretKey::new([3u8; BLOCK_SIZE]));
+
+		assert_eq!((first, second, third), (KeyId(1), KeyId(2), KeyId(3)));
+		assert_eq!(keyring.primary(), Some(second));
+		assert_eq!(
+			keyring.keys().collect::<Vec<_>>(),
+			[
+				(first, KeyState::DecryptOnly),
+				(second, KeyState::Primary),
+				(third, KeyState::DecryptOnly)
+			]
+		);
+	}
+
+	#[test]
+	fn test_decrypt_picks_the_right_key() {
+		let mut keyring = Keyring::new();
+		let old = keyring.rotate();
+		let old_cbc = keyring.encrypt_cbc(PLAIN_TEXT.to_vec()).unwrap();
+		let old_ctr = keyring.encrypt_ctr(PLAIN_TEXT.to_vec()).unwrap();
+
+		let new = keyring.rotate();
+		let new_cbc = keyring.encrypt_cbc(PLAIN_TEXT.to_vec()).unwrap();
+
+		assert_eq!(key_id(&old_cbc).unwrap(), old);
+		assert_eq!(key_id(&new_cbc).unwrap(), new);
+		assert_eq!(keyring.decrypt_cbc(&old_cbc).unwrap(), PLAIN_TEXT);
+		assert_eq!(keyring.decrypt_cbc(&new_cbc).unwrap(), PLAIN_TEXT);
+		assert_eq!(keyring.decrypt_ctr(&old_ctr).unwrap(), PLAIN_TEXT);
+
+		// Past the ID, it is an ordinary CBC ciphertext.
+		let key = keyring.key(new).unwrap();
+		assert_eq!(cbc_decrypt(new_cbc[KEY_ID_SIZE..].to_vec(), key), PLAIN_TEXT);
+	}
+
+	#[test]
+	fn test_retired_and_unknown_keys() {
+		let mut keyring = Keyring::new();
+		let old = keyring.rotate();
+		let cipher_text = keyring.encrypt_cbc(PLAIN_TEXT.to_vec()).unwrap();
+
+		assert!(matches!(keyring.retire(old), Err(Error::KeyInUse)));
+		keyring.rotate();
+		keyring.retire(old).unwrap();
+		assert_eq!(keyring.state(old), Some(KeyState::Retired));
+		assert!(matches!(keyring.decrypt_cbc(&cipher_text), Err(Error::KeyRetired)));
+		assert!(matches!(keyring.set_primary(old), Err(Error::KeyRetired)));
+
+		keyring.remove(old).unwrap();
+		assert!(matches!(keyring.decrypt_cbc(&cipher_text), Err(Error::UnknownKey)));
+		assert!(matches!(keyring.remove(old), Err(Error::UnknownKey)));
+
+		// Removing the newest key doesn't free its ID.
+		let newest = keyring.insert(SecretKey::new([1u8; BLOCK_SIZE]));
+		keyring.remove(newest).unwrap();
+		assert_ne!(keyring.insert(SecretKey::new([2u8; BLOCK_SIZE])), newest);
+	}
+
+	#[test]
+	fn test_no_primary_key() {
+		let mut keyring = Keyring::new();
+		keyring.insert(SecretKey::new([1u8; BLOCK_SIZE]));
+
+		assert!(matches!(keyring.encrypt_cbc(PLAIN_TEXT.to_vec()), Err(Error::UnknownKey)));
+	}
+
+	#[test]
+	fn test_malformed_cipher_texts() {
+		let mut keyring = Keyring::new();
+		let id = keyring.rotate();
+		let cipher_text = keyring.encrypt_cbc(PLAIN_TEXT.to_vec()).unwrap();
+
+		assert!(matches!(keyring.decrypt_cbc(&[0, 0]), Err(Error::Truncated)));
+		// Just an ID, or a cut-off block, fails instead of panicking.
+		assert!(matches!(keyring.decrypt_cbc(&id.0.to_be_bytes()), Err(Error::Truncated)));
+		let cut = &cipher_text[..cipher_text.len() - 1];
+		assert!(matches!(keyring.decrypt_cbc(cut), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_reencrypt() {
+		let mut keyring = Keyring::new();
+		let old = keyring.rotate();
+		let cbc = keyring.encrypt_cbc(PLAIN_TEXT.to_vec()).unwrap();
+		let ctr = keyring.encrypt_ctr(PLAIN_TEXT.to_vec()).unwrap();
+
+		let new = keyring.rotate();
+		let migrated_cbc = keyring.reencrypt_cbc(&cbc).unwrap();
+		let migrated_ctr = keyring.reencrypt_ctr(&ctr).unwrap();
+		keyring.retire(old).unwrap();
+
+		assert_eq!(key_id(&migrated_cbc).unwrap(), new);
+		assert_eq!(keyring.decrypt_cbc(&migrated_cbc).unwrap(), PLAIN_TEXT);
+		assert_eq!(keyring.decrypt_ctr(&migrated_ctr).unwrap(), PLAIN_TEXT);
+		// Already under the primary key: nothing to do.
+		assert_eq!(keyring.reencrypt_cbc(&migrated_cbc).unwrap(), migrated_cbc);
+	}
+}
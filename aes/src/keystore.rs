@@ -0,0 +1,334 @@
+//! Named keys kept in a file, encrypted under a passphrase.
+//!
+//! The whole keystore is serialized and encrypted as one message with
+//! `password::encrypt_with_password`, so the file gets the same KDF, salt and GCM
+//! authentication as any password-encrypted data: a wrong passphrase or a modified file
+//! fails with `Error::Authentication`, and nothing about the keys, not even their names or
+//! how many there are, can be read without the passphrase.
+//!
+//! The plaintext is:
+//!
+//! ```text
+//! "AESK" | version (1) | key count (4) | { name length (2) | name | key (16) } ...
+//! ```
+//!
+//! with the counts as big-endian integers and the names in UTF-8, sorted.
+//!
+//! Every change is written straight back to the file. The new contents go to a temporary
+//! file next to it, which is synced and then renamed over the old one, so a crash leaves
+//! either the old keystore or the new one, never half of each. On Unix the file is only
+//! readable by its owner, and `Keystore::open` refuses a file that anyone else can access.
+use std::{
+	collections::BTreeMap,
+	fs::{self, OpenOptions},
+	io::{self, Write},
+	path::{Path, PathBuf},
+};
+
+use zeroize::Zeroizing;
+
+use crate::{
+	password::{decrypt_with_password, encrypt_with_password, password_kdf, Kdf},
+	Error, SecretKey, BLOCK_SIZE,
+};
+
+const MAGIC: &[u8; 4] = b"AESK";
+const VERSION: u8 = 1;
+
+/// A set of named keys in an encrypted file.
+#[derive(Debug)]
+pub struct Keystore {
+	path: PathBuf,
+	password: Zeroizing<Vec<u8>>,
+	kdf: Kdf,
+	keys: BTreeMap<String, SecretKey>,
+}
+
+impl Keystore {
+	/// Creates an empty keystore at `path`, protected by `password`. Fails if the file
+	/// already exists.
+	pub fn create(path: impl AsRef<Path>, password: &[u8], kdf: Kdf) -> Result<Self, Error> {
+		let path = path.as_ref().to_path_buf();
+		if path.try_exists()? {
+			return Err(
+				io::Error::new(io::ErrorKind::AlreadyExists, "keystore already exists").into()
+			);
+		}
+
+		let keystore =
+			Self { path, password: Zeroizing::new(password.to_vec()), kdf, keys: BTreeMap::new() };
+		keystore.save()?;
+		Ok(keystore)
+	}
+
+	/// Opens and decrypts the keystore at `path`. Changes are saved with the same KDF
+	/// parameters it was created with.
+	pub fn open(path: impl AsRef<Path>, password: &[u8]) -> Result<Self, Error> {
+		let path = path.as_ref().to_path_buf();
+		check_permissions(&path)?;
+
+		let contents = fs::read(&path)?;
+		let kdf = password_kdf(&contents)?;
+		let plain_text = Zeroizing::new(decrypt_with_password(&contents, password)?);
+		let keys = deserialize(&plain_text)?;
+		Ok(Self { path, password: Zeroizing::new(password.to_vec()), kdf, keys })
+	}
+
+	/// The path of the keystore file.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// The names of the keys, in sorted order.
+	pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+		self.keys.keys().map(String::as_str)
+	}
+
+	/// Stores `key` under `name`. Fails with `Error::KeyExists` if the name is taken, and
+	/// with `Error::InvalidParameters` if it is empty or longer than 65535 bytes.
+	pub fn add(&mut self, name: &str, key: SecretKey) -> Result<(), Error> {
+		if name.is_empty() || name.len() > u16::MAX as usize {
+			return Err(Error::InvalidParameters);
+		}
+		if self.keys.contains_key(name) {
+			return Err(Error::KeyExists);
+		}
+
+		self.keys.insert(name.to_string(), key);
+		self.save().inspect_err(|_| {
+			self.keys.remove(name);
+		})
+	}
+
+	/// A copy of the key stored under `name`.
+	pub fn export(&self, name: &str) -> Result<SecretKey, Error> {
+		self.keys.get(name).cloned().ok_or(Error::UnknownKey)
+	}
+
+	/// Removes the key stored under `name` from the keystore.
+	pub fn delete(&mut self, name: &str) -> Result<(), Error> {
+		let key = self.keys.remove(name).ok_or(Error::UnknownKey)?;
+		self.save().inspect_err(|_| {
+			self.keys.insert(name.to_string(), key);
+		})
+	}
+
+	/// Re-encrypts the keystore under a new password and KDF.
+	pub fn change_password(&mut self, password: &[u8], kdf: Kdf) -> Result<(), Error> {
+		let old_password = std::mem::replace(&mut self.password, Zeroizing::new(password.to_vec()));
+		let old_kdf = std::mem::replace(&mut self.kdf, kdf);
+		self.save().inspect_err(|_| {
+			self.password = old_password;
+			self.kdf = old_kdf;
+		})
+	}
+
+	/// Encrypts the keys and atomically replaces the file with them.
+	fn save(&self) -> Result<(), Error> {
+		let plain_text = serialize(&self.keys);
+		let contents = encrypt_with_password(&plain_text, &self.password, &self.kdf)?;
+		write_atomically(&self.path, &contents)?;
+		Ok(())
+	}
+}
+
+fn serialize(keys: &BTreeMap<String, SecretKey>) -> Zeroizing<Vec<u8>> {
+	// Sized up front, so the keys are never left behind by a reallocation.
+	let len = keys.keys().map(|name| 2 + name.len() + BLOCK_SIZE).sum::<usize>();
+	let mut output = Zeroizing::new(Vec::with_capacity(MAGIC.len() + 1 + 4 + len));
+	output.extend_from_slice(MAGIC);
+	output.push(VERSION);
+	output.extend_from_slice(&(keys.len() as u32).to_be_bytes());
+	for (name, key) in keys {
+		output.extend_from_slice(&(name.len() as u16).to_be_bytes());
+		output.extend_from_slice(name.as_bytes());
+		output.extend_from_slice(key.expose_secret());
+	}
+	output
+}
+
+fn deserialize(data: &[u8]) -> Result<BTreeMap<String, SecretKey>, Error> {
+	let rest = data.strip_prefix(MAGIC).ok_or(Error::InvalidHeader)?;
+	let rest = rest.strip_prefix(&[VERSION]).ok_or(Error::InvalidHeader)?;
+	let (count, mut rest) = take(rest, 4)?;
+	let count = u32::from_be_bytes(count.try_into().unwrap());
+
+	let mut keys = BTreeMap::new();
+	for _ in 0..count {
+		let (len, tail) = take(rest, 2)?;
+		let (name, tail) = take(tail, u16::from_be_bytes(len.try_into().unwrap()) as usize)?;
+		let (key, tail) = take(tail, BLOCK_SIZE)?;
+		let name = String::from_utf8(name.to_vec()).map_err(|_| Error::InvalidHeader)?;
+		keys.insert(name, SecretKey::from_slice(key)?);
+		rest = tail;
+	}
+	if !rest.is_empty() {
+		return Err(Error::InvalidHeader);
+	}
+	Ok(keys)
+}
+
+fn take(data: &[u8], len: usize) -> Result<(&[u8], &[u8]), Error> {
+	if data.len() < len {
+		return Err(Error::Truncated);
+	}
+	Ok(data.split_at(len))
+}
+
+/// Replaces the file at `path` with `contents`, via a synced temporary file next to it.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+	let mut temp_path = path.to_path_buf().into_os_string();
+	temp_path.push(".tmp");
+
+	let mut options = OpenOptions::new();
+	options.write(true).create(true).truncate(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+	let mut temp = options.open(&temp_path)?;
+	// A leftover temporary file keeps its old permissions, so set them again.
+	#[cfg(unix)]
+	temp.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+	temp.write_all(contents)?;
+	temp.sync_all()?;
+	fs::rename(&temp_path, path)?;
+
+	// Make the rename itself durable.
+	#[cfg(unix)]
+	if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+		fs::File::open(dir)?.sync_all()?;
+	}
+	Ok(())
+}
+
+/// Refuses keystores that can be read or written by anyone but their owner.
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> io::Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+
+	let mode = fs::metadata(path)?.permissions().mode();
+	if mode & 0o077 != 0 {
+		return Err(io::Error::new(
+			io::ErrorKind::PermissionDenied,
+			format!(
+				"keystore is accessible by other users (mode {:o}), expected 600",
+				mode & 0o777
+			),
+		));
+	}
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> io::Result<()> {
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::{temp_path, FAST_KDFS};
+
+	#[test]
+	fn test_add_list_export_delete() {
+		let path = temp_path("keystore_basic");
+		let mut keystore = Keystore::create(&path, b"passphrase", FAST_KDFS[0]).unwrap();
+		keystore.add("backup", SecretKey::new([1; BLOCK_SIZE])).unwrap();
+		keystore.add("api", SecretKey::new([2; BLOCK_SIZE])).unwrap();
+		assert_eq!(keystore.names().collect::<Vec<_>>(), ["api", "backup"]);
+
+		let mut keystore = Keystore::open(&path, b"passphrase").unwrap();
+		assert_eq!(keystore.export("backup").unwrap(), SecretKey::new([1; BLOCK_SIZE]));
+		assert!(matches!(keystore.export("missing"), Err(Error::UnknownKey)));
+
+		keystore.delete("backup").unwrap();
+		assert!(matches!(keystore.delete("backup"), Err(Error::UnknownKey)));
+		let keystore = Keystore::open(&path, b"passphrase").unwrap();
+		assert_eq!(keystore.names().collect::<Vec<_>>(), ["api"]);
+
+		assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_rejects_bad_names() {
+		let path = temp_path("keystore_names");
+		let mut keystore = Keystore::create(&path, b"passphrase", FAST_KDFS[0]).unwrap();
+		keystore.add("key", SecretKey::new([1; BLOCK_SIZE])).unwrap();
+
+		let duplicate = keystore.add("key", SecretKey::new([2; BLOCK_SIZE]));
+		assert!(matches!(duplicate, Err(Error::KeyExists)));
+		assert_eq!(keystore.export("key").unwrap(), SecretKey::new([1; BLOCK_SIZE]));
+		let empty = keystore.add("", SecretKey::new([2; BLOCK_SIZE]));
+		assert!(matches!(empty, Err(Error::InvalidParameters)));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_create_refuses_existing_file() {
+		let path = temp_path("keystore_exists");
+		Keystore::create(&path, b"passphrase", FAST_KDFS[0]).unwrap();
+		assert!(matches!(
+			Keystore::create(&path, b"passphrase", FAST_KDFS[0]),
+			Err(Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists
+		));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_wrong_password_or_modified_file() {
+		let path = temp_path("keystore_tampered");
+		Keystore::create(&path, b"right", FAST_KDFS[0]).unwrap();
+		assert!(matches!(Keystore::open(&path, b"wrong"), Err(Error::Authentication)));
+
+		let mut contents = fs::read(&path).unwrap();
+		*contents.last_mut().unwrap() ^= 1;
+		fs::write(&path, &contents).unwrap();
+		assert!(matches!(Keystore::open(&path, b"right"), Err(Error::Authentication)));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_change_password() {
+		let path = temp_path("keystore_password");
+		let mut keystore = Keystore::create(&path, b"old", FAST_KDFS[0]).unwrap();
+		keystore.add("key", SecretKey::new([1; BLOCK_SIZE])).unwrap();
+
+		keystore.change_password(b"new", Kdf::Pbkdf2 { iterations: 2000 }).unwrap();
+		assert!(matches!(Keystore::open(&path, b"old"), Err(Error::Authentication)));
+		let keystore = Keystore::open(&path, b"new").unwrap();
+		assert_eq!(keystore.kdf, Kdf::Pbkdf2 { iterations: 2000 });
+		assert_eq!(keystore.export("key").unwrap(), SecretKey::new([1; BLOCK_SIZE]));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_serialization_round_trip() {
+		let mut keys = BTreeMap::new();
+		keys.insert("é".to_string(), SecretKey::new([3; BLOCK_SIZE]));
+		keys.insert("x".repeat(300), SecretKey::new([4; BLOCK_SIZE]));
+		let data = serialize(&keys);
+		assert_eq!(deserialize(&data).unwrap(), keys);
+
+		assert!(matches!(deserialize(&data[..data.len() - 1]), Err(Error::Truncated)));
+		let mut extra = data.to_vec();
+		extra.push(0);
+		assert!(matches!(deserialize(&extra), Err(Error::InvalidHeader)));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_permissions() {
+		use std::os::unix::fs::PermissionsExt;
+
+		let path = temp_path("keystore_permissions");
+		Keystore::create(&path, b"passphrase", FAST_KDFS[0]).unwrap();
+		assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+		assert!(matches!(
+			Keystore::open(&path, b"passphrase"),
+			Err(Error::Io(e)) if e.kind() == io::ErrorKind::PermissionDenied
+		));
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,656 @@
+//! In Module 1, we discussed Block ciphers like AES. Block ciphers have a fixed length input.
+//! Real wold data that we wish to encrypt _may_ be exactly the right length, but is probably not.
+//! When your data is too short, you can simply pad it up to the correct length.
+//! When your data is too long, you have some options.
+//!
+//! In this exercise, we will explore a few of the common ways that large pieces of data can be
+//! broken up and combined in order to encrypt it with a fixed-length block cipher.
+//!
+//! WARNING: ECB MODE IS NOT SECURE.
+//! Seriously, ECB is NOT secure. Don't use it irl. We are implementing it here to understand _why_
+//! it is not secure and make the point that the most straight-forward approach isn't always the
+//! best, and can sometimes be trivially broken.
+use aes::{
+	cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+	Aes128,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+#[cfg(target_arch = "x86_64")]
+pub mod aesni;
+pub mod bitsliced;
+pub mod chacha20;
+pub mod cmac;
+pub mod drbg;
+pub mod encoding;
+pub mod envelope;
+mod error;
+pub mod gcm;
+pub mod ghash;
+pub mod in_place;
+pub mod incremental;
+pub mod io;
+pub mod kdf;
+pub mod keyring;
+pub mod keystore;
+pub mod nonce;
+pub mod parallel;
+pub mod password;
+pub mod poly1305;
+pub mod reference;
+pub mod secret;
+pub mod seek;
+pub mod stream;
+#[cfg(test)]
+mod test_util;
+pub mod trace;
+pub mod usage;
+
+pub use error::Error;
+pub use secret::SecretKey;
+pub use trace::aes_encrypt_trace;
+use incremental::{CbcDecryptor, CbcEncryptor, CtrDecryptor, CtrEncryptor, Decryptor, Encryptor};
+use nonce::NonceSource;
+use rand::{CryptoRng, Rng};
+
+///We're using AES 128 which has 16-byte (128 bit) blocks.
+pub const BLOCK_SIZE: usize = 16;
+
+/// Simple AES encryption
+/// Helper function to make the core AES block cipher easier to understand.
+///
+/// With the `reference-aes` feature this runs the from-scratch AES in the `reference`
+/// module instead of the `aes` crate, and with `bitsliced-aes` the constant-time one in
+/// `bitsliced` (if both are on, `reference-aes` wins). Otherwise, on x86_64 CPUs with
+/// AES-NI it uses the instructions directly through the `aesni` module. The same goes for
+/// aes_decrypt and the batch helpers.
+pub fn aes_encrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+	if cfg!(feature = "reference-aes") {
+		return reference::Aes::new_128(key).encrypt_block(data);
+	}
+	if cfg!(feature = "bitsliced-aes") {
+		return bitsliced::Aes::new_128(key).encrypt_block(data);
+	}
+	#[cfg(target_arch = "x86_64")]
+	if let Some(cipher) = aesni::Aes128::new(key) {
+		let mut blocks = [data];
+		cipher.encrypt_blocks(&mut blocks);
+		return blocks[0];
+	}
+
+	// Convert the inputs to the necessary data type. The cipher borrows the key instead of
+	// copying it, and the `aes` crate wipes its expanded round keys when dropped.
+	let mut block = GenericArray::from(data);
+	let cipher = Aes128::new(GenericArray::from_slice(key));
+
+	cipher.encrypt_block(&mut block);
+
+	block.into()
+}
+
+/// Simple AES encryption
+/// Helper function to make the core AES block cipher easier to understand.
+pub fn aes_decrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+	if cfg!(feature = "reference-aes") {
+		return reference::Aes::new_128(key).decrypt_block(data);
+	}
+	if cfg!(feature = "bitsliced-aes") {
+		return bitsliced::Aes::new_128(key).decrypt_block(data);
+	}
+	#[cfg(target_arch = "x86_64")]
+	if let Some(cipher) = aesni::Aes128::new(key) {
+		let mut blocks = [data];
+		cipher.decrypt_blocks(&mut blocks);
+		return blocks[0];
+	}
+
+	// Convert the inputs to the necessary data type
+	let mut block = GenericArray::from(data);
+	let cipher = Aes128::new(GenericArray::from_slice(key));
+
+	cipher.decrypt_block(&mut block);
+
+	block.into()
+}
+
+/// How many blocks we hand to the cipher at once. With AES-NI the `aes` crate keeps 8
+/// blocks in flight, so the CPU works on the next block while the previous ones are still
+/// going through the pipeline.
+const BATCH_SIZE: usize = 8;
+
+/// Batch AES encryption
+/// Like aes_encrypt, but for many blocks at once. The key is expanded only once, and the
+/// blocks go to the cipher in batches instead of one by one. Only modes where the blocks
+/// don't depend on each other (like the counter mode inside GCM) can use this.
+pub fn aes_encrypt_blocks(blocks: &mut [[u8; BLOCK_SIZE]], key: &[u8; BLOCK_SIZE]) {
+	if cfg!(feature = "reference-aes") {
+		let cipher = reference::Aes::new_128(key);
+		blocks.iter_mut().for_each(|block| *block = cipher.encrypt_block(*block));
+		return;
+	}
+	if cfg!(feature = "bitsliced-aes") {
+		bitsliced::Aes::new_128(key).encrypt_blocks(blocks);
+		return;
+	}
+	#[cfg(target_arch = "x86_64")]
+	if let Some(cipher) = aesni::Aes128::new(key) {
+		cipher.encrypt_blocks(blocks);
+		return;
+	}
+
+	let cipher = Aes128::new(GenericArray::from_slice(key));
+
+	let mut batch = [GenericArray::default(); BATCH_SIZE];
+	for chunk in blocks.chunks_mut(BATCH_SIZE) {
+		for (slot, block) in batch.iter_mut().zip(chunk.iter()) {
+			*slot = GenericArray::from(*block);
+		}
+
+		cipher.encrypt_blocks(&mut batch[..chunk.len()]);
+
+		for (block, slot) in chunk.iter_mut().zip(batch.iter()) {
+			*block = (*slot).into();
+		}
+	}
+	// One side of the batch was plaintext.
+	batch.iter_mut().for_each(|slot| slot.as_mut_slice().zeroize());
+}
+
+/// Batch AES decryption
+/// Like aes_decrypt, but for many blocks at once. CBC _decryption_ can use this, since
+/// every ciphertext block is already known up front.
+pub fn aes_decrypt_blocks(blocks: &mut [[u8; BLOCK_SIZE]], key: &[u8; BLOCK_SIZE]) {
+	if cfg!(feature = "reference-aes") {
+		let cipher = reference::Aes::new_128(key);
+		blocks.iter_mut().for_each(|block| *block = cipher.decrypt_block(*block));
+		return;
+	}
+	if cfg!(feature = "bitsliced-aes") {
+		bitsliced::Aes::new_128(key).decrypt_blocks(blocks);
+		return;
+	}
+	#[cfg(target_arch = "x86_64")]
+	if let Some(cipher) = aesni::Aes128::new(key) {
+		cipher.decrypt_blocks(blocks);
+		return;
+	}
+
+	let cipher = Aes128::new(GenericArray::from_slice(key));
+
+	let mut batch = [GenericArray::default(); BATCH_SIZE];
+	for chunk in blocks.chunks_mut(BATCH_SIZE) {
+		for (slot, block) in batch.iter_mut().zip(chunk.iter()) {
+			*slot = GenericArray::from(*block);
+		}
+
+		cipher.decrypt_blocks(&mut batch[..chunk.len()]);
+
+		for (block, slot) in chunk.iter_mut().zip(batch.iter()) {
+			*block = (*slot).into();
+		}
+	}
+	// One side of the batch was plaintext.
+	batch.iter_mut().for_each(|slot| slot.as_mut_slice().zeroize());
+}
+
+/// Before we can begin encrypting our raw data, we need it to be a multiple of the
+/// block length which is 16 bytes (128 bits) in AES128.
+///
+/// The padding algorithm here is actually not trivial. The trouble is that if we just
+/// naively throw a bunch of zeros on the end, there is no way to know, later, whether
+/// those zeros are padding, or part of the message, or some of each.
+///
+/// The scheme works like this. If the data is not a multiple of the block length,  we
+/// compute how many pad bytes we need, and then write that number into the last several bytes.
+/// Later we look at the last byte, and remove that number of bytes.
+///
+/// But if the data _is_ a multiple of the block length, then we have a problem. We don't want
+/// to later look at the last byte and remove part of the data. Instead, in this case, we add
+/// another entire block containing the block length in each byte. In our case,
+/// [16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16]
+fn pad(mut data: Vec<u8>) -> Vec<u8> {
+	// When twe have a multiple the second term is 0
+	let number_pad_bytes = BLOCK_SIZE - data.len() % BLOCK_SIZE;
+	secret::reserve_zeroizing(&mut data, number_pad_bytes);
+
+	for _ in 0..number_pad_bytes {
+		data.push(number_pad_bytes as u8);
+	}
+
+	data
+}
+
+/// Groups the data into BLOCK_SIZE blocks. Assumes the data is already
+/// a multiple of the block size. If this is not the case, call `pad` first.
+///
+/// The data is usually plaintext, so the original is wiped once it has been copied.
+fn group(mut data: Vec<u8>) -> Vec<[u8; BLOCK_SIZE]> {
+	let mut blocks = Vec::new();
+	let mut i = 0;
+	while i < data.len() {
+		let mut block: [u8; BLOCK_SIZE] = Default::default();
+		block.copy_from_slice(&data[i..i + BLOCK_SIZE]);
+		blocks.push(block);
+
+		i += BLOCK_SIZE;
+	}
+	data.zeroize();
+
+	blocks
+}
+
+/// Does the opposite of the group function
+fn un_group(blocks: Vec<[u8; BLOCK_SIZE]>) -> Vec<u8> {
+
+	blocks.into_iter().flat_map(|block| block.to_vec()).collect()
+}
+
+/// Does the opposite of the pad function.
+///
+/// Truncates in place rather than copying, so no stray copy of the plaintext is left
+/// behind in freed memory.
+fn un_pad(mut data: Vec<u8>) -> Vec<u8> {
+
+	let pad_byte = *data.last().unwrap();
+	let pad_len = pad_byte as usize;
+	let data_len = data.len();
+
+	if pad_len <= BLOCK_SIZE && data_len >= pad_len {
+		data.truncate(data_len - pad_len);
+	}
+	data
+}
+
+/// The first mode we will implement is the Electronic Code Book, or ECB mode.
+/// Warning: THIS MODE IS NOT SECURE!!!!
+///
+/// This is probably the first thing you think of when considering how to encrypt
+/// large data. In this mode we simply encrypt each block of data under the same key.
+/// One good thing about this mode is that it is parallelizable. But to see why it is
+/// insecure look at: https://www.ubiqsecurity.com/wp-content/uploads/2022/02/ECB2.png
+pub fn ecb_encrypt(plain_text: Vec<u8>, key: &SecretKey) -> Vec<u8> {
+	let mut blocks = group(pad(plain_text));
+	aes_encrypt_blocks(&mut blocks, key.expose_secret());
+	un_group(blocks)
+}
+
+/// Opposite of ecb_encrypt.
+pub fn ecb_decrypt(cipher_text: Vec<u8>, key: &SecretKey) -> Vec<u8> {
+	assert!(
+		!cipher_text.is_empty() && cipher_text.len().is_multiple_of(BLOCK_SIZE),
+		"cipher text must be whole blocks"
+	);
+
+	let mut blocks = group(cipher_text);
+	aes_decrypt_blocks(&mut blocks, key.expose_secret());
+
+	let mut plain_text = Vec::with_capacity(blocks.len() * BLOCK_SIZE);
+	for block in &blocks {
+		plain_text.extend_from_slice(block);
+	}
+	blocks.zeroize();
+	un_pad(plain_text)
+}
+
+/// The next mode, which you can implement on your own is cipherblock chaining.
+/// This mode actually is secure, and it often used in real world applications.
+///
+/// In this mode, the ciphertext from the first block is XORed with the
+/// plaintext of the next block before it is encrypted.
+///
+/// For more information, and a very clear diagram,
+/// see https://de.wikipedia.org/wiki/Cipher_Block_Chaining_Mode
+///
+/// You will need to generate a random initialization vector (IV) to encrypt the
+/// very first block because it doesn't have a previous block. Typically this IV
+/// is inserted as the first block of ciphertext.
+pub fn cbc_encrypt(plain_text: Vec<u8>, key: &SecretKey) -> Vec<u8> {
+	cbc_encrypt_with_rng(plain_text, key, &mut rand::thread_rng())
+}
+
+/// Like `cbc_encrypt`, but the IV is drawn from `rng`. A seeded RNG makes the output
+/// reproducible, e.g. for golden-file tests.
+pub fn cbc_encrypt_with_rng(
+	plain_text: Vec<u8>,
+	key: &SecretKey,
+	rng: &mut impl CryptoRng,
+) -> Vec<u8> {
+	cbc_encrypt_with_iv(plain_text, key, rng.gen())
+}
+
+/// Like `cbc_encrypt`, but the IV is made from a nonce drawn from `nonces`.
+///
+/// The nonce is encrypted under the key to get the IV, as in NIST SP 800-38A appendix C.
+/// CBC needs IVs that can't be predicted, and this makes even a counter give such IVs.
+pub fn cbc_encrypt_with_nonces(
+	plain_text: Vec<u8>,
+	key: &SecretKey,
+	nonces: &mut impl NonceSource,
+) -> Result<Vec<u8>, Error> {
+	// Wipes the plaintext even if no nonce can be had.
+	let mut plain_text = Zeroizing::new(plain_text);
+	let mut nonce = [0u8; BLOCK_SIZE];
+	nonces.fill_nonce(&mut nonce)?;
+
+	let iv = aes_encrypt(nonce, key.expose_secret());
+	Ok(cbc_encrypt_with_iv(std::mem::take(&mut *plain_text), key, iv))
+}
+
+fn cbc_encrypt_with_iv(plain_text: Vec<u8>, key: &SecretKey, iv: [u8; BLOCK_SIZE]) -> Vec<u8> {
+	let plain_text = Zeroizing::new(plain_text);
+
+	// The actual block loop lives in `CbcEncryptor`, so that it can also be fed in pieces.
+	let mut encryptor = CbcEncryptor::with_iv(key, iv);
+	let mut cipher_text = encryptor.update(&plain_text);
+	cipher_text.extend(encryptor.finalize());
+
+	cipher_text
+}
+
+pub fn cbc_decrypt(cipher_text: Vec<u8>, key: &SecretKey) -> Vec<u8> {
+	let mut decryptor = CbcDecryptor::new(key);
+	let mut plain_text = decryptor.update(&cipher_text);
+	let rest = decryptor.finalize().expect("cipher text must be an IV plus whole blocks");
+	secret::append_zeroizing(&mut plain_text, rest);
+
+	plain_text
+}
+
+/// Like `cbc_decrypt`, but fails with `Error::Truncated` instead of panicking if the
+/// ciphertext is not an IV plus whole blocks, and with `Error::InvalidPadding` if the
+/// padding is malformed, which usually means the key is wrong.
+///
+/// Be careful who gets to see the difference: telling an attacker whether the padding of
+/// a ciphertext of their choosing is valid lets them decrypt it (a padding oracle).
+pub fn cbc_decrypt_checked(cipher_text: &[u8], key: &SecretKey) -> Result<Vec<u8>, Error> {
+	let len = cipher_text.len();
+	if len < 2 * BLOCK_SIZE || !len.is_multiple_of(BLOCK_SIZE) {
+		return Err(Error::Truncated);
+	}
+
+	let (prev_block, block) = cipher_text[len - 2 * BLOCK_SIZE..].split_at(BLOCK_SIZE);
+	let decrypted = aes_decrypt(block.try_into().unwrap(), key.expose_secret());
+	let last_block = Zeroizing::new(xor_blocks(decrypted, prev_block.try_into().unwrap()));
+	let pad_len = last_block[BLOCK_SIZE - 1] as usize;
+	let padding_ok = (1..=BLOCK_SIZE).contains(&pad_len)
+		&& last_block[BLOCK_SIZE - pad_len..].iter().all(|&b| b as usize == pad_len);
+	if !padding_ok {
+		return Err(Error::InvalidPadding);
+	}
+
+	Ok(cbc_decrypt(cipher_text.to_vec(), key))
+}
+
+/// XORs two blocks together
+fn xor_blocks(a: [u8; BLOCK_SIZE], b: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+	let mut result = [0u8; BLOCK_SIZE];
+	for i in 0..BLOCK_SIZE {
+		result[i] = a[i] ^ b[i];
+	}
+	result
+}
+
+/// Another mode which you can implement on your own is counter mode.
+/// This mode is secure as well, and is used in real world applications.
+/// It allows parallelized encryption and decryption, as well as random read access when decrypting.
+///
+/// In this mode, there is an index for each block being encrypted (the "counter"), as well as a random nonce.
+/// For a 128-bit cipher, the nonce is 64 bits long.
+///
+/// For the ith block, the 128-bit value V of `nonce | counter` is constructed, where | denotes
+/// concatenation. Then, V is encrypted with the key using ECB mode. Finally, the encrypted V is
+/// XOR'd with the plaintext to produce the ciphertext.
+///
+/// A very clear diagram is present here:
+/// https://en.wikipedia.org/wiki/Block_cipher_mode_of_operation#Counter_(CTR)
+///
+/// Once again, you will need to generate a random nonce which is 64 bits long. This should be
+/// inserted as the first block of the ciphertext.
+pub fn ctr_encrypt(plain_text: Vec<u8>, key: &SecretKey) -> Vec<u8> {
+	ctr_encrypt_with_rng(plain_text, key, &mut rand::thread_rng())
+}
+
+/// Like `ctr_encrypt`, but the nonce is drawn from `rng`.
+pub fn ctr_encrypt_with_rng(
+	plain_text: Vec<u8>,
+	key: &SecretKey,
+	rng: &mut impl CryptoRng,
+) -> Vec<u8> {
+	ctr_encrypt_with_nonce(plain_text, key, u64::from_be_bytes(rng.gen()))
+}
+
+/// Like `ctr_encrypt`, but the nonce is drawn from `nonces`.
+pub fn ctr_encrypt_with_nonces(
+	plain_text: Vec<u8>,
+	key: &SecretKey,
+	nonces: &mut impl NonceSource,
+) -> Result<Vec<u8>, Error> {
+	let mut plain_text = Zeroizing::new(plain_text);
+	let mut nonce = [0u8; incremental::CTR_NONCE_SIZE];
+	nonces.fill_nonce(&mut nonce)?;
+
+	Ok(ctr_encrypt_with_nonce(std::mem::take(&mut *plain_text), key, u64::from_be_bytes(nonce)))
+}
+
+fn ctr_encrypt_with_nonce(plain_text: Vec<u8>, key: &SecretKey, nonce: u64) -> Vec<u8> {
+	let plain_text = Zeroizing::new(plain_text);
+
+	// Like CBC, the block loop lives in `CtrEncryptor`.
+	let mut encryptor = CtrEncryptor::with_nonce(key, nonce);
+	let mut cipher_text = encryptor.update(&plain_text);
+	cipher_text.extend(encryptor.finalize());
+
+	cipher_text
+}
+
+/// Opposite of ctr_encrypt. To decrypt just part of the message, see the `seek` module.
+pub fn ctr_decrypt(cipher_text: Vec<u8>, key: &SecretKey) -> Vec<u8> {
+	let mut decryptor = CtrDecryptor::new(key);
+	let mut plain_text = decryptor.update(&cipher_text);
+	let rest = decryptor.finalize().expect("cipher text must be a nonce plus at least one block");
+	secret::append_zeroizing(&mut plain_text, rest);
+
+	plain_text
+}
+
+/// The keystream block for the ith block: V = `nonce | counter`, encrypted with the key.
+///
+/// Both halves are big-endian, so the same nonce gives the same keystream on every machine.
+fn ctr_keystream(nonce: u64, counter: u64, key: &SecretKey) -> [u8; BLOCK_SIZE] {
+	aes_encrypt(ctr_block(nonce, counter), key.expose_secret())
+}
+
+/// The block V = `nonce | counter` that `ctr_keystream` encrypts.
+fn ctr_block(nonce: u64, counter: u64) -> [u8; BLOCK_SIZE] {
+	let mut nonce_counter = [0u8; BLOCK_SIZE];
+	nonce_counter[..8].copy_from_slice(&nonce.to_be_bytes());
+	nonce_counter[8..].copy_from_slice(&counter.to_be_bytes());
+	nonce_counter
+}
+
+/// How many keystream blocks `apply_ctr_keystream` encrypts per call to
+/// `aes_encrypt_blocks`. They live on the stack, so this bounds the stack use.
+const KEYSTREAM_BATCH_BLOCKS: usize = 64;
+
+/// XORs `data` with the CTR keystream, starting at block `first_counter`. The last block may
+/// be partial.
+///
+/// The keystream blocks don't depend on each other, so they are encrypted in batches like
+/// the counter mode inside GCM.
+fn apply_ctr_keystream(data: &mut [u8], nonce: u64, first_counter: u64, key: &SecretKey) {
+	let mut keystream = [[0u8; BLOCK_SIZE]; KEYSTREAM_BATCH_BLOCKS];
+	for (batch, chunk) in (0u64..).zip(data.chunks_mut(KEYSTREAM_BATCH_BLOCKS * BLOCK_SIZE)) {
+		let blocks = chunk.len().div_ceil(BLOCK_SIZE);
+		let first_counter = first_counter + batch * KEYSTREAM_BATCH_BLOCKS as u64;
+		for (counter, block) in (first_counter..).zip(&mut keystream[..blocks]) {
+			*block = ctr_block(nonce, counter);
+		}
+		aes_encrypt_blocks(&mut keystream[..blocks], key.expose_secret());
+
+		for (x1, &x2) in chunk.iter_mut().zip(keystream.as_flattened()) {
+			*x1 ^= x2;
+		}
+	}
+	keystream.zeroize();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hex_block(text: &str) -> [u8; BLOCK_SIZE] {
+		let bytes: Vec<u8> = (0..text.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+			.collect();
+		bytes.try_into().unwrap()
+	}
+
+	#[test]
+	fn test_cbc_encrypt_decrypt() {
+		let key = SecretKey::new([0u8; BLOCK_SIZE]);
+		let plain_text_value = b"Hello PBA Team, This is a fun Activity!".to_vec();
+
+		let encrypted_value = cbc_encrypt(plain_text_value.clone(), &key);
+		let decrypted_value = cbc_decrypt(encrypted_value, &key);
+
+		assert_eq!(plain_text_value, decrypted_value);
+	}
+
+	#[test]
+	fn test_cbc_encrypt_decrypt_with_padding() {
+		let key = SecretKey::new([0u8; BLOCK_SIZE]);
+		let plain_text_value = b"16-byte-block-msg".to_vec();
+
+		let encrypted_value = cbc_encrypt(plain_text_value.clone(), &key);
+		let decrypted_value = cbc_decrypt(encrypted_value, &key);
+
+		assert_eq!(plain_text_value, decrypted_value);
+	}
+
+	#[test]
+	fn test_cbc_decrypt_checked() {
+		let key = SecretKey::new([0u8; BLOCK_SIZE]);
+		let cipher_text = cbc_encrypt(b"fifteen bytes!!".to_vec(), &key);
+		assert_eq!(cbc_decrypt_checked(&cipher_text, &key).unwrap(), b"fifteen bytes!!");
+
+		// The last plaintext byte is the padding 0x01. Flipping the IV turns it into 0x11.
+		let mut bad_padding = cipher_text.clone();
+		bad_padding[BLOCK_SIZE - 1] ^= 0x10;
+		assert!(matches!(cbc_decrypt_checked(&bad_padding, &key), Err(Error::InvalidPadding)));
+
+		assert!(matches!(cbc_decrypt_checked(&cipher_text[1..], &key), Err(Error::Truncated)));
+		assert!(matches!(cbc_decrypt_checked(&[0; BLOCK_SIZE], &key), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_cbc_encrypt_decrypt_empty_message() {
+		let key = SecretKey::new([0u8; BLOCK_SIZE]);
+		let plain_text_value = vec![];
+
+		let encrypted_value = cbc_encrypt(plain_text_value.clone(), &key);
+		let decrypted_value = cbc_decrypt(encrypted_value, &key);
+
+		assert_eq!(plain_text_value, decrypted_value);
+	}
+
+	#[test]
+	fn test_ecb_encrypt() {
+		// FIPS-197 appendix C.1. ECB pads, so the known answer is the first block.
+		let key = SecretKey::new(hex_block("000102030405060708090a0b0c0d0e0f"));
+		let plain_text = hex_block("00112233445566778899aabbccddeeff").to_vec();
+		let encrypted = ecb_encrypt(plain_text, &key);
+		assert_eq!(encrypted.len(), 2 * BLOCK_SIZE);
+		assert_eq!(encrypted[..BLOCK_SIZE], hex_block("69c4e0d86a7b0430d8cdb78070b4c55a"));
+		// A whole block of padding follows, encrypted on its own.
+		assert_eq!(encrypted[BLOCK_SIZE..], aes_encrypt([16; BLOCK_SIZE], key.expose_secret()));
+	}
+
+	#[test]
+	fn test_ecb_decrypt() {
+		let key = SecretKey::new([3; 16]);
+		for len in [0, 3, BLOCK_SIZE, 40] {
+			let plain_text: Vec<u8> = (0..len as u8).collect();
+			let cipher_text = ecb_encrypt(plain_text.clone(), &key);
+			assert_eq!(cipher_text.len(), in_place::padded_len(len));
+			assert_eq!(ecb_decrypt(cipher_text, &key), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_ctr_keystream_is_aes_of_big_endian_block() {
+		let key = SecretKey::new([7; 16]);
+		let mut block = [0u8; BLOCK_SIZE];
+		block[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+		block[15] = 9;
+
+		assert_eq!(
+			ctr_keystream(0x0102030405060708, 9, &key),
+			aes_encrypt(block, key.expose_secret())
+		);
+	}
+
+	#[test]
+	fn test_apply_ctr_keystream_matches_ctr_keystream() {
+		let key = SecretKey::new([7; 16]);
+		let len = 2 * KEYSTREAM_BATCH_BLOCKS * BLOCK_SIZE + 5;
+
+		let mut data = vec![0u8; len];
+		apply_ctr_keystream(&mut data, 42, 3, &key);
+
+		let expected: Vec<u8> =
+			(3..).flat_map(|counter| ctr_keystream(42, counter, &key)).take(len).collect();
+		assert_eq!(data, expected);
+	}
+
+	#[test]
+	fn test_aes_blocks_match_single_block_helpers() {
+		let key = [5u8; BLOCK_SIZE];
+		for count in 0..3 * BATCH_SIZE {
+			let blocks: Vec<[u8; BLOCK_SIZE]> = (0..count).map(|i| [i as u8; BLOCK_SIZE]).collect();
+
+			let mut encrypted = blocks.clone();
+			aes_encrypt_blocks(&mut encrypted, &key);
+			let expected: Vec<_> = blocks.iter().map(|&block| aes_encrypt(block, &key)).collect();
+			assert_eq!(encrypted, expected);
+
+			aes_decrypt_blocks(&mut encrypted, &key);
+			assert_eq!(encrypted, blocks);
+		}
+	}
+
+    #[test]
+    fn test_ctr() {
+        let key = SecretKey::new([2; BLOCK_SIZE]);
+        let plain_text = b"Hello, world!".to_vec();
+
+        let cipher_text = ctr_encrypt(plain_text.clone(), &key);
+
+        let decrypted_text = ctr_decrypt(cipher_text.clone(), &key);
+
+        assert_eq!(plain_text, decrypted_text);
+    }
+
+	#[test]
+    fn test_ctr_encrypt_decrypt() {
+        let key = SecretKey::new([0u8; BLOCK_SIZE]);
+        let plain_text_value = b"Hello PBA Team, This is another fun activity!".to_vec();
+
+        let encrypted_value = ctr_encrypt(plain_text_value.clone(), &key);
+        let decrypted_value = ctr_decrypt(encrypted_value, &key);
+
+        assert_eq!(plain_text_value, decrypted_value);
+    }
+
+    #[test]
+    fn test_ctr_encrypt_decrypt_with_padding() {
+        let key = SecretKey::new([0u8; BLOCK_SIZE]);
+        let plain_text_value = b"16-byte-block-msg".to_vec();
+
+        let encrypted_value = ctr_encrypt(plain_text_value.clone(), &key);
+        let decrypted_value = ctr_decrypt(encrypted_value, &key);
+
+        assert_eq!(plain_text_value, decrypted_value);
+    }
+
+}
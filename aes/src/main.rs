@@ -1,351 +1,448 @@
-//! In Module 1, we discussed Block ciphers like AES. Block ciphers have a fixed length input.
-//! Real wold data that we wish to encrypt _may_ be exactly the right length, but is probably not.
-//! When your data is too short, you can simply pad it up to the correct length.
-//! When your data is too long, you have some options.
+//! The `aes` command-line tool: encrypts and decrypts files with the modes in `aes_modes`.
 //!
-//! In this exercise, we will explore a few of the common ways that large pieces of data can be
-//! broken up and combined in order to encrypt it with a fixed-length block cipher.
-//!
-//! WARNING: ECB MODE IS NOT SECURE.
-//! Seriously, ECB is NOT secure. Don't use it irl. We are implementing it here to understand _why_
-//! it is not secure and make the point that the most straight-forward approach isn't always the
-//! best, and can sometimes be trivially broken.
-use rand::Rng;
-use aes::{
-	cipher::{generic_array::GenericArray, BlockCipher, BlockDecrypt, BlockEncrypt, KeyInit},
-	Aes128,
+//! Run `aes help` for the usage. Files encrypted under a key start with a small header,
+//! `"AESF" | version (1) | mode (1)`, so that `decrypt` and `inspect` know the mode. Files
+//! encrypted under a password are the output of `password::encrypt_with_password`, which
+//! has a header of its own.
+use std::{
+	env, fs,
+	io::{self, Read, Write},
+	path::PathBuf,
+	process::ExitCode,
 };
 
-///We're using AES 128 which has 16-byte (128 bit) blocks.
-const BLOCK_SIZE: usize = 16;
-
-fn main() {
-    let mut rng = rand::thread_rng();
-    let key: [u8; BLOCK_SIZE] = rng.gen();
-    let plain_text = b"Hello, world!".to_vec();
-
-   // ECB
-    let ecb_encrypted = ecb_encrypt(plain_text.clone(), key);
-    let ecb_decrypted = ecb_decrypt(ecb_encrypted, key);
-    println!("ECB decrypted: {:?}", String::from_utf8(ecb_decrypted));
-
-    // CBC
-    let cbc_encrypted = cbc_encrypt(plain_text.clone(), key);
-    let cbc_decrypted = cbc_decrypt(cbc_encrypted, key);
-    println!("CBC decrypted: {:?}", String::from_utf8(cbc_decrypted));
-
-    // CTR
-    let ctr_encrypted = ctr_encrypt(plain_text.clone(), key);
-    let ctr_decrypted = ctr_decrypt(ctr_encrypted, key);
-    println!("CTR decrypted: {:?}", String::from_utf8(ctr_decrypted));
+use aes_modes::{
+	cbc_decrypt_checked, cbc_encrypt, ctr_encrypt,
+	encoding::{armor, hex_decode, hex_encode, Encoding, ARMOR_LABEL},
+	envelope::envelope_wrapped_key,
+	gcm::{self, gcm_decrypt, gcm_encrypt},
+	in_place::ctr_decrypt_in_place,
+	keystore::Keystore,
+	password::{decrypt_with_password, encrypt_with_password, password_kdf, Kdf},
+	Error, SecretKey, BLOCK_SIZE,
+};
+use rand::Rng;
+use zeroize::Zeroizing;
+
+const USAGE: &str = "\
+Usage:
+    aes encrypt [--mode MODE] KEY [--kdf KDF] [--encoding ENCODING] [-i INPUT] [-o OUTPUT]
+    aes decrypt [--mode MODE] KEY [--encoding ENCODING] [-i INPUT] [-o OUTPUT]
+    aes keygen [-o OUTPUT]
+    aes keygen --keystore FILE --key-name NAME --password PASSWORD [--kdf KDF]
+    aes inspect [--encoding ENCODING] [-i INPUT] [-o OUTPUT]
+
+KEY is one of:
+    --key HEX                   a 16-byte key as 32 hex digits
+    --key-file FILE             a file holding the key as raw bytes or hex
+    --password PASSWORD         derive the key from a password (GCM only)
+    --keystore FILE --key-name NAME --password PASSWORD
+                                a key from a keystore made by `aes keygen`
+
+MODE is gcm (the default), cbc or ctr. Only gcm detects a wrong key or a modified file.
+KDF is pbkdf2, scrypt or argon2id (the default), optionally followed by its parameters:
+pbkdf2:ITERATIONS, scrypt:LOG_N,R,P or argon2id:MEMORY_KIB,ITERATIONS,PARALLELISM.
+ENCODING is how the ciphertext is written: binary (the default), hex, base64, base64url
+or armor, a PEM-style block with a checksum. `--armor` is short for `--encoding armor`.
+INPUT and OUTPUT default to stdin and stdout, as does `-`.
+
+Exit codes:
+    0   success
+    1   an I/O error or malformed input
+    2   invalid arguments
+    3   decryption failed: wrong key, modified or truncated ciphertext, or bad padding
+";
+
+const MAGIC: &[u8; 4] = b"AESF";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+	Gcm,
+	Cbc,
+	Ctr,
 }
 
-/// Simple AES encryption
-/// Helper function to make the core AES block cipher easier to understand.
-fn aes_encrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
-	// Convert the inputs to the necessary data type
-	let mut block = GenericArray::from(data);
-	let key = GenericArray::from(*key);
-
-	let cipher = Aes128::new(&key);
+impl Mode {
+	const ALL: [Mode; 3] = [Mode::Gcm, Mode::Cbc, Mode::Ctr];
 
-	cipher.encrypt_block(&mut block);
+	fn id(self) -> u8 {
+		match self {
+			Mode::Gcm => 1,
+			Mode::Cbc => 2,
+			Mode::Ctr => 3,
+		}
+	}
 
-	block.into()
+	fn name(self) -> &'static str {
+		match self {
+			Mode::Gcm => "gcm",
+			Mode::Cbc => "cbc",
+			Mode::Ctr => "ctr",
+		}
+	}
 }
 
-/// Simple AES encryption
-/// Helper function to make the core AES block cipher easier to understand.
-fn aes_decrypt(data: [u8; BLOCK_SIZE], key: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
-	// Convert the inputs to the necessary data type
-	let mut block = GenericArray::from(data);
-	let key = GenericArray::from(*key);
-
-	let cipher = Aes128::new(&key);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+	Encrypt,
+	Decrypt,
+	Keygen,
+	Inspect,
+	Help,
+}
 
-	cipher.decrypt_block(&mut block);
+#[derive(Debug, Default)]
+struct Options {
+	mode: Option<Mode>,
+	key: Option<Zeroizing<String>>,
+	key_file: Option<PathBuf>,
+	password: Option<Zeroizing<String>>,
+	keystore: Option<PathBuf>,
+	key_name: Option<String>,
+	kdf: Option<Kdf>,
+	encoding: Option<Encoding>,
+	input: Option<PathBuf>,
+	output: Option<PathBuf>,
+}
 
-	block.into()
+/// Why the tool gave up, which decides the exit code.
+#[derive(Debug)]
+enum Failure {
+	Usage(String),
+	Error(Error),
 }
 
-/// Before we can begin encrypting our raw data, we need it to be a multiple of the
-/// block length which is 16 bytes (128 bits) in AES128.
-///
-/// The padding algorithm here is actually not trivial. The trouble is that if we just
-/// naively throw a bunch of zeros on the end, there is no way to know, later, whether
-/// those zeros are padding, or part of the message, or some of each.
-///
-/// The scheme works like this. If the data is not a multiple of the block length,  we
-/// compute how many pad bytes we need, and then write that number into the last several bytes.
-/// Later we look at the last byte, and remove that number of bytes.
-///
-/// But if the data _is_ a multiple of the block length, then we have a problem. We don't want
-/// to later look at the last byte and remove part of the data. Instead, in this case, we add
-/// another entire block containing the block length in each byte. In our case,
-/// [16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16, 16]
-fn pad(mut data: Vec<u8>) -> Vec<u8> {
-	// When twe have a multiple the second term is 0
-	let number_pad_bytes = BLOCK_SIZE - data.len() % BLOCK_SIZE;
-
-	for _ in 0..number_pad_bytes {
-		data.push(number_pad_bytes as u8);
+impl From<Error> for Failure {
+	fn from(e: Error) -> Self {
+		Failure::Error(e)
 	}
-
-	data
 }
 
-/// Groups the data into BLOCK_SIZE blocks. Assumes the data is already
-/// a multiple of the block size. If this is not the case, call `pad` first.
-fn group(data: Vec<u8>) -> Vec<[u8; BLOCK_SIZE]> {
-	let mut blocks = Vec::new();
-	let mut i = 0;
-	while i < data.len() {
-		let mut block: [u8; BLOCK_SIZE] = Default::default();
-		block.copy_from_slice(&data[i..i + BLOCK_SIZE]);
-		blocks.push(block);
-
-		i += BLOCK_SIZE;
+impl From<io::Error> for Failure {
+	fn from(e: io::Error) -> Self {
+		Failure::Error(e.into())
 	}
-
-	blocks
 }
 
-/// Does the opposite of the group function
-fn un_group(blocks: Vec<[u8; BLOCK_SIZE]>) -> Vec<u8> {
-
-	blocks.into_iter().flat_map(|block| block.to_vec()).collect()
+impl Failure {
+	fn exit_code(&self) -> u8 {
+		match self {
+			Failure::Usage(_) => 2,
+			Failure::Error(Error::Authentication | Error::InvalidPadding | Error::Truncated) => 3,
+			Failure::Error(_) => 1,
+		}
+	}
 }
 
-/// Does the opposite of the pad function.
-fn un_pad(data: Vec<u8>) -> Vec<u8> {
-
-	let pad_byte = *data.last().unwrap();
-	let pad_len = pad_byte as usize;
-	let data_len = data.len();
+fn usage(message: impl Into<String>) -> Failure {
+	Failure::Usage(message.into())
+}
 
-	if pad_len <= BLOCK_SIZE && data_len >= pad_len {
-		data[0..(data_len - pad_len)].to_vec()
-	} else {
-		data
+fn main() -> ExitCode {
+	match parse_args(env::args().skip(1)).and_then(|(command, options)| run(command, options)) {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(failure) => {
+			match &failure {
+				Failure::Usage(message) => eprintln!("aes: {}\n\n{}", message, USAGE),
+				Failure::Error(e) => eprintln!("aes: {}", e),
+			}
+			ExitCode::from(failure.exit_code())
+		},
 	}
 }
 
-/// The first mode we will implement is the Electronic Code Book, or ECB mode.
-/// Warning: THIS MODE IS NOT SECURE!!!!
-///
-/// This is probably the first thing you think of when considering how to encrypt
-/// large data. In this mode we simply encrypt each block of data under the same key.
-/// One good thing about this mode is that it is parallelizable. But to see why it is
-/// insecure look at: https://www.ubiqsecurity.com/wp-content/uploads/2022/02/ECB2.png
-fn ecb_encrypt(plain_text: Vec<u8>, key: [u8; 16]) -> Vec<u8> {
-    plain_text.iter().map(|&b| b ^ key[0]).collect()
-}
-/// Opposite of ecb_encrypt.
-fn ecb_decrypt(cipher_text: Vec<u8>, key: [u8; 16]) -> Vec<u8> {
-    cipher_text.iter().map(|&b| b ^ key[0]).collect()
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(Command, Options), Failure> {
+	let command = match args.next().as_deref() {
+		Some("encrypt") => Command::Encrypt,
+		Some("decrypt") => Command::Decrypt,
+		Some("keygen") => Command::Keygen,
+		Some("inspect") => Command::Inspect,
+		Some("help" | "-h" | "--help") => Command::Help,
+		Some(other) => return Err(usage(format!("unknown command `{}`", other))),
+		None => return Err(usage("no command given")),
+	};
+
+	let mut options = Options::default();
+	while let Some(flag) = args.next() {
+		let mut value = || args.next().ok_or_else(|| usage(format!("{} needs a value", flag)));
+		match flag.as_str() {
+			"--mode" => options.mode = Some(parse_mode(&value()?)?),
+			"--key" => options.key = Some(Zeroizing::new(value()?)),
+			"--key-file" => options.key_file = Some(value()?.into()),
+			"--password" => options.password = Some(Zeroizing::new(value()?)),
+			"--keystore" => options.keystore = Some(value()?.into()),
+			"--key-name" => options.key_name = Some(value()?),
+			"--kdf" => options.kdf = Some(parse_kdf(&value()?)?),
+			"--encoding" => options.encoding = Some(parse_encoding(&value()?)?),
+			"--armor" => options.encoding = Some(Encoding::Armor),
+			"-i" | "--input" => options.input = Some(value()?.into()),
+			"-o" | "--output" => options.output = Some(value()?.into()),
+			"-h" | "--help" => return Ok((Command::Help, options)),
+			_ => return Err(usage(format!("unknown option `{}`", flag))),
+		}
+	}
+	Ok((command, options))
 }
 
-/// The next mode, which you can implement on your own is cipherblock chaining.
-/// This mode actually is secure, and it often used in real world applications.
-///
-/// In this mode, the ciphertext from the first block is XORed with the
-/// plaintext of the next block before it is encrypted.
-///
-/// For more information, and a very clear diagram,
-/// see https://de.wikipedia.org/wiki/Cipher_Block_Chaining_Mode
-///
-/// You will need to generate a random initialization vector (IV) to encrypt the
-/// very first block because it doesn't have a previous block. Typically this IV
-/// is inserted as the first block of ciphertext.
-fn cbc_encrypt(plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
-	// Remember to generate a random initialization vector for the first block.
-
-	let mut random_generator = rand::thread_rng();
-	let initialization_vector: [u8; BLOCK_SIZE] = random_generator.gen();
-	let mut prev_block = initialization_vector;
-
-	let mut cipher_blocks = vec![initialization_vector];
-	let padded_text = pad(plain_text);
-	group(padded_text)
+fn parse_mode(name: &str) -> Result<Mode, Failure> {
+	Mode::ALL
 		.into_iter()
-		.for_each(|block| {
-			let xored_block = xor_blocks(block, prev_block);
-			let encrypted_block = aes_encrypt(xored_block, &key);
-			cipher_blocks.push(encrypted_block);
-			prev_block = encrypted_block;
-		});
-
-	un_group(cipher_blocks)
+		.find(|mode| mode.name() == name)
+		.ok_or_else(|| usage(format!("unknown mode `{}`", name)))
 }
 
-fn cbc_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
-
-	let blocks = group(cipher_text);
-	let iv = blocks[0];
-	let mut prev_block = iv;
-
-	let mut decrypted_blocks = Vec::new();
-
-	for block in &blocks[1..] {
-		let decrypted_block = aes_decrypt(*block, &key);
-		let xored_block = xor_blocks(decrypted_block, prev_block);
-		decrypted_blocks.push(xored_block);
-		prev_block = *block;
+fn parse_encoding(name: &str) -> Result<Encoding, Failure> {
+	match name {
+		"binary" => Ok(Encoding::Binary),
+		"hex" => Ok(Encoding::Hex),
+		"base64" => Ok(Encoding::Base64),
+		"base64url" => Ok(Encoding::Base64Url),
+		"armor" => Ok(Encoding::Armor),
+		_ => Err(usage(format!("unknown encoding `{}`", name))),
 	}
+}
 
-	let decrypted_data = un_group(decrypted_blocks);
-	un_pad(decrypted_data)
+/// Parses `name[:param,param,...]`, with the recommended parameters if there are none.
+fn parse_kdf(spec: &str) -> Result<Kdf, Failure> {
+	let (name, params) = match spec.split_once(':') {
+		Some((name, params)) => (name, Some(params)),
+		None => (spec, None),
+	};
+	let params = params
+		.map(|params| params.split(',').map(str::parse).collect::<Result<Vec<u32>, _>>())
+		.transpose()
+		.map_err(|_| usage(format!("invalid KDF parameters in `{}`", spec)))?;
+
+	let kdf = match (name, params.as_deref()) {
+		("pbkdf2", None) => Kdf::pbkdf2(),
+		("pbkdf2", Some(&[iterations])) => Kdf::Pbkdf2 { iterations },
+		("scrypt", None) => Kdf::scrypt(),
+		("scrypt", Some(&[log_n, r, p])) if log_n <= u8::MAX as u32 => {
+			Kdf::Scrypt { log_n: log_n as u8, r, p }
+		},
+		("argon2id", None) => Kdf::argon2id(),
+		("argon2id", Some(&[memory_kib, iterations, parallelism])) => {
+			Kdf::Argon2id { memory_kib, iterations, parallelism }
+		},
+		_ => return Err(usage(format!("invalid KDF `{}`", spec))),
+	};
+	Ok(kdf)
 }
 
-/// XORs two blocks together
-fn xor_blocks(a: [u8; BLOCK_SIZE], b: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
-	let mut result = [0u8; BLOCK_SIZE];
-	for i in 0..BLOCK_SIZE {
-		result[i] = a[i] ^ b[i];
+fn run(command: Command, options: Options) -> Result<(), Failure> {
+	match command {
+		Command::Encrypt => encrypt(&options),
+		Command::Decrypt => decrypt(&options),
+		Command::Keygen => keygen(&options),
+		Command::Inspect => inspect(&options),
+		Command::Help => {
+			print!("{}", USAGE);
+			Ok(())
+		},
 	}
-	result
 }
 
-/// Another mode which you can implement on your own is counter mode.
-/// This mode is secure as well, and is used in real world applications.
-/// It allows parallelized encryption and decryption, as well as random read access when decrypting.
-///
-/// In this mode, there is an index for each block being encrypted (the "counter"), as well as a random nonce.
-/// For a 128-bit cipher, the nonce is 64 bits long.
-///
-/// For the ith block, the 128-bit value V of `nonce | counter` is constructed, where | denotes
-/// concatenation. Then, V is encrypted with the key using ECB mode. Finally, the encrypted V is
-/// XOR'd with the plaintext to produce the ciphertext.
-///
-/// A very clear diagram is present here:
-/// https://en.wikipedia.org/wiki/Block_cipher_mode_of_operation#Counter_(CTR)
-///
-/// Once again, you will need to generate a random nonce which is 64 bits long. This should be
-/// inserted as the first block of the ciphertext.
-fn ctr_encrypt(plain_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
-    let mut rng = rand::thread_rng();
-    let nonce: u64 = rng.gen();
-    let mut counter: u64 = 0;
-    let mut cipher_text = vec![];
-	let padded_text = pad(plain_text);
-    cipher_text.extend(&nonce.to_ne_bytes());
-
-    for block in padded_text.chunks(BLOCK_SIZE) {
-        let mut nonce_counter = [0u8; BLOCK_SIZE];
-        nonce_counter[..8].copy_from_slice(&nonce.to_ne_bytes());
-        nonce_counter[8..].copy_from_slice(&counter.to_ne_bytes());
-        let encrypted_v = ecb_encrypt(nonce_counter.to_vec(), key);
-        let cipher_block: Vec<u8> = block.iter().zip(encrypted_v.iter()).map(|(&x1, &x2)| x1 ^ x2).collect();
-        cipher_text.extend(cipher_block);
-        counter += 1;
-    }
-
-    cipher_text
+/// Where the key comes from.
+enum KeySource {
+	Key(SecretKey),
+	Password(Zeroizing<String>),
 }
 
-fn ctr_decrypt(cipher_text: Vec<u8>, key: [u8; BLOCK_SIZE]) -> Vec<u8> {
-    let nonce = u64::from_ne_bytes(cipher_text[..8].try_into().unwrap());
-    let mut counter: u64 = 0;
-    let mut plain_text = vec![];
-
-    for block in cipher_text[8..].chunks(BLOCK_SIZE) {
-        let mut nonce_counter = [0u8; BLOCK_SIZE];
-        nonce_counter[..8].copy_from_slice(&nonce.to_ne_bytes());
-        nonce_counter[8..].copy_from_slice(&counter.to_ne_bytes());
-        let encrypted_v = ecb_encrypt(nonce_counter.to_vec(), key);
-        let plain_block: Vec<u8> = block.iter().zip(encrypted_v.iter()).map(|(&x1, &x2)| x1 ^ x2).collect();
-        plain_text.extend(plain_block);
-        counter += 1;
-    }
-
-    un_pad(plain_text)
+fn key_source(options: &Options) -> Result<KeySource, Failure> {
+	match (&options.key, &options.key_file, &options.keystore, &options.password) {
+		(Some(key), None, None, None) => Ok(KeySource::Key(parse_key(key.as_bytes())?)),
+		(None, Some(path), None, None) => {
+			let contents = Zeroizing::new(fs::read(path)?);
+			Ok(KeySource::Key(parse_key(&contents)?))
+		},
+		(None, None, Some(path), Some(password)) => {
+			let name =
+				options.key_name.as_deref().ok_or_else(|| usage("--keystore needs --key-name"))?;
+			let keystore = Keystore::open(path, password.as_bytes())?;
+			Ok(KeySource::Key(keystore.export(name)?))
+		},
+		(None, None, Some(_), None) => Err(usage("--keystore needs --password")),
+		(None, None, None, Some(password)) => Ok(KeySource::Password(password.clone())),
+		(None, None, None, None) => Err(usage("no key given")),
+		_ => Err(usage("more than one key given")),
+	}
 }
 
-#[cfg(test)]
-mod tests {
-	use super::*;
-
-	#[test]
-	fn test_cbc_encrypt_decrypt() {
-		let key = [0u8; BLOCK_SIZE];
-		let plain_text_value = b"Hello PBA Team, This is a fun Activity!".to_vec();
-
-		let encrypted_value = cbc_encrypt(plain_text_value.clone(), key);
-		let decrypted_value = cbc_decrypt(encrypted_value, key);
-
-		assert_eq!(plain_text_value, decrypted_value);
+/// Accepts a key as 16 raw bytes, or as 32 hex digits with optional surrounding whitespace.
+fn parse_key(data: &[u8]) -> Result<SecretKey, Error> {
+	if data.len() == BLOCK_SIZE {
+		return SecretKey::from_slice(data);
 	}
+	let text = std::str::from_utf8(data).map_err(|_| Error::InvalidKeyLength)?;
+	let bytes = Zeroizing::new(hex_decode(text).map_err(|_| Error::InvalidKeyLength)?);
+	SecretKey::from_slice(&bytes)
+}
 
-	#[test]
-	fn test_cbc_encrypt_decrypt_with_padding() {
-		let key = [0u8; BLOCK_SIZE];
-		let plain_text_value = b"16-byte-block-msg".to_vec();
+fn encrypt(options: &Options) -> Result<(), Failure> {
+	let mode = options.mode.unwrap_or(Mode::Gcm);
+	let key = key_source(options)?;
+	if options.kdf.is_some() && !matches!(key, KeySource::Password(_)) {
+		return Err(usage("--kdf only applies to --password"));
+	}
+	let plain_text = Zeroizing::new(read_input(options)?);
+
+	let (format, output) = match key {
+		KeySource::Password(password) => {
+			if mode != Mode::Gcm {
+				return Err(usage("--password only supports --mode gcm"));
+			}
+			let kdf = options.kdf.unwrap_or_default();
+			("password", encrypt_with_password(&plain_text, password.as_bytes(), &kdf)?)
+		},
+		KeySource::Key(key) => {
+			let header = header(mode);
+			let body = match mode {
+				Mode::Gcm => {
+					let nonce: [u8; gcm::NONCE_SIZE] = rand::thread_rng().gen();
+					let mut body = nonce.to_vec();
+					body.extend(gcm_encrypt(&plain_text, &header, &nonce, &key)?);
+					body
+				},
+				Mode::Cbc => cbc_encrypt(plain_text.to_vec(), &key),
+				Mode::Ctr => ctr_encrypt(plain_text.to_vec(), &key),
+			};
+			("key", [header.as_slice(), &body].concat())
+		},
+	};
+
+	let output = match options.encoding.unwrap_or_default() {
+		// The headers are only for people reading the file; decrypt goes by the binary ones.
+		Encoding::Armor => {
+			armor(ARMOR_LABEL, &[("Format", format), ("Mode", mode.name())], &output).into_bytes()
+		},
+		encoding => encoding.encode(&output),
+	};
+	write_output(options, &output)
+}
 
-		let encrypted_value = cbc_encrypt(plain_text_value.clone(), key);
-		let decrypted_value = cbc_decrypt(encrypted_value, key);
+fn decrypt(options: &Options) -> Result<(), Failure> {
+	let key = key_source(options)?;
+	let input = options.encoding.unwrap_or_default().decode(&read_input(options)?)?;
+
+	let plain_text = Zeroizing::new(match key {
+		KeySource::Password(password) => {
+			if options.mode.is_some_and(|mode| mode != Mode::Gcm) {
+				return Err(usage("--password only supports --mode gcm"));
+			}
+			decrypt_with_password(&input, password.as_bytes())?
+		},
+		KeySource::Key(key) => {
+			let mode = read_header(&input)?;
+			if options.mode.is_some_and(|expected| expected != mode) {
+				let message = format!("input was encrypted with --mode {}", mode.name());
+				return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+			}
+			let (header, body) = input.split_at(HEADER_SIZE);
+			match mode {
+				Mode::Gcm => {
+					if body.len() < gcm::NONCE_SIZE {
+						return Err(Error::Truncated.into());
+					}
+					let (nonce, body) = body.split_at(gcm::NONCE_SIZE);
+					gcm_decrypt(body, header, nonce.try_into().unwrap(), &key)?
+				},
+				Mode::Cbc => cbc_decrypt_checked(body, &key)?,
+				Mode::Ctr => {
+					let mut buffer = body.to_vec();
+					let len = ctr_decrypt_in_place(&mut buffer, &key)?;
+					buffer.truncate(len);
+					buffer
+				},
+			}
+		},
+	});
+	write_output(options, &plain_text)
+}
 
-		assert_eq!(plain_text_value, decrypted_value);
+fn keygen(options: &Options) -> Result<(), Failure> {
+	if options.encoding.is_some() {
+		return Err(usage("--encoding does not apply to keygen, keys are always hex"));
+	}
+	let key = SecretKey::random();
+	match (&options.keystore, &options.key_name, &options.password) {
+		(None, None, None) => {
+			if options.kdf.is_some() {
+				return Err(usage("--kdf only applies to --keystore"));
+			}
+			write_output(options, &Zeroizing::new(Encoding::Hex.encode(key.expose_secret())))
+		},
+		(Some(path), Some(name), Some(password)) => {
+			if options.output.is_some() {
+				return Err(usage("-o does not apply to --keystore"));
+			}
+			let mut keystore = if path.exists() {
+				if options.kdf.is_some() {
+					return Err(usage("--kdf only applies to a new keystore"));
+				}
+				Keystore::open(path, password.as_bytes())?
+			} else {
+				Keystore::create(path, password.as_bytes(), options.kdf.unwrap_or_default())?
+			};
+			keystore.add(name, key)?;
+			Ok(())
+		},
+		_ => Err(usage("--keystore needs --key-name and --password")),
 	}
+}
 
-	#[test]
-	fn test_cbc_encrypt_decrypt_empty_message() {
-		let key = [0u8; BLOCK_SIZE];
-		let plain_text_value = vec![];
+fn inspect(options: &Options) -> Result<(), Failure> {
+	let input = options.encoding.unwrap_or_default().decode(&read_input(options)?)?;
+	let description = if let Ok(mode) = read_header(&input) {
+		format!("format: key\nmode: {}\nlength: {}\n", mode.name(), input.len())
+	} else if let Ok(kdf) = password_kdf(&input) {
+		format!("format: password\nmode: gcm\nkdf: {:?}\nlength: {}\n", kdf, input.len())
+	} else if let Ok(wrapped) = envelope_wrapped_key(&input) {
+		let key_id = hex_encode(&wrapped.key_id);
+		format!("format: envelope\nmode: gcm\nkey id: {}\nlength: {}\n", key_id, input.len())
+	} else {
+		return Err(Error::InvalidHeader.into());
+	};
+	write_output(options, description.as_bytes())
+}
 
-		let encrypted_value = cbc_encrypt(plain_text_value.clone(), key);
-		let decrypted_value = cbc_decrypt(encrypted_value, key);
+fn header(mode: Mode) -> Vec<u8> {
+	let mut header = MAGIC.to_vec();
+	header.extend_from_slice(&[VERSION, mode.id()]);
+	header
+}
 
-		assert_eq!(plain_text_value, decrypted_value);
+fn read_header(input: &[u8]) -> Result<Mode, Error> {
+	let rest = input.strip_prefix(MAGIC).ok_or(Error::InvalidHeader)?;
+	match rest {
+		[VERSION, id, ..] => {
+			Mode::ALL.into_iter().find(|mode| mode.id() == *id).ok_or(Error::InvalidHeader)
+		},
+		[VERSION] => Err(Error::Truncated),
+		_ => Err(Error::InvalidHeader),
 	}
+}
 
-	#[test]
-    fn test_ecb_encrypt() {
-        let plain_text: Vec<u8> = vec![1, 2, 3];
-        let key: [u8; 16] = [3; 16];
-        let encrypted = ecb_encrypt(plain_text, key);
-        assert_eq!(encrypted, vec![2, 1, 0]);
+fn read_input(options: &Options) -> io::Result<Vec<u8>> {
+	match options.input.as_deref().filter(|path| path.as_os_str() != "-") {
+		Some(path) => fs::read(path),
+		None => {
+			let mut input = Vec::new();
+			io::stdin().read_to_end(&mut input)?;
+			Ok(input)
+		},
 	}
+}
 
-    #[test]
-    fn test_ecb_decrypt() {
-        let cipher_text: Vec<u8> = vec![2, 1, 0];
-        let key: [u8; 16] = [3; 16];
-        let decrypted = ecb_decrypt(cipher_text, key);
-        assert_eq!(decrypted, vec![1, 2, 3]);
+fn write_output(options: &Options, data: &[u8]) -> Result<(), Failure> {
+	match options.output.as_deref().filter(|path| path.as_os_str() != "-") {
+		Some(path) => fs::write(path, data)?,
+		None => {
+			let mut stdout = io::stdout().lock();
+			stdout.write_all(data)?;
+			stdout.flush()?;
+		},
 	}
-
-    #[test]
-    fn test_ctr() {
-        let key: [u8; BLOCK_SIZE] = [2; BLOCK_SIZE];
-        let plain_text = b"Hello, world!".to_vec();
-
-        let cipher_text = ctr_encrypt(plain_text.clone(), key);
-
-        let decrypted_text = ctr_decrypt(cipher_text.clone(), key);
-
-        assert_eq!(plain_text, decrypted_text);
-    }
-
-	#[test]
-    fn test_ctr_encrypt_decrypt() {
-        let key = [0u8; BLOCK_SIZE];
-        let plain_text_value = b"Hello PBA Team, This is another fun activity!".to_vec();
-
-        let encrypted_value = ctr_encrypt(plain_text_value.clone(), key);
-        let decrypted_value = ctr_decrypt(encrypted_value, key);
-
-        assert_eq!(plain_text_value, decrypted_value);
-    }
-
-    #[test]
-    fn test_ctr_encrypt_decrypt_with_padding() {
-        let key = [0u8; BLOCK_SIZE];
-        let plain_text_value = b"16-byte-block-msg".to_vec();
-
-        let encrypted_value = ctr_encrypt(plain_text_value.clone(), key);
-        let decrypted_value = ctr_decrypt(encrypted_value, key);
-
-        assert_eq!(plain_text_value, decrypted_value);
-    }
-
+	Ok(())
 }
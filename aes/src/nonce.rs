@@ -0,0 +1,470 @@
+//! Where IVs and nonces come from, and catching the ones that come twice.
+//!
+//! `cbc_encrypt` and `ctr_encrypt` draw their IV or nonce from the thread-local RNG, which
+//! is the right default but leaves the caller no say in it. The `_with_rng` variants take
+//! any RNG, and the `_with_nonces` variants take a `NonceSource`:
+//!
+//! - `RandomNonces`, the thread-local RNG, same as the defaults, or `RngNonces` for any
+//!   other RNG.
+//! - `CounterNonces`, which counts up from a starting value. Counters never repeat as long
+//!   as the state is never lost, and make tests deterministic.
+//! - `PersistentCounterNonces`, a counter kept in a file so that it survives restarts.
+//!
+//! Reusing a nonce under the same key is catastrophic for CTR and GCM: the XOR of the two
+//! ciphertexts is the XOR of the two plaintexts. Bugs that cause it are usually not in the
+//! source itself but around it: a counter file restored from a backup, a VM snapshot that
+//! is resumed twice, two processes sharing a key. A `NonceRegistry` remembers the nonces it
+//! has seen for each key, and wrapping a source in `RegisteredNonces` makes it fail with
+//! `Error::NonceReuse` rather than hand out a nonce a second time.
+use std::{
+	collections::{HashMap, HashSet},
+	fs,
+	io::{self, Write},
+	path::{Path, PathBuf},
+};
+
+use rand::{CryptoRng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::{Error, SecretKey};
+
+/// How far ahead `PersistentCounterNonces` reserves counter values in its file.
+pub const COUNTER_RESERVATION: u64 = 1024;
+
+/// Produces the IVs or nonces for a mode.
+pub trait NonceSource {
+	/// Fills `nonce` with the next nonce. Fails if the source has run out, or if it could
+	/// not record that the nonce has been handed out.
+	fn fill_nonce(&mut self, nonce: &mut [u8]) -> Result<(), Error>;
+}
+
+/// Random nonces from the thread-local RNG.
+///
+/// Random 64-bit CTR nonces are expected to collide after about 2^32 messages under the
+/// same key, and random 96-bit GCM nonces after about 2^48.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomNonces;
+
+impl NonceSource for RandomNonces {
+	fn fill_nonce(&mut self, nonce: &mut [u8]) -> Result<(), Error> {
+		rand::thread_rng().fill(nonce);
+		Ok(())
+	}
+}
+
+/// Random nonces from the given RNG, e.g. a seeded one for reproducible tests.
+#[derive(Debug, Clone)]
+pub struct RngNonces<R> {
+	rng: R,
+}
+
+impl<R: CryptoRng> RngNonces<R> {
+	pub fn new(rng: R) -> Self {
+		Self { rng }
+	}
+}
+
+impl<R: CryptoRng> NonceSource for RngNonces<R> {
+	fn fill_nonce(&mut self, nonce: &mut [u8]) -> Result<(), Error> {
+		self.rng.fill(nonce);
+		Ok(())
+	}
+}
+
+/// Nonces that count up: each one is the counter as a big-endian integer, padded on the
+/// left with zeros.
+///
+/// Counters are predictable, which is fine for CTR and GCM. `cbc_encrypt_with_nonces`
+/// encrypts them before using them as IVs, but a counter must never be used as a raw CBC
+/// IV, e.g. with `CbcEncryptor::with_iv`.
+#[derive(Debug, Clone)]
+pub struct CounterNonces {
+	next: Option<u64>,
+}
+
+impl CounterNonces {
+	/// Starts counting at `first`.
+	pub fn new(first: u64) -> Self {
+		Self { next: Some(first) }
+	}
+
+	/// The counter value the next nonce will have, or `None` once the counter has run out.
+	pub fn peek(&self) -> Option<u64> {
+		self.next
+	}
+}
+
+impl NonceSource for CounterNonces {
+	fn fill_nonce(&mut self, nonce: &mut [u8]) -> Result<(), Error> {
+		let counter = self.next.ok_or(Error::NonceExhausted)?;
+		write_counter(counter, nonce)?;
+		self.next = counter.checked_add(1);
+		Ok(())
+	}
+}
+
+/// A `CounterNonces` whose state lives in a file.
+///
+/// Writing the file for every nonce would be slow, so values are reserved in blocks of
+/// `COUNTER_RESERVATION`: the file always holds the first value that has not been
+/// reserved yet. If the process dies, the unused rest of its block is skipped, but no
+/// value is ever handed out twice. The file is replaced atomically, by writing a temporary
+/// file next to it and renaming it over the old one.
+///
+/// This only works if nothing else writes the file at the same time and it is never
+/// rolled back, e.g. by restoring a backup. A `NonceRegistry` can catch the latter within
+/// a process.
+#[derive(Debug)]
+pub struct PersistentCounterNonces {
+	path: PathBuf,
+	counter: CounterNonces,
+	reserved_until: u64,
+}
+
+impl PersistentCounterNonces {
+	/// Opens the counter file at `path`, creating it at 0 if it doesn't exist, and reserves
+	/// the first block of values.
+	pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+		let path = path.as_ref().to_path_buf();
+		let first = match fs::read_to_string(&path) {
+			Ok(contents) => contents.trim().parse().map_err(|_| {
+				io::Error::new(io::ErrorKind::InvalidData, "counter file is not a number")
+			})?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+			Err(e) => return Err(e.into()),
+		};
+
+		let mut nonces = Self { path, counter: CounterNonces::new(first), reserved_until: first };
+		nonces.reserve()?;
+		Ok(nonces)
+	}
+
+	/// Moves the value stored in the file one block further along.
+	fn reserve(&mut self) -> Result<(), Error> {
+		let until = self.reserved_until.saturating_add(COUNTER_RESERVATION);
+
+		let mut temp_path = self.path.clone().into_os_string();
+		temp_path.push(".tmp");
+		let mut temp = fs::File::create(&temp_path)?;
+		writeln!(temp, "{}", until)?;
+		temp.sync_all()?;
+		fs::rename(&temp_path, &self.path)?;
+
+		self.reserved_until = until;
+		Ok(())
+	}
+}
+
+impl NonceSource for PersistentCounterNonces {
+	fn fill_nonce(&mut self, nonce: &mut [u8]) -> Result<(), Error> {
+		let next = self.counter.peek().ok_or(Error::NonceExhausted)?;
+		if next >= self.reserved_until {
+			self.reserve()?;
+		}
+		self.counter.fill_nonce(nonce)
+	}
+}
+
+/// Writes `counter` big-endian into the whole of `nonce`, failing if it doesn't fit.
+fn write_counter(counter: u64, nonce: &mut [u8]) -> Result<(), Error> {
+	let bytes = counter.to_be_bytes();
+	let (high, low) = bytes.split_at(bytes.len() - nonce.len().min(bytes.len()));
+	if high.iter().any(|&byte| byte != 0) {
+		return Err(Error::NonceExhausted);
+	}
+
+	let (padding, tail) = nonce.split_at_mut(nonce.len() - low.len());
+	padding.fill(0);
+	tail.copy_from_slice(low);
+	Ok(())
+}
+
+/// Identifies a key without revealing it: a truncated SHA-256 hash of the key with a
+/// domain separation prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyFingerprint([u8; 16]);
+
+impl KeyFingerprint {
+	pub fn of<const N: usize>(key: &SecretKey<N>) -> Self {
+		let digest = Sha256::new()
+			.chain_update(b"aes_modes key fingerprint\0")
+			.chain_update(key.expose_secret())
+			.finalize();
+		Self(digest[..16].try_into().unwrap())
+	}
+
+	pub fn as_bytes(&self) -> &[u8; 16] {
+		&self.0
+	}
+}
+
+/// Remembers which nonces have been used with which key.
+pub trait NonceRegistry {
+	/// Records that `nonce` has been used with the key with `fingerprint`. Returns `false`
+	/// if it had already been recorded, in which case the nonce must not be used.
+	fn insert(&mut self, fingerprint: &KeyFingerprint, nonce: &[u8]) -> bool;
+}
+
+impl<R: NonceRegistry + ?Sized> NonceRegistry for &mut R {
+	fn insert(&mut self, fingerprint: &KeyFingerprint, nonce: &[u8]) -> bool {
+		(**self).insert(fingerprint, nonce)
+	}
+}
+
+/// A registry that stores every nonce. Exact, but grows with every message.
+#[derive(Debug, Default)]
+pub struct MemoryNonceRegistry {
+	seen: HashMap<KeyFingerprint, HashSet<Vec<u8>>>,
+}
+
+impl MemoryNonceRegistry {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// How many nonces have been recorded, across all keys.
+	pub fn len(&self) -> usize {
+		self.seen.values().map(HashSet::len).sum()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl NonceRegistry for MemoryNonceRegistry {
+	fn insert(&mut self, fingerprint: &KeyFingerprint, nonce: &[u8]) -> bool {
+		self.seen.entry(*fingerprint).or_default().insert(nonce.to_vec())
+	}
+}
+
+/// A registry in a fixed amount of memory, using a Bloom filter.
+///
+/// It never misses a reused nonce, but it can mistake a fresh nonce for a reused one, with
+/// about the given false positive rate once `capacity` nonces have been recorded. That
+/// errs on the safe side: the caller just has to draw another nonce.
+#[derive(Debug, Clone)]
+pub struct BloomNonceRegistry {
+	bits: Vec<u64>,
+	bit_count: u64,
+	hash_count: u32,
+}
+
+impl BloomNonceRegistry {
+	/// Sizes the filter for `capacity` nonces at the given false positive rate.
+	///
+	/// Panics unless `false_positive_rate` is strictly between 0 and 1.
+	pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+		assert!(
+			false_positive_rate > 0.0 && false_positive_rate < 1.0,
+			"the false positive rate must be between 0 and 1"
+		);
+		let ln2 = std::f64::consts::LN_2;
+		let capacity = capacity.max(1) as f64;
+		let bit_count = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+		let hash_count = (bit_count / capacity * ln2).round().max(1.0);
+
+		let bit_count = bit_count as u64;
+		Self {
+			bits: vec![0; bit_count.div_ceil(64) as usize],
+			bit_count,
+			hash_count: hash_count as u32,
+		}
+	}
+}
+
+impl NonceRegistry for BloomNonceRegistry {
+	fn insert(&mut self, fingerprint: &KeyFingerprint, nonce: &[u8]) -> bool {
+		// Double hashing: the i-th index is h1 + i * h2, with h2 odd so the indices don't
+		// repeat early.
+		let digest =
+			Sha256::new().chain_update(fingerprint.as_bytes()).chain_update(nonce).finalize();
+		let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+		let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap()) | 1;
+
+		let mut fresh = false;
+		for i in 0..self.hash_count as u64 {
+			let index = h1.wrapping_add(i.wrapping_mul(h2)) % self.bit_count;
+			let (word, bit) = ((index / 64) as usize, 1u64 << (index % 64));
+			fresh |= self.bits[word] & bit == 0;
+			self.bits[word] |= bit;
+		}
+		fresh
+	}
+}
+
+/// A `NonceSource` that checks every nonce it hands out against a registry, for one key.
+///
+/// The registry can be shared between keys by passing it as `&mut registry`.
+pub struct RegisteredNonces<S, R> {
+	source: S,
+	registry: R,
+	fingerprint: KeyFingerprint,
+}
+
+impl<S: NonceSource, R: NonceRegistry> RegisteredNonces<S, R> {
+	pub fn new<const N: usize>(source: S, registry: R, key: &SecretKey<N>) -> Self {
+		Self { source, registry, fingerprint: KeyFingerprint::of(key) }
+	}
+}
+
+impl<S: NonceSource, R: NonceRegistry> NonceSource for RegisteredNonces<S, R> {
+	/// Fails with `Error::NonceReuse` if the source produces a nonce the registry has seen.
+	fn fill_nonce(&mut self, nonce: &mut [u8]) -> Result<(), Error> {
+		self.source.fill_nonce(nonce)?;
+		if !self.registry.insert(&self.fingerprint, nonce) {
+			return Err(Error::NonceReuse);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		cbc_decrypt, cbc_encrypt_with_nonces, ctr_decrypt, ctr_encrypt_with_nonces,
+		incremental::CTR_NONCE_SIZE, test_util::temp_path, BLOCK_SIZE,
+	};
+
+	#[test]
+	fn test_counter_nonces() {
+		let mut nonces = CounterNonces::new(0x0102);
+		let mut nonce = [0xFFu8; 12];
+
+		nonces.fill_nonce(&mut nonce).unwrap();
+		assert_eq!(nonce, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+		nonces.fill_nonce(&mut nonce).unwrap();
+		assert_eq!(nonce, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 3]);
+		assert_eq!(nonces.peek(), Some(0x0104));
+	}
+
+	#[test]
+	fn test_counter_nonces_run_out() {
+		let mut nonces = CounterNonces::new(u64::MAX);
+		let mut nonce = [0u8; 8];
+
+		nonces.fill_nonce(&mut nonce).unwrap();
+		assert_eq!(nonce, [0xFF; 8]);
+		assert!(matches!(nonces.fill_nonce(&mut nonce), Err(Error::NonceExhausted)));
+
+		// A counter that doesn't fit in a short nonce is just as exhausted.
+		let mut short = [0u8; 1];
+		assert!(CounterNonces::new(0xFF).fill_nonce(&mut short).is_ok());
+		assert!(matches!(
+			CounterNonces::new(0x100).fill_nonce(&mut short),
+			Err(Error::NonceExhausted)
+		));
+	}
+
+	#[test]
+	fn test_random_nonces_differ() {
+		let (mut a, mut b) = ([0u8; 12], [0u8; 12]);
+		RandomNonces.fill_nonce(&mut a).unwrap();
+		RandomNonces.fill_nonce(&mut b).unwrap();
+		assert_ne!(a, b);
+	}
+
+	#[test]
+	fn test_persistent_counter_never_repeats() {
+		let path = temp_path("persistent_counter");
+		let _ = fs::remove_file(&path);
+		let mut nonce = [0u8; 8];
+
+		let mut nonces = PersistentCounterNonces::open(&path).unwrap();
+		for expected in 0..COUNTER_RESERVATION + 2 {
+			nonces.fill_nonce(&mut nonce).unwrap();
+			assert_eq!(u64::from_be_bytes(nonce), expected);
+		}
+		assert_eq!(fs::read_to_string(&path).unwrap().trim(), "2048");
+
+		// A restart skips the rest of the reserved block instead of starting over.
+		drop(nonces);
+		let mut nonces = PersistentCounterNonces::open(&path).unwrap();
+		nonces.fill_nonce(&mut nonce).unwrap();
+		assert_eq!(u64::from_be_bytes(nonce), 2 * COUNTER_RESERVATION);
+
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_persistent_counter_rejects_garbage() {
+		let path = temp_path("persistent_counter_garbage");
+		fs::write(&path, "not a number").unwrap();
+
+		assert!(matches!(PersistentCounterNonces::open(&path), Err(Error::Io(_))));
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn test_memory_registry() {
+		let key = KeyFingerprint::of(&SecretKey::new([1u8; BLOCK_SIZE]));
+		let other_key = KeyFingerprint::of(&SecretKey::new([2u8; BLOCK_SIZE]));
+		let mut registry = MemoryNonceRegistry::new();
+
+		assert!(registry.insert(&key, b"nonce 1"));
+		assert!(registry.insert(&key, b"nonce 2"));
+		assert!(!registry.insert(&key, b"nonce 1"));
+		// The same nonce under a different key is fine.
+		assert!(registry.insert(&other_key, b"nonce 1"));
+		assert_eq!(registry.len(), 3);
+	}
+
+	#[test]
+	fn test_bloom_registry() {
+		let key = KeyFingerprint::of(&SecretKey::new([1u8; BLOCK_SIZE]));
+		let other_key = KeyFingerprint::of(&SecretKey::new([2u8; BLOCK_SIZE]));
+		let mut registry = BloomNonceRegistry::new(1000, 0.001);
+
+		for i in 0u32..1000 {
+			assert!(registry.insert(&key, &i.to_be_bytes()));
+		}
+		for i in 0u32..1000 {
+			assert!(!registry.insert(&key, &i.to_be_bytes()));
+		}
+
+		// Fresh nonces are only rarely mistaken for reused ones. Each is checked against a
+		// copy, so the filter doesn't fill up past its capacity.
+		let false_positives =
+			(0u32..1000).filter(|i| !registry.clone().insert(&other_key, &i.to_be_bytes())).count();
+		assert!(false_positives < 20, "{} false positives", false_positives);
+	}
+
+	#[test]
+	fn test_registered_nonces_catch_a_repeating_source() {
+		let key = SecretKey::new([3u8; BLOCK_SIZE]);
+		let mut registry = MemoryNonceRegistry::new();
+		let mut nonce = [0u8; CTR_NONCE_SIZE];
+
+		let mut first = RegisteredNonces::new(CounterNonces::new(0), &mut registry, &key);
+		first.fill_nonce(&mut nonce).unwrap();
+		first.fill_nonce(&mut nonce).unwrap();
+
+		// Like a counter file that was restored from a backup.
+		let mut restarted = RegisteredNonces::new(CounterNonces::new(1), &mut registry, &key);
+		assert!(matches!(restarted.fill_nonce(&mut nonce), Err(Error::NonceReuse)));
+		restarted.fill_nonce(&mut nonce).unwrap();
+	}
+
+	#[test]
+	fn test_modes_with_counter_nonces() {
+		let key = SecretKey::new([4u8; BLOCK_SIZE]);
+		let plain_text = b"Hello, world!".to_vec();
+		let mut nonces = CounterNonces::new(7);
+
+		let cbc = cbc_encrypt_with_nonces(plain_text.clone(), &key, &mut nonces).unwrap();
+		let mut expected_nonce = [0u8; BLOCK_SIZE];
+		expected_nonce[BLOCK_SIZE - 1] = 7;
+		// The counter is encrypted into an unpredictable IV.
+		assert_eq!(cbc[..BLOCK_SIZE], crate::aes_encrypt(expected_nonce, key.expose_secret()));
+		assert_eq!(cbc_decrypt(cbc, &key), plain_text);
+
+		let ctr = ctr_encrypt_with_nonces(plain_text.clone(), &key, &mut nonces).unwrap();
+		assert_eq!(ctr[..CTR_NONCE_SIZE], 8u64.to_be_bytes());
+		assert_eq!(ctr_decrypt(ctr.clone(), &key), plain_text);
+
+		// The same counter value gives the same ciphertext.
+		let again = ctr_encrypt_with_nonces(plain_text, &key, &mut CounterNonces::new(8)).unwrap();
+		assert_eq!(again, ctr);
+	}
+}
@@ -0,0 +1,244 @@
+//! Multi-threaded versions of the parallelizable modes.
+//!
+//! The crate docs point out that ECB and CTR encrypt every block independently, and that
+//! CBC _decryption_ does too: plaintext block `i` only needs ciphertext blocks `i` and
+//! `i - 1`, which are both already known. (CBC encryption is inherently serial, since every
+//! block needs the ciphertext of the one before.)
+//!
+//! Here we cut the data into one run of whole blocks per thread and process the runs with
+//! scoped threads. Every block is computed exactly as in the serial code, so the output is
+//! byte-for-byte the same. Spawning threads is not free, so messages shorter than
+//! `PARALLEL_THRESHOLD` just take the serial path.
+use std::thread;
+
+use rand::{CryptoRng, Rng};
+use zeroize::Zeroize;
+
+use crate::{
+	aes_decrypt_blocks, aes_encrypt_blocks, apply_ctr_keystream, cbc_decrypt, ctr_decrypt,
+	ctr_encrypt_with_rng, ecb_decrypt, ecb_encrypt, incremental::CTR_NONCE_SIZE, pad, un_pad,
+	SecretKey, BLOCK_SIZE,
+};
+
+/// Below this many bytes, the parallel functions fall back to the serial ones.
+pub const PARALLEL_THRESHOLD: usize = 64 * 1024;
+
+/// How many threads the machine can usefully run at once.
+pub fn available_threads() -> usize {
+	thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Parallel form of `ecb_encrypt`.
+pub fn ecb_encrypt_parallel(plain_text: Vec<u8>, key: &SecretKey, threads: usize) -> Vec<u8> {
+	if is_serial(plain_text.len(), threads) {
+		return ecb_encrypt(plain_text, key);
+	}
+
+	let mut data = pad(plain_text);
+	for_each_run(&mut data, threads, |run, _| {
+		let (blocks, _) = run.as_chunks_mut::<BLOCK_SIZE>();
+		aes_encrypt_blocks(blocks, key.expose_secret());
+	});
+	data
+}
+
+/// Parallel form of `ecb_decrypt`.
+pub fn ecb_decrypt_parallel(cipher_text: Vec<u8>, key: &SecretKey, threads: usize) -> Vec<u8> {
+	if is_serial(cipher_text.len(), threads) {
+		return ecb_decrypt(cipher_text, key);
+	}
+
+	assert!(cipher_text.len().is_multiple_of(BLOCK_SIZE), "cipher text must be whole blocks");
+
+	let mut data = cipher_text;
+	for_each_run(&mut data, threads, |run, _| {
+		let (blocks, _) = run.as_chunks_mut::<BLOCK_SIZE>();
+		aes_decrypt_blocks(blocks, key.expose_secret());
+	});
+	un_pad(data)
+}
+
+/// Parallel form of `ctr_encrypt`.
+pub fn ctr_encrypt_parallel(plain_text: Vec<u8>, key: &SecretKey, threads: usize) -> Vec<u8> {
+	ctr_encrypt_parallel_with_rng(plain_text, key, threads, &mut rand::thread_rng())
+}
+
+/// Like `ctr_encrypt_parallel`, but the nonce is drawn from `rng`.
+pub fn ctr_encrypt_parallel_with_rng(
+	plain_text: Vec<u8>,
+	key: &SecretKey,
+	threads: usize,
+	rng: &mut impl CryptoRng,
+) -> Vec<u8> {
+	if is_serial(plain_text.len(), threads) {
+		return ctr_encrypt_with_rng(plain_text, key, rng);
+	}
+
+	let nonce = u64::from_be_bytes(rng.gen());
+	ctr_encrypt_with_nonce(plain_text, nonce, key, threads)
+}
+
+/// Parallel form of `ctr_decrypt`.
+pub fn ctr_decrypt_parallel(cipher_text: Vec<u8>, key: &SecretKey, threads: usize) -> Vec<u8> {
+	if is_serial(cipher_text.len(), threads) {
+		return ctr_decrypt(cipher_text, key);
+	}
+
+	let nonce = u64::from_be_bytes(cipher_text[..CTR_NONCE_SIZE].try_into().unwrap());
+	let mut plain_text = cipher_text[CTR_NONCE_SIZE..].to_vec();
+	for_each_run(&mut plain_text, threads, |run, first_block| {
+		apply_ctr_keystream(run, nonce, first_block as u64, key)
+	});
+
+	un_pad(plain_text)
+}
+
+/// Parallel form of `cbc_decrypt`.
+pub fn cbc_decrypt_parallel(cipher_text: Vec<u8>, key: &SecretKey, threads: usize) -> Vec<u8> {
+	if is_serial(cipher_text.len(), threads) {
+		return cbc_decrypt(cipher_text, key);
+	}
+	assert!(
+		cipher_text.len().is_multiple_of(BLOCK_SIZE),
+		"cipher text must be an IV plus whole blocks"
+	);
+
+	let mut plain_text = cipher_text[BLOCK_SIZE..].to_vec();
+	for_each_run(&mut plain_text, threads, |run, first_block| {
+		let (blocks, _) = run.as_chunks_mut::<BLOCK_SIZE>();
+		aes_decrypt_blocks(blocks, key.expose_secret());
+
+		// The ciphertext still starts with the IV, so plaintext block `i` pairs with block
+		// `i` of the ciphertext as its "previous" block.
+		let prev_blocks = &cipher_text[first_block * BLOCK_SIZE..];
+		for (block, prev_block) in blocks.iter_mut().zip(prev_blocks.chunks_exact(BLOCK_SIZE)) {
+			block.iter_mut().zip(prev_block).for_each(|(x1, &x2)| *x1 ^= x2);
+		}
+	});
+
+	un_pad(plain_text)
+}
+
+fn ctr_encrypt_with_nonce(
+	plain_text: Vec<u8>,
+	nonce: u64,
+	key: &SecretKey,
+	threads: usize,
+) -> Vec<u8> {
+	let mut padded = pad(plain_text);
+	let mut cipher_text = nonce.to_be_bytes().to_vec();
+	cipher_text.extend_from_slice(&padded);
+	padded.zeroize();
+
+	for_each_run(&mut cipher_text[CTR_NONCE_SIZE..], threads, |run, first_block| {
+		apply_ctr_keystream(run, nonce, first_block as u64, key)
+	});
+
+	cipher_text
+}
+
+fn is_serial(len: usize, threads: usize) -> bool {
+	threads <= 1 || len < PARALLEL_THRESHOLD
+}
+
+/// Cuts `data` into at most `threads` runs of whole blocks (the last one may be shorter)
+/// and calls `f` on each run in its own thread, along with the index of the run's first
+/// block.
+fn for_each_run(data: &mut [u8], threads: usize, f: impl Fn(&mut [u8], usize) + Sync) {
+	let blocks = data.len().div_ceil(BLOCK_SIZE);
+	let blocks_per_run = blocks.div_ceil(threads).max(1);
+
+	thread::scope(|scope| {
+		for (i, run) in data.chunks_mut(blocks_per_run * BLOCK_SIZE).enumerate() {
+			let f = &f;
+			scope.spawn(move || f(run, i * blocks_per_run));
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		cbc_encrypt,
+		incremental::{CtrEncryptor, Encryptor},
+		test_util::sample,
+	};
+
+	const KEY: SecretKey = SecretKey::new([11u8; BLOCK_SIZE]);
+
+	/// Big enough to go parallel, and not a whole number of blocks.
+	fn big_sample() -> Vec<u8> {
+		sample(2 * PARALLEL_THRESHOLD + 7)
+	}
+
+	#[test]
+	fn test_ecb_parallel_matches_serial() {
+		let plain_text = big_sample();
+		let expected = ecb_encrypt(plain_text.clone(), &KEY);
+
+		for threads in 1..=8 {
+			let encrypted = ecb_encrypt_parallel(plain_text.clone(), &KEY, threads);
+			assert_eq!(encrypted, expected);
+			assert_eq!(ecb_decrypt_parallel(encrypted, &KEY, threads), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_ctr_encrypt_parallel_matches_serial() {
+		let plain_text = big_sample();
+		let mut encryptor = CtrEncryptor::with_nonce(&KEY, 1234);
+		let mut expected = encryptor.update(&plain_text);
+		expected.extend(encryptor.finalize());
+
+		for threads in 1..=8 {
+			assert_eq!(ctr_encrypt_with_nonce(plain_text.clone(), 1234, &KEY, threads), expected);
+		}
+	}
+
+	#[test]
+	fn test_ctr_decrypt_parallel_matches_serial() {
+		let plain_text = big_sample();
+		let cipher_text = ctr_encrypt_parallel(plain_text.clone(), &KEY, 4);
+
+		assert_eq!(ctr_decrypt(cipher_text.clone(), &KEY), plain_text);
+		for threads in 1..=8 {
+			assert_eq!(ctr_decrypt_parallel(cipher_text.clone(), &KEY, threads), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_cbc_decrypt_parallel_matches_serial() {
+		let plain_text = big_sample();
+		let cipher_text = cbc_encrypt(plain_text.clone(), &KEY);
+
+		for threads in 1..=8 {
+			assert_eq!(cbc_decrypt_parallel(cipher_text.clone(), &KEY, threads), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_small_messages_stay_serial() {
+		let plain_text = sample(100);
+
+		let cipher_text = ctr_encrypt_parallel(plain_text.clone(), &KEY, 8);
+		assert_eq!(ctr_decrypt_parallel(cipher_text, &KEY, 8), plain_text);
+
+		let cipher_text = cbc_encrypt(plain_text.clone(), &KEY);
+		assert_eq!(cbc_decrypt_parallel(cipher_text, &KEY, 8), plain_text);
+	}
+
+	#[test]
+	fn test_more_threads_than_blocks() {
+		let plain_text = sample(3 * BLOCK_SIZE);
+		let expected = ecb_encrypt(plain_text.clone(), &KEY);
+
+		let mut data = pad(plain_text);
+		for_each_run(&mut data, 100, |run, _| {
+			let (blocks, _) = run.as_chunks_mut::<BLOCK_SIZE>();
+			aes_encrypt_blocks(blocks, KEY.expose_secret());
+		});
+
+		assert_eq!(data, expected);
+	}
+}
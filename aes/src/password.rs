@@ -0,0 +1,385 @@
+//! Encryption under a passphrase instead of a random key.
+//!
+//! Passwords are short and guessable, so they cannot be used as AES keys directly. A key
+//! derivation function (KDF) stretches the password together with a random salt into a
+//! key, and is deliberately slow so that every guess costs an attacker the same work. Three
+//! are on offer:
+//!
+//! - PBKDF2-HMAC-SHA256 (RFC 8018): the oldest and most widely supported, but only costs
+//!   CPU time, which GPUs are good at.
+//! - scrypt (RFC 7914): also costs memory.
+//! - Argon2id (RFC 9106): the current recommendation, and the default.
+//!
+//! The salt, the KDF and its parameters are stored in a header in front of the ciphertext,
+//! so decryption needs nothing but the password. The data itself is encrypted with GCM and
+//! the header is passed as associated data: a wrong password or a modified header fails
+//! with `Error::Authentication` rather than producing garbage.
+//!
+//! The layout is:
+//!
+//! ```text
+//! "AESP" | version (1) | KDF id (1) | KDF parameters | salt (16) | nonce (12) | GCM output
+//! ```
+//!
+//! with the parameters as big-endian integers (see `Kdf`).
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::{CryptoRng, Rng};
+use sha2::Sha256;
+
+use crate::{
+	gcm::{self, gcm_decrypt, gcm_encrypt},
+	Error, SecretKey, BLOCK_SIZE,
+};
+
+pub const SALT_SIZE: usize = 16;
+
+const MAGIC: &[u8; 4] = b"AESP";
+const VERSION: u8 = 1;
+
+// The parameters come from the header, which anyone can write. These caps bound what a
+// hostile header can cost: at most 256 MiB of memory, with memory times passes (scrypt's
+// `p`, Argon2's iterations) at most 1 GiB, or 2 million PBKDF2 iterations. That is a few
+// times the work of the defaults below, and leaves room for OWASP's other settings.
+const MAX_PBKDF2_ITERATIONS: u32 = 2_000_000;
+const MAX_SCRYPT_LOG_N: u8 = 20;
+const MAX_SCRYPT_R: u32 = 16;
+const MAX_SCRYPT_P: u32 = 16;
+const MAX_MEMORY_BYTES: u64 = 256 << 20;
+const MAX_MEMORY_WORK_BYTES: u64 = 1 << 30;
+const MAX_ARGON2_ITERATIONS: u32 = 8;
+const MAX_ARGON2_PARALLELISM: u32 = 8;
+
+/// A password-based key derivation function and its cost parameters.
+///
+/// The constructors pick parameters from the OWASP password storage recommendations. They
+/// take a noticeable fraction of a second, which is the point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kdf {
+	/// Stored as `iterations: u32`.
+	Pbkdf2 { iterations: u32 },
+	/// `N = 2^log_n`. Stored as `log_n: u8, r: u32, p: u32`.
+	Scrypt { log_n: u8, r: u32, p: u32 },
+	/// Memory is in KiB. Stored as `memory_kib: u32, iterations: u32, parallelism: u32`.
+	Argon2id { memory_kib: u32, iterations: u32, parallelism: u32 },
+}
+
+impl Kdf {
+	pub fn pbkdf2() -> Self {
+		Kdf::Pbkdf2 { iterations: 600_000 }
+	}
+
+	pub fn scrypt() -> Self {
+		Kdf::Scrypt { log_n: 17, r: 8, p: 1 }
+	}
+
+	pub fn argon2id() -> Self {
+		Kdf::Argon2id { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+	}
+
+	/// Stretches `password` and `salt` into an AES key.
+	///
+	/// Fails with `Error::InvalidParameters` if the parameters are out of the KDF's range,
+	/// e.g. zero iterations, or so costly that they can only come from a hostile header:
+	/// more than 2 million PBKDF2 iterations, more than 256 MiB of memory, and so on.
+	pub fn derive_key(&self, password: &[u8], salt: &[u8]) -> Result<SecretKey, Error> {
+		self.check_cost()?;
+
+		let mut key = SecretKey::new([0u8; BLOCK_SIZE]);
+		let output = key.expose_secret_mut();
+		match *self {
+			Kdf::Pbkdf2 { iterations } => {
+				if iterations == 0 {
+					return Err(Error::InvalidParameters);
+				}
+				pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, output);
+			},
+			Kdf::Scrypt { log_n, r, p } => {
+				let params = scrypt::Params::new(log_n, r, p, BLOCK_SIZE)
+					.map_err(|_| Error::InvalidParameters)?;
+				scrypt::scrypt(password, salt, &params, output)
+					.map_err(|_| Error::InvalidParameters)?;
+			},
+			Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+				let params = Params::new(memory_kib, iterations, parallelism, Some(BLOCK_SIZE))
+					.map_err(|_| Error::InvalidParameters)?;
+				Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+					.hash_password_into(password, salt, output)
+					.map_err(|_| Error::InvalidParameters)?;
+			},
+		}
+
+		Ok(key)
+	}
+
+	/// Rejects parameters above the `MAX_` limits.
+	fn check_cost(&self) -> Result<(), Error> {
+		let affordable = match *self {
+			Kdf::Pbkdf2 { iterations } => iterations <= MAX_PBKDF2_ITERATIONS,
+			Kdf::Scrypt { log_n, r, p } => {
+				let memory = 128 * r as u64 * (1 << log_n.min(MAX_SCRYPT_LOG_N));
+				log_n <= MAX_SCRYPT_LOG_N
+					&& r <= MAX_SCRYPT_R
+					&& p <= MAX_SCRYPT_P
+					&& memory <= MAX_MEMORY_BYTES
+					&& memory * p as u64 <= MAX_MEMORY_WORK_BYTES
+			},
+			Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+				let memory = memory_kib as u64 * 1024;
+				memory <= MAX_MEMORY_BYTES
+					&& iterations <= MAX_ARGON2_ITERATIONS
+					&& parallelism <= MAX_ARGON2_PARALLELISM
+					&& memory * iterations as u64 <= MAX_MEMORY_WORK_BYTES
+			},
+		};
+
+		if affordable {
+			Ok(())
+		} else {
+			Err(Error::InvalidParameters)
+		}
+	}
+
+	fn id(&self) -> u8 {
+		match self {
+			Kdf::Pbkdf2 { .. } => 1,
+			Kdf::Scrypt { .. } => 2,
+			Kdf::Argon2id { .. } => 3,
+		}
+	}
+
+	fn write_params(&self, header: &mut Vec<u8>) {
+		match *self {
+			Kdf::Pbkdf2 { iterations } => header.extend_from_slice(&iterations.to_be_bytes()),
+			Kdf::Scrypt { log_n, r, p } => {
+				header.push(log_n);
+				header.extend_from_slice(&r.to_be_bytes());
+				header.extend_from_slice(&p.to_be_bytes());
+			},
+			Kdf::Argon2id { memory_kib, iterations, parallelism } => {
+				for value in [memory_kib, iterations, parallelism] {
+					header.extend_from_slice(&value.to_be_bytes());
+				}
+			},
+		}
+	}
+
+	/// Parses the KDF id and parameters, returning the KDF and the rest of the input.
+	fn read(data: &[u8]) -> Result<(Self, &[u8]), Error> {
+		let (&id, rest) = data.split_first().ok_or(Error::InvalidHeader)?;
+		let (kdf, len) = match id {
+			1 => (Kdf::Pbkdf2 { iterations: be32(rest, 0)? }, 4),
+			2 => {
+				let log_n = *rest.first().ok_or(Error::InvalidHeader)?;
+				(Kdf::Scrypt { log_n, r: be32(rest, 1)?, p: be32(rest, 5)? }, 9)
+			},
+			3 => {
+				let kdf = Kdf::Argon2id {
+					memory_kib: be32(rest, 0)?,
+					iterations: be32(rest, 4)?,
+					parallelism: be32(rest, 8)?,
+				};
+				(kdf, 12)
+			},
+			_ => return Err(Error::InvalidHeader),
+		};
+
+		Ok((kdf, &rest[len..]))
+	}
+}
+
+impl Default for Kdf {
+	fn default() -> Self {
+		Kdf::argon2id()
+	}
+}
+
+/// Encrypts `plain_text` under a key derived from `password` with a fresh random salt.
+///
+/// Returns the header followed by the GCM ciphertext and tag.
+pub fn encrypt_with_password(
+	plain_text: &[u8],
+	password: &[u8],
+	kdf: &Kdf,
+) -> Result<Vec<u8>, Error> {
+	encrypt_with_password_with_rng(plain_text, password, kdf, &mut rand::thread_rng())
+}
+
+/// Like `encrypt_with_password`, but the salt and nonce are drawn from `rng`.
+pub fn encrypt_with_password_with_rng(
+	plain_text: &[u8],
+	password: &[u8],
+	kdf: &Kdf,
+	rng: &mut impl CryptoRng,
+) -> Result<Vec<u8>, Error> {
+	let salt: [u8; SALT_SIZE] = rng.gen();
+	let nonce: [u8; gcm::NONCE_SIZE] = rng.gen();
+	let key = kdf.derive_key(password, &salt)?;
+
+	let mut header = MAGIC.to_vec();
+	header.push(VERSION);
+	header.push(kdf.id());
+	kdf.write_params(&mut header);
+	header.extend_from_slice(&salt);
+	header.extend_from_slice(&nonce);
+
+	let cipher_text = gcm_encrypt(plain_text, &header, &nonce, &key)?;
+	header.extend(cipher_text);
+
+	Ok(header)
+}
+
+/// Opposite of encrypt_with_password. The KDF and salt are read from the header.
+pub fn decrypt_with_password(cipher_text: &[u8], password: &[u8]) -> Result<Vec<u8>, Error> {
+	let (kdf, rest) = read_kdf(cipher_text)?;
+	if rest.len() < SALT_SIZE + gcm::NONCE_SIZE {
+		return Err(Error::Truncated);
+	}
+	let (salt, rest) = rest.split_at(SALT_SIZE);
+	let (nonce, body) = rest.split_at(gcm::NONCE_SIZE);
+	let header = &cipher_text[..cipher_text.len() - body.len()];
+
+	let key = kdf.derive_key(password, salt)?;
+	gcm_decrypt(body, header, nonce.try_into().unwrap(), &key)
+}
+
+/// The KDF and parameters a ciphertext from encrypt_with_password was encrypted with,
+/// without decrypting it.
+pub fn password_kdf(cipher_text: &[u8]) -> Result<Kdf, Error> {
+	Ok(read_kdf(cipher_text)?.0)
+}
+
+fn read_kdf(cipher_text: &[u8]) -> Result<(Kdf, &[u8]), Error> {
+	let rest = cipher_text.strip_prefix(MAGIC).ok_or(Error::InvalidHeader)?;
+	let rest = rest.strip_prefix(&[VERSION]).ok_or(Error::InvalidHeader)?;
+	Kdf::read(rest)
+}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32, Error> {
+	let bytes = data.get(offset..offset + 4).ok_or(Error::InvalidHeader)?;
+	Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::{hex, FAST_KDFS};
+
+	#[test]
+	fn test_kdf_vectors() {
+		// The first 16 bytes of the RFC 7914 PBKDF2-HMAC-SHA256 and scrypt test vectors.
+		let pbkdf2 = Kdf::Pbkdf2 { iterations: 1 }.derive_key(b"passwd", b"salt").unwrap();
+		assert_eq!(pbkdf2.expose_secret().to_vec(), hex("55ac046e56e3089fec1691c22544b605"));
+
+		let scrypt = Kdf::Scrypt { log_n: 10, r: 8, p: 16 }.derive_key(b"password", b"NaCl");
+		assert_eq!(
+			scrypt.unwrap().expose_secret().to_vec(),
+			hex("fdbabe1c9d3472007856e7190d01e9fe")
+		);
+
+		let argon2id = Kdf::Argon2id { memory_kib: 64, iterations: 2, parallelism: 1 }
+			.derive_key(b"password", b"somesaltsomesalt");
+		assert_eq!(
+			argon2id.unwrap().expose_secret().to_vec(),
+			hex("792a97be9a1a50fea36d796d22d20103")
+		);
+	}
+
+	#[test]
+	fn test_round_trip() {
+		for kdf in FAST_KDFS {
+			let cipher_text = encrypt_with_password(b"attack at dawn", b"hunter2", &kdf).unwrap();
+			let decrypted = decrypt_with_password(&cipher_text, b"hunter2").unwrap();
+			assert_eq!(decrypted, b"attack at dawn");
+			assert_eq!(password_kdf(&cipher_text).unwrap(), kdf);
+		}
+	}
+
+	#[test]
+	fn test_salt_is_random() {
+		let kdf = FAST_KDFS[0];
+		let first = encrypt_with_password(b"same", b"password", &kdf).unwrap();
+		let second = encrypt_with_password(b"same", b"password", &kdf).unwrap();
+		assert_ne!(first, second);
+	}
+
+	#[test]
+	fn test_wrong_password_or_modified_header() {
+		let kdf = FAST_KDFS[0];
+		let mut cipher_text = encrypt_with_password(b"secret", b"right", &kdf).unwrap();
+
+		let wrong_password = decrypt_with_password(&cipher_text, b"wrong");
+		assert!(matches!(wrong_password, Err(Error::Authentication)));
+
+		// Lowering the iteration count has to be caught by the tag, not just the KDF.
+		cipher_text[9] ^= 1;
+		let modified = decrypt_with_password(&cipher_text, b"right");
+		assert!(matches!(modified, Err(Error::Authentication)));
+	}
+
+	#[test]
+	fn test_malformed_headers() {
+		let cipher_text = encrypt_with_password(b"secret", b"pw", &FAST_KDFS[1]).unwrap();
+
+		let mut unknown_kdf = cipher_text.clone();
+		unknown_kdf[5] = 9;
+		assert!(matches!(decrypt_with_password(&unknown_kdf, b"pw"), Err(Error::InvalidHeader)));
+		assert!(matches!(decrypt_with_password(b"nope", b"pw"), Err(Error::InvalidHeader)));
+		assert!(matches!(decrypt_with_password(&cipher_text[..20], b"pw"), Err(Error::Truncated)));
+
+		for zero_cost in [
+			Kdf::Pbkdf2 { iterations: 0 },
+			Kdf::Argon2id { memory_kib: 0, iterations: 2, parallelism: 1 },
+		] {
+			assert!(matches!(zero_cost.derive_key(b"pw", b"salt"), Err(Error::InvalidParameters)));
+		}
+	}
+
+	#[test]
+	fn test_hostile_kdf_parameters() {
+		// The parameters start at offset 6, after "AESP", the version and the KDF id.
+		let argon2 = encrypt_with_password(b"secret", b"pw", &FAST_KDFS[2]).unwrap();
+		let mut huge_memory = argon2.clone();
+		huge_memory[6..10].copy_from_slice(&0xfffffff0u32.to_be_bytes());
+		let result = decrypt_with_password(&huge_memory, b"pw");
+		assert!(matches!(result, Err(Error::InvalidParameters)));
+
+		let scrypt = encrypt_with_password(b"secret", b"pw", &FAST_KDFS[1]).unwrap();
+		let mut huge_n = scrypt.clone();
+		huge_n[6] = 40;
+		assert!(matches!(decrypt_with_password(&huge_n, b"pw"), Err(Error::InvalidParameters)));
+
+		for too_costly in [
+			Kdf::Pbkdf2 { iterations: u32::MAX },
+			// Each limit alone is fine, but together they need 2 GiB.
+			Kdf::Scrypt { log_n: MAX_SCRYPT_LOG_N, r: MAX_SCRYPT_R, p: 1 },
+			// 256 MiB is allowed, but not passed over 16 or 8 times.
+			Kdf::Scrypt { log_n: 18, r: 8, p: MAX_SCRYPT_P },
+			Kdf::Argon2id {
+				memory_kib: 256 * 1024,
+				iterations: MAX_ARGON2_ITERATIONS,
+				parallelism: 1,
+			},
+			Kdf::Scrypt { log_n: 10, r: 8, p: MAX_SCRYPT_P + 1 },
+			Kdf::Argon2id { memory_kib: 64, iterations: MAX_ARGON2_ITERATIONS + 1, parallelism: 1 },
+			Kdf::Argon2id {
+				memory_kib: 64,
+				iterations: 2,
+				parallelism: MAX_ARGON2_PARALLELISM + 1,
+			},
+		] {
+			assert!(matches!(too_costly.derive_key(b"pw", b"salt"), Err(Error::InvalidParameters)));
+		}
+		for default in [Kdf::pbkdf2(), Kdf::scrypt(), Kdf::argon2id()] {
+			assert!(default.check_cost().is_ok());
+		}
+		// OWASP's other Argon2id and scrypt settings.
+		for alternative in [
+			Kdf::Argon2id { memory_kib: 46 * 1024, iterations: 1, parallelism: 1 },
+			Kdf::Argon2id { memory_kib: 7 * 1024, iterations: 5, parallelism: 1 },
+			Kdf::Scrypt { log_n: 16, r: 8, p: 2 },
+			Kdf::Scrypt { log_n: 12, r: 8, p: 3 },
+		] {
+			assert!(alternative.check_cost().is_ok());
+		}
+	}
+}
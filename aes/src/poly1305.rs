@@ -0,0 +1,280 @@
+//! The Poly1305 one-time authenticator (RFC 8439 section 2.5).
+//!
+//! Poly1305 evaluates the message, read as 16-byte little-endian numbers, as a polynomial
+//! at a secret point `r` modulo the prime 2^130 - 5, then adds a secret `s`. The key must
+//! never be used for two messages; ChaCha20-Poly1305 derives a fresh one per nonce.
+//!
+//! The 130-bit numbers are kept in five 26-bit limbs so that products fit in a `u64`. This
+//! is the well-known "donna" layout, and nothing in it branches on secret data.
+//!
+//! Besides the AEAD, the type can be used on its own, for instance as Poly1305-AES
+//! (`poly1305_aes`), where `s` is a nonce encrypted under an AES key.
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::{aes_encrypt, BLOCK_SIZE};
+
+pub const KEY_SIZE: usize = 32;
+pub const TAG_SIZE: usize = 16;
+
+const LIMB_MASK: u32 = (1 << 26) - 1;
+
+/// Poly1305 keyed with `r || s`. Data can be fed in pieces of any size.
+pub struct Poly1305 {
+	r: [u32; 5],
+	s: [u32; 4],
+	accumulator: [u32; 5],
+	buffer: Vec<u8>,
+}
+
+impl Poly1305 {
+	pub fn new(key: &[u8; KEY_SIZE]) -> Self {
+		// Some bits of r are "clamped" to zero, which keeps the products below 2^64.
+		let r = [
+			le32(&key[0..]) & 0x3ffffff,
+			(le32(&key[3..]) >> 2) & 0x3ffff03,
+			(le32(&key[6..]) >> 4) & 0x3ffc0ff,
+			(le32(&key[9..]) >> 6) & 0x3f03fff,
+			(le32(&key[12..]) >> 8) & 0x00fffff,
+		];
+		let s = std::array::from_fn(|i| le32(&key[16 + 4 * i..]));
+
+		Self { r, s, accumulator: [0; 5], buffer: Vec::new() }
+	}
+
+	/// Feeds more of the message. Partial blocks are buffered.
+	pub fn update(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+		let whole = self.buffer.len() - self.buffer.len() % BLOCK_SIZE;
+		let blocks: Vec<u8> = self.buffer.drain(..whole).collect();
+
+		for block in blocks.chunks_exact(BLOCK_SIZE) {
+			self.absorb(block.try_into().unwrap(), 1 << 24);
+		}
+	}
+
+	/// Feeds zeros up to the next block boundary, as the AEAD construction requires.
+	pub fn pad(&mut self) {
+		let remainder = self.buffer.len() % BLOCK_SIZE;
+		if remainder != 0 {
+			self.update(&[0u8; BLOCK_SIZE][remainder..]);
+		}
+	}
+
+	pub fn finalize(mut self) -> [u8; TAG_SIZE] {
+		if !self.buffer.is_empty() {
+			// A final partial block gets a 1 byte appended instead of the 2^128 bit.
+			let mut block = [0u8; BLOCK_SIZE];
+			block[..self.buffer.len()].copy_from_slice(&self.buffer);
+			block[self.buffer.len()] = 1;
+			self.absorb(block, 0);
+		}
+
+		let h = fully_reduce(self.accumulator);
+
+		// Pack the limbs into four 32-bit words and add s, modulo 2^128.
+		let words = [
+			h[0] | (h[1] << 26),
+			(h[1] >> 6) | (h[2] << 20),
+			(h[2] >> 12) | (h[3] << 14),
+			(h[3] >> 18) | (h[4] << 8),
+		];
+		let mut tag = [0u8; TAG_SIZE];
+		let mut carry = 0u64;
+		for (i, (word, s)) in words.iter().zip(self.s).enumerate() {
+			let sum = *word as u64 + s as u64 + carry;
+			tag[4 * i..4 * i + 4].copy_from_slice(&(sum as u32).to_le_bytes());
+			carry = sum >> 32;
+		}
+
+		tag
+	}
+
+	/// `accumulator = (accumulator + block) * r mod 2^130 - 5`. `high_bit` is the 2^128 bit
+	/// that every full block gets.
+	fn absorb(&mut self, block: [u8; BLOCK_SIZE], high_bit: u32) {
+		let [r0, r1, r2, r3, r4] = self.r.map(|limb| limb as u64);
+		// 2^130 = 5 mod p, so limbs that overflow past 2^130 wrap around times 5.
+		let [s1, s2, s3, s4] = [r1 * 5, r2 * 5, r3 * 5, r4 * 5];
+
+		let h = &mut self.accumulator;
+		h[0] += le32(&block[0..]) & LIMB_MASK;
+		h[1] += (le32(&block[3..]) >> 2) & LIMB_MASK;
+		h[2] += (le32(&block[6..]) >> 4) & LIMB_MASK;
+		h[3] += (le32(&block[9..]) >> 6) & LIMB_MASK;
+		h[4] += (le32(&block[12..]) >> 8) | high_bit;
+
+		let [h0, h1, h2, h3, h4] = h.map(|limb| limb as u64);
+		let d = [
+			h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1,
+			h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2,
+			h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3,
+			h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4,
+			h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0,
+		];
+
+		// Carry the products back down to 26 bits per limb.
+		let mut carry = 0u64;
+		for (limb, product) in h.iter_mut().zip(d) {
+			let sum = product + carry;
+			*limb = sum as u32 & LIMB_MASK;
+			carry = sum >> 26;
+		}
+		let h0 = h[0] as u64 + carry * 5;
+		h[0] = h0 as u32 & LIMB_MASK;
+		h[1] += (h0 >> 26) as u32;
+	}
+}
+
+impl Zeroize for Poly1305 {
+	fn zeroize(&mut self) {
+		self.r.zeroize();
+		self.s.zeroize();
+		self.accumulator.zeroize();
+		self.buffer.zeroize();
+	}
+}
+
+impl Drop for Poly1305 {
+	fn drop(&mut self) {
+		self.zeroize();
+	}
+}
+
+impl ZeroizeOnDrop for Poly1305 {}
+
+/// Poly1305-AES: the `s` half of the key is `nonce` encrypted under `aes_key`, so a single
+/// `(r, aes_key)` pair can authenticate many messages as long as every nonce is unique.
+pub fn poly1305_aes(
+	message: &[u8],
+	r: &[u8; BLOCK_SIZE],
+	aes_key: &[u8; BLOCK_SIZE],
+	nonce: &[u8; BLOCK_SIZE],
+) -> [u8; TAG_SIZE] {
+	let mut key = [0u8; KEY_SIZE];
+	key[..BLOCK_SIZE].copy_from_slice(r);
+	key[BLOCK_SIZE..].copy_from_slice(&aes_encrypt(*nonce, aes_key));
+
+	let mut poly = Poly1305::new(&key);
+	key.zeroize();
+	poly.update(message);
+	poly.finalize()
+}
+
+/// Carries fully and subtracts p once if the value is at least p, without branching.
+fn fully_reduce(mut h: [u32; 5]) -> [u32; 5] {
+	let mut carry = 0;
+	for limb in h.iter_mut().skip(1) {
+		*limb += carry;
+		carry = *limb >> 26;
+		*limb &= LIMB_MASK;
+	}
+	h[0] += carry * 5;
+	h[1] += h[0] >> 26;
+	h[0] &= LIMB_MASK;
+
+	// g = h + 5 - 2^130, which is h - p.
+	let mut g = [0u32; 5];
+	let mut carry = 5;
+	for (g_limb, h_limb) in g.iter_mut().zip(h) {
+		let sum = h_limb + carry;
+		*g_limb = sum & LIMB_MASK;
+		carry = sum >> 26;
+	}
+	// If h + 5 reached 2^130, h >= p and g is the reduced value.
+	let use_g = carry.wrapping_neg();
+	std::array::from_fn(|i| (g[i] & use_g) | (h[i] & !use_g))
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+	u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_util::hex;
+
+	#[test]
+	fn test_rfc_8439_section_2_5_2() {
+		let key = hex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+		let mut poly = Poly1305::new(&key.try_into().unwrap());
+		poly.update(b"Cryptographic Forum Research Group");
+
+		assert_eq!(poly.finalize().to_vec(), hex("a8061dc1305136c6c22b8baf0c0127a9"));
+	}
+
+	fn tag(key: &str, message: &str) -> Vec<u8> {
+		let mut poly = Poly1305::new(&hex(key).try_into().unwrap());
+		poly.update(&hex(message));
+		poly.finalize().to_vec()
+	}
+
+	#[test]
+	fn test_rfc_8439_appendix_a_3_edge_cases() {
+		// Test vectors #5 to #10, which exercise the final reduction and carries.
+		let r2 = "02000000000000000000000000000000";
+		let r1 = "01000000000000000000000000000000";
+		let zero = "00000000000000000000000000000000";
+		let ones = "ffffffffffffffffffffffffffffffff";
+
+		assert_eq!(tag(&format!("{r2}{zero}"), ones), hex("03000000000000000000000000000000"));
+		assert_eq!(
+			tag(&format!("{r2}{ones}"), "02000000000000000000000000000000"),
+			hex("03000000000000000000000000000000")
+		);
+		assert_eq!(
+			tag(
+				&format!("{r1}{zero}"),
+				&format!("{ones}f0ffffffffffffffffffffffffffffff11000000000000000000000000000000")
+			),
+			hex("05000000000000000000000000000000")
+		);
+		assert_eq!(
+			tag(
+				&format!("{r1}{zero}"),
+				&format!("{ones}fbfefefefefefefefefefefefefefefe01010101010101010101010101010101")
+			),
+			hex(zero)
+		);
+		assert_eq!(
+			tag(&format!("{r2}{zero}"), "fdffffffffffffffffffffffffffffff"),
+			hex("faffffffffffffffffffffffffffffff")
+		);
+	}
+
+	#[test]
+	fn test_incremental_updates() {
+		let key = [7u8; KEY_SIZE];
+		let message: Vec<u8> = (0..=255).collect();
+
+		let mut whole = Poly1305::new(&key);
+		whole.update(&message);
+		let mut pieces = Poly1305::new(&key);
+		message.chunks(13).for_each(|piece| pieces.update(piece));
+
+		assert_eq!(whole.finalize(), pieces.finalize());
+	}
+
+	#[test]
+	fn test_poly1305_aes() {
+		// The first example from Bernstein's Poly1305-AES paper.
+		let tag = poly1305_aes(
+			&hex("f3f6"),
+			&hex("851fc40c3467ac0be05cc20404f3f700").try_into().unwrap(),
+			&hex("ec074c835580741701425b623235add6").try_into().unwrap(),
+			&hex("fb447350c4e868c52ac3275cf9d4327e").try_into().unwrap(),
+		);
+
+		assert_eq!(tag.to_vec(), hex("f4c633c3044fc145f84f335cb81953de"));
+	}
+
+	#[test]
+	fn test_zeroize() {
+		let mut poly = Poly1305::new(&[7u8; KEY_SIZE]);
+		poly.update(b"a partial block");
+		poly.zeroize();
+
+		assert_eq!((poly.r, poly.s, poly.accumulator), ([0; 5], [0; 4], [0; 5]));
+		assert!(poly.buffer.is_empty());
+	}
+}
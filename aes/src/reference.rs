@@ -0,0 +1,417 @@
+//! AES written from scratch, to be read rather than to be fast.
+//!
+//! Everything else in the crate treats AES as a black box from the `aes` crate. This module
+//! opens the box: it implements the cipher step by step as FIPS-197 describes it, for all
+//! three key sizes. Build with `--features reference-aes` to make `aes_encrypt`,
+//! `aes_decrypt` and their batch forms (and so every AES mode in the crate) run on this
+//! code instead.
+//!
+//! The state is 16 bytes in the spec's column-major order: byte `r + 4 * c` is row `r` of
+//! column `c`, so the input bytes fill the first column, then the second, and so on.
+//!
+//! **Do not use this for real secrets.** The S-box lookups index a table with secret data,
+//! which leaks key bits through the cache on most machines.
+//!
+//! The spec is FIPS-197: https://csrc.nist.gov/pubs/fips/197/final
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::{trace::Step, Error, BLOCK_SIZE};
+
+/// The S-box, built at compile time from its definition instead of being pasted in.
+const S_BOX: [u8; 256] = s_box();
+
+/// The inverse S-box, for decryption.
+const INV_S_BOX: [u8; 256] = inv_s_box();
+
+/// AES-256 has the most rounds.
+pub(crate) const MAX_ROUNDS: usize = 14;
+
+/// An AES key, expanded into its round keys.
+///
+/// The schedule lives in a fixed-size array so that using the cipher never allocates.
+pub struct Aes {
+	round_keys: [[u8; BLOCK_SIZE]; MAX_ROUNDS + 1],
+	rounds: usize,
+}
+
+impl Aes {
+	/// Expands a 16, 24 or 32 byte key, for AES-128, AES-192 or AES-256.
+	pub fn new(key: &[u8]) -> Result<Self, Error> {
+		match key.len() {
+			16 | 24 | 32 => Ok(Self::from_key(key)),
+			_ => Err(Error::InvalidKeyLength),
+		}
+	}
+
+	/// AES-128, the variant the rest of the crate uses.
+	pub fn new_128(key: &[u8; BLOCK_SIZE]) -> Self {
+		Self::from_key(key)
+	}
+
+	fn from_key(key: &[u8]) -> Self {
+		let (round_keys, rounds) = expand_key(key, |mut word| {
+			word.iter_mut().for_each(|byte| *byte = S_BOX[*byte as usize]);
+			word
+		});
+		Self { round_keys, rounds }
+	}
+
+	/// 10, 12 or 14, depending on the key size.
+	pub fn rounds(&self) -> usize {
+		self.rounds
+	}
+
+	/// The key schedule: one 16-byte round key for the initial AddRoundKey and one for each
+	/// round.
+	pub fn round_keys(&self) -> &[[u8; BLOCK_SIZE]] {
+		&self.round_keys[..=self.rounds]
+	}
+
+	/// The cipher, FIPS-197 section 5.1.
+	pub fn encrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+		self.encrypt_block_observed(block, |_, _, _| {})
+	}
+
+	/// encrypt_block, calling `observe` with the round number and the state after every
+	/// step. This is what the `trace` module is built on.
+	pub(crate) fn encrypt_block_observed(
+		&self,
+		block: [u8; BLOCK_SIZE],
+		mut observe: impl FnMut(usize, Step, &[u8; BLOCK_SIZE]),
+	) -> [u8; BLOCK_SIZE] {
+		let mut state = block;
+		add_round_key(&mut state, &self.round_keys[0]);
+		observe(0, Step::AddRoundKey, &state);
+
+		for round in 1..=self.rounds {
+			sub_bytes(&mut state);
+			observe(round, Step::SubBytes, &state);
+			shift_rows(&mut state);
+			observe(round, Step::ShiftRows, &state);
+			// The last round skips MixColumns.
+			if round < self.rounds {
+				mix_columns(&mut state);
+				observe(round, Step::MixColumns, &state);
+			}
+			add_round_key(&mut state, &self.round_keys[round]);
+			observe(round, Step::AddRoundKey, &state);
+		}
+
+		state
+	}
+
+	/// The inverse cipher, FIPS-197 section 5.3: the same steps undone in reverse order.
+	pub fn decrypt_block(&self, block: [u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+		let mut state = block;
+		add_round_key(&mut state, &self.round_keys[self.rounds()]);
+
+		for round in (1..self.rounds()).rev() {
+			inv_shift_rows(&mut state);
+			inv_sub_bytes(&mut state);
+			add_round_key(&mut state, &self.round_keys[round]);
+			inv_mix_columns(&mut state);
+		}
+
+		inv_shift_rows(&mut state);
+		inv_sub_bytes(&mut state);
+		add_round_key(&mut state, &self.round_keys[0]);
+
+		state
+	}
+}
+
+impl Zeroize for Aes {
+	fn zeroize(&mut self) {
+		self.round_keys.zeroize();
+	}
+}
+
+impl Drop for Aes {
+	fn drop(&mut self) {
+		self.zeroize();
+	}
+}
+
+impl ZeroizeOnDrop for Aes {}
+
+/// Replaces every byte of the state with its S-box entry. This is the only non-linear step.
+pub fn sub_bytes(state: &mut [u8; BLOCK_SIZE]) {
+	state.iter_mut().for_each(|byte| *byte = S_BOX[*byte as usize]);
+}
+
+/// Opposite of sub_bytes.
+pub fn inv_sub_bytes(state: &mut [u8; BLOCK_SIZE]) {
+	state.iter_mut().for_each(|byte| *byte = INV_S_BOX[*byte as usize]);
+}
+
+/// Rotates row `r` of the state `r` places to the left, so every column of the output
+/// takes one byte from each input column.
+pub fn shift_rows(state: &mut [u8; BLOCK_SIZE]) {
+	let old = *state;
+	for row in 1..4 {
+		for column in 0..4 {
+			state[row + 4 * column] = old[row + 4 * ((column + row) % 4)];
+		}
+	}
+}
+
+/// Opposite of shift_rows.
+pub fn inv_shift_rows(state: &mut [u8; BLOCK_SIZE]) {
+	let old = *state;
+	for row in 1..4 {
+		for column in 0..4 {
+			state[row + 4 * ((column + row) % 4)] = old[row + 4 * column];
+		}
+	}
+}
+
+/// Multiplies every column, read as a polynomial over GF(2^8), by `3x^3 + x^2 + x + 2`.
+/// Together with shift_rows this spreads every input byte over the whole state within two
+/// rounds.
+pub fn mix_columns(state: &mut [u8; BLOCK_SIZE]) {
+	for column in state.chunks_exact_mut(4) {
+		let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+		column[0] = gf_mul(a0, 2) ^ gf_mul(a1, 3) ^ a2 ^ a3;
+		column[1] = a0 ^ gf_mul(a1, 2) ^ gf_mul(a2, 3) ^ a3;
+		column[2] = a0 ^ a1 ^ gf_mul(a2, 2) ^ gf_mul(a3, 3);
+		column[3] = gf_mul(a0, 3) ^ a1 ^ a2 ^ gf_mul(a3, 2);
+	}
+}
+
+/// Opposite of mix_columns: multiplies by the inverse polynomial `11x^3 + 13x^2 + 9x + 14`.
+pub fn inv_mix_columns(state: &mut [u8; BLOCK_SIZE]) {
+	for column in state.chunks_exact_mut(4) {
+		let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+		column[0] = gf_mul(a0, 14) ^ gf_mul(a1, 11) ^ gf_mul(a2, 13) ^ gf_mul(a3, 9);
+		column[1] = gf_mul(a0, 9) ^ gf_mul(a1, 14) ^ gf_mul(a2, 11) ^ gf_mul(a3, 13);
+		column[2] = gf_mul(a0, 13) ^ gf_mul(a1, 9) ^ gf_mul(a2, 14) ^ gf_mul(a3, 11);
+		column[3] = gf_mul(a0, 11) ^ gf_mul(a1, 13) ^ gf_mul(a2, 9) ^ gf_mul(a3, 14);
+	}
+}
+
+/// XORs the round key into the state. It is its own inverse.
+pub fn add_round_key(state: &mut [u8; BLOCK_SIZE], round_key: &[u8; BLOCK_SIZE]) {
+	state.iter_mut().zip(round_key.iter()).for_each(|(x1, &x2)| *x1 ^= x2);
+}
+
+/// The key expansion, FIPS-197 section 5.2.
+///
+/// The schedule is a sequence of 4-byte words. The first `Nk` words are the key itself;
+/// every later word is the word `Nk` places back XORed with the previous word, which at
+/// the start of every key length gets rotated, substituted and mixed with a round constant.
+///
+/// `sub_word` applies the S-box to a word. It is a parameter so the `bitsliced` backend can
+/// share this code without the table lookups. Returns the round keys and the number of
+/// rounds.
+pub(crate) fn expand_key(
+	key: &[u8],
+	sub_word: impl Fn([u8; 4]) -> [u8; 4],
+) -> ([[u8; BLOCK_SIZE]; MAX_ROUNDS + 1], usize) {
+	let key_words = key.len() / 4;
+	let rounds = key_words + 6;
+
+	let mut words = [[0u8; 4]; 4 * (MAX_ROUNDS + 1)];
+	for (word, key_word) in words.iter_mut().zip(key.chunks_exact(4)) {
+		word.copy_from_slice(key_word);
+	}
+	let mut round_constant = 1u8;
+
+	for i in key_words..4 * (rounds + 1) {
+		let mut temp = words[i - 1];
+		if i % key_words == 0 {
+			temp.rotate_left(1);
+			temp = sub_word(temp);
+			temp[0] ^= round_constant;
+			round_constant = gf_mul(round_constant, 2);
+		} else if key_words > 6 && i % key_words == 4 {
+			// AES-256 only: an extra substitution halfway through each key length.
+			temp = sub_word(temp);
+		}
+
+		words[i] = std::array::from_fn(|j| words[i - key_words][j] ^ temp[j]);
+	}
+
+	let round_keys =
+		std::array::from_fn(|round| std::array::from_fn(|j| words[4 * round + j / 4][j % 4]));
+	words.zeroize();
+	(round_keys, rounds)
+}
+
+/// Multiplication in GF(2^8), modulo the AES polynomial `x^8 + x^4 + x^3 + x + 1`.
+///
+/// This is the shift-and-add method: for every bit set in `b`, add (XOR) the matching
+/// multiple of `a`, doubling `a` as we go.
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+	let mut product = 0;
+	while b != 0 {
+		if b & 1 == 1 {
+			product ^= a;
+		}
+		// Doubling overflows past x^7 exactly when the top bit is set; reduce by XORing in
+		// the low byte of the polynomial.
+		let carry = a & 0x80 != 0;
+		a <<= 1;
+		if carry {
+			a ^= 0x1b;
+		}
+		b >>= 1;
+	}
+	product
+}
+
+/// The multiplicative inverse in GF(2^8), with 0 mapped to 0. The non-zero elements form a
+/// group of order 255, so `a^254 = a^-1`.
+const fn gf_inverse(a: u8) -> u8 {
+	let mut result = 1;
+	let mut i = 0;
+	while i < 254 {
+		result = gf_mul(result, a);
+		i += 1;
+	}
+	result
+}
+
+/// FIPS-197 section 5.1.1: the inverse in GF(2^8), followed by an affine transformation.
+const fn s_box() -> [u8; 256] {
+	let mut table = [0u8; 256];
+	let mut i = 0;
+	while i < 256 {
+		let b = gf_inverse(i as u8);
+		table[i] =
+			b ^ b.rotate_left(1) ^ b.rotate_left(2) ^ b.rotate_left(3) ^ b.rotate_left(4) ^ 0x63;
+		i += 1;
+	}
+	table
+}
+
+const fn inv_s_box() -> [u8; 256] {
+	let mut table = [0u8; 256];
+	let mut i = 0;
+	while i < 256 {
+		table[S_BOX[i] as usize] = i as u8;
+		i += 1;
+	}
+	table
+}
+
+#[cfg(test)]
+mod tests {
+	use aes::{
+		cipher::{
+			consts::U16, generic_array::GenericArray, BlockDecrypt, BlockEncrypt, BlockSizeUser,
+			KeyInit,
+		},
+		Aes128, Aes192, Aes256,
+	};
+	use rand::{rngs::StdRng, Rng, SeedableRng};
+
+	use super::*;
+	use crate::test_util::hex;
+
+	fn block(s: &str) -> [u8; BLOCK_SIZE] {
+		hex(s).try_into().unwrap()
+	}
+
+	#[test]
+	fn test_s_box() {
+		// Spot checks against the table in FIPS-197 figure 7.
+		assert_eq!(S_BOX[0x00], 0x63);
+		assert_eq!(S_BOX[0x53], 0xed);
+		assert_eq!(S_BOX[0xff], 0x16);
+		assert_eq!(INV_S_BOX[0x63], 0x00);
+	}
+
+	#[test]
+	fn test_fips_197_appendix_c() {
+		let plain_text = block("00112233445566778899aabbccddeeff");
+		let vectors = [
+			("000102030405060708090a0b0c0d0e0f", "69c4e0d86a7b0430d8cdb78070b4c55a", 10),
+			(
+				"000102030405060708090a0b0c0d0e0f1011121314151617",
+				"dda97ca4864cdfe06eaf70a0ec0d7191",
+				12,
+			),
+			(
+				"000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+				"8ea2b7ca516745bfeafc49904b496089",
+				14,
+			),
+		];
+
+		for (key, cipher_text, rounds) in vectors {
+			let aes = Aes::new(&hex(key)).unwrap();
+			assert_eq!(aes.rounds(), rounds);
+			assert_eq!(aes.encrypt_block(plain_text), block(cipher_text));
+			assert_eq!(aes.decrypt_block(block(cipher_text)), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_fips_197_appendix_a_key_expansion() {
+		let aes = Aes::new_128(&block("2b7e151628aed2a6abf7158809cf4f3c"));
+
+		assert_eq!(aes.round_keys()[1], block("a0fafe1788542cb123a339392a6c7605"));
+		assert_eq!(aes.round_keys()[10], block("d014f9a8c9ee2589e13f0cc8b6630ca6"));
+	}
+
+	#[test]
+	fn test_invalid_key_length() {
+		assert!(matches!(Aes::new(&[0u8; 20]), Err(Error::InvalidKeyLength)));
+	}
+
+	#[test]
+	fn test_steps_invert() {
+		let mut rng = StdRng::seed_from_u64(33);
+		let original: [u8; BLOCK_SIZE] = rng.gen();
+		let mut state = original;
+
+		shift_rows(&mut state);
+		inv_shift_rows(&mut state);
+		mix_columns(&mut state);
+		inv_mix_columns(&mut state);
+		sub_bytes(&mut state);
+		inv_sub_bytes(&mut state);
+
+		assert_eq!(state, original);
+	}
+
+	/// Encrypts and decrypts random blocks under random keys with both implementations.
+	fn cross_check<C>(key_len: usize)
+	where
+		C: KeyInit + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+	{
+		let mut rng = StdRng::seed_from_u64(key_len as u64);
+
+		for _ in 0..100 {
+			let key: Vec<u8> = (0..key_len).map(|_| rng.gen()).collect();
+			let plain_text: [u8; BLOCK_SIZE] = rng.gen();
+
+			let ours = Aes::new(&key).unwrap();
+			let theirs = C::new_from_slice(&key).unwrap();
+
+			let mut expected = GenericArray::from(plain_text);
+			theirs.encrypt_block(&mut expected);
+			let cipher_text = ours.encrypt_block(plain_text);
+			assert_eq!(cipher_text, <[u8; BLOCK_SIZE]>::from(expected));
+
+			let mut expected = GenericArray::from(cipher_text);
+			theirs.decrypt_block(&mut expected);
+			assert_eq!(ours.decrypt_block(cipher_text), <[u8; BLOCK_SIZE]>::from(expected));
+		}
+	}
+
+	#[test]
+	fn test_matches_aes_crate() {
+		cross_check::<Aes128>(16);
+		cross_check::<Aes192>(24);
+		cross_check::<Aes256>(32);
+	}
+
+	#[test]
+	fn test_zeroize() {
+		let mut aes = Aes::new_128(&[7u8; BLOCK_SIZE]);
+		aes.zeroize();
+
+		assert_eq!(aes.round_keys, [[0u8; BLOCK_SIZE]; MAX_ROUNDS + 1]);
+	}
+}
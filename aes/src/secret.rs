@@ -0,0 +1,173 @@
+//! Key material that cleans up after itself.
+//!
+//! A plain `[u8; 16]` is `Copy`, so every function call and struct field quietly makes
+//! another copy of the key, and none of them are ever wiped. When the memory is freed the
+//! key stays in it until something else happens to overwrite it, where a core dump, a swap
+//! file or a memory disclosure bug can find it.
+//!
+//! `SecretKey` is not `Copy`: copies have to be made with an explicit `clone`, and each
+//! one overwrites itself with zeros when dropped. Every mode takes its key as a
+//! `&SecretKey`. The block cipher helpers like `aes_encrypt` still take plain arrays, since
+//! they sit below the modes and are handed `key.expose_secret()`.
+//!
+//! This is best effort. The compiler is free to leave copies in registers or on the stack,
+//! and a `Vec` that grows leaves its old allocation behind, so this narrows the window
+//! rather than closing it.
+use std::fmt;
+
+use rand::{CryptoRng, Rng};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::{gcm::constant_time_eq, Error, BLOCK_SIZE};
+
+/// A secret key of `N` bytes, by default an AES-128 key.
+pub struct SecretKey<const N: usize = BLOCK_SIZE> {
+	bytes: [u8; N],
+}
+
+impl<const N: usize> SecretKey<N> {
+	pub const fn new(bytes: [u8; N]) -> Self {
+		Self { bytes }
+	}
+
+	/// A fresh key from the thread-local random number generator.
+	pub fn random() -> Self {
+		Self::random_with_rng(&mut rand::thread_rng())
+	}
+
+	/// A fresh key drawn from `rng`.
+	pub fn random_with_rng(rng: &mut impl CryptoRng) -> Self {
+		let mut bytes = [0u8; N];
+		rng.fill(&mut bytes[..]);
+		Self { bytes }
+	}
+
+	/// Fails with `Error::InvalidKeyLength` unless `bytes` is exactly `N` bytes long.
+	pub fn from_slice(bytes: &[u8]) -> Result<Self, Error> {
+		let bytes = bytes.try_into().map_err(|_| Error::InvalidKeyLength)?;
+		Ok(Self { bytes })
+	}
+
+	/// The raw key. The name is meant to stand out in review: any use of the bytes other
+	/// than handing them to a cipher deserves a second look.
+	pub fn expose_secret(&self) -> &[u8; N] {
+		&self.bytes
+	}
+
+	/// Mutable access, for filling in a key where it sits, e.g. as a KDF's output buffer.
+	pub fn expose_secret_mut(&mut self) -> &mut [u8; N] {
+		&mut self.bytes
+	}
+}
+
+impl<const N: usize> From<[u8; N]> for SecretKey<N> {
+	fn from(bytes: [u8; N]) -> Self {
+		Self::new(bytes)
+	}
+}
+
+impl<const N: usize> Clone for SecretKey<N> {
+	fn clone(&self) -> Self {
+		Self::new(self.bytes)
+	}
+}
+
+/// Compares in constant time, so that checking a guessed key does not leak how much of it
+/// was right.
+impl<const N: usize> PartialEq for SecretKey<N> {
+	fn eq(&self, other: &Self) -> bool {
+		constant_time_eq(&self.bytes, &other.bytes)
+	}
+}
+
+impl<const N: usize> Eq for SecretKey<N> {}
+
+/// Never prints the key, so that it can't end up in logs or panic messages.
+impl<const N: usize> fmt::Debug for SecretKey<N> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "SecretKey<{}>([REDACTED])", N)
+	}
+}
+
+impl<const N: usize> Zeroize for SecretKey<N> {
+	fn zeroize(&mut self) {
+		self.bytes.zeroize();
+	}
+}
+
+impl<const N: usize> Drop for SecretKey<N> {
+	fn drop(&mut self) {
+		self.zeroize();
+	}
+}
+
+impl<const N: usize> ZeroizeOnDrop for SecretKey<N> {}
+
+/// Makes room for `additional` more bytes in `data` without leaving a copy of its contents
+/// behind: if the buffer has to grow, the contents are moved to a new allocation by hand
+/// and the old one is wiped before it is freed.
+pub(crate) fn reserve_zeroizing(data: &mut Vec<u8>, additional: usize) {
+	if data.capacity() - data.len() >= additional {
+		return;
+	}
+
+	let mut grown = Vec::with_capacity(data.len() + additional);
+	grown.extend_from_slice(data);
+	data.zeroize();
+	*data = grown;
+}
+
+/// Appends `tail` to `data` with `reserve_zeroizing`, then wipes `tail`.
+pub(crate) fn append_zeroizing(data: &mut Vec<u8>, mut tail: Vec<u8>) {
+	reserve_zeroizing(data, tail.len());
+	data.extend_from_slice(&tail);
+	tail.zeroize();
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_debug_is_redacted() {
+		let key = SecretKey::new([0xAB; BLOCK_SIZE]);
+		let debug = format!("{:?}", key);
+
+		assert_eq!(debug, "SecretKey<16>([REDACTED])");
+		assert!(!debug.contains("171"));
+	}
+
+	#[test]
+	fn test_from_slice() {
+		assert_eq!(
+			SecretKey::<4>::from_slice(&[1, 2, 3, 4]).unwrap(),
+			SecretKey::new([1, 2, 3, 4])
+		);
+		assert!(matches!(SecretKey::<4>::from_slice(&[1, 2, 3]), Err(Error::InvalidKeyLength)));
+	}
+
+	#[test]
+	fn test_random_keys_differ() {
+		assert_ne!(SecretKey::<BLOCK_SIZE>::random(), SecretKey::random());
+	}
+
+	#[test]
+	fn test_zeroize() {
+		let mut key = SecretKey::new([7u8; BLOCK_SIZE]);
+		key.zeroize();
+
+		assert_eq!(key.bytes, [0u8; BLOCK_SIZE]);
+	}
+
+	#[test]
+	fn test_append_zeroizing() {
+		let mut data = Vec::with_capacity(2);
+		data.extend_from_slice(b"ab");
+		append_zeroizing(&mut data, b"cdef".to_vec());
+		assert_eq!(data, b"abcdef");
+
+		reserve_zeroizing(&mut data, 100);
+		assert_eq!(data, b"abcdef");
+		assert!(data.capacity() >= 106);
+	}
+}
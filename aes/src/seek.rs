@@ -0,0 +1,257 @@
+//! Random access into CTR ciphertexts.
+//!
+//! In counter mode the keystream for block `i` only depends on the nonce and `i`, so to
+//! read some bytes from the middle of a message we just compute the keystream blocks that
+//! cover them. There is no need to decrypt everything before, like CBC would have to.
+//!
+//! The one wrinkle is the padding that `ctr_encrypt` adds. To know where the plaintext ends
+//! we decrypt the very last byte of the ciphertext, which tells us the padding length.
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{
+	apply_ctr_keystream, ctr_keystream, incremental::CTR_NONCE_SIZE, Error, SecretKey, BLOCK_SIZE,
+};
+
+/// Decrypts the plaintext bytes `[offset, offset + len)` of a `ctr_encrypt` ciphertext.
+///
+/// Like reading a file, a range that runs past the end of the plaintext is cut short, and
+/// one that starts past the end is empty.
+pub fn ctr_decrypt_range(
+	cipher_text: &[u8],
+	offset: usize,
+	len: usize,
+	key: &SecretKey,
+) -> Result<Vec<u8>, Error> {
+	if cipher_text.len() <= CTR_NONCE_SIZE {
+		return Err(Error::Truncated);
+	}
+	let (nonce_bytes, body) = cipher_text.split_at(CTR_NONCE_SIZE);
+	let nonce = u64::from_be_bytes(nonce_bytes.try_into().unwrap());
+
+	let mut last_byte = [body[body.len() - 1]];
+	apply_keystream_at(&mut last_byte, nonce, body.len() as u64 - 1, key);
+	let plain_len = plain_len(body.len() as u64, last_byte[0]) as usize;
+
+	let start = offset.min(plain_len);
+	let end = offset.saturating_add(len).min(plain_len);
+
+	let mut plain_text = body[start..end].to_vec();
+	apply_keystream_at(&mut plain_text, nonce, start as u64, key);
+
+	Ok(plain_text)
+}
+
+/// Reads the plaintext of a `ctr_encrypt` ciphertext, with seeking.
+///
+/// Seeking is free: only the ciphertext that is actually read gets decrypted. That makes
+/// it possible to, say, read the index at the end of a large encrypted archive without
+/// decrypting the archive.
+pub struct CtrReader<R: Read + Seek> {
+	inner: R,
+	key: SecretKey,
+	nonce: u64,
+	plain_len: u64,
+	position: u64,
+}
+
+impl<R: Read + Seek> CtrReader<R> {
+	/// Reads the nonce and the final block to learn the length of the plaintext.
+	pub fn new(mut inner: R, key: &SecretKey) -> io::Result<Self> {
+		let mut nonce_bytes = [0u8; CTR_NONCE_SIZE];
+		inner.seek(SeekFrom::Start(0))?;
+		inner.read_exact(&mut nonce_bytes).map_err(|_| truncated())?;
+		let nonce = u64::from_be_bytes(nonce_bytes);
+
+		let body_len = inner.seek(SeekFrom::End(0))? - CTR_NONCE_SIZE as u64;
+		if body_len == 0 {
+			return Err(truncated());
+		}
+
+		let mut last_byte = [0u8];
+		inner.seek(SeekFrom::End(-1))?;
+		inner.read_exact(&mut last_byte)?;
+		apply_keystream_at(&mut last_byte, nonce, body_len - 1, key);
+
+		let plain_len = plain_len(body_len, last_byte[0]);
+		Ok(Self { inner, key: key.clone(), nonce, plain_len, position: 0 })
+	}
+
+	/// Length of the plaintext, without the padding.
+	pub fn len(&self) -> u64 {
+		self.plain_len
+	}
+
+	/// Whether the plaintext is empty.
+	pub fn is_empty(&self) -> bool {
+		self.plain_len == 0
+	}
+
+	/// Hands back the inner reader.
+	pub fn into_inner(self) -> R {
+		self.inner
+	}
+}
+
+impl<R: Read + Seek> Read for CtrReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let remaining = self.plain_len.saturating_sub(self.position);
+		let len = (buf.len() as u64).min(remaining) as usize;
+		if len == 0 {
+			return Ok(0);
+		}
+
+		self.inner.seek(SeekFrom::Start(CTR_NONCE_SIZE as u64 + self.position))?;
+		let read = self.inner.read(&mut buf[..len])?;
+		apply_keystream_at(&mut buf[..read], self.nonce, self.position, &self.key);
+		self.position += read as u64;
+
+		Ok(read)
+	}
+}
+
+impl<R: Read + Seek> Seek for CtrReader<R> {
+	/// Positions are plaintext offsets. Seeking past the end is allowed, just like for a
+	/// file, and reads from there return nothing.
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_position = match pos {
+			SeekFrom::Start(offset) => Some(offset),
+			SeekFrom::End(delta) => self.plain_len.checked_add_signed(delta),
+			SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+		};
+
+		match new_position {
+			Some(position) => {
+				self.position = position;
+				Ok(position)
+			},
+			None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")),
+		}
+	}
+}
+
+/// XORs `data`, which starts at plaintext offset `offset`, with the matching keystream.
+/// Only the keystream blocks that overlap the data are computed.
+fn apply_keystream_at(data: &mut [u8], nonce: u64, offset: u64, key: &SecretKey) {
+	let mut counter = offset / BLOCK_SIZE as u64;
+	let skip = (offset % BLOCK_SIZE as u64) as usize;
+
+	// A range that starts in the middle of a block needs only the end of its keystream.
+	let mut data = data;
+	if skip != 0 {
+		let keystream = ctr_keystream(nonce, counter, key);
+		let (head, rest) = data.split_at_mut((BLOCK_SIZE - skip).min(data.len()));
+		for (byte, key_byte) in head.iter_mut().zip(&keystream[skip..]) {
+			*byte ^= key_byte;
+		}
+		data = rest;
+		counter += 1;
+	}
+
+	apply_ctr_keystream(data, nonce, counter, key);
+}
+
+/// Works out the plaintext length from the decrypted last byte, the same way `un_pad`
+/// does: if that byte can't be a padding length, nothing is removed.
+fn plain_len(body_len: u64, last_byte: u8) -> u64 {
+	let pad_len = last_byte as u64;
+	if pad_len <= BLOCK_SIZE as u64 && body_len >= pad_len {
+		body_len - pad_len
+	} else {
+		body_len
+	}
+}
+
+fn truncated() -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, Error::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+	use crate::ctr_encrypt;
+	use crate::test_util::sample;
+
+	const KEY: SecretKey = SecretKey::new([3u8; BLOCK_SIZE]);
+
+	#[test]
+	fn test_ctr_decrypt_range_every_range() {
+		let plain_text = sample(3 * BLOCK_SIZE + 5);
+		let cipher_text = ctr_encrypt(plain_text.clone(), &KEY);
+
+		for offset in 0..=plain_text.len() {
+			for len in 0..=plain_text.len() - offset {
+				let decrypted = ctr_decrypt_range(&cipher_text, offset, len, &KEY).unwrap();
+				assert_eq!(decrypted, &plain_text[offset..offset + len]);
+			}
+		}
+	}
+
+	#[test]
+	fn test_ctr_decrypt_range_past_the_end() {
+		let plain_text = sample(20);
+		let cipher_text = ctr_encrypt(plain_text.clone(), &KEY);
+
+		assert_eq!(ctr_decrypt_range(&cipher_text, 15, 100, &KEY).unwrap(), &plain_text[15..]);
+		assert!(ctr_decrypt_range(&cipher_text, 25, 5, &KEY).unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_ctr_decrypt_range_truncated() {
+		let result = ctr_decrypt_range(&[0u8; CTR_NONCE_SIZE], 0, 1, &KEY);
+		assert!(matches!(result, Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_ctr_reader_seek_and_read() {
+		let plain_text = sample(1000);
+		let cipher_text = ctr_encrypt(plain_text.clone(), &KEY);
+
+		let mut reader = CtrReader::new(Cursor::new(cipher_text), &KEY).unwrap();
+		assert_eq!(reader.len(), 1000);
+
+		let mut buf = [0u8; 50];
+		reader.seek(SeekFrom::Start(123)).unwrap();
+		reader.read_exact(&mut buf).unwrap();
+		assert_eq!(buf, plain_text[123..173]);
+
+		reader.seek(SeekFrom::Current(-100)).unwrap();
+		reader.read_exact(&mut buf).unwrap();
+		assert_eq!(buf, plain_text[73..123]);
+
+		reader.seek(SeekFrom::End(-10)).unwrap();
+		let mut rest = Vec::new();
+		reader.read_to_end(&mut rest).unwrap();
+		assert_eq!(rest, plain_text[990..]);
+	}
+
+	#[test]
+	fn test_ctr_reader_reads_everything() {
+		for len in [0, 1, BLOCK_SIZE, 100] {
+			let plain_text = sample(len);
+			let cipher_text = ctr_encrypt(plain_text.clone(), &KEY);
+
+			let mut reader = CtrReader::new(Cursor::new(cipher_text), &KEY).unwrap();
+			let mut decrypted = Vec::new();
+			reader.read_to_end(&mut decrypted).unwrap();
+
+			assert_eq!(decrypted, plain_text);
+		}
+	}
+
+	#[test]
+	fn test_ctr_reader_seek_errors() {
+		let cipher_text = ctr_encrypt(sample(10), &KEY);
+		let mut reader = CtrReader::new(Cursor::new(cipher_text), &KEY).unwrap();
+
+		assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+		assert_eq!(reader.seek(SeekFrom::End(5)).unwrap(), 15);
+		assert_eq!(reader.read(&mut [0u8; 4]).unwrap(), 0);
+	}
+
+	#[test]
+	fn test_ctr_reader_truncated() {
+		assert!(CtrReader::new(Cursor::new(vec![1, 2, 3]), &KEY).is_err());
+	}
+}
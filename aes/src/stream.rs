@@ -0,0 +1,369 @@
+//! Streaming authenticated encryption for data that doesn't fit in memory.
+//!
+//! All the modes in the crate root take the whole message as a `Vec<u8>`. That is fine for
+//! "Hello, world!", but not for a 50 GB backup. The obvious fix, encrypting the data in
+//! chunks with GCM, is subtly broken: an attacker can drop chunks from the end, swap two
+//! chunks, or repeat one, and every chunk still authenticates on its own.
+//!
+//! The STREAM construction (Hoang, Reyhanitabar, Rogaway, Vizár 2015) closes those gaps by
+//! deriving each chunk's nonce from its position:
+//!
+//! `nonce = prefix (7 bytes) | segment counter (4 bytes, big-endian) | last flag (1 byte)`
+//!
+//! The counter makes a segment only valid at its own index, which rules out reordering and
+//! duplication. The last flag is set on the final segment only, so cutting the stream at a
+//! segment boundary leaves a final segment that fails to authenticate.
+//!
+//! The random prefix is written in front of the first segment, like the IV in `cbc_encrypt`.
+use std::io::{self, Read, Write};
+
+use rand::{CryptoRng, Rng};
+
+use crate::{
+	gcm::{gcm_decrypt, gcm_encrypt, NONCE_SIZE, TAG_SIZE},
+	Error, SecretKey,
+};
+
+/// Plaintext bytes per segment. Every segment except the last one is exactly this long.
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Random bytes at the start of every nonce. Seven bytes leaves room for the counter and
+/// the last flag in GCM's 12-byte nonce.
+pub const NONCE_PREFIX_SIZE: usize = 7;
+
+/// Encrypts a stream one segment at a time.
+///
+/// Segments can have any length, but they must be decrypted with the same boundaries.
+/// Calling `encrypt_last` consumes the encryptor, so nothing can be appended after the
+/// final segment.
+pub struct StreamEncryptor {
+	key: SecretKey,
+	nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+	counter: SegmentCounter,
+}
+
+impl StreamEncryptor {
+	/// The nonce prefix must never be reused with the same key.
+	pub fn new(key: &SecretKey, nonce_prefix: [u8; NONCE_PREFIX_SIZE]) -> Self {
+		Self { key: key.clone(), nonce_prefix, counter: SegmentCounter::default() }
+	}
+
+	/// Encrypts a segment that is not the last one.
+	pub fn encrypt_next(&mut self, segment: &[u8]) -> Result<Vec<u8>, Error> {
+		let counter = self.counter.next()?;
+		gcm_encrypt(segment, &[], &segment_nonce(&self.nonce_prefix, counter, false), &self.key)
+	}
+
+	/// Encrypts the final segment, which may be empty.
+	pub fn encrypt_last(mut self, segment: &[u8]) -> Result<Vec<u8>, Error> {
+		let counter = self.counter.next()?;
+		gcm_encrypt(segment, &[], &segment_nonce(&self.nonce_prefix, counter, true), &self.key)
+	}
+}
+
+/// Opposite of StreamEncryptor.
+pub struct StreamDecryptor {
+	key: SecretKey,
+	nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+	counter: SegmentCounter,
+}
+
+impl StreamDecryptor {
+	pub fn new(key: &SecretKey, nonce_prefix: [u8; NONCE_PREFIX_SIZE]) -> Self {
+		Self { key: key.clone(), nonce_prefix, counter: SegmentCounter::default() }
+	}
+
+	/// Decrypts a segment that is not the last one.
+	pub fn decrypt_next(&mut self, segment: &[u8]) -> Result<Vec<u8>, Error> {
+		let counter = self.counter.next()?;
+		gcm_decrypt(segment, &[], &segment_nonce(&self.nonce_prefix, counter, false), &self.key)
+	}
+
+	/// Decrypts the final segment.
+	///
+	/// If the segment turns out to be a valid _non-final_ segment, the stream was cut
+	/// short and we report that instead of a generic authentication failure.
+	pub fn decrypt_last(mut self, segment: &[u8]) -> Result<Vec<u8>, Error> {
+		let counter = self.counter.next()?;
+		let prefix = self.nonce_prefix;
+		match gcm_decrypt(segment, &[], &segment_nonce(&prefix, counter, true), &self.key) {
+			Err(Error::Authentication)
+				if gcm_decrypt(segment, &[], &segment_nonce(&prefix, counter, false), &self.key)
+					.is_ok() =>
+			{
+				Err(Error::Truncated)
+			},
+			result => result,
+		}
+	}
+}
+
+/// Numbers the segments of one stream.
+#[derive(Default)]
+struct SegmentCounter {
+	next: u32,
+	exhausted: bool,
+}
+
+impl SegmentCounter {
+	/// Hands out the current counter and advances it. Once the counter has wrapped, every
+	/// further segment would reuse a nonce, so we refuse instead.
+	fn next(&mut self) -> Result<u32, Error> {
+		if self.exhausted {
+			return Err(Error::StreamTooLong);
+		}
+		let current = self.next;
+		match self.next.checked_add(1) {
+			Some(next) => self.next = next,
+			None => self.exhausted = true,
+		}
+		Ok(current)
+	}
+}
+
+/// Builds `prefix | counter | last flag`.
+fn segment_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> [u8; NONCE_SIZE] {
+	let mut nonce = [0u8; NONCE_SIZE];
+	nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+	nonce[NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&counter.to_be_bytes());
+	nonce[NONCE_SIZE - 1] = last as u8;
+	nonce
+}
+
+/// Encrypts everything `reader` produces into `writer`, holding at most one segment in
+/// memory at a time. A random nonce prefix is generated and written first.
+///
+/// Returns the number of plaintext bytes that were encrypted.
+pub fn encrypt_stream<R: Read, W: Write>(
+	reader: R,
+	writer: W,
+	key: &SecretKey,
+) -> Result<u64, Error> {
+	encrypt_stream_with_rng(reader, writer, key, &mut rand::thread_rng())
+}
+
+/// Like `encrypt_stream`, but the nonce prefix is drawn from `rng`.
+pub fn encrypt_stream_with_rng<R: Read, W: Write>(
+	mut reader: R,
+	mut writer: W,
+	key: &SecretKey,
+	rng: &mut impl CryptoRng,
+) -> Result<u64, Error> {
+	let nonce_prefix: [u8; NONCE_PREFIX_SIZE] = rng.gen();
+	writer.write_all(&nonce_prefix)?;
+
+	let mut encryptor = Some(StreamEncryptor::new(key, nonce_prefix));
+	let mut total = 0u64;
+
+	for_each_segment(&mut reader, SEGMENT_SIZE, |segment, last| {
+		let encrypted = if last {
+			// for_each_segment calls us with `last` exactly once, at the very end.
+			encryptor.take().expect("only one last segment").encrypt_last(segment)?
+		} else {
+			encryptor.as_mut().expect("no segments after the last").encrypt_next(segment)?
+		};
+		writer.write_all(&encrypted)?;
+		total += segment.len() as u64;
+		Ok(())
+	})?;
+
+	writer.flush()?;
+	Ok(total)
+}
+
+/// Opposite of encrypt_stream.
+///
+/// Segments are authenticated and written one by one. If an error is returned, whatever
+/// has already been written to `writer` must be discarded: the stream as a whole has
+/// not been authenticated.
+///
+/// Returns the number of plaintext bytes that were written.
+pub fn decrypt_stream<R: Read, W: Write>(
+	mut reader: R,
+	mut writer: W,
+	key: &SecretKey,
+) -> Result<u64, Error> {
+	let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+	if read_full(&mut reader, &mut nonce_prefix)? < NONCE_PREFIX_SIZE {
+		return Err(Error::Truncated);
+	}
+
+	let mut decryptor = Some(StreamDecryptor::new(key, nonce_prefix));
+	let mut total = 0u64;
+
+	for_each_segment(&mut reader, SEGMENT_SIZE + TAG_SIZE, |segment, last| {
+		let decrypted = if last {
+			decryptor.take().expect("only one last segment").decrypt_last(segment)?
+		} else {
+			decryptor.as_mut().expect("no segments after the last").decrypt_next(segment)?
+		};
+		writer.write_all(&decrypted)?;
+		total += decrypted.len() as u64;
+		Ok(())
+	})?;
+
+	writer.flush()?;
+	Ok(total)
+}
+
+/// Splits the reader into `segment_len` pieces and tells `f` which one is the last.
+///
+/// We can only know a full segment is the last one by trying to read past it, so we read
+/// one extra byte and carry it over to the front of the next segment.
+fn for_each_segment<R: Read>(
+	reader: &mut R,
+	segment_len: usize,
+	mut f: impl FnMut(&[u8], bool) -> Result<(), Error>,
+) -> Result<(), Error> {
+	let mut buffer = vec![0u8; segment_len + 1];
+	let mut carried = 0;
+
+	loop {
+		let filled = carried + read_full(reader, &mut buffer[carried..])?;
+		if filled <= segment_len {
+			return f(&buffer[..filled], true);
+		}
+		f(&buffer[..segment_len], false)?;
+		buffer[0] = buffer[segment_len];
+		carried = 1;
+	}
+}
+
+/// Like `read_exact`, but a short read at the end of the input is not an error.
+/// Returns how many bytes were read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+	let mut filled = 0;
+	while filled < buffer.len() {
+		match reader.read(&mut buffer[filled..]) {
+			Ok(0) => break,
+			Ok(n) => filled += n,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::BLOCK_SIZE;
+	use crate::test_util::sample;
+
+	const ENCRYPTED_SEGMENT_SIZE: usize = SEGMENT_SIZE + TAG_SIZE;
+
+	fn encrypt(plain_text: &[u8], key: &SecretKey) -> Vec<u8> {
+		let mut cipher_text = Vec::new();
+		encrypt_stream(plain_text, &mut cipher_text, key).unwrap();
+		cipher_text
+	}
+
+	fn decrypt(cipher_text: &[u8], key: &SecretKey) -> Result<Vec<u8>, Error> {
+		let mut plain_text = Vec::new();
+		decrypt_stream(cipher_text, &mut plain_text, key)?;
+		Ok(plain_text)
+	}
+
+	/// Splits a stream into its header and encrypted segments.
+	fn split(cipher_text: &[u8]) -> (&[u8], Vec<&[u8]>) {
+		let (header, body) = cipher_text.split_at(NONCE_PREFIX_SIZE);
+		(header, body.chunks(ENCRYPTED_SEGMENT_SIZE).collect())
+	}
+
+	#[test]
+	fn test_stream_encrypt_decrypt() {
+		let key = SecretKey::new([5u8; BLOCK_SIZE]);
+		for len in [0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE + 7] {
+			let plain_text = sample(len);
+
+			let cipher_text = encrypt(&plain_text, &key);
+			let segments = len.div_ceil(SEGMENT_SIZE).max(1);
+			assert_eq!(cipher_text.len(), NONCE_PREFIX_SIZE + len + segments * TAG_SIZE);
+
+			assert_eq!(decrypt(&cipher_text, &key).unwrap(), plain_text);
+		}
+	}
+
+	#[test]
+	fn test_stream_wrong_key() {
+		let cipher_text = encrypt(b"Hello, world!", &SecretKey::new([5u8; BLOCK_SIZE]));
+		assert!(matches!(
+			decrypt(&cipher_text, &SecretKey::new([6u8; BLOCK_SIZE])),
+			Err(Error::Authentication)
+		));
+	}
+
+	#[test]
+	fn test_stream_detects_truncation_at_segment_boundary() {
+		let key = SecretKey::new([5u8; BLOCK_SIZE]);
+		let cipher_text = encrypt(&sample(3 * SEGMENT_SIZE + 7), &key);
+
+		let truncated = &cipher_text[..NONCE_PREFIX_SIZE + 2 * ENCRYPTED_SEGMENT_SIZE];
+
+		assert!(matches!(decrypt(truncated, &key), Err(Error::Truncated)));
+	}
+
+	#[test]
+	fn test_stream_detects_truncation_inside_segment() {
+		let key = SecretKey::new([5u8; BLOCK_SIZE]);
+		let cipher_text = encrypt(&sample(2 * SEGMENT_SIZE), &key);
+
+		let truncated = &cipher_text[..cipher_text.len() - 1];
+
+		assert!(matches!(decrypt(truncated, &key), Err(Error::Authentication)));
+	}
+
+	#[test]
+	fn test_stream_detects_missing_header() {
+		assert!(matches!(
+			decrypt(&[1, 2, 3], &SecretKey::new([5u8; BLOCK_SIZE])),
+			Err(Error::Truncated)
+		));
+	}
+
+	#[test]
+	fn test_stream_detects_reordering() {
+		let key = SecretKey::new([5u8; BLOCK_SIZE]);
+		let cipher_text = encrypt(&sample(3 * SEGMENT_SIZE + 7), &key);
+		let (header, segments) = split(&cipher_text);
+
+		let reordered = [header, segments[1], segments[0], segments[2], segments[3]].concat();
+
+		assert!(matches!(decrypt(&reordered, &key), Err(Error::Authentication)));
+	}
+
+	#[test]
+	fn test_stream_detects_duplication() {
+		let key = SecretKey::new([5u8; BLOCK_SIZE]);
+		let cipher_text = encrypt(&sample(2 * SEGMENT_SIZE + 7), &key);
+		let (header, segments) = split(&cipher_text);
+
+		let duplicated = [header, segments[0], segments[0], segments[1], segments[2]].concat();
+
+		assert!(matches!(decrypt(&duplicated, &key), Err(Error::Authentication)));
+	}
+
+	#[test]
+	fn test_stream_segment_api() {
+		let key = SecretKey::new([5u8; BLOCK_SIZE]);
+		let prefix = [9u8; NONCE_PREFIX_SIZE];
+
+		let mut encryptor = StreamEncryptor::new(&key, prefix);
+		let first = encryptor.encrypt_next(b"Hello, ").unwrap();
+		let last = encryptor.encrypt_last(b"world!").unwrap();
+
+		let mut decryptor = StreamDecryptor::new(&key, prefix);
+		assert_eq!(decryptor.decrypt_next(&first).unwrap(), b"Hello, ");
+		assert_eq!(decryptor.decrypt_last(&last).unwrap(), b"world!");
+	}
+
+	#[test]
+	fn test_stream_counter_exhaustion() {
+		let mut encryptor =
+			StreamEncryptor::new(&SecretKey::new([5u8; BLOCK_SIZE]), [9u8; NONCE_PREFIX_SIZE]);
+		encryptor.counter.next = u32::MAX;
+
+		assert!(encryptor.encrypt_next(b"the last possible segment").is_ok());
+		assert!(matches!(encryptor.encrypt_next(b"one too many"), Err(Error::StreamTooLong)));
+	}
+}
@@ -0,0 +1,28 @@
+//! Helpers shared by the unit tests.
+use std::path::PathBuf;
+
+use crate::{encoding::hex_decode, password::Kdf};
+
+/// Decodes a hex test vector.
+pub(crate) fn hex(s: &str) -> Vec<u8> {
+	hex_decode(s).unwrap()
+}
+
+/// `len` bytes of plaintext that don't repeat with the block size.
+pub(crate) fn sample(len: usize) -> Vec<u8> {
+	(0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// A path in the temporary directory, unique to this process, with nothing at it yet.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+	let path = std::env::temp_dir().join(format!("aes_modes_{}_{}", std::process::id(), name));
+	let _ = std::fs::remove_file(&path);
+	path
+}
+
+/// One cheap setting per KDF, so that tests going through them run quickly.
+pub(crate) const FAST_KDFS: [Kdf; 3] = [
+	Kdf::Pbkdf2 { iterations: 1000 },
+	Kdf::Scrypt { log_n: 10, r: 8, p: 1 },
+	Kdf::Argon2id { memory_kib: 64, iterations: 2, parallelism: 1 },
+];
@@ -0,0 +1,183 @@
+//! Watching AES work, one step at a time.
+//!
+//! `aes_encrypt_trace` runs the from-scratch cipher in the `reference` module and records
+//! the state after every step of every round, together with the round keys. Printing the
+//! trace gives the same layout as the example vectors in FIPS-197 Appendix C, so it can be
+//! held right next to the spec:
+//!
+//! ```text
+//! round[ 0].input    3243f6a8885a308d313198a2e0370734
+//! round[ 0].k_sch    2b7e151628aed2a6abf7158809cf4f3c
+//! round[ 1].start    193de3bea0f4e22b9ac68d2ae9f84808
+//! round[ 1].s_box    d42711aee0bf98f1b8b45de51e415230
+//! ...
+//! ```
+//!
+//! States are printed column by column, the same order as the input and output bytes.
+use std::fmt;
+
+use crate::{reference::Aes, Error, BLOCK_SIZE};
+
+/// One of the four round transformations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+	SubBytes,
+	ShiftRows,
+	MixColumns,
+	AddRoundKey,
+}
+
+/// The state right after one step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+	/// 0 for the initial AddRoundKey, then 1 up to the number of rounds.
+	pub round: usize,
+	pub step: Step,
+	pub state: [u8; BLOCK_SIZE],
+}
+
+/// Everything that happened while encrypting one block.
+#[derive(Clone, Debug)]
+pub struct Trace {
+	pub input: [u8; BLOCK_SIZE],
+	/// The expanded key schedule, one round key per round plus the initial one.
+	pub round_keys: Vec<[u8; BLOCK_SIZE]>,
+	/// The steps in the order they ran. The last round has no MixColumns.
+	pub steps: Vec<TraceStep>,
+}
+
+/// Encrypts one block like aes_encrypt, and records every intermediate state.
+///
+/// Unlike aes_encrypt, any key size works: 16, 24 or 32 bytes.
+pub fn aes_encrypt_trace(data: [u8; BLOCK_SIZE], key: &[u8]) -> Result<Trace, Error> {
+	let aes = Aes::new(key)?;
+
+	let mut steps = Vec::new();
+	aes.encrypt_block_observed(data, |round, step, state| {
+		steps.push(TraceStep { round, step, state: *state });
+	});
+
+	Ok(Trace { input: data, round_keys: aes.round_keys().to_vec(), steps })
+}
+
+impl Trace {
+	/// The state after `step` in `round`, if that step ran.
+	pub fn state_after(&self, round: usize, step: Step) -> Option<[u8; BLOCK_SIZE]> {
+		self.steps.iter().find(|s| s.round == round && s.step == step).map(|s| s.state)
+	}
+
+	/// The ciphertext, i.e. the state after the final AddRoundKey.
+	pub fn output(&self) -> [u8; BLOCK_SIZE] {
+		self.steps.last().expect("a trace always has steps").state
+	}
+}
+
+impl fmt::Display for Trace {
+	/// The FIPS-197 Appendix C layout. The state going into a round is labelled `start`, and
+	/// `k_sch` is the round key that round's AddRoundKey uses.
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let rounds = self.round_keys.len() - 1;
+		writeln!(f, "round[{:2}].input    {}", 0, Hex(&self.input))?;
+
+		for step in &self.steps {
+			let label = match step.step {
+				Step::SubBytes => "s_box",
+				Step::ShiftRows => "s_row",
+				Step::MixColumns => "m_col",
+				Step::AddRoundKey => {
+					writeln!(
+						f,
+						"round[{:2}].k_sch    {}",
+						step.round,
+						Hex(&self.round_keys[step.round])
+					)?;
+					if step.round == rounds {
+						"output"
+					} else {
+						// The result of AddRoundKey is where the next round starts.
+						writeln!(f, "round[{:2}].start    {}", step.round + 1, Hex(&step.state))?;
+						continue;
+					}
+				},
+			};
+			writeln!(f, "round[{:2}].{:<9}{}", step.round, label, Hex(&step.state))?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Formats bytes as lowercase hex without separators, the way FIPS-197 prints them.
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::aes_encrypt;
+
+	fn block(s: &str) -> [u8; BLOCK_SIZE] {
+		let bytes: Vec<u8> = (0..s.len())
+			.step_by(2)
+			.map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+			.collect();
+		bytes.try_into().unwrap()
+	}
+
+	// The worked example of FIPS-197 Appendix B.
+	const INPUT: &str = "3243f6a8885a308d313198a2e0370734";
+	const KEY: &str = "2b7e151628aed2a6abf7158809cf4f3c";
+
+	#[test]
+	fn test_fips_197_appendix_b() {
+		let trace = aes_encrypt_trace(block(INPUT), &block(KEY)).unwrap();
+
+		let expected = [
+			(0, Step::AddRoundKey, "193de3bea0f4e22b9ac68d2ae9f84808"),
+			(1, Step::SubBytes, "d42711aee0bf98f1b8b45de51e415230"),
+			(1, Step::ShiftRows, "d4bf5d30e0b452aeb84111f11e2798e5"),
+			(1, Step::MixColumns, "046681e5e0cb199a48f8d37a2806264c"),
+			(1, Step::AddRoundKey, "a49c7ff2689f352b6b5bea43026a5049"),
+			(9, Step::AddRoundKey, "eb40f21e592e38848ba113e71bc342d2"),
+			(10, Step::SubBytes, "e9098972cb31075f3d327d94af2e2cb5"),
+			(10, Step::ShiftRows, "e9317db5cb322c723d2e895faf090794"),
+			(10, Step::AddRoundKey, "3925841d02dc09fbdc118597196a0b32"),
+		];
+		for (round, step, state) in expected {
+			assert_eq!(trace.state_after(round, step), Some(block(state)), "{round} {step:?}");
+		}
+
+		assert_eq!(trace.state_after(10, Step::MixColumns), None);
+		assert_eq!(trace.round_keys.len(), 11);
+		assert_eq!(trace.round_keys[1], block("a0fafe1788542cb123a339392a6c7605"));
+		assert_eq!(trace.round_keys[10], block("d014f9a8c9ee2589e13f0cc8b6630ca6"));
+	}
+
+	#[test]
+	fn test_output_matches_aes_encrypt() {
+		let trace = aes_encrypt_trace(block(INPUT), &block(KEY)).unwrap();
+		assert_eq!(trace.output(), aes_encrypt(block(INPUT), &block(KEY)));
+	}
+
+	#[test]
+	fn test_display() {
+		let key: Vec<u8> = (0..32).collect();
+		let trace = aes_encrypt_trace(block("00112233445566778899aabbccddeeff"), &key).unwrap();
+		let printed = trace.to_string();
+		let lines: Vec<&str> = printed.lines().collect();
+
+		// FIPS-197 Appendix C.3, AES-256.
+		assert_eq!(lines[0], "round[ 0].input    00112233445566778899aabbccddeeff");
+		assert_eq!(lines[1], "round[ 0].k_sch    000102030405060708090a0b0c0d0e0f");
+		assert_eq!(lines[2], "round[ 1].start    00102030405060708090a0b0c0d0e0f0");
+		assert_eq!(lines[3], "round[ 1].s_box    63cab7040953d051cd60e0e7ba70e18c");
+		assert_eq!(lines.last(), Some(&"round[14].output   8ea2b7ca516745bfeafc49904b496089"));
+		// input, k_sch and start, then five lines for rounds 1 to 13 and four for round 14.
+		assert_eq!(lines.len(), 3 + 13 * 5 + 4);
+	}
+}
@@ -0,0 +1,280 @@
+//! Counting what a key has encrypted, and moving on to a new key in time.
+//!
+//! Every mode wears out its key. With random nonces, GCM may only be used for 2^32 messages,
+//! and our CTR, whose nonces are only 64 bits, starts risking nonce collisions well before
+//! that. CTR and CBC leak information once about 2^64 blocks have been encrypted under one
+//! key (much less for CBC in practice), and a single GCM message can't be longer than its
+//! 32-bit block counter allows. None of the functions in the crate keep count, so nothing
+//! stops a long-running process from going past those limits.
+//!
+//! A `KeyContext` holds a key together with its `Usage` and `UsageLimits`, and has to be
+//! asked for the key before every message. Once the next message would go over a limit it
+//! either fails with `Error::KeyExhausted`, or, if it was created with `with_rekeying`,
+//! switches to the next key generation. Generation `g` is `kdf::derive_subkey(master,
+//! b"rekey", g)`, so the receiver can derive the key for any generation it is told about.
+use crate::{gcm, kdf::derive_subkey, Error, SecretKey, BLOCK_SIZE};
+
+/// How much a key has been used for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+	pub messages: u64,
+	pub blocks: u128,
+	pub bytes: u128,
+}
+
+/// The most a key may be used for. A key that has reached any one limit is used up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsageLimits {
+	pub max_messages: u64,
+	pub max_blocks: u128,
+	pub max_bytes: u128,
+	/// The most blocks a single message may take, counted like `Usage::blocks`.
+	pub max_message_blocks: u128,
+}
+
+impl UsageLimits {
+	/// GCM with random 96-bit nonces: 2^32 messages, per NIST SP 800-38D section 8.3, and
+	/// at most 2^32 - 2 blocks of plain text per message, one more with the tag.
+	pub const GCM: Self = Self {
+		max_messages: 1 << 32,
+		max_blocks: 1 << 64,
+		max_bytes: u128::MAX,
+		max_message_blocks: (1 << 32) - 1,
+	};
+
+	/// CTR with random 64-bit nonces: 2^16 messages, which keeps the chance of two of them
+	/// sharing a nonce below 2^-32, and 2^64 blocks.
+	pub const CTR: Self = Self {
+		max_messages: 1 << 16,
+		max_blocks: 1 << 64,
+		max_bytes: u128::MAX,
+		max_message_blocks: 1 << 64,
+	};
+
+	/// CBC: 2^48 blocks, well below the 2^64 where collisions become likely.
+	pub const CBC: Self = Self {
+		max_messages: u64::MAX,
+		max_blocks: 1 << 48,
+		max_bytes: u128::MAX,
+		max_message_blocks: u128::MAX,
+	};
+
+	fn allow(&self, usage: &Usage) -> bool {
+		usage.messages <= self.max_messages
+			&& usage.blocks <= self.max_blocks
+			&& usage.bytes <= self.max_bytes
+	}
+}
+
+/// A key that keeps count of what it has encrypted.
+pub struct KeyContext {
+	master: Option<SecretKey>,
+	key: SecretKey,
+	generation: u32,
+	usage: Usage,
+	limits: UsageLimits,
+}
+
+impl KeyContext {
+	/// Uses `key` itself until it reaches the limits, then fails.
+	pub fn new(key: &SecretKey, limits: UsageLimits) -> Self {
+		Self { master: None, key: key.clone(), generation: 0, usage: Usage::default(), limits }
+	}
+
+	/// Uses keys derived from `master`, moving on to the next generation whenever the
+	/// current one reaches the limits. `master` itself is never used to encrypt.
+	pub fn with_rekeying(master: &SecretKey, limits: UsageLimits) -> Self {
+		Self {
+			master: Some(master.clone()),
+			key: generation_key(master, 0),
+			generation: 0,
+			usage: Usage::default(),
+			limits,
+		}
+	}
+
+	/// The generation of the current key. Without rekeying it is always 0.
+	pub fn generation(&self) -> u32 {
+		self.generation
+	}
+
+	/// What the current key has been used for so far.
+	pub fn usage(&self) -> Usage {
+		self.usage
+	}
+
+	/// Counts a message of `len` bytes against the key and returns the generation and the
+	/// key to encrypt it with.
+	///
+	/// The block count includes one extra block per message, for the IV or nonce block,
+	/// padding or tag, so it is an upper bound for every mode in the crate. Fails with
+	/// `Error::KeyExhausted` if the message would take the key over its limits and the key
+	/// can't be replaced, or if the message alone is over the limits. Fails with
+	/// `Error::StreamTooLong` if it is over `max_message_blocks`.
+	pub fn next_key(&mut self, len: usize) -> Result<(u32, &SecretKey), Error> {
+		let message =
+			Usage { messages: 1, blocks: len.div_ceil(BLOCK_SIZE) as u128 + 1, bytes: len as u128 };
+		if message.blocks > self.limits.max_message_blocks {
+			return Err(Error::StreamTooLong);
+		}
+		if !self.limits.allow(&message) {
+			return Err(Error::KeyExhausted);
+		}
+
+		let mut usage = add(&self.usage, &message);
+		if !self.limits.allow(&usage) {
+			self.rekey()?;
+			usage = message;
+		}
+
+		self.usage = usage;
+		Ok((self.generation, &self.key))
+	}
+
+	/// The key for `generation`, e.g. to decrypt a message encrypted with an earlier one.
+	///
+	/// Without rekeying, only generation 0 exists. Fails with `Error::KeyExhausted` for
+	/// any other.
+	pub fn key_for_generation(&self, generation: u32) -> Result<SecretKey, Error> {
+		match &self.master {
+			Some(master) => Ok(generation_key(master, generation)),
+			None if generation == 0 => Ok(self.key.clone()),
+			None => Err(Error::KeyExhausted),
+		}
+	}
+
+	/// `gcm::gcm_encrypt` under the current key. Returns the generation along with the
+	/// ciphertext, which the receiver needs to pick the key to decrypt with.
+	pub fn gcm_encrypt(
+		&mut self,
+		plain_text: &[u8],
+		associated_data: &[u8],
+		nonce: &[u8; gcm::NONCE_SIZE],
+	) -> Result<(u32, Vec<u8>), Error> {
+		let (generation, key) = self.next_key(plain_text.len())?;
+		Ok((generation, gcm::gcm_encrypt(plain_text, associated_data, nonce, key)?))
+	}
+
+	/// `ctr_encrypt` under the current key. Returns the generation along with the
+	/// ciphertext.
+	pub fn ctr_encrypt(&mut self, plain_text: Vec<u8>) -> Result<(u32, Vec<u8>), Error> {
+		let (generation, key) = self.next_key(plain_text.len())?;
+		Ok((generation, crate::ctr_encrypt(plain_text, key)))
+	}
+
+	/// Switches to the next generation with a fresh count.
+	fn rekey(&mut self) -> Result<(), Error> {
+		let master = self.master.as_ref().ok_or(Error::KeyExhausted)?;
+		let generation = self.generation.checked_add(1).ok_or(Error::KeyExhausted)?;
+
+		self.key = generation_key(master, generation);
+		self.generation = generation;
+		self.usage = Usage::default();
+		Ok(())
+	}
+}
+
+fn generation_key(master: &SecretKey, generation: u32) -> SecretKey {
+	derive_subkey(master, b"rekey", &generation.to_be_bytes())
+}
+
+fn add(a: &Usage, b: &Usage) -> Usage {
+	Usage {
+		messages: a.messages.saturating_add(b.messages),
+		blocks: a.blocks.saturating_add(b.blocks),
+		bytes: a.bytes.saturating_add(b.bytes),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::ctr_decrypt;
+
+	const KEY: SecretKey = SecretKey::new([6u8; BLOCK_SIZE]);
+	const SMALL: UsageLimits =
+		UsageLimits { max_messages: 3, max_blocks: 10, max_bytes: 100, max_message_blocks: 10 };
+
+	#[test]
+	fn test_counts_usage() {
+		let mut context = KeyContext::new(&KEY, UsageLimits::GCM);
+		context.next_key(0).unwrap();
+		context.next_key(17).unwrap();
+
+		assert_eq!(context.usage(), Usage { messages: 2, blocks: 1 + 3, bytes: 17 });
+	}
+
+	#[test]
+	fn test_refuses_to_go_over_limits() {
+		let mut context = KeyContext::new(&KEY, SMALL);
+		for _ in 0..3 {
+			assert_eq!(context.next_key(1).unwrap().1, &KEY);
+		}
+		assert!(matches!(context.next_key(1), Err(Error::KeyExhausted)));
+		// A failed request doesn't count.
+		assert_eq!(context.usage().messages, 3);
+
+		let blocks_only = UsageLimits { max_blocks: 10, ..UsageLimits::CBC };
+		let mut context = KeyContext::new(&KEY, blocks_only);
+		context.next_key(7 * BLOCK_SIZE).unwrap();
+		assert!(matches!(context.next_key(BLOCK_SIZE + 1), Err(Error::KeyExhausted)));
+		assert!(context.next_key(BLOCK_SIZE).is_ok());
+	}
+
+	#[test]
+	fn test_rejects_message_over_limits() {
+		let mut context = KeyContext::with_rekeying(&KEY, SMALL);
+		assert!(matches!(context.next_key(101), Err(Error::KeyExhausted)));
+		assert_eq!(context.generation(), 0);
+	}
+
+	#[test]
+	fn test_rejects_message_too_long_for_counter() {
+		let one_block = UsageLimits { max_message_blocks: 2, ..UsageLimits::CBC };
+		let mut context = KeyContext::with_rekeying(&KEY, one_block);
+		assert!(context.next_key(BLOCK_SIZE).is_ok());
+		assert!(matches!(context.next_key(BLOCK_SIZE + 1), Err(Error::StreamTooLong)));
+		assert_eq!(context.usage().messages, 1);
+		assert_eq!(context.generation(), 0);
+	}
+
+	#[test]
+	fn test_rekeys_automatically() {
+		let mut context = KeyContext::with_rekeying(&KEY, SMALL);
+		let (generation, first_key) = context.next_key(1).unwrap();
+		let first_key = first_key.clone();
+		assert_eq!(generation, 0);
+		// The master key is never used directly.
+		assert_ne!(first_key, KEY);
+
+		context.next_key(1).unwrap();
+		context.next_key(1).unwrap();
+		let (generation, second_key) = context.next_key(1).unwrap();
+		assert_eq!(generation, 1);
+		assert_ne!(second_key, &first_key);
+		assert_eq!(context.usage().messages, 1);
+
+		assert_eq!(context.key_for_generation(0).unwrap(), first_key);
+	}
+
+	#[test]
+	fn test_key_for_generation_without_rekeying() {
+		let context = KeyContext::new(&KEY, SMALL);
+		assert_eq!(context.key_for_generation(0).unwrap(), KEY);
+		assert!(matches!(context.key_for_generation(1), Err(Error::KeyExhausted)));
+	}
+
+	#[test]
+	fn test_mode_helpers() {
+		let mut context = KeyContext::with_rekeying(&KEY, SMALL);
+		let nonce = [1u8; gcm::NONCE_SIZE];
+
+		let (generation, cipher_text) = context.gcm_encrypt(b"hello", b"", &nonce).unwrap();
+		let key = context.key_for_generation(generation).unwrap();
+		assert_eq!(gcm::gcm_decrypt(&cipher_text, b"", &nonce, &key).unwrap(), b"hello");
+
+		let (generation, cipher_text) = context.ctr_encrypt(b"world".to_vec()).unwrap();
+		let key = context.key_for_generation(generation).unwrap();
+		assert_eq!(ctr_decrypt(cipher_text, &key), b"world");
+	}
+}
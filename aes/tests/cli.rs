@@ -0,0 +1,271 @@
+//! Runs the `aes` binary the way a user would, through files, stdin and stdout.
+use std::{
+	fs,
+	io::Write,
+	path::PathBuf,
+	process::{Command, Output, Stdio},
+};
+
+use aes_modes::{ctr_decrypt, SecretKey};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f";
+// A thousand PBKDF2 iterations instead of the default Argon2id, so password tests are fast.
+const KDF: &str = "pbkdf2:1000";
+
+fn temp_path(name: &str) -> PathBuf {
+	let path = std::env::temp_dir().join(format!("aes_modes_{}_{}", std::process::id(), name));
+	let _ = fs::remove_file(&path);
+	path
+}
+
+/// Runs `aes` with `args`, feeding it `stdin`.
+fn aes(args: &[&str], stdin: &[u8]) -> Output {
+	let mut child = Command::new(env!("CARGO_BIN_EXE_aes"))
+		.args(args)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.unwrap();
+	// The tool may exit before reading its input, e.g. on a usage error.
+	let _ = child.stdin.take().unwrap().write_all(stdin);
+	child.wait_with_output().unwrap()
+}
+
+fn assert_exit_code(output: &Output, code: i32) {
+	assert_eq!(
+		output.status.code(),
+		Some(code),
+		"stderr: {}",
+		String::from_utf8_lossy(&output.stderr)
+	);
+}
+
+#[test]
+fn test_round_trip_through_pipes() {
+	for mode in ["gcm", "cbc", "ctr"] {
+		let encrypted = aes(&["encrypt", "--mode", mode, "--key", KEY], b"attack at dawn");
+		assert_exit_code(&encrypted, 0);
+		assert_ne!(encrypted.stdout, b"attack at dawn");
+
+		// The mode is read from the header.
+		let decrypted = aes(&["decrypt", "--key", KEY], &encrypted.stdout);
+		assert_exit_code(&decrypted, 0);
+		assert_eq!(decrypted.stdout, b"attack at dawn");
+	}
+}
+
+/// After the 6-byte header, `--mode ctr` output is exactly what `ctr_encrypt` produces,
+/// whose keystream `tests/golden.rs` checks against the `aes` crate.
+#[test]
+fn test_ctr_matches_library() {
+	let plain_text: Vec<u8> = (0..=255).cycle().take(100).collect();
+	let encrypted = aes(&["encrypt", "--mode", "ctr", "--key", KEY], &plain_text);
+	assert_exit_code(&encrypted, 0);
+
+	let (header, cipher_text) = encrypted.stdout.split_at(6);
+	assert_eq!(header, b"AESF\x01\x03");
+	let key = SecretKey::new(std::array::from_fn(|i| i as u8));
+	assert_eq!(ctr_decrypt(cipher_text.to_vec(), &key), plain_text);
+}
+
+#[test]
+fn test_round_trip_through_files() {
+	let (key_file, input, encrypted, decrypted) = (
+		temp_path("cli_key"),
+		temp_path("cli_plain"),
+		temp_path("cli_encrypted"),
+		temp_path("cli_decrypted"),
+	);
+	let output = aes(&["keygen", "-o", key_file.to_str().unwrap()], b"");
+	assert_exit_code(&output, 0);
+	assert_eq!(fs::read_to_string(&key_file).unwrap().trim().len(), 32);
+
+	let plain_text: Vec<u8> = (0..=255).cycle().take(5000).collect();
+	fs::write(&input, &plain_text).unwrap();
+	let key_args = ["--key-file", key_file.to_str().unwrap()];
+	let output = aes(
+		&[
+			&["encrypt", "-i", input.to_str().unwrap(), "-o", encrypted.to_str().unwrap()],
+			&key_args[..],
+		]
+		.concat(),
+		b"",
+	);
+	assert_exit_code(&output, 0);
+	let output = aes(
+		&[
+			&["decrypt", "-i", encrypted.to_str().unwrap(), "-o", decrypted.to_str().unwrap()],
+			&key_args[..],
+		]
+		.concat(),
+		b"",
+	);
+	assert_exit_code(&output, 0);
+	assert_eq!(fs::read(&decrypted).unwrap(), plain_text);
+
+	for path in [key_file, input, encrypted, decrypted] {
+		fs::remove_file(path).unwrap();
+	}
+}
+
+#[test]
+fn test_password() {
+	let encrypted = aes(&["encrypt", "--password", "hunter2", "--kdf", KDF], b"secret");
+	assert_exit_code(&encrypted, 0);
+
+	let decrypted = aes(&["decrypt", "--password", "hunter2"], &encrypted.stdout);
+	assert_exit_code(&decrypted, 0);
+	assert_eq!(decrypted.stdout, b"secret");
+
+	let wrong = aes(&["decrypt", "--password", "hunter3"], &encrypted.stdout);
+	assert_exit_code(&wrong, 3);
+	assert!(wrong.stdout.is_empty());
+}
+
+#[test]
+fn test_keystore() {
+	let keystore = temp_path("cli_keystore");
+	let keystore_args =
+		["--keystore", keystore.to_str().unwrap(), "--key-name", "backup", "--password", "pw"];
+	let output = aes(&[&["keygen", "--kdf", KDF], &keystore_args[..]].concat(), b"");
+	assert_exit_code(&output, 0);
+	// The keystore exists, so its KDF can't change.
+	let output = aes(&[&["keygen", "--kdf", KDF], &keystore_args[..]].concat(), b"");
+	assert_exit_code(&output, 2);
+	// The name is taken now.
+	let output = aes(&[&["keygen"], &keystore_args[..]].concat(), b"");
+	assert_exit_code(&output, 1);
+
+	let encrypted = aes(&[&["encrypt"], &keystore_args[..]].concat(), b"data");
+	assert_exit_code(&encrypted, 0);
+	let decrypted = aes(&[&["decrypt"], &keystore_args[..]].concat(), &encrypted.stdout);
+	assert_eq!(decrypted.stdout, b"data");
+	fs::remove_file(&keystore).unwrap();
+}
+
+#[test]
+fn test_authentication_failure() {
+	let mut encrypted = aes(&["encrypt", "--key", KEY], b"attack at dawn").stdout;
+	*encrypted.last_mut().unwrap() ^= 1;
+	let output = aes(&["decrypt", "--key", KEY], &encrypted);
+	assert_exit_code(&output, 3);
+	assert!(output.stdout.is_empty());
+	assert!(String::from_utf8_lossy(&output.stderr).contains("authentication failed"));
+
+	let truncated = aes(&["decrypt", "--key", KEY], &encrypted[..10]);
+	assert_exit_code(&truncated, 3);
+}
+
+#[test]
+fn test_padding_failure() {
+	let mut encrypted = aes(&["encrypt", "--mode", "cbc", "--key", KEY], b"fifteen bytes!!").stdout;
+	// Turns the single padding byte 0x01 into 0x11 by flipping the IV, after the 6-byte header.
+	encrypted[6 + 15] ^= 0x10;
+	let output = aes(&["decrypt", "--key", KEY], &encrypted);
+	assert_exit_code(&output, 3);
+	assert!(String::from_utf8_lossy(&output.stderr).contains("padding is invalid"));
+}
+
+#[test]
+fn test_usage_errors() {
+	for args in [
+		&[][..],
+		&["frobnicate"],
+		&["encrypt"],
+		&["encrypt", "--key"],
+		&["encrypt", "--key", KEY, "--mode", "ecb"],
+		&["encrypt", "--key", KEY, "--password", "pw"],
+		&["encrypt", "--password", "pw", "--mode", "cbc"],
+		&["encrypt", "--password", "pw", "--kdf", "md5"],
+		&["encrypt", "--key", KEY, "--verbose"],
+	] {
+		let output = aes(args, b"");
+		assert_exit_code(&output, 2);
+		assert!(String::from_utf8_lossy(&output.stderr).contains("Usage:"));
+	}
+	assert_exit_code(&aes(&["help"], b""), 0);
+}
+
+#[test]
+fn test_bad_inputs() {
+	// Not a key.
+	assert_exit_code(&aes(&["encrypt", "--key", "abcd"], b"data"), 1);
+	// Not something the tool wrote.
+	assert_exit_code(&aes(&["decrypt", "--key", KEY], b"plain text"), 1);
+	assert_exit_code(&aes(&["inspect"], b"plain text"), 1);
+
+	let encrypted = aes(&["encrypt", "--mode", "ctr", "--key", KEY], b"data").stdout;
+	let wrong_mode = aes(&["decrypt", "--mode", "cbc", "--key", KEY], &encrypted);
+	assert_exit_code(&wrong_mode, 1);
+
+	let missing = temp_path("cli_missing");
+	assert_exit_code(&aes(&["encrypt", "--key", KEY, "-i", missing.to_str().unwrap()], b""), 1);
+}
+
+#[test]
+fn test_inspect() {
+	let encrypted = aes(&["encrypt", "--mode", "cbc", "--key", KEY], b"data").stdout;
+	let output = aes(&["inspect"], &encrypted);
+	assert_exit_code(&output, 0);
+	let report = String::from_utf8(output.stdout).unwrap();
+	assert!(report.contains("format: key\nmode: cbc\n"), "{}", report);
+
+	let encrypted = aes(&["encrypt", "--password", "pw", "--kdf", KDF], b"data").stdout;
+	let report = String::from_utf8(aes(&["inspect"], &encrypted).stdout).unwrap();
+	assert!(report.contains("format: password"), "{}", report);
+	assert!(report.contains("Pbkdf2 { iterations: 1000 }"), "{}", report);
+
+	let report_file = temp_path("cli_report");
+	let output = aes(&["inspect", "-o", report_file.to_str().unwrap()], &encrypted);
+	assert_exit_code(&output, 0);
+	assert!(output.stdout.is_empty());
+	assert!(fs::read_to_string(&report_file).unwrap().contains("format: password"));
+	fs::remove_file(&report_file).unwrap();
+}
+
+#[test]
+fn test_encodings() {
+	for encoding in ["binary", "hex", "base64", "base64url", "armor"] {
+		let args = ["--encoding", encoding, "--key", KEY];
+		let encrypted = aes(&[&["encrypt"], &args[..]].concat(), b"attack at dawn");
+		assert_exit_code(&encrypted, 0);
+		if encoding != "binary" {
+			assert!(encrypted.stdout.is_ascii(), "{}", encoding);
+		}
+
+		let decrypted = aes(&[&["decrypt"], &args[..]].concat(), &encrypted.stdout);
+		assert_exit_code(&decrypted, 0);
+		assert_eq!(decrypted.stdout, b"attack at dawn");
+	}
+	assert_exit_code(&aes(&["encrypt", "--key", KEY, "--encoding", "rot13"], b""), 2);
+}
+
+#[test]
+fn test_armor() {
+	let encrypted = aes(&["encrypt", "--mode", "ctr", "--key", KEY, "--armor"], b"data").stdout;
+	let text = String::from_utf8(encrypted).unwrap();
+	assert!(
+		text.starts_with("-----BEGIN AES MESSAGE-----\nFormat: key\nMode: ctr\n\n"),
+		"{}",
+		text
+	);
+	assert!(text.ends_with("-----END AES MESSAGE-----\n"), "{}", text);
+
+	// Pasted into an email, with line endings changed on the way.
+	let email = format!("Here you go:\r\n\r\n{}\r\nCheers\r\n", text.replace('\n', "\r\n"));
+	let decrypted = aes(&["decrypt", "--key", KEY, "--armor"], email.as_bytes());
+	assert_exit_code(&decrypted, 0);
+	assert_eq!(decrypted.stdout, b"data");
+
+	let report = aes(&["inspect", "--armor"], text.as_bytes());
+	assert!(String::from_utf8(report.stdout).unwrap().contains("mode: ctr"));
+
+	// A damaged paste fails the checksum before decryption is attempted.
+	let data_line = text.lines().nth(4).unwrap();
+	let replacement = if data_line.starts_with('A') { "B" } else { "A" };
+	let damaged = text.replace(data_line, &format!("{}{}", replacement, &data_line[1..]));
+	let output = aes(&["decrypt", "--key", KEY, "--armor"], damaged.as_bytes());
+	assert_exit_code(&output, 1);
+	assert!(String::from_utf8_lossy(&output.stderr).contains("not validly encoded"));
+}
@@ -0,0 +1,121 @@
+//! Golden ciphertexts for CBC and CTR.
+//!
+//! Each fixture line is a plaintext length and the ciphertext of that many bytes of
+//! `sample`, encrypted under `KEY` with the IV or nonce drawn from a ChaCha20 RNG seeded
+//! with `SEED`, in file order. Any change to the output of a mode, intended or not, fails
+//! these tests. If it was intended, regenerate the fixtures with
+//! `UPDATE_GOLDEN=1 cargo test --test golden` and review the diff.
+use std::{fs, path::PathBuf};
+
+use aes::{
+	cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+	Aes128,
+};
+use aes_modes::{
+	cbc_decrypt, cbc_encrypt_with_rng, ctr_decrypt, ctr_encrypt_with_rng, SecretKey, BLOCK_SIZE,
+};
+use rand_chacha::{rand_core::SeedableRng, ChaCha20Rng};
+
+const KEY: SecretKey = SecretKey::new([
+	0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+]);
+const SEED: u64 = 2024;
+const LENGTHS: [usize; 7] = [0, 1, 15, 16, 17, 32, 100];
+
+fn sample(len: usize) -> Vec<u8> {
+	(0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn fixture_path(name: &str) -> PathBuf {
+	PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// Encrypts every length in order with one seeded RNG, and compares the result with the
+/// fixture (or overwrites it, with `UPDATE_GOLDEN` set).
+fn check_golden(name: &str, encrypt: impl Fn(Vec<u8>, &mut ChaCha20Rng) -> Vec<u8>) {
+	let mut rng = ChaCha20Rng::seed_from_u64(SEED);
+	let actual: String = LENGTHS
+		.iter()
+		.map(|&len| format!("{} {}\n", len, hex(&encrypt(sample(len), &mut rng))))
+		.collect();
+
+	let path = fixture_path(name);
+	if std::env::var_os("UPDATE_GOLDEN").is_some() {
+		fs::write(&path, &actual).unwrap();
+	}
+	let expected = fs::read_to_string(&path).unwrap();
+	assert_eq!(actual, expected, "{} no longer matches, see the top of this file", name);
+}
+
+/// Parses a fixture back into (length, ciphertext) pairs.
+fn read_fixture(name: &str) -> Vec<(usize, Vec<u8>)> {
+	let contents = fs::read_to_string(fixture_path(name)).unwrap();
+	contents
+		.lines()
+		.map(|line| {
+			let (len, cipher_text) = line.split_once(' ').unwrap();
+			let cipher_text = (0..cipher_text.len())
+				.step_by(2)
+				.map(|i| u8::from_str_radix(&cipher_text[i..i + 2], 16).unwrap())
+				.collect();
+			(len.parse().unwrap(), cipher_text)
+		})
+		.collect()
+}
+
+#[test]
+fn test_cbc_golden() {
+	check_golden("cbc.txt", |plain_text, rng| cbc_encrypt_with_rng(plain_text, &KEY, rng));
+}
+
+#[test]
+fn test_ctr_golden() {
+	check_golden("ctr.txt", |plain_text, rng| ctr_encrypt_with_rng(plain_text, &KEY, rng));
+}
+
+#[test]
+fn test_golden_fixtures_decrypt() {
+	for (len, cipher_text) in read_fixture("cbc.txt") {
+		assert_eq!(cbc_decrypt(cipher_text, &KEY), sample(len));
+	}
+	for (len, cipher_text) in read_fixture("ctr.txt") {
+		assert_eq!(ctr_decrypt(cipher_text, &KEY), sample(len));
+	}
+}
+
+/// The CTR fixture decrypted with the `aes` crate, without going through this crate's CTR
+/// code: block `i` of the keystream is AES of the nonce followed by `i`, both big-endian.
+#[test]
+fn test_ctr_fixture_matches_reference() {
+	let cipher = Aes128::new(GenericArray::from_slice(KEY.expose_secret()));
+	for (len, cipher_text) in read_fixture("ctr.txt") {
+		let (nonce, body) = cipher_text.split_at(8);
+		let mut plain_text = body.to_vec();
+		for (counter, chunk) in (0u64..).zip(plain_text.chunks_mut(BLOCK_SIZE)) {
+			let mut block =
+				GenericArray::clone_from_slice(&[nonce, &counter.to_be_bytes()].concat());
+			cipher.encrypt_block(&mut block);
+			chunk.iter_mut().zip(block).for_each(|(x1, x2)| *x1 ^= x2);
+		}
+
+		// The crate pads CTR messages like CBC ones.
+		let pad_len = *plain_text.last().unwrap() as usize;
+		assert!(plain_text[len..].iter().all(|&byte| byte as usize == pad_len));
+		assert_eq!(plain_text[..len], sample(len));
+	}
+}
+
+#[test]
+fn test_same_seed_same_ciphertext() {
+	let encrypt = |seed| {
+		let mut rng = ChaCha20Rng::seed_from_u64(seed);
+		cbc_encrypt_with_rng(sample(BLOCK_SIZE), &KEY, &mut rng)
+	};
+
+	assert_eq!(encrypt(1), encrypt(1));
+	assert_ne!(encrypt(1), encrypt(2));
+}